@@ -2,15 +2,19 @@ from __future__ import annotations
 
 from typing import Annotated, List
 
+import base64
+import binascii
+
 import aio_pika
+import grpc  # type: ignore
 import pydantic
-from fastapi import APIRouter, Depends, HTTPException, Query, WebSocket
+from fastapi import APIRouter, Depends, Header, HTTPException, Query, WebSocket, status
 
 from ..core import amqp, rpc
 from ..proto import channels_pb2, channels_pb2_grpc
 from ..models.adapters import get_converter
 from ..models.authorization import AccountToken
-from ..models.channels import Channel, Message
+from ..models.channels import Channel, ChannelPage, Message
 from ..models.users import User
 
 
@@ -33,6 +37,9 @@ class __CreateChannelBody(pydantic.BaseModel):
 async def create_channel(
     body: __CreateChannelBody,
     owner: Annotated[User, Depends(AccountToken.verify)],
+    idempotency_key: Annotated[
+        str, Header(description="A key identifying retries of the same request")
+    ] = "",
 ) -> Channel:
     stub = channels_pb2_grpc.ChannelServiceStub(await rpc())
     channel: channels_pb2.PChannel = await stub.CreateChannel(
@@ -40,6 +47,7 @@ async def create_channel(
             name=body.name,
             description=body.description,
             owner_id=owner.id,
+            idempotency_key=idempotency_key,
         )
     )
 
@@ -58,13 +66,40 @@ async def create_channel(
 @router.get(
     "/",
     name="List channels",
-    description="Query all channels",
+    description="Query a page of channels, a page may hold fewer channels than requested while more remain",
 )
-async def list_channels() -> List[Channel]:
+async def list_channels(
+    *,
+    cursor: Annotated[str, Query(description="The cursor returned with the previous page")] = "",
+    page_size: Annotated[int, Query(description="The maximum number of channels to return (maximum 500)")] = 50,
+    owner_id: Annotated[int, Query(description="Only return channels owned by this user")] = 0,
+    member_id: Annotated[int, Query(description="Only return channels this user is a member of")] = 0,
+    name_prefix: Annotated[str, Query(description="Only return channels whose name starts with this prefix")] = "",
+    newest: Annotated[bool, Query(description="Sort channels from newest to oldest")] = False,
+) -> ChannelPage:
+    try:
+        paging_state = base64.urlsafe_b64decode(cursor)
+    except (binascii.Error, ValueError):
+        raise HTTPException(400, detail="Invalid cursor")
+
     stub = channels_pb2_grpc.ChannelServiceStub(await rpc())
-    c: channels_pb2.PChannelQueryResult = await stub.Query(channels_pb2.PChannelQuery(id=0))
+    c: channels_pb2.PChannelQueryResult = await stub.Query(
+        channels_pb2.PChannelQuery(
+            id=0,
+            paging_state=paging_state,
+            page_size=page_size,
+            owner_id=owner_id,
+            member_id=member_id,
+            name_prefix=name_prefix,
+            newest=newest,
+        )
+    )
+
     converter = get_converter(channels_pb2.PChannel, Channel)
-    return [converter(channel) for channel in c.channels]
+    return ChannelPage(
+        channels=[converter(channel) for channel in c.channels],
+        cursor=base64.urlsafe_b64encode(c.paging_state).decode(),
+    )
 
 
 @router.get(
@@ -87,7 +122,23 @@ async def query_channel(channel_id: int) -> Channel:
     "/{channel_id}/ws",
     name="Listen to messages in real-time",
 )
-async def receive_messages(ws: WebSocket, channel_id: int) -> None:
+async def receive_messages(
+    ws: WebSocket,
+    channel_id: int,
+    token: Annotated[str, Query(description="The access token of the user, as browsers cannot send headers with websockets")],
+    last_seen_id: Annotated[int, Query(description="Replay the messages created after this snowflake ID first")] = 0,
+) -> None:
+    stub = channels_pb2_grpc.ChannelServiceStub(await rpc())
+    try:
+        user = await AccountToken.verify(token)
+
+        # Users banned from the channel cannot read its history, and cannot listen to it either
+        await stub.History(channels_pb2.PHistoryQuery(id=channel_id, limit=1, user_id=user.id))
+
+    except (HTTPException, grpc.aio.AioRpcError):
+        await ws.close(code=status.WS_1008_POLICY_VIOLATION)
+        return
+
     await ws.accept()
     channel = await amqp()
 
@@ -98,14 +149,59 @@ async def receive_messages(ws: WebSocket, channel_id: int) -> None:
     queue = await channel.declare_queue()
     await queue.bind(exchange, f"channel-{channel_id}")
 
+    converter = get_converter(channels_pb2.PMessage, Message)
+
+    async def catchup() -> int:
+        """Send every message created after `last_seen_id`, returning the latest sequence number of the channel."""
+        nonlocal last_seen_id
+        while True:
+            result: channels_pb2.PCatchupResult = await stub.Catchup(
+                channels_pb2.PCatchupRequest(
+                    channel_id=channel_id, last_seen_id=last_seen_id, user_id=user.id
+                )
+            )
+            for m in result.messages:
+                await ws.send_json(converter(m).model_dump())
+                last_seen_id = m.id
+
+            if result.complete:
+                return result.sequence
+
+    # The queue is bound before catching up, so that messages created in the meantime are not missed
+    sequence = await catchup() if last_seen_id else None
+
     async with queue.iterator() as q:
         data = channels_pb2.PMessage()
-        converter = get_converter(channels_pb2.PMessage, Message)
+        event = channels_pb2.PChannelEvent()
 
         async for message in q:
             async with message.process():
-                data.ParseFromString(message.body)
-                await ws.send_json(converter(data).model_dump())
+                # Other events share the routing key of the channel to keep sequence numbers contiguous, but are
+                # tagged with a message type and not relayed to websocket clients yet
+                is_message = not message.type
+                if is_message:
+                    data.ParseFromString(message.body)
+
+                elif message.type == "channel":
+                    event.ParseFromString(message.body)
+                    if (
+                        event.event_type == channels_pb2.PChannelEventType.MEMBER_BANNED
+                        and event.ban.user.id == user.id
+                    ):
+                        await ws.close(code=status.WS_1008_POLICY_VIOLATION, reason="Banned from the channel")
+                        return
+
+                received = (message.headers or {}).get("sequence")
+                if sequence is not None and received is not None and received > sequence + 1:
+                    # Some messages were not delivered to this queue, fetch them from the history
+                    sequence = await catchup()
+
+                if is_message and data.id > last_seen_id:
+                    await ws.send_json(converter(data).model_dump())
+                    last_seen_id = data.id
+
+                if received is not None:
+                    sequence = max(sequence or 0, received)
 
 
 class __CreateMessageBody(pydantic.BaseModel):
@@ -121,6 +217,9 @@ async def create_message(
     channel_id: int,
     body: __CreateMessageBody,
     author: Annotated[User, Depends(AccountToken.verify)],
+    idempotency_key: Annotated[
+        str, Header(description="A key identifying retries of the same request")
+    ] = "",
 ) -> Message:
     stub = channels_pb2_grpc.ChannelServiceStub(await rpc())
     m: channels_pb2.PMessage = await stub.CreateMessage(
@@ -128,6 +227,7 @@ async def create_message(
             content=body.content,
             author_id=author.id,
             channel_id=channel_id,
+            idempotency_key=idempotency_key,
         )
     )
     return Message(
@@ -163,6 +263,7 @@ async def list_messages(
     before_id: Annotated[int, Query(description="The upper limit of snowflake ID")] = (1 << 53) - 1,  # https://github.com/fastapi/fastapi/discussions/6237
     after_id: Annotated[int, Query(description="The lower limit of snowflake ID")] = 0,
     limit: Annotated[int, Query(description="The maximum number of messages to return (maximum 50)")] = 50,
+    user: Annotated[User, Depends(AccountToken.verify)],
 ) -> List[Message]:
     stub = channels_pb2_grpc.ChannelServiceStub(await rpc())
     m: channels_pb2.PHistoryQueryResult = await stub.History(
@@ -172,6 +273,7 @@ async def list_messages(
             before_id=before_id,
             after_id=after_id,
             limit=limit,
+            user_id=user.id,
         )
     )
 
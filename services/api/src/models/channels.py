@@ -1,11 +1,13 @@
 from __future__ import annotations
 
+from typing import Annotated, List
+
 import pydantic
 
 from .users import User
 
 
-__all__ = ("Channel", "Message",)
+__all__ = ("Channel", "ChannelPage", "Message",)
 
 
 class Channel(pydantic.BaseModel):
@@ -15,6 +17,11 @@ class Channel(pydantic.BaseModel):
     owner: User
 
 
+class ChannelPage(pydantic.BaseModel):
+    channels: List[Channel]
+    cursor: Annotated[str, pydantic.Field(description="The cursor of the next page, empty if there are no more channels")]
+
+
 class Message(pydantic.BaseModel):
     id: int
     content: str
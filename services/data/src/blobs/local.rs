@@ -0,0 +1,186 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use futures::stream;
+use tokio::fs;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use super::{BlobStore, BlobStream, BlobWriter};
+
+/// Size of the chunks blobs are read in.
+const _CHUNK_SIZE: usize = 64 * 1024;
+
+/// Directory of blobs being written, relative to the root directory.
+const _PARTIAL_DIRECTORY: &str = ".partial";
+
+/// Age after which a temporary file is considered left behind by a writer which crashed, rather than still being
+/// written by another process sharing the directory.
+const _STALE_PARTIAL_AGE: Duration = Duration::from_secs(24 * 3600);
+
+/// Blobs stored as files in a local directory, one file per key.
+///
+/// Blobs are written to a temporary file first, then atomically renamed into place once committed.
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    /// Use `root` to store blobs, creating it if needed.
+    ///
+    /// Temporary files older than [`_STALE_PARTIAL_AGE`] are removed, since they were never committed nor aborted.
+    pub async fn open(root: impl Into<PathBuf>) -> Result<Self, Box<dyn std::error::Error>> {
+        let root = root.into();
+        let partial = root.join(_PARTIAL_DIRECTORY);
+        fs::create_dir_all(&partial).await?;
+
+        let mut entries = fs::read_dir(&partial).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let modified = entry.metadata().await?.modified()?;
+            if modified
+                .elapsed()
+                .is_ok_and(|age| age >= _STALE_PARTIAL_AGE)
+            {
+                match fs::remove_file(entry.path()).await {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(Self { root })
+    }
+
+    /// Path of the file of `key`, rejecting keys which could escape the root directory.
+    fn path(&self, key: &str) -> Result<PathBuf, Box<dyn std::error::Error + Send + Sync>> {
+        if key.is_empty()
+            || !key
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!("Invalid blob key {:?}", key).into());
+        }
+
+        Ok(self.root.join(key))
+    }
+}
+
+/// A blob being written to a temporary file, see [`LocalBlobStore`].
+struct _LocalBlobWriter {
+    file: fs::File,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+#[tonic::async_trait]
+impl BlobWriter for _LocalBlobWriter {
+    async fn write(
+        &mut self,
+        chunk: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.file.write_all(chunk).await?;
+        Ok(())
+    }
+
+    async fn commit(mut self: Box<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.file.sync_all().await?;
+        fs::rename(&self.partial, &self.path).await?;
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _remove(&self.partial).await
+    }
+}
+
+/// Remove a file, ignoring files which do not exist.
+async fn _remove(path: &Path) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    match fs::remove_file(path).await {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Read the next chunk of `file`, or `None` once the end of the file is reached.
+async fn _read_chunk(
+    mut file: fs::File,
+) -> Result<Option<(Vec<u8>, fs::File)>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut chunk = vec![0; _CHUNK_SIZE];
+    let read = file.read(&mut chunk).await?;
+    if read == 0 {
+        return Ok(None);
+    }
+
+    chunk.truncate(read);
+    Ok(Some((chunk, file)))
+}
+
+#[tonic::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn write(
+        &self,
+        key: &str,
+    ) -> Result<Box<dyn BlobWriter>, Box<dyn std::error::Error + Send + Sync>> {
+        let path = self.path(key)?;
+
+        // Concurrent writers of the same key each get their own temporary file, the last commit wins
+        let partial = self.root.join(_PARTIAL_DIRECTORY).join(format!(
+            "{}-{:016x}",
+            key,
+            rand::random::<u64>()
+        ));
+        let file = fs::File::create(&partial).await?;
+
+        Ok(Box::new(_LocalBlobWriter {
+            file,
+            partial,
+            path,
+        }))
+    }
+
+    async fn read(
+        &self,
+        key: &str,
+    ) -> Result<Option<BlobStream>, Box<dyn std::error::Error + Send + Sync>> {
+        let file = match fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        Ok(Some(Box::pin(stream::try_unfold(file, _read_chunk))))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        _remove(&self.path(key)?).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn open_removes_stale_partial_files_only() {
+        let root =
+            std::env::temp_dir().join(format!("data-service-test-{}", rand::random::<u64>()));
+        let partial = root.join(_PARTIAL_DIRECTORY);
+        std::fs::create_dir_all(&partial).unwrap();
+
+        let stale = partial.join("stale");
+        std::fs::File::create(&stale)
+            .unwrap()
+            .set_modified(SystemTime::now() - _STALE_PARTIAL_AGE - Duration::from_secs(60))
+            .unwrap();
+        let fresh = partial.join("fresh");
+        std::fs::File::create(&fresh).unwrap();
+
+        LocalBlobStore::open(&root).await.unwrap();
+        assert!(!stale.exists());
+        assert!(fresh.exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,41 @@
+use std::pin::Pin;
+
+use futures::Stream;
+
+pub mod local;
+
+/// A stream of chunks of a blob, see [`BlobStore::read`].
+pub type BlobStream =
+    Pin<Box<dyn Stream<Item = Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>> + Send>>;
+
+/// A blob being written, which only becomes visible to readers once committed.
+#[tonic::async_trait]
+pub trait BlobWriter: Send {
+    /// Append a chunk to the blob.
+    async fn write(&mut self, chunk: &[u8])
+        -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Make the blob visible under its key.
+    async fn commit(self: Box<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Discard everything written so far.
+    async fn abort(self: Box<Self>) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Start writing a new blob under `key`, replacing any existing blob once committed.
+    async fn write(
+        &self,
+        key: &str,
+    ) -> Result<Box<dyn BlobWriter>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Read a committed blob in chunks, or `None` if there is no blob under `key`.
+    async fn read(
+        &self,
+        key: &str,
+    ) -> Result<Option<BlobStream>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Delete a blob. This operation is idempotent.
+    async fn delete(&self, key: &str) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
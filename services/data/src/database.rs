@@ -0,0 +1,136 @@
+use std::fmt;
+use std::str::FromStr;
+
+use scylla::statement::{Consistency, SerialConsistency};
+
+/// Replication strategy of the keyspaces created by the data service.
+#[derive(Clone, Debug)]
+pub enum Replication {
+    /// `SimpleStrategy` with a single replication factor, suitable for development clusters.
+    Simple(u32),
+
+    /// `NetworkTopologyStrategy` with a replication factor per datacenter.
+    NetworkTopology(Vec<(String, u32)>),
+}
+
+impl fmt::Display for Replication {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Simple(factor) => write!(
+                f,
+                "{{'class': 'SimpleStrategy', 'replication_factor': {}}}",
+                factor
+            ),
+            Self::NetworkTopology(datacenters) => {
+                write!(f, "{{'class': 'NetworkTopologyStrategy'")?;
+                for (datacenter, factor) in datacenters {
+                    write!(f, ", '{}': {}", datacenter.replace('\'', "''"), factor)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl FromStr for Replication {
+    type Err = String;
+
+    /// Parse either `simple:<factor>` or a comma-separated list of `<datacenter>:<factor>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn _factor(s: &str) -> Result<u32, String> {
+            match s.trim().parse::<u32>() {
+                Ok(factor) if factor > 0 => Ok(factor),
+                _ => Err(format!("Invalid replication factor {:?}", s)),
+            }
+        }
+
+        if let Some(factor) = s.strip_prefix("simple:") {
+            return Ok(Self::Simple(_factor(factor)?));
+        }
+
+        let mut datacenters = Vec::new();
+        for entry in s.split(",") {
+            let (datacenter, factor) = entry
+                .split_once(":")
+                .ok_or_else(|| format!("Expected <datacenter>:<factor>, got {:?}", entry))?;
+            datacenters.push((datacenter.trim().to_string(), _factor(factor)?));
+        }
+
+        Ok(Self::NetworkTopology(datacenters))
+    }
+}
+
+/// Validate a keyspace name passed on the command line.
+pub fn parse_keyspace(s: &str) -> Result<String, String> {
+    if !s.is_empty() && s.len() <= 48 && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        Ok(s.to_string())
+    } else {
+        Err(format!("Invalid keyspace name {:?}", s))
+    }
+}
+
+/// Keyspace names and replication settings of the database.
+#[derive(Clone, Debug)]
+pub struct DatabaseLayout {
+    pub accounts: String,
+    pub config: String,
+    pub data: String,
+    pub replication: Replication,
+}
+
+impl DatabaseLayout {
+    /// Substitute the `${accounts}`, `${config}`, `${data}` and `${replication}` placeholders in a CQL statement.
+    pub fn resolve(&self, cql: &str) -> String {
+        cql.replace("${accounts}", &self.accounts)
+            .replace("${config}", &self.config)
+            .replace("${data}", &self.data)
+            .replace("${replication}", &self.replication.to_string())
+    }
+}
+
+/// Parse a consistency level passed on the command line, e.g. `local-quorum`.
+pub fn parse_consistency(s: &str) -> Result<Consistency, String> {
+    match s.to_lowercase().replace('_', "-").as_str() {
+        "any" => Ok(Consistency::Any),
+        "one" => Ok(Consistency::One),
+        "two" => Ok(Consistency::Two),
+        "three" => Ok(Consistency::Three),
+        "quorum" => Ok(Consistency::Quorum),
+        "all" => Ok(Consistency::All),
+        "local-quorum" => Ok(Consistency::LocalQuorum),
+        "each-quorum" => Ok(Consistency::EachQuorum),
+        "local-one" => Ok(Consistency::LocalOne),
+        _ => Err(format!("Invalid consistency level {:?}", s)),
+    }
+}
+
+/// Parse a serial consistency level passed on the command line, either `serial` or `local-serial`.
+pub fn parse_serial_consistency(s: &str) -> Result<SerialConsistency, String> {
+    match s.to_lowercase().replace('_', "-").as_str() {
+        "serial" => Ok(SerialConsistency::Serial),
+        "local-serial" => Ok(SerialConsistency::LocalSerial),
+        _ => Err(format!("Invalid serial consistency level {:?}", s)),
+    }
+}
+
+/// Consistency levels of each class of database operations.
+#[derive(Clone, Debug)]
+pub struct ConsistencyProfile {
+    /// Reads of user accounts, e.g. during login
+    pub account_reads: Consistency,
+
+    /// Creation of user accounts
+    pub account_writes: Consistency,
+
+    /// Reads of channels and messages
+    pub reads: Consistency,
+
+    /// Unconditional writes of channels, messages and their indexes
+    pub writes: Consistency,
+
+    /// Lightweight transactions (`IF NOT EXISTS`, `IF ...`) outside of user accounts
+    pub lightweight_transactions: Consistency,
+
+    /// Serial consistency of every lightweight transaction
+    pub serial: SerialConsistency,
+}
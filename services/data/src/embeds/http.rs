@@ -0,0 +1,137 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+use super::Fetcher;
+
+/// Time allowed to fetch a whole page, including redirects.
+const _TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of redirects followed.
+const _MAX_REDIRECTS: usize = 5;
+
+/// Maximum number of bytes read from a page, metadata is expected near its beginning.
+const _MAX_BYTES: usize = 512 * 1024;
+
+/// Whether `ip` can be reached from the internet, so that links cannot be used to probe internal services.
+fn _is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || a == 0
+                || (a == 100 && (64..128).contains(&b)))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => _is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || (first & 0xfe00) == 0xfc00
+                    || (first & 0xffc0) == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Whether the host of `url` is an IP address which is not public, such links bypass [`_PublicResolver`].
+fn _is_private_literal(url: &reqwest::Url) -> bool {
+    url.host_str()
+        .map(|host| host.trim_start_matches('[').trim_end_matches(']'))
+        .and_then(|host| host.parse::<IpAddr>().ok())
+        .is_some_and(|ip| !_is_public(ip))
+}
+
+/// Resolve `name`, keeping public addresses only.
+async fn _resolve_public(name: Name) -> Result<Addrs, Box<dyn std::error::Error + Send + Sync>> {
+    let addresses = tokio::net::lookup_host((name.as_str(), 0))
+        .await?
+        .filter(|address: &SocketAddr| _is_public(address.ip()))
+        .collect::<Vec<_>>();
+    if addresses.is_empty() {
+        return Err(format!("{} has no public address", name.as_str()).into());
+    }
+
+    Ok(Box::new(addresses.into_iter()))
+}
+
+/// A DNS resolver dropping every address which is not public, see [`_is_public`].
+struct _PublicResolver;
+
+impl Resolve for _PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(_resolve_public(name))
+    }
+}
+
+/// Pages fetched over HTTP(S), restricted to public addresses.
+pub struct HttpFetcher {
+    client: reqwest::Client,
+}
+
+impl HttpFetcher {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let client = reqwest::Client::builder()
+            .timeout(_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::custom(|attempt| {
+                if attempt.previous().len() >= _MAX_REDIRECTS {
+                    attempt.error("Too many redirects")
+                } else if _is_private_literal(attempt.url()) {
+                    attempt.stop()
+                } else {
+                    attempt.follow()
+                }
+            }))
+            .dns_resolver(Arc::new(_PublicResolver))
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build()?;
+
+        Ok(Self { client })
+    }
+}
+
+#[tonic::async_trait]
+impl Fetcher for HttpFetcher {
+    async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let parsed = reqwest::Url::parse(url)?;
+        if _is_private_literal(&parsed) {
+            return Ok(None);
+        }
+
+        let mut response = self.client.get(parsed).send().await?;
+        let html = response
+            .headers()
+            .get(reqwest::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.starts_with("text/html"));
+        if !response.status().is_success() || !html {
+            return Ok(None);
+        }
+
+        let mut body = Vec::new();
+        while let Some(chunk) = response.chunk().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= _MAX_BYTES {
+                body.truncate(_MAX_BYTES);
+                break;
+            }
+        }
+
+        Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+    }
+}
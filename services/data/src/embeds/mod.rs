@@ -0,0 +1,256 @@
+use crate::storage::Embed;
+
+pub mod http;
+pub mod noop;
+
+/// Maximum number of links unfurled per message.
+pub const MAX_LINKS_PER_MESSAGE: usize = 5;
+
+/// Maximum length of an embed title, in characters.
+const _MAX_TITLE_LENGTH: usize = 256;
+
+/// Maximum length of an embed description, in characters.
+const _MAX_DESCRIPTION_LENGTH: usize = 1024;
+
+#[tonic::async_trait]
+pub trait Fetcher: Send + Sync {
+    /// Fetch the HTML document at `url`, or `None` if there is nothing to unfurl (e.g. not an HTML page).
+    async fn fetch(
+        &self,
+        url: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// Find the distinct `http://` and `https://` links of `content`, at most [`MAX_LINKS_PER_MESSAGE`].
+///
+/// Links are delimited by whitespace and angle brackets, trailing punctuation is ignored.
+pub fn extract_urls(content: &str) -> Vec<String> {
+    let mut urls = Vec::new();
+    for word in content.split(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+        let start = match word.find("http://").or_else(|| word.find("https://")) {
+            Some(start) => start,
+            None => continue,
+        };
+
+        let url = word[start..].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+        if url.len() > "https://".len() && !urls.iter().any(|u| u == url) {
+            urls.push(url.to_string());
+            if urls.len() == MAX_LINKS_PER_MESSAGE {
+                break;
+            }
+        }
+    }
+
+    urls
+}
+
+/// Decode the few HTML entities commonly found in metadata.
+fn _unescape(text: &str) -> String {
+    text.replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&#x27;", "'")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+/// Collapse whitespace and truncate `text` to `max_length` characters.
+fn _clean(text: &str, max_length: usize) -> String {
+    _unescape(&text.split_whitespace().collect::<Vec<_>>().join(" "))
+        .chars()
+        .take(max_length)
+        .collect()
+}
+
+/// Parse the attributes of an HTML tag, e.g. `property="og:title" content="..."`, lowercasing their names.
+fn _attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = Vec::new();
+    let mut rest = tag;
+    while let Some(equals) = rest.find('=') {
+        let name = rest[..equals]
+            .rsplit(|c: char| c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+
+        let value = rest[equals + 1..].trim_start();
+        let (value, remaining) = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => match value[1..].find(quote) {
+                Some(end) => (&value[1..end + 1], &value[end + 2..]),
+                None => (&value[1..], ""),
+            },
+            _ => {
+                let end = value.find(char::is_whitespace).unwrap_or(value.len());
+                (&value[..end], &value[end..])
+            }
+        };
+
+        attributes.push((name, value.to_string()));
+        rest = remaining;
+    }
+
+    attributes
+}
+
+/// Resolve a possibly relative image link of the page at `url`, dropping anything which is not HTTP(S).
+fn _resolve(url: &str, link: &str) -> Option<String> {
+    if link.starts_with("http://") || link.starts_with("https://") {
+        return Some(link.to_string());
+    }
+
+    let scheme_end = url.find("://")? + 3;
+    if let Some(rest) = link.strip_prefix("//") {
+        return Some(format!("{}{}", &url[..scheme_end], rest));
+    }
+
+    if link.starts_with('/') {
+        let origin_end = url[scheme_end..]
+            .find('/')
+            .map_or(url.len(), |i| scheme_end + i);
+        return Some(format!("{}{}", &url[..origin_end], link));
+    }
+
+    None
+}
+
+/// Extract the Open Graph (or Twitter card) metadata of an HTML document, falling back to its `<title>`.
+///
+/// Returns `None` if the document has neither a title nor a description.
+pub fn parse(url: &str, html: &str) -> Option<Embed> {
+    // Metadata lives in `<head>`, there is no need to look further
+    let lowercase = html.to_ascii_lowercase();
+    let head_end = lowercase.find("</head>").unwrap_or(html.len());
+    let (html, lowercase) = (&html[..head_end], &lowercase[..head_end]);
+
+    let mut properties = std::collections::HashMap::new();
+    let mut offset = 0;
+    while let Some(start) = lowercase[offset..].find("<meta") {
+        let start = offset + start;
+        let end = lowercase[start..]
+            .find('>')
+            .map_or(html.len(), |i| start + i);
+        let attributes = _attributes(&html[start + "<meta".len()..end]);
+        offset = end;
+
+        let key = attributes
+            .iter()
+            .find(|(name, _)| name == "property" || name == "name")
+            .map(|(_, value)| value.to_ascii_lowercase());
+        let content = attributes
+            .iter()
+            .find(|(name, _)| name == "content")
+            .map(|(_, value)| value.clone());
+        if let (Some(key), Some(content)) = (key, content) {
+            properties.entry(key).or_insert(content);
+        }
+    }
+
+    let first = |keys: &[&str]| {
+        keys.iter()
+            .find_map(|key| properties.get(*key))
+            .filter(|value| !value.trim().is_empty())
+            .cloned()
+    };
+
+    let title = first(&["og:title", "twitter:title"]).or_else(|| {
+        let start = lowercase.find("<title")?;
+        let start = start + lowercase[start..].find('>')? + 1;
+        let end = start + lowercase[start..].find("</title>")?;
+        Some(html[start..end].to_string())
+    });
+    let description = first(&["og:description", "twitter:description", "description"]);
+    if title.is_none() && description.is_none() {
+        return None;
+    }
+
+    Some(Embed {
+        url: url.to_string(),
+        title: title.map_or_else(String::new, |t| _clean(&t, _MAX_TITLE_LENGTH)),
+        description: description.map_or_else(String::new, |d| _clean(&d, _MAX_DESCRIPTION_LENGTH)),
+        image_url: first(&["og:image", "twitter:image"])
+            .and_then(|image| _resolve(url, &_unescape(image.trim())))
+            .unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_distinct_links() {
+        for (content, expected) in [
+            ("no links here", &[][..]),
+            (
+                "see https://a.b/c, and http://d.e/f.",
+                &["https://a.b/c", "http://d.e/f"][..],
+            ),
+            ("(https://a.b) https://a.b!", &["https://a.b"][..]),
+            ("<https://a.b/x>y", &["https://a.b/x"][..]),
+            ("link:https://a.b/?q='1'", &["https://a.b/?q='1"][..]),
+            ("https:// ftp://a.b", &[][..]),
+        ] {
+            assert_eq!(extract_urls(content), expected, "{:?}", content);
+        }
+
+        let many = (0..10)
+            .map(|i| format!("https://a.b/{}", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        assert_eq!(extract_urls(&many).len(), MAX_LINKS_PER_MESSAGE);
+    }
+
+    #[test]
+    fn parse_open_graph_metadata() {
+        let html = r#"<html><head>
+            <title>Fallback</title>
+            <meta property="og:title" content="The  &quot;Title&quot;">
+            <META NAME='description' CONTENT='A
+                description'>
+            <meta property=og:image content=/image.png>
+            </head><body><meta property="og:description" content="Ignored"></body></html>"#;
+        let embed = parse("https://a.b/page", html).unwrap();
+        assert_eq!(embed.url, "https://a.b/page");
+        assert_eq!(embed.title, "The \"Title\"");
+        assert_eq!(embed.description, "A description");
+        assert_eq!(embed.image_url, "https://a.b/image.png");
+    }
+
+    #[test]
+    fn parse_falls_back_to_the_title() {
+        let embed = parse("https://a.b", "<title>Only a title</title>").unwrap();
+        assert_eq!(embed.title, "Only a title");
+        assert_eq!(embed.description, "");
+        assert_eq!(embed.image_url, "");
+
+        assert!(parse("https://a.b", "<p>Nothing to show</p>").is_none());
+        assert!(parse("https://a.b", r#"<meta property="og:title" content=" ">"#).is_none());
+
+        let long = format!(
+            r#"<meta name="twitter:title" content="{}">"#,
+            "x".repeat(1000)
+        );
+        assert_eq!(
+            parse("https://a.b", &long).unwrap().title.len(),
+            _MAX_TITLE_LENGTH
+        );
+    }
+
+    #[test]
+    fn resolve_image_links() {
+        let url = "https://a.b/c/d";
+        for (link, expected) in [
+            ("http://e.f/g.png", Some("http://e.f/g.png")),
+            ("//e.f/g.png", Some("https://e.f/g.png")),
+            ("/g.png", Some("https://a.b/g.png")),
+            ("g.png", None),
+            ("data:image/png;base64,AAAA", None),
+        ] {
+            assert_eq!(_resolve(url, link).as_deref(), expected, "{:?}", link);
+        }
+        assert_eq!(
+            _resolve("https://a.b", "/g.png").as_deref(),
+            Some("https://a.b/g.png")
+        );
+    }
+}
@@ -0,0 +1,14 @@
+use super::Fetcher;
+
+/// Links are never fetched, messages are left without embeds.
+pub struct NoopFetcher;
+
+#[tonic::async_trait]
+impl Fetcher for NoopFetcher {
+    async fn fetch(
+        &self,
+        _url: &str,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(None)
+    }
+}
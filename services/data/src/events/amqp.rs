@@ -0,0 +1,347 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::{Stream, StreamExt};
+use lapin::options;
+use lapin::types;
+use prost::Message;
+
+use super::{Event, EventPublisher, EventStream, Topics};
+use crate::services::{p_channels, p_presence};
+
+/// Direct exchange receiving every sequenced event, routed by `channel-{id}` to the subscribers of a channel.
+///
+/// Created messages are published as a bare [`p_channels::PMessage`] without a message type, other events are tagged
+/// with [`_CHANNEL_EVENT_KIND`] or [`_MESSAGE_EVENT_KIND`]. The sequence number is carried in a `sequence` header.
+const _CHANNEL_MESSAGES: &str = "channel-messages";
+
+/// Topic exchange of [`p_channels::PChannelEvent`]s and [`p_channels::PMessageEvent`]s, routed by `channel-{id}` to the
+/// subscribers of a channel and told apart by their message type.
+const _CHANNEL_EVENTS: &str = "channel-stream";
+
+/// Message type of the [`p_channels::PChannelEvent`]s published to [`_CHANNEL_EVENTS`] and [`_CHANNEL_MESSAGES`].
+const _CHANNEL_EVENT_KIND: &str = "channel";
+
+/// Message type of the [`p_channels::PMessageEvent`]s published to [`_CHANNEL_MESSAGES`], other than creations.
+const _MESSAGE_EVENT_KIND: &str = "message";
+
+/// Topic exchange of [`p_channels::PReadStateEvent`]s, routed by `user.{id}` to the sessions of a user.
+const _USER_EVENTS: &str = "user-events";
+
+/// Topic exchange of [`p_presence::PTypingEvent`]s routed by `typing.{channel_id}`, and [`p_presence::PPresence`]s
+/// routed by `presence.{user_id}`.
+const _EPHEMERAL_EVENTS: &str = "ephemeral-events";
+
+/// Time after which an undelivered ephemeral event is discarded by the broker, in milliseconds.
+const _EPHEMERAL_EXPIRATION_MILLISECONDS: &str = "10000";
+
+/// A subscription to the exchanges, whose consumer is cancelled (and queue deleted) once dropped.
+struct _Subscription {
+    channel: lapin::Channel,
+    tag: String,
+    events: EventStream,
+}
+
+impl Stream for _Subscription {
+    type Item = Result<Event, Box<dyn std::error::Error + Send + Sync>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_next_unpin(cx)
+    }
+}
+
+impl Drop for _Subscription {
+    fn drop(&mut self) {
+        let (channel, tag) = (self.channel.clone(), std::mem::take(&mut self.tag));
+        tokio::spawn(async move {
+            if let Err(e) = channel
+                .basic_cancel(&tag, options::BasicCancelOptions::default())
+                .await
+            {
+                eprintln!("Unable to cancel consumer {}: {}", tag, e);
+            }
+        });
+    }
+}
+
+/// Events published to RabbitMQ, and therefore shared between every replica connected to the same broker.
+pub struct AmqpPublisher {
+    channel: lapin::Channel,
+}
+
+impl AmqpPublisher {
+    /// Connect to the broker at `url` and declare the exchanges events are published to.
+    pub async fn connect(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let channel = lapin::Connection::connect(
+            url,
+            lapin::ConnectionProperties::default()
+                .with_executor(tokio_executor_trait::Tokio::current())
+                .with_reactor(tokio_reactor_trait::Tokio),
+        )
+        .await?
+        .create_channel()
+        .await?;
+
+        for (exchange, kind) in [
+            (_CHANNEL_MESSAGES, lapin::ExchangeKind::Direct),
+            (_CHANNEL_EVENTS, lapin::ExchangeKind::Topic),
+            (_USER_EVENTS, lapin::ExchangeKind::Topic),
+            (_EPHEMERAL_EVENTS, lapin::ExchangeKind::Topic),
+        ] {
+            channel
+                .exchange_declare(
+                    exchange,
+                    kind,
+                    options::ExchangeDeclareOptions::default(),
+                    types::FieldTable::default(),
+                )
+                .await?;
+        }
+
+        Ok(Self { channel })
+    }
+
+    async fn _publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        payload: &[u8],
+        properties: lapin::BasicProperties,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                options::BasicPublishOptions::default(),
+                payload,
+                properties,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Relay a sequenced event to the subscribers of its channel, see [`_CHANNEL_MESSAGES`].
+    async fn _relay(
+        &self,
+        channel_id: i64,
+        sequence: i64,
+        payload: &[u8],
+        kind: Option<&str>,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut headers = types::FieldTable::default();
+        headers.insert("sequence".into(), types::AMQPValue::LongLongInt(sequence));
+        let mut properties = lapin::BasicProperties::default().with_headers(headers);
+        if let Some(kind) = kind {
+            properties = properties.with_type(kind.into());
+        }
+
+        self._publish(
+            _CHANNEL_MESSAGES,
+            &format!("channel-{}", channel_id),
+            payload,
+            properties,
+        )
+        .await
+    }
+}
+
+#[tonic::async_trait]
+impl EventPublisher for AmqpPublisher {
+    async fn publish(&self, event: Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        match event {
+            Event::Channel(event) => {
+                // Every sequenced event is relayed alongside created messages, so that the sequence numbers received
+                // from the per-channel routing keys never skip a value
+                let channel_id = event.channel.as_ref().map_or(0, |c| c.id);
+                let payload = event.encode_to_vec();
+                self._relay(
+                    channel_id,
+                    event.sequence,
+                    &payload,
+                    Some(_CHANNEL_EVENT_KIND),
+                )
+                .await?;
+
+                self._publish(
+                    _CHANNEL_EVENTS,
+                    &format!("channel-{}", channel_id),
+                    &payload,
+                    lapin::BasicProperties::default().with_type(_CHANNEL_EVENT_KIND.into()),
+                )
+                .await
+            }
+            Event::Message(event) => {
+                let channel_id = event
+                    .message
+                    .as_ref()
+                    .and_then(|m| m.channel.as_ref())
+                    .map_or(0, |c| c.id);
+
+                // The API service relays created messages to websocket clients from the per-channel routing keys
+                let payload = event.encode_to_vec();
+                match (event.event_type(), &event.message) {
+                    (p_channels::PMessageEventType::MessageCreated, Some(message)) => {
+                        self._relay(channel_id, event.sequence, &message.encode_to_vec(), None)
+                            .await?
+                    }
+                    _ => {
+                        self._relay(
+                            channel_id,
+                            event.sequence,
+                            &payload,
+                            Some(_MESSAGE_EVENT_KIND),
+                        )
+                        .await?
+                    }
+                }
+
+                self._publish(
+                    _CHANNEL_EVENTS,
+                    &format!("channel-{}", channel_id),
+                    &payload,
+                    lapin::BasicProperties::default(),
+                )
+                .await
+            }
+            Event::ReadState(event) => {
+                self._publish(
+                    _USER_EVENTS,
+                    &format!("user.{}", event.user_id),
+                    &event.encode_to_vec(),
+                    lapin::BasicProperties::default(),
+                )
+                .await
+            }
+            Event::Typing(event) => {
+                self._publish(
+                    _EPHEMERAL_EVENTS,
+                    &format!("typing.{}", event.channel_id),
+                    &event.encode_to_vec(),
+                    lapin::BasicProperties::default()
+                        .with_expiration(_EPHEMERAL_EXPIRATION_MILLISECONDS.into()),
+                )
+                .await
+            }
+            Event::Presence(event) => {
+                self._publish(
+                    _EPHEMERAL_EVENTS,
+                    &format!("presence.{}", event.user_id),
+                    &event.encode_to_vec(),
+                    lapin::BasicProperties::default()
+                        .with_expiration(_EPHEMERAL_EXPIRATION_MILLISECONDS.into()),
+                )
+                .await
+            }
+        }
+    }
+
+    async fn subscribe(
+        &self,
+        name: &str,
+        topics: Topics,
+    ) -> Result<EventStream, Box<dyn std::error::Error + Send + Sync>> {
+        // Bind the routing keys of the requested IDs only, so that the broker does not deliver the whole firehose
+        let mut bindings = Vec::new();
+        match &topics.channel_ids {
+            Some(ids) => {
+                for id in ids {
+                    bindings.push((_CHANNEL_EVENTS, format!("channel-{}", id)));
+                    if !topics.skip_typing {
+                        bindings.push((_EPHEMERAL_EVENTS, format!("typing.{}", id)));
+                    }
+                }
+            }
+            None => {
+                bindings.push((_CHANNEL_EVENTS, "#".to_string()));
+                if !topics.skip_typing {
+                    bindings.push((_EPHEMERAL_EVENTS, "typing.*".to_string()));
+                }
+            }
+        }
+        match &topics.user_ids {
+            Some(ids) => {
+                bindings.extend(ids.iter().map(|id| (_USER_EVENTS, format!("user.{}", id))))
+            }
+            None => bindings.push((_USER_EVENTS, "#".to_string())),
+        }
+        match &topics.presence_user_ids {
+            Some(ids) => bindings.extend(
+                ids.iter()
+                    .map(|id| (_EPHEMERAL_EVENTS, format!("presence.{}", id))),
+            ),
+            None => bindings.push((_EPHEMERAL_EVENTS, "presence.*".to_string())),
+        }
+
+        let queue = self
+            .channel
+            .queue_declare(
+                "",
+                options::QueueDeclareOptions {
+                    exclusive: true,
+                    auto_delete: true,
+                    ..Default::default()
+                },
+                types::FieldTable::default(),
+            )
+            .await?;
+        for (exchange, routing_key) in bindings {
+            self.channel
+                .queue_bind(
+                    queue.name().as_str(),
+                    exchange,
+                    &routing_key,
+                    options::QueueBindOptions::default(),
+                    types::FieldTable::default(),
+                )
+                .await?;
+        }
+        let consumer = self
+            .channel
+            .basic_consume(
+                queue.name().as_str(),
+                name,
+                options::BasicConsumeOptions {
+                    no_ack: true,
+                    ..Default::default()
+                },
+                types::FieldTable::default(),
+            )
+            .await?;
+
+        let events = consumer.map(
+            |delivery| -> Result<Event, Box<dyn std::error::Error + Send + Sync>> {
+                let delivery = delivery?;
+                Ok(match delivery.exchange.as_str() {
+                    _CHANNEL_EVENTS
+                        if delivery
+                            .properties
+                            .kind()
+                            .as_ref()
+                            .is_some_and(|kind| kind.as_str() == _CHANNEL_EVENT_KIND) =>
+                    {
+                        Event::Channel(p_channels::PChannelEvent::decode(delivery.data.as_slice())?)
+                    }
+                    _USER_EVENTS => Event::ReadState(p_channels::PReadStateEvent::decode(
+                        delivery.data.as_slice(),
+                    )?),
+                    _EPHEMERAL_EVENTS if delivery.routing_key.as_str().starts_with("typing.") => {
+                        Event::Typing(p_presence::PTypingEvent::decode(delivery.data.as_slice())?)
+                    }
+                    _EPHEMERAL_EVENTS => {
+                        Event::Presence(p_presence::PPresence::decode(delivery.data.as_slice())?)
+                    }
+                    _ => {
+                        Event::Message(p_channels::PMessageEvent::decode(delivery.data.as_slice())?)
+                    }
+                })
+            },
+        );
+
+        Ok(Box::pin(_Subscription {
+            channel: self.channel.clone(),
+            tag: name.to_string(),
+            events: Box::pin(events),
+        }))
+    }
+}
@@ -0,0 +1,145 @@
+use futures::stream;
+use tokio::sync::broadcast;
+
+use super::{Event, EventPublisher, EventStream, Topics};
+
+/// Number of events buffered for each subscriber before the slowest ones start missing events.
+const _CAPACITY: usize = 4096;
+
+/// Events delivered within the process only, for running a single replica without a broker.
+pub struct BroadcastPublisher {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Default for BroadcastPublisher {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(_CAPACITY).0,
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl EventPublisher for BroadcastPublisher {
+    async fn publish(&self, event: Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Sending only fails if nobody is subscribed, in which case the event is simply dropped
+        let _ = self.sender.send(event);
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        name: &str,
+        topics: Topics,
+    ) -> Result<EventStream, Box<dyn std::error::Error + Send + Sync>> {
+        let name = name.to_string();
+        Ok(Box::pin(stream::unfold(
+            self.sender.subscribe(),
+            move |mut receiver| {
+                let (name, topics) = (name.clone(), topics.clone());
+                async move {
+                    loop {
+                        match receiver.recv().await {
+                            Ok(event) if !topics.matches(&event) => continue,
+                            Ok(event) => return Some((Ok(event), receiver)),
+                            Err(broadcast::error::RecvError::Lagged(count)) => {
+                                return Some((
+                                    Err(format!("Subscriber {} missed {} event(s)", name, count)
+                                        .into()),
+                                    receiver,
+                                ))
+                            }
+                            Err(broadcast::error::RecvError::Closed) => return None,
+                        }
+                    }
+                }
+            },
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::services::p_channels;
+
+    fn _read_state(user_id: i64) -> Event {
+        Event::ReadState(p_channels::PReadStateEvent {
+            user_id,
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_events_published_after_subscribing() {
+        let publisher = BroadcastPublisher::default();
+        publisher.publish(_read_state(1)).await.unwrap();
+
+        let mut first = publisher
+            .subscribe("first", Topics::default())
+            .await
+            .unwrap();
+        let mut second = publisher
+            .subscribe("second", Topics::default())
+            .await
+            .unwrap();
+        publisher.publish(_read_state(2)).await.unwrap();
+
+        for events in [&mut first, &mut second] {
+            match events.next().await {
+                Some(Ok(Event::ReadState(event))) => assert_eq!(event.user_id, 2),
+                other => panic!("Unexpected event {:?}", other.map(|e| e.ok())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_receive_the_events_of_their_topics_only() {
+        let publisher = BroadcastPublisher::default();
+        let events = publisher
+            .subscribe(
+                "user-2",
+                Topics {
+                    channel_ids: Some(vec![]),
+                    user_ids: Some(vec![2]),
+                    presence_user_ids: Some(vec![]),
+                    skip_typing: false,
+                },
+            )
+            .await
+            .unwrap();
+        for user_id in 1..=3 {
+            publisher.publish(_read_state(user_id)).await.unwrap();
+        }
+        drop(publisher);
+
+        let received = events
+            .map(|event| match event {
+                Ok(Event::ReadState(event)) => event.user_id,
+                other => panic!("Unexpected event {:?}", other.ok()),
+            })
+            .collect::<Vec<_>>()
+            .await;
+        assert_eq!(received, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_are_told_they_missed_events() {
+        let publisher = BroadcastPublisher::default();
+        let mut events = publisher
+            .subscribe("slow", Topics::default())
+            .await
+            .unwrap();
+        for user_id in 0..=_CAPACITY as i64 {
+            publisher.publish(_read_state(user_id)).await.unwrap();
+        }
+
+        assert!(events.next().await.unwrap().is_err());
+        match events.next().await {
+            Some(Ok(Event::ReadState(event))) => assert_eq!(event.user_id, 1),
+            other => panic!("Unexpected event {:?}", other.map(|e| e.ok())),
+        }
+    }
+}
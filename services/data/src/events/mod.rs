@@ -0,0 +1,88 @@
+use std::pin::Pin;
+
+use futures::Stream;
+
+use crate::services::{p_channels, p_presence};
+
+pub mod amqp;
+pub mod broadcast;
+pub mod noop;
+
+/// A change published by the data service.
+///
+/// Events are moved straight into the publisher, so the size of the largest variant is not worth boxing.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum Event {
+    Channel(p_channels::PChannelEvent),
+    Message(p_channels::PMessageEvent),
+    ReadState(p_channels::PReadStateEvent),
+
+    /// Ephemeral events, which are never persisted and may be dropped by the broker
+    Typing(p_presence::PTypingEvent),
+    Presence(p_presence::PPresence),
+}
+
+/// The events a subscriber receives, see [`EventPublisher::subscribe`].
+///
+/// `None` selects every ID, so the default selects every event.
+#[derive(Clone, Debug, Default)]
+pub struct Topics {
+    /// Channels whose channel, message and typing events are received
+    pub channel_ids: Option<Vec<i64>>,
+
+    /// Users whose read states are received
+    pub user_ids: Option<Vec<i64>>,
+
+    /// Users whose presences are received
+    pub presence_user_ids: Option<Vec<i64>>,
+
+    /// Leave out the typing events of the selected channels, for subscribers of persisted events only
+    pub skip_typing: bool,
+}
+
+impl Topics {
+    /// Whether a subscriber to these topics receives `event`.
+    pub fn matches(&self, event: &Event) -> bool {
+        let selected =
+            |ids: &Option<Vec<i64>>, id: i64| ids.as_ref().is_none_or(|ids| ids.contains(&id));
+        match event {
+            Event::Channel(event) => selected(
+                &self.channel_ids,
+                event.channel.as_ref().map_or(0, |c| c.id),
+            ),
+            Event::Message(event) => selected(
+                &self.channel_ids,
+                event
+                    .message
+                    .as_ref()
+                    .and_then(|m| m.channel.as_ref())
+                    .map_or(0, |c| c.id),
+            ),
+            Event::ReadState(event) => selected(&self.user_ids, event.user_id),
+            Event::Typing(event) => {
+                !self.skip_typing && selected(&self.channel_ids, event.channel_id)
+            }
+            Event::Presence(event) => selected(&self.presence_user_ids, event.user_id),
+        }
+    }
+}
+
+/// A stream of events received by a subscriber, see [`EventPublisher::subscribe`].
+pub type EventStream =
+    Pin<Box<dyn Stream<Item = Result<Event, Box<dyn std::error::Error + Send + Sync>>> + Send>>;
+
+#[tonic::async_trait]
+pub trait EventPublisher: Send + Sync {
+    /// Publish an event to every subscriber, including those of other replicas if the backend is shared.
+    async fn publish(&self, event: Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Receive the events matching `topics` published from now on.
+    ///
+    /// `name` identifies the subscriber in the broker, e.g. as a consumer tag.
+    async fn subscribe(
+        &self,
+        name: &str,
+        topics: Topics,
+    ) -> Result<EventStream, Box<dyn std::error::Error + Send + Sync>>;
+}
@@ -0,0 +1,21 @@
+use futures::stream;
+
+use super::{Event, EventPublisher, EventStream, Topics};
+
+/// Events discarded as soon as they are published, subscribers never receive anything.
+pub struct NoopPublisher;
+
+#[tonic::async_trait]
+impl EventPublisher for NoopPublisher {
+    async fn publish(&self, _event: Event) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn subscribe(
+        &self,
+        _name: &str,
+        _topics: Topics,
+    ) -> Result<EventStream, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(Box::pin(stream::pending()))
+    }
+}
@@ -0,0 +1,795 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+use rand::distr;
+use rand::rngs;
+use rand::Rng;
+use rand::SeedableRng;
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::statement::Consistency;
+use sha2::{Digest, Sha256};
+
+use crate::database::DatabaseLayout;
+use crate::storage;
+
+/// Time-to-live of the migration lock in seconds, after which a crashed migrator no longer blocks others.
+const _LOCK_TTL_SECONDS: i32 = 600;
+
+/// Delay between refreshes of the held migration lock, well within [`_LOCK_TTL_SECONDS`].
+const _LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delay between attempts to acquire the migration lock.
+const _LOCK_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Statements creating the bookkeeping tables, executed before anything else.
+const _BOOTSTRAP: &str = r"
+CREATE KEYSPACE IF NOT EXISTS ${config}
+WITH replication = ${replication}
+AND tablets = {'enabled': false};
+
+CREATE TABLE IF NOT EXISTS ${config}.schema_migrations (
+    version INT,
+    name TEXT,
+    checksum TEXT,
+    applied_at BIGINT,
+    PRIMARY KEY (version)
+);
+
+CREATE TABLE IF NOT EXISTS ${config}.schema_migrations_lock (
+    name TEXT,
+    owner TEXT,
+    PRIMARY KEY (name)
+);
+";
+
+type _Future<'a> = Pin<Box<dyn Future<Output = Result<(), Box<dyn std::error::Error>>> + 'a>>;
+
+enum _Action {
+    /// A CQL script, possibly containing multiple statements.
+    Cql(&'static str),
+
+    /// A data migration implemented in Rust.
+    Rust(for<'a> fn(&'a scylla::Session, &'a DatabaseLayout) -> _Future<'a>),
+}
+
+struct _Migration {
+    version: i32,
+    name: &'static str,
+    action: _Action,
+}
+
+/// All migrations in order of application. Never modify or reorder an existing entry, add a new one instead.
+const _MIGRATIONS: &[_Migration] = &[
+    _Migration {
+        version: 1,
+        name: "initial",
+        action: _Action::Cql(include_str!("../../migrations/0001_initial.cql")),
+    },
+    _Migration {
+        version: 2,
+        name: "message_buckets",
+        action: _Action::Cql(include_str!("../../migrations/0002_message_buckets.cql")),
+    },
+    _Migration {
+        version: 3,
+        name: "backfill_message_buckets",
+        action: _Action::Rust(_backfill_message_buckets),
+    },
+    _Migration {
+        version: 4,
+        name: "channel_indexes",
+        action: _Action::Cql(include_str!("../../migrations/0004_channel_indexes.cql")),
+    },
+    _Migration {
+        version: 5,
+        name: "backfill_channel_indexes",
+        action: _Action::Rust(_backfill_channel_indexes),
+    },
+    _Migration {
+        version: 6,
+        name: "channel_sequences",
+        action: _Action::Cql(include_str!("../../migrations/0006_channel_sequences.cql")),
+    },
+    _Migration {
+        version: 7,
+        name: "read_states",
+        action: _Action::Cql(include_str!("../../migrations/0007_read_states.cql")),
+    },
+    _Migration {
+        version: 8,
+        name: "mentions",
+        action: _Action::Cql(include_str!("../../migrations/0008_mentions.cql")),
+    },
+    _Migration {
+        version: 9,
+        name: "pins",
+        action: _Action::Cql(include_str!("../../migrations/0009_pins.cql")),
+    },
+    _Migration {
+        version: 10,
+        name: "attachments",
+        action: _Action::Cql(include_str!("../../migrations/0010_attachments.cql")),
+    },
+    _Migration {
+        version: 11,
+        name: "embeds",
+        action: _Action::Cql(include_str!("../../migrations/0011_embeds.cql")),
+    },
+    _Migration {
+        version: 12,
+        name: "moderation",
+        action: _Action::Cql(include_str!("../../migrations/0012_moderation.cql")),
+    },
+    _Migration {
+        version: 13,
+        name: "rate_limits",
+        action: _Action::Cql(include_str!("../../migrations/0013_rate_limits.cql")),
+    },
+    _Migration {
+        version: 14,
+        name: "idempotency_keys",
+        action: _Action::Cql(include_str!("../../migrations/0014_idempotency_keys.cql")),
+    },
+    _Migration {
+        version: 15,
+        name: "relationships",
+        action: _Action::Cql(include_str!("../../migrations/0015_relationships.cql")),
+    },
+    _Migration {
+        version: 16,
+        name: "channel_bans",
+        action: _Action::Cql(include_str!("../../migrations/0016_channel_bans.cql")),
+    },
+    _Migration {
+        version: 17,
+        name: "audit_log",
+        action: _Action::Cql(include_str!("../../migrations/0017_audit_log.cql")),
+    },
+    _Migration {
+        version: 18,
+        name: "retention_policies",
+        action: _Action::Cql(include_str!("../../migrations/0018_retention_policies.cql")),
+    },
+    _Migration {
+        version: 19,
+        name: "membership_times",
+        action: _Action::Cql(include_str!("../../migrations/0019_membership_times.cql")),
+    },
+    _Migration {
+        version: 20,
+        name: "rich_content",
+        action: _Action::Cql(include_str!("../../migrations/0020_rich_content.cql")),
+    },
+    _Migration {
+        version: 21,
+        name: "retention_lease",
+        action: _Action::Cql(include_str!("../../migrations/0021_retention_lease.cql")),
+    },
+    _Migration {
+        version: 22,
+        name: "pin_versions",
+        action: _Action::Cql(include_str!("../../migrations/0022_pin_versions.cql")),
+    },
+    _Migration {
+        version: 23,
+        name: "attachment_references",
+        action: _Action::Cql(include_str!(
+            "../../migrations/0023_attachment_references.cql"
+        )),
+    },
+    _Migration {
+        version: 24,
+        name: "backfill_attachment_references",
+        action: _Action::Rust(_backfill_attachment_references),
+    },
+];
+
+fn _backfill_message_buckets<'a>(
+    session: &'a scylla::Session,
+    layout: &'a DatabaseLayout,
+) -> _Future<'a> {
+    Box::pin(async move {
+        let count = storage::scylla::migrate_message_buckets(session, layout).await?;
+        println!("Copied {} messages into time-bucketed partitions", count);
+        Ok(())
+    })
+}
+
+fn _backfill_attachment_references<'a>(
+    session: &'a scylla::Session,
+    layout: &'a DatabaseLayout,
+) -> _Future<'a> {
+    Box::pin(async move {
+        let count = storage::scylla::migrate_attachment_references(session, layout).await?;
+        println!("Indexed the attachments of {} messages", count);
+        Ok(())
+    })
+}
+
+fn _backfill_channel_indexes<'a>(
+    session: &'a scylla::Session,
+    layout: &'a DatabaseLayout,
+) -> _Future<'a> {
+    Box::pin(async move {
+        let count = storage::scylla::migrate_channel_indexes(session, layout).await?;
+        println!("Indexed channels and members from {} rows", count);
+        Ok(())
+    })
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AppliedRow {
+    version: i32,
+    name: String,
+    checksum: String,
+    applied_at: i64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _LockRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    name: Option<String>,
+    owner: Option<String>,
+}
+
+fn _sha256(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+impl _Migration {
+    /// The SHA-256 checksum of this migration, used to detect modifications after it has been applied.
+    fn checksum(&self) -> String {
+        match self.action {
+            _Action::Cql(script) => _sha256(script.as_bytes()),
+            _Action::Rust(_) => _sha256(self.name.as_bytes()),
+        }
+    }
+
+    /// Apply this migration.
+    ///
+    /// Scylla refuses to add a column twice, so `ALTER TABLE ... ADD` statements whose columns already exist are
+    /// skipped, and a migration interrupted midway can be applied again.
+    async fn apply(
+        &self,
+        session: &scylla::Session,
+        layout: &DatabaseLayout,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        match self.action {
+            _Action::Cql(script) => {
+                for statement in split(script) {
+                    let statement = layout.resolve(&statement);
+                    if let Some((keyspace, table, columns)) = _added_columns(&statement) {
+                        let existing = _columns(session, &keyspace, &table).await?;
+                        if columns.iter().all(|column| existing.contains(column)) {
+                            continue;
+                        }
+                    }
+
+                    session.query_unpaged(statement, ()).await?;
+                }
+            }
+            _Action::Rust(function) => function(session, layout).await?,
+        }
+
+        Ok(())
+    }
+}
+
+/// The keyspace, table and column names of an `ALTER TABLE keyspace.table ADD ...` statement, `None` for other
+/// statements.
+///
+/// Both `ADD column type` and `ADD (column type, ...)` are recognized. Names are lowercased like unquoted
+/// identifiers.
+fn _added_columns(statement: &str) -> Option<(String, String, Vec<String>)> {
+    let pattern = regex::Regex::new(r"(?is)^ALTER\s+TABLE\s+(\w+)\.(\w+)\s+ADD\s+(.+)$").unwrap();
+    let captures = pattern.captures(statement.trim())?;
+    let definitions = captures[3].trim();
+    let definitions = definitions
+        .strip_prefix('(')
+        .and_then(|d| d.strip_suffix(')'))
+        .unwrap_or(definitions);
+
+    // Commas also separate the parameters of collection types, e.g. `MAP<TEXT, BIGINT>`
+    let mut columns = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, c) in definitions.char_indices().chain([(definitions.len(), ',')]) {
+        match c {
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                columns.push(
+                    definitions[start..index]
+                        .split_whitespace()
+                        .next()?
+                        .to_lowercase(),
+                );
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    Some((
+        captures[1].to_lowercase(),
+        captures[2].to_lowercase(),
+        columns,
+    ))
+}
+
+/// The names of the columns of `keyspace.table`.
+async fn _columns(
+    session: &scylla::Session,
+    keyspace: &str,
+    table: &str,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    Ok(session
+        .query_unpaged(
+            r"SELECT column_name
+            FROM system_schema.columns
+            WHERE keyspace_name = ? AND table_name = ?",
+            (keyspace, table),
+        )
+        .await?
+        .into_rows_result()?
+        .rows::<(String,)>()?
+        .map(|row| row.map(|(name,)| name))
+        .collect::<Result<Vec<_>, _>>()?)
+}
+
+/// Split a CQL script into statements.
+///
+/// Unlike a plain split on `;`, semicolons inside string literals, `$$` blocks and comments are
+/// left untouched. Comments are stripped from the returned statements.
+fn split(script: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut chars = script.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                current.push(c);
+                while let Some(c) = chars.next() {
+                    current.push(c);
+                    if c == '\'' {
+                        // A doubled quote is an escaped quote
+                        if chars.peek() == Some(&'\'') {
+                            current.push(chars.next().unwrap());
+                        } else {
+                            break;
+                        }
+                    }
+                }
+            }
+            '$' if chars.peek() == Some(&'$') => {
+                current.push(c);
+                current.push(chars.next().unwrap());
+                while let Some(c) = chars.next() {
+                    current.push(c);
+                    if c == '$' && chars.peek() == Some(&'$') {
+                        current.push(chars.next().unwrap());
+                        break;
+                    }
+                }
+            }
+            '-' | '/' if chars.peek() == Some(&c) => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        current.push(c);
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut previous = '\0';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            ';' => {
+                let statement = current.trim();
+                if !statement.is_empty() {
+                    statements.push(statement.to_string());
+                }
+                current.clear();
+            }
+            _ => current.push(c),
+        }
+    }
+
+    let statement = current.trim();
+    if !statement.is_empty() {
+        statements.push(statement.to_string());
+    }
+
+    statements
+}
+
+async fn _bootstrap(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<(), Box<dyn std::error::Error>> {
+    for statement in split(_BOOTSTRAP) {
+        session
+            .query_unpaged(layout.resolve(&statement), ())
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Fetch the applied migrations, or an empty list if migrations have never been run.
+async fn _applied(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<Vec<_AppliedRow>, Box<dyn std::error::Error>> {
+    let exists = session
+        .query_unpaged(
+            r"SELECT table_name
+            FROM system_schema.tables
+            WHERE keyspace_name = ? AND table_name = 'schema_migrations'",
+            (&layout.config,),
+        )
+        .await?
+        .into_rows_result()?
+        .rows_num()
+        > 0;
+
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    let mut statement = scylla::query::Query::new(layout.resolve(
+        r"SELECT version, name, checksum, applied_at
+        FROM ${config}.schema_migrations",
+    ));
+    statement.set_consistency(Consistency::Quorum);
+
+    let mut rows = session
+        .query_unpaged(statement, ())
+        .await?
+        .into_rows_result()?
+        .rows::<_AppliedRow>()?
+        .collect::<Result<Vec<_>, _>>()?;
+    rows.sort_by_key(|row| row.version);
+
+    Ok(rows)
+}
+
+/// Ensure that every applied migration still matches its definition, returning the pending ones.
+fn _pending(
+    applied: &[_AppliedRow],
+) -> Result<Vec<&'static _Migration>, Box<dyn std::error::Error>> {
+    for row in applied {
+        match _MIGRATIONS.iter().find(|m| m.version == row.version) {
+            Some(migration) => {
+                if migration.checksum() != row.checksum {
+                    return Err(format!(
+                        "Checksum mismatch for migration {} ({}): it was modified after being applied",
+                        row.version, row.name
+                    )
+                    .into());
+                }
+            }
+            None => {
+                return Err(format!(
+                    "Migration {} ({}) was applied to the database but is unknown to this build",
+                    row.version, row.name
+                )
+                .into())
+            }
+        }
+    }
+
+    Ok(_MIGRATIONS
+        .iter()
+        .filter(|m| !applied.iter().any(|row| row.version == m.version))
+        .collect())
+}
+
+/// A held migration lock, see [`_Lock::acquire`].
+struct _Lock<'a> {
+    session: &'a scylla::Session,
+    layout: &'a DatabaseLayout,
+    owner: String,
+}
+
+impl<'a> _Lock<'a> {
+    /// Acquire the cluster-wide migration lock using a lightweight transaction, waiting for other holders.
+    async fn acquire(
+        session: &'a scylla::Session,
+        layout: &'a DatabaseLayout,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut rng = rngs::StdRng::from_os_rng();
+        let owner = (0..16)
+            .map(|_| rng.sample(distr::Alphanumeric) as char)
+            .collect::<String>();
+
+        let mut statement = scylla::query::Query::new(layout.resolve(&format!(
+            r"INSERT INTO ${{config}}.schema_migrations_lock (name, owner)
+            VALUES ('lock', ?)
+            IF NOT EXISTS
+            USING TTL {}",
+            _LOCK_TTL_SECONDS
+        )));
+        statement.set_consistency(Consistency::Quorum);
+
+        loop {
+            let row = session
+                .query_unpaged(statement.clone(), (&owner,))
+                .await?
+                .into_rows_result()?
+                .single_row::<_LockRow>()?;
+
+            if row.applied {
+                return Ok(Self {
+                    session,
+                    layout,
+                    owner,
+                });
+            }
+
+            println!(
+                "Migration lock is held by {}, retrying in {:?}",
+                row.owner.unwrap_or_default(),
+                _LOCK_RETRY_INTERVAL
+            );
+            tokio::time::sleep(_LOCK_RETRY_INTERVAL).await;
+        }
+    }
+
+    /// Extend the lifetime of this lock, failing if it has already expired and been taken over.
+    async fn refresh(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut statement = scylla::query::Query::new(self.layout.resolve(&format!(
+            r"UPDATE ${{config}}.schema_migrations_lock
+            USING TTL {}
+            SET owner = ?
+            WHERE name = 'lock'
+            IF owner = ?",
+            _LOCK_TTL_SECONDS
+        )));
+        statement.set_consistency(Consistency::Quorum);
+
+        // The columns returned alongside `[applied]` by a conditional update vary, only inspect the first one
+        let row = self
+            .session
+            .query_unpaged(statement, (&self.owner, &self.owner))
+            .await?
+            .into_rows_result()?
+            .single_row::<Row>()?;
+
+        if matches!(row.columns.first(), Some(Some(CqlValue::Boolean(true)))) {
+            Ok(())
+        } else {
+            Err("Lost the migration lock".into())
+        }
+    }
+
+    async fn release(self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut statement = scylla::query::Query::new(self.layout.resolve(
+            r"DELETE FROM ${config}.schema_migrations_lock
+            WHERE name = 'lock'
+            IF owner = ?",
+        ));
+        statement.set_consistency(Consistency::Quorum);
+
+        self.session
+            .query_unpaged(statement, (&self.owner,))
+            .await?;
+        Ok(())
+    }
+}
+
+/// Print every known migration and whether it has been applied.
+pub async fn status(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let applied = _applied(session, layout).await?;
+    for migration in _MIGRATIONS {
+        match applied.iter().find(|row| row.version == migration.version) {
+            Some(row) => println!(
+                "{:04} {:<32} applied at {}{}",
+                migration.version,
+                migration.name,
+                chrono::DateTime::from_timestamp_millis(row.applied_at)
+                    .map_or_else(|| row.applied_at.to_string(), |time| time.to_rfc3339()),
+                if migration.checksum() == row.checksum {
+                    ""
+                } else {
+                    " (checksum mismatch)"
+                }
+            ),
+            None => println!("{:04} {:<32} pending", migration.version, migration.name),
+        }
+    }
+
+    for row in &applied {
+        if !_MIGRATIONS.iter().any(|m| m.version == row.version) {
+            println!(
+                "{:04} {:<32} applied, unknown to this build",
+                row.version, row.name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Print the pending migrations without applying them.
+pub async fn dry_run(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pending = _pending(&_applied(session, layout).await?)?;
+    if pending.is_empty() {
+        println!("Database schema is up to date");
+    }
+
+    for migration in pending {
+        println!("-- {:04} {}", migration.version, migration.name);
+        match migration.action {
+            _Action::Cql(script) => {
+                for statement in split(script) {
+                    println!("{};", layout.resolve(&statement));
+                }
+            }
+            _Action::Rust(_) => println!("-- (data migration implemented in Rust)"),
+        }
+        println!();
+    }
+
+    Ok(())
+}
+
+/// Apply every pending migration in order, returning the number of applied migrations.
+///
+/// This is safe to run concurrently from multiple processes: a cluster-wide lock serializes them.
+pub async fn up(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    _bootstrap(session, layout).await?;
+
+    let lock = _Lock::acquire(session, layout).await?;
+    let result = _up_locked(session, layout, &lock).await;
+    lock.release().await?;
+
+    result
+}
+
+async fn _up_locked(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+    lock: &_Lock<'_>,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    // Another process may have applied migrations while we were waiting for the lock
+    let pending = _pending(&_applied(session, layout).await?)?;
+
+    let mut record = scylla::query::Query::new(layout.resolve(
+        r"INSERT INTO ${config}.schema_migrations (version, name, checksum, applied_at)
+        VALUES (?, ?, ?, ?)",
+    ));
+    record.set_consistency(Consistency::Quorum);
+
+    for migration in &pending {
+        println!(
+            "Applying migration {:04} ({})",
+            migration.version, migration.name
+        );
+        _apply_locked(migration, session, layout, lock).await?;
+
+        session
+            .query_unpaged(
+                record.clone(),
+                (
+                    migration.version,
+                    migration.name,
+                    migration.checksum(),
+                    chrono::Utc::now().timestamp_millis(),
+                ),
+            )
+            .await?;
+    }
+
+    Ok(pending.len())
+}
+
+/// Apply `migration` while refreshing `lock` every [`_LOCK_REFRESH_INTERVAL`], so that data migrations running
+/// longer than [`_LOCK_TTL_SECONDS`] keep the lock. The migration is abandoned as soon as the lock is lost.
+async fn _apply_locked(
+    migration: &_Migration,
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+    lock: &_Lock<'_>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let apply = migration.apply(session, layout);
+    tokio::pin!(apply);
+
+    // The first tick completes immediately, ensuring the lock is still held before starting
+    let mut heartbeat = tokio::time::interval(_LOCK_REFRESH_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut apply => return result,
+            _ = heartbeat.tick() => lock.refresh().await?,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripts_are_split_into_statements() {
+        let cases: &[(&str, &[&str])] = &[
+            ("", &[]),
+            (" ; ;\n", &[]),
+            (
+                "CREATE TABLE a (x INT);\nCREATE TABLE b (y INT)",
+                &["CREATE TABLE a (x INT)", "CREATE TABLE b (y INT)"],
+            ),
+            (
+                "INSERT INTO t (v) VALUES ('a;b');",
+                &["INSERT INTO t (v) VALUES ('a;b')"],
+            ),
+            (
+                "INSERT INTO t (v) VALUES ('it''s; fine');",
+                &["INSERT INTO t (v) VALUES ('it''s; fine')"],
+            ),
+            (
+                "CREATE FUNCTION f() AS $$ a; b $$;",
+                &["CREATE FUNCTION f() AS $$ a; b $$"],
+            ),
+            (
+                "-- first; comment\nSELECT 1; // second; comment\nSELECT 2;",
+                &["SELECT 1", "SELECT 2"],
+            ),
+            ("SELECT /* inline; comment */ 1;", &["SELECT  1"]),
+            (
+                "CREATE KEYSPACE ${data} WITH replication = ${replication};",
+                &["CREATE KEYSPACE ${data} WITH replication = ${replication}"],
+            ),
+        ];
+
+        for (script, expected) in cases {
+            assert_eq!(split(script), *expected, "{:?}", script);
+        }
+    }
+
+    #[test]
+    fn added_columns_are_parsed_from_alter_statements() {
+        let columns = |statement| _added_columns(statement).map(|(_, _, columns)| columns);
+
+        assert_eq!(
+            _added_columns("ALTER TABLE data.channel_by_id ADD slow_mode_seconds INT"),
+            Some((
+                "data".to_string(),
+                "channel_by_id".to_string(),
+                vec!["slow_mode_seconds".to_string()]
+            ))
+        );
+        assert_eq!(
+            columns("alter table data.t\n    add (A INT, b MAP<TEXT, BIGINT>, c LIST<BIGINT>)"),
+            Some(vec!["a".to_string(), "b".to_string(), "c".to_string()])
+        );
+        assert_eq!(columns("ALTER TABLE data.t DROP a"), None);
+        assert_eq!(
+            columns("CREATE TABLE IF NOT EXISTS data.t (a INT PRIMARY KEY)"),
+            None
+        );
+    }
+}
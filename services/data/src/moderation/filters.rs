@@ -0,0 +1,156 @@
+use std::collections;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use regex::{Regex, RegexBuilder};
+
+use super::{Draft, Moderator, Verdict};
+use crate::storage::{ModerationAction, ModerationFilter, Storage};
+
+/// Maximum size of a compiled filter, so that a single filter cannot exhaust memory.
+const _MAX_COMPILED_BYTES: usize = 1024 * 1024;
+
+/// Number of channels whose compiled filters are kept in cache, the least recently loaded being evicted beyond.
+const _MAX_CACHED_CHANNELS: usize = 10_000;
+
+/// How long compiled filters are cached, i.e. how long changes made on another replica take to apply.
+const _CACHE_TTL: Duration = Duration::from_secs(10);
+
+/// The regular expression matching `filter`.
+///
+/// Words and phrases are matched case-insensitively, and only as whole words where they start or end with a word
+/// character, so that e.g. `ass` does not match `class`.
+fn _source(filter: &ModerationFilter) -> String {
+    if filter.regex {
+        return filter.pattern.clone();
+    }
+
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '_');
+    format!(
+        "(?i){}{}{}",
+        if is_word(filter.pattern.chars().next()) {
+            r"\b"
+        } else {
+            ""
+        },
+        regex::escape(&filter.pattern),
+        if is_word(filter.pattern.chars().next_back()) {
+            r"\b"
+        } else {
+            ""
+        },
+    )
+}
+
+/// Compile `filter`, failing if it is an invalid or oversized regular expression.
+pub fn compile(filter: &ModerationFilter) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(&_source(filter))
+        .size_limit(_MAX_COMPILED_BYTES)
+        .build()
+}
+
+/// Word, phrase and regular expression filters configured per channel, see [`crate::storage::ModerationRepository`].
+///
+/// The compiled filters of recently active channels are cached. Changes made on this replica apply immediately, see
+/// [`Moderator::invalidate`], while those made on another replica apply after at most [`_CACHE_TTL`].
+pub struct ChannelFilters {
+    storage: Storage,
+    cache: RwLock<_Cache>,
+}
+
+/// The compiled filters of a channel, in order.
+type _Compiled = Arc<Vec<(ModerationFilter, Regex)>>;
+
+#[derive(Default)]
+struct _Cache {
+    channels: collections::HashMap<i64, (Instant, _Compiled)>,
+
+    /// Cached channel IDs, least recently loaded first, possibly including channels which were invalidated since
+    order: collections::VecDeque<i64>,
+
+    /// Incremented on every invalidation, so that filters read from storage before are not cached
+    generation: u64,
+}
+
+impl ChannelFilters {
+    pub fn new(storage: Storage) -> Self {
+        Self {
+            storage,
+            cache: Default::default(),
+        }
+    }
+
+    async fn filters(
+        &self,
+        channel_id: i64,
+    ) -> Result<_Compiled, Box<dyn std::error::Error + Send + Sync>> {
+        let generation = {
+            let cache = self.cache.read().unwrap();
+            if let Some((loaded_at, compiled)) = cache.channels.get(&channel_id) {
+                if loaded_at.elapsed() < _CACHE_TTL {
+                    return Ok(compiled.clone());
+                }
+            }
+            cache.generation
+        };
+
+        let mut compiled = Vec::new();
+        for filter in self.storage.moderation.filters(channel_id).await? {
+            let regex = compile(&filter)?;
+            compiled.push((filter, regex));
+        }
+        let compiled = Arc::new(compiled);
+
+        let mut cache = self.cache.write().unwrap();
+        if cache.generation == generation {
+            if cache
+                .channels
+                .insert(channel_id, (Instant::now(), compiled.clone()))
+                .is_none()
+            {
+                cache.order.push_back(channel_id);
+            }
+            while cache.order.len() > _MAX_CACHED_CHANNELS {
+                if let Some(evicted) = cache.order.pop_front() {
+                    cache.channels.remove(&evicted);
+                }
+            }
+        }
+        Ok(compiled)
+    }
+}
+
+#[tonic::async_trait]
+impl Moderator for ChannelFilters {
+    async fn moderate(
+        &self,
+        draft: &mut Draft,
+    ) -> Result<Verdict, Box<dyn std::error::Error + Send + Sync>> {
+        for (filter, regex) in self.filters(draft.channel_id).await?.iter() {
+            if !regex.is_match(&draft.content) {
+                continue;
+            }
+
+            let reason = format!("Matches filter {:?}", filter.pattern);
+            match filter.action {
+                ModerationAction::Reject => return Ok(Verdict::Reject(reason)),
+                ModerationAction::Flag => draft.flag(reason),
+                ModerationAction::Mask => {
+                    draft.content = regex
+                        .replace_all(&draft.content, |captures: &regex::Captures| {
+                            "*".repeat(captures[0].chars().count())
+                        })
+                        .into_owned();
+                }
+            }
+        }
+
+        Ok(Verdict::Allow)
+    }
+
+    fn invalidate(&self, channel_id: i64) {
+        let mut cache = self.cache.write().unwrap();
+        cache.channels.remove(&channel_id);
+        cache.generation += 1;
+    }
+}
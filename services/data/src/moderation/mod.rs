@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+pub mod filters;
+pub mod spam;
+
+/// A message about to be created, checked by every [`Moderator`] of a [`Pipeline`].
+pub struct Draft {
+    pub author_id: i64,
+    pub channel_id: i64,
+
+    /// Content of the message, which moderators may mask
+    pub content: String,
+
+    /// Why the message should be reviewed by a moderator, empty if it should not
+    pub reasons: Vec<String>,
+}
+
+impl Draft {
+    /// Add the message to the review queue once created.
+    pub fn flag(&mut self, reason: String) {
+        if !self.reasons.contains(&reason) {
+            self.reasons.push(reason);
+        }
+    }
+}
+
+/// Whether a message can be created.
+pub enum Verdict {
+    /// The message can be created, with its possibly masked content
+    Allow,
+
+    /// The message must not be created, for the given reason
+    Reject(String),
+}
+
+#[tonic::async_trait]
+pub trait Moderator: Send + Sync {
+    /// Check `draft` before it is stored, masking its content or flagging it if needed.
+    async fn moderate(
+        &self,
+        draft: &mut Draft,
+    ) -> Result<Verdict, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Forget anything cached about the moderation settings of `channel_id`, which just changed.
+    fn invalidate(&self, _channel_id: i64) {}
+
+    /// Record that a message of `author_id` was created with the submitted `content`, before any masking.
+    ///
+    /// Messages rejected by any stage, rate limited or replayed are never created, so they are not recorded.
+    fn created(&self, _author_id: i64, _content: &str) {}
+}
+
+/// Moderators applied in order to every message, until one of them rejects it.
+pub struct Pipeline {
+    stages: Vec<Arc<dyn Moderator>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Arc<dyn Moderator>>) -> Self {
+        Self { stages }
+    }
+
+    pub async fn run(
+        &self,
+        draft: &mut Draft,
+    ) -> Result<Verdict, Box<dyn std::error::Error + Send + Sync>> {
+        for stage in &self.stages {
+            if let Verdict::Reject(reason) = stage.moderate(draft).await? {
+                return Ok(Verdict::Reject(reason));
+            }
+        }
+
+        Ok(Verdict::Allow)
+    }
+
+    /// See [`Moderator::invalidate`].
+    pub fn invalidate(&self, channel_id: i64) {
+        for stage in &self.stages {
+            stage.invalidate(channel_id);
+        }
+    }
+
+    /// See [`Moderator::created`].
+    pub fn created(&self, author_id: i64, content: &str) {
+        for stage in &self.stages {
+            stage.created(author_id, content);
+        }
+    }
+}
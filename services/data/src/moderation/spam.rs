@@ -0,0 +1,248 @@
+use std::collections;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::Mutex;
+
+use super::{Draft, Moderator, Verdict};
+use crate::storage::ModerationAction;
+
+/// Number of tracked authors beyond which authors without recent messages are forgotten.
+const _MAX_TRACKED_AUTHORS: usize = 10_000;
+
+/// Count the valid `http://` and `https://` links of `content`, including links glued to each other or to text.
+fn _count_links(content: &str) -> usize {
+    let mut count = 0;
+    for word in content.split(|c: char| c.is_whitespace() || c == '<' || c == '>') {
+        // Schemes are case-insensitive, and lowercasing ASCII keeps every index valid in `word`
+        let lowercase = word.to_ascii_lowercase();
+        let starts = lowercase
+            .match_indices("http")
+            .map(|(start, _)| start)
+            .filter(|start| {
+                lowercase[*start..].starts_with("http://")
+                    || lowercase[*start..].starts_with("https://")
+            })
+            .collect::<Vec<_>>();
+        for (index, start) in starts.iter().enumerate() {
+            let end = starts.get(index + 1).copied().unwrap_or(word.len());
+            let url =
+                word[*start..end].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"']);
+            if reqwest::Url::parse(url)
+                .is_ok_and(|url| url.host_str().is_some_and(|host| !host.is_empty()))
+            {
+                count += 1;
+            }
+        }
+    }
+
+    count
+}
+
+/// Heuristics catching spam regardless of the channel: link flooding, and the same content posted over and over.
+///
+/// Recent messages are tracked in process memory, so repeated content is only caught per replica.
+pub struct SpamHeuristics {
+    /// Maximum number of links in a single message
+    max_links: usize,
+
+    /// Maximum number of messages with the same content an author can post within `window_milliseconds`
+    max_repeats: usize,
+    window_milliseconds: i64,
+
+    /// What happens to spam, either [`ModerationAction::Reject`] or [`ModerationAction::Flag`]
+    action: ModerationAction,
+
+    /// Timestamps and content digests of the recent messages of every author, oldest first
+    recent: Mutex<collections::HashMap<i64, collections::VecDeque<(i64, u64)>>>,
+}
+
+impl SpamHeuristics {
+    pub fn new(
+        max_links: usize,
+        max_repeats: usize,
+        window_milliseconds: i64,
+        action: ModerationAction,
+    ) -> Self {
+        Self {
+            max_links,
+            max_repeats,
+            window_milliseconds,
+            action,
+            recent: Default::default(),
+        }
+    }
+
+    /// How many messages with the same content as `content` were created recently by `author_id`.
+    fn repeats(&self, author_id: i64, content: &str) -> usize {
+        let digest = _digest(content);
+        let since = chrono::Utc::now().timestamp_millis() - self.window_milliseconds;
+        self.recent
+            .lock()
+            .unwrap()
+            .get(&author_id)
+            .map_or(0, |messages| {
+                messages
+                    .iter()
+                    .filter(|(time, d)| *time > since && *d == digest)
+                    .count()
+            })
+    }
+}
+
+/// A digest of `content` ignoring case and whitespace, so that trivial variations of the same content are caught too.
+fn _digest(content: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for word in content.split_whitespace() {
+        word.to_lowercase().hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[tonic::async_trait]
+impl Moderator for SpamHeuristics {
+    async fn moderate(
+        &self,
+        draft: &mut Draft,
+    ) -> Result<Verdict, Box<dyn std::error::Error + Send + Sync>> {
+        let reason = if _count_links(&draft.content) > self.max_links {
+            format!("More than {} links", self.max_links)
+        } else if !draft.content.trim().is_empty()
+            && self.repeats(draft.author_id, &draft.content) >= self.max_repeats
+        {
+            format!("Same content posted more than {} times", self.max_repeats)
+        } else {
+            return Ok(Verdict::Allow);
+        };
+
+        if self.action == ModerationAction::Reject {
+            return Ok(Verdict::Reject(reason));
+        }
+
+        draft.flag(reason);
+        Ok(Verdict::Allow)
+    }
+
+    fn created(&self, author_id: i64, content: &str) {
+        if content.trim().is_empty() {
+            return;
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let since = now - self.window_milliseconds;
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() >= _MAX_TRACKED_AUTHORS {
+            recent.retain(|_, messages| messages.back().is_some_and(|(time, _)| *time > since));
+        }
+
+        let messages = recent.entry(author_id).or_default();
+        while messages.front().is_some_and(|(time, _)| *time <= since) {
+            messages.pop_front();
+        }
+        messages.push_back((now, _digest(content)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _draft(content: &str) -> Draft {
+        Draft {
+            author_id: 1,
+            channel_id: 2,
+            content: content.to_string(),
+            reasons: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn links_are_counted_whatever_their_scheme() {
+        for (content, expected) in [
+            ("no links here", 0),
+            ("http://a.example https://b.example", 2),
+            ("HTTPS://a.example and (https://b.example).", 2),
+            ("https://a.example,https://b.example", 2),
+            ("<https://a.example>https://b.example", 2),
+            ("http:// https:// httpx://a.example", 0),
+            ("https://a.example/path?query#fragment", 1),
+        ] {
+            assert_eq!(_count_links(content), expected, "{:?}", content);
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_with_too_many_links_are_rejected() {
+        let spam = SpamHeuristics::new(2, 10, 60_000, ModerationAction::Reject);
+
+        let mut draft = _draft("https://a.example https://b.example");
+        assert!(matches!(
+            spam.moderate(&mut draft).await.unwrap(),
+            Verdict::Allow
+        ));
+        let mut draft = _draft("https://a.example https://b.example https://c.example");
+        assert!(matches!(
+            spam.moderate(&mut draft).await.unwrap(),
+            Verdict::Reject(_)
+        ));
+    }
+
+    #[tokio::test]
+    async fn repeated_content_is_flagged_ignoring_case_and_whitespace() {
+        let spam = SpamHeuristics::new(5, 2, 60_000, ModerationAction::Flag);
+
+        for content in ["Buy  now", "buy now", "BUY NOW"] {
+            let mut draft = _draft(content);
+            assert!(matches!(
+                spam.moderate(&mut draft).await.unwrap(),
+                Verdict::Allow
+            ));
+            spam.created(draft.author_id, content);
+            assert_eq!(
+                draft.reasons.is_empty(),
+                content != "BUY NOW",
+                "{:?}",
+                content
+            );
+        }
+
+        // Other authors and other content are tracked separately
+        let mut draft = _draft("buy now");
+        draft.author_id = 3;
+        spam.moderate(&mut draft).await.unwrap();
+        assert!(draft.reasons.is_empty());
+    }
+
+    #[tokio::test]
+    async fn repeated_content_is_forgotten_after_the_window() {
+        let spam = SpamHeuristics::new(5, 1, 0, ModerationAction::Reject);
+
+        for _ in 0..3 {
+            let mut draft = _draft("hello");
+            assert!(matches!(
+                spam.moderate(&mut draft).await.unwrap(),
+                Verdict::Allow
+            ));
+            spam.created(draft.author_id, "hello");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_created_messages_count_as_repeats() {
+        let spam = SpamHeuristics::new(5, 1, 60_000, ModerationAction::Reject);
+
+        // Messages which were moderated but never created, e.g. rate limited, can be retried
+        for _ in 0..3 {
+            let mut draft = _draft("hello");
+            assert!(matches!(
+                spam.moderate(&mut draft).await.unwrap(),
+                Verdict::Allow
+            ));
+        }
+
+        spam.created(1, "hello");
+        let mut draft = _draft("hello");
+        assert!(matches!(
+            spam.moderate(&mut draft).await.unwrap(),
+            Verdict::Reject(_)
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+use std::collections;
+use std::pin::Pin;
+
+use futures::{stream, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+
+use super::p_attachments;
+use super::p_attachments::attachment_service_server;
+use crate::blobs::BlobWriter;
+use crate::storage;
+
+/// MIME type of uploads which do not specify one.
+const _DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Maximum length of an attachment filename, in characters.
+const _MAX_FILENAME_LENGTH: usize = 255;
+
+/// Convert attachment metadata into its protobuf representation.
+fn _to_proto(attachment: storage::Attachment) -> p_attachments::PAttachment {
+    p_attachments::PAttachment {
+        id: attachment.id,
+        uploader_id: attachment.uploader_id,
+        filename: attachment.filename,
+        mime_type: attachment.mime_type,
+        size: attachment.size,
+        sha256: attachment.sha256,
+    }
+}
+
+/// Fetch the metadata of attachments, skipping those which do not exist.
+pub async fn fetch_attachments(
+    application: &super::ApplicationService,
+    ids: &[i64],
+) -> Result<Vec<p_attachments::PAttachment>, Box<dyn std::error::Error + Send + Sync>> {
+    let attachments = fetch_attachments_by_id(application, ids.iter().copied()).await?;
+    Ok(ids
+        .iter()
+        .filter_map(|id| attachments.get(id).cloned())
+        .collect())
+}
+
+/// Fetch the metadata of attachments in a single query, e.g. those of a page of messages, keyed by ID.
+pub async fn fetch_attachments_by_id(
+    application: &super::ApplicationService,
+    ids: impl IntoIterator<Item = i64>,
+) -> Result<
+    collections::HashMap<i64, p_attachments::PAttachment>,
+    Box<dyn std::error::Error + Send + Sync>,
+> {
+    let ids = ids
+        .into_iter()
+        .collect::<collections::BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Ok(collections::HashMap::new());
+    }
+
+    Ok(application
+        .storage
+        .attachments
+        .get_many(&ids)
+        .await?
+        .into_iter()
+        .map(|attachment| (attachment.id, _to_proto(attachment)))
+        .collect())
+}
+
+/// Write the `data` chunks of an upload to `writer`, returning the size and SHA-256 digest of the content.
+async fn _receive(
+    chunks: &mut tonic::Streaming<p_attachments::PUploadAttachmentChunk>,
+    writer: &mut Box<dyn BlobWriter>,
+    max_bytes: i64,
+) -> Result<(i64, String), tonic::Status> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = chunks.message().await? {
+        let data = match chunk.chunk {
+            Some(p_attachments::p_upload_attachment_chunk::Chunk::Data(data)) => data,
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "Only the first chunk may contain metadata",
+                ))
+            }
+        };
+
+        size += data.len() as i64;
+        if size > max_bytes {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Attachments cannot exceed {} bytes",
+                max_bytes
+            )));
+        }
+
+        hasher.update(&data);
+        writer
+            .write(&data)
+            .await
+            .map_err(super::ApplicationService::error)?;
+    }
+
+    let sha256 = hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    Ok((size, sha256))
+}
+
+#[tonic::async_trait]
+impl attachment_service_server::AttachmentService for super::ApplicationService {
+    type DownloadAttachmentStream = Pin<
+        Box<
+            dyn Stream<Item = Result<p_attachments::PDownloadAttachmentChunk, tonic::Status>>
+                + Send,
+        >,
+    >;
+
+    async fn upload_attachment(
+        &self,
+        request: tonic::Request<tonic::Streaming<p_attachments::PUploadAttachmentChunk>>,
+    ) -> Result<tonic::Response<p_attachments::PAttachment>, tonic::Status> {
+        let mut chunks = request.into_inner();
+        let metadata = match chunks.message().await?.and_then(|chunk| chunk.chunk) {
+            Some(p_attachments::p_upload_attachment_chunk::Chunk::Metadata(metadata)) => metadata,
+            _ => {
+                return Err(tonic::Status::invalid_argument(
+                    "The first chunk must contain metadata",
+                ))
+            }
+        };
+
+        let filename = metadata.filename.trim();
+        if filename.is_empty() || filename.chars().count() > _MAX_FILENAME_LENGTH {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Filenames must be between 1 and {} characters",
+                _MAX_FILENAME_LENGTH
+            )));
+        }
+
+        self.storage
+            .accounts
+            .by_id(metadata.uploader_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+
+        // The content is stored under the ID of the attachment, its metadata is only stored once fully uploaded
+        let id = self.generate_id();
+        let mut writer = self
+            .blobs
+            .write(&id.to_string())
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let (size, sha256) =
+            match _receive(&mut chunks, &mut writer, self.options.max_attachment_bytes).await {
+                Ok(result) => result,
+                Err(e) => {
+                    if let Err(e) = writer.abort().await {
+                        eprintln!("Unable to discard attachment {}: {}", id, e);
+                    }
+                    return Err(e);
+                }
+            };
+        writer
+            .commit()
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let attachment = storage::Attachment {
+            id,
+            uploader_id: metadata.uploader_id,
+            filename: filename.to_string(),
+            mime_type: if metadata.mime_type.is_empty() {
+                _DEFAULT_MIME_TYPE.to_string()
+            } else {
+                metadata.mime_type
+            },
+            size,
+            sha256,
+        };
+        self.storage
+            .attachments
+            .create(&attachment)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(_to_proto(attachment)))
+    }
+
+    async fn download_attachment(
+        &self,
+        request: tonic::Request<p_attachments::PDownloadAttachmentRequest>,
+    ) -> Result<tonic::Response<Self::DownloadAttachmentStream>, tonic::Status> {
+        let request = request.into_inner();
+        let attachment = self
+            .storage
+            .attachments
+            .get(request.attachment_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Attachment not found"))?;
+
+        let data = self
+            .blobs
+            .read(&attachment.id.to_string())
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Attachment content is missing"))?;
+
+        let head = stream::once(async move {
+            Ok(p_attachments::PDownloadAttachmentChunk {
+                chunk: Some(
+                    p_attachments::p_download_attachment_chunk::Chunk::Attachment(_to_proto(
+                        attachment,
+                    )),
+                ),
+            })
+        });
+        let data = data.map(|chunk| {
+            chunk
+                .map(|data| p_attachments::PDownloadAttachmentChunk {
+                    chunk: Some(p_attachments::p_download_attachment_chunk::Chunk::Data(
+                        data,
+                    )),
+                })
+                .map_err(super::ApplicationService::error)
+        });
+
+        Ok(tonic::Response::new(Box::pin(head.chain(data))))
+    }
+}
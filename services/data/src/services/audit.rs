@@ -0,0 +1,183 @@
+use chrono::DateTime;
+
+use super::p_moderation;
+use crate::storage;
+
+/// Default number of entries returned by a single audit log query.
+const _DEFAULT_LIMIT: i32 = 50;
+
+/// Maximum number of entries returned by a single audit log query.
+const _MAX_LIMIT: i32 = 500;
+
+/// Number of entries read at once while filtering the audit log.
+const _SCAN_PAGE_SIZE: i32 = 500;
+
+/// Time range queried when none is given, in milliseconds.
+const _DEFAULT_RANGE_MILLISECONDS: i64 = 7 * 24 * 60 * 60 * 1000;
+
+/// Maximum time range of a query without a target, in milliseconds, which bounds the partitions to read.
+const _MAX_RANGE_MILLISECONDS: i64 = 93 * 24 * 60 * 60 * 1000;
+
+fn _action_to_proto(action: storage::AuditAction) -> p_moderation::PAuditAction {
+    match action {
+        storage::AuditAction::ChannelCreated => p_moderation::PAuditAction::ChannelCreated,
+        storage::AuditAction::SlowModeChanged => p_moderation::PAuditAction::SlowModeChanged,
+        storage::AuditAction::ChannelFiltersChanged => {
+            p_moderation::PAuditAction::ChannelFiltersChanged
+        }
+        storage::AuditAction::MessagePinned => p_moderation::PAuditAction::MessagePinned,
+        storage::AuditAction::MessageUnpinned => p_moderation::PAuditAction::MessageUnpinned,
+        storage::AuditAction::FlagResolved => p_moderation::PAuditAction::FlagResolved,
+        storage::AuditAction::MemberBanned => p_moderation::PAuditAction::MemberBanned,
+        storage::AuditAction::MemberUnbanned => p_moderation::PAuditAction::MemberUnbanned,
+        storage::AuditAction::MemberTimedOut => p_moderation::PAuditAction::MemberTimedOut,
+        storage::AuditAction::RetentionPolicyChanged => {
+            p_moderation::PAuditAction::RetentionPolicyChanged
+        }
+        storage::AuditAction::LegalHoldChanged => p_moderation::PAuditAction::LegalHoldChanged,
+    }
+}
+
+fn _to_proto(entry: storage::AuditEntry) -> p_moderation::PAuditEntry {
+    p_moderation::PAuditEntry {
+        id: entry.id,
+        actor_id: entry.actor_id,
+        action: _action_to_proto(entry.action).into(),
+        target_id: entry.target_id,
+        channel_id: entry.channel_id,
+        details: entry.details,
+        created_at: entry.created_at,
+    }
+}
+
+/// Record a privileged operation of `actor_id` in the audit log.
+pub async fn record(
+    application: &super::ApplicationService,
+    actor_id: i64,
+    action: storage::AuditAction,
+    target_id: i64,
+    channel_id: i64,
+    details: String,
+) -> Result<(), tonic::Status> {
+    application
+        .storage
+        .audit_log
+        .append(&storage::AuditEntry {
+            id: application.generate_id(),
+            actor_id,
+            action,
+            target_id,
+            channel_id,
+            details,
+            created_at: chrono::Utc::now().timestamp_millis(),
+        })
+        .await
+        .map_err(super::ApplicationService::error)
+}
+
+/// The entries of the audit log matching `request`, newest first. The caller must be authorized.
+///
+/// Entries of a target are read from its partition, other entries from the partitions of every day of the time
+/// range. Actor and action filters are applied while reading.
+pub async fn query(
+    application: &super::ApplicationService,
+    request: &p_moderation::PQueryAuditLogRequest,
+) -> Result<Vec<p_moderation::PAuditEntry>, tonic::Status> {
+    let limit = if request.limit > 0 {
+        request.limit.min(_MAX_LIMIT) as usize
+    } else {
+        _DEFAULT_LIMIT as usize
+    };
+    let end_time = if request.end_time > 0 {
+        request.end_time
+    } else {
+        chrono::Utc::now().timestamp_millis()
+    };
+    let start_time = if request.start_time > 0 {
+        request.start_time
+    } else {
+        end_time - _DEFAULT_RANGE_MILLISECONDS
+    };
+    if start_time >= end_time {
+        return Err(tonic::Status::invalid_argument(
+            "The start time must be before the end time",
+        ));
+    }
+    if request.target_id == 0 && end_time - start_time > _MAX_RANGE_MILLISECONDS {
+        return Err(tonic::Status::invalid_argument(format!(
+            "The time range cannot exceed {} days without a target",
+            _MAX_RANGE_MILLISECONDS / (24 * 60 * 60 * 1000)
+        )));
+    }
+
+    let snowflake = |time| {
+        DateTime::from_timestamp_millis(time)
+            .map(|time| application.snowflake(time))
+            .ok_or_else(|| tonic::Status::invalid_argument("Invalid time range"))
+    };
+    let lower = snowflake(start_time)? - 1;
+
+    // Without an end time, entries created during the current millisecond are included too
+    let mut upper = if request.end_time > 0 {
+        snowflake(end_time)?
+    } else {
+        application.latest_id() + 1
+    };
+    if request.before_id > 0 {
+        upper = upper.min(request.before_id);
+    }
+
+    let actions = request.actions().collect::<Vec<_>>();
+    let matches = |entry: &storage::AuditEntry| {
+        (request.actor_id == 0 || entry.actor_id == request.actor_id)
+            && (actions.is_empty() || actions.contains(&_action_to_proto(entry.action)))
+    };
+
+    let partitions = if request.target_id != 0 {
+        vec![None]
+    } else {
+        (storage::day(start_time)..=storage::day(end_time - 1))
+            .rev()
+            .map(Some)
+            .collect()
+    };
+
+    let mut result = Vec::new();
+    for day in partitions {
+        let mut upper = upper;
+        loop {
+            let page = match day {
+                Some(day) => {
+                    application
+                        .storage
+                        .audit_log
+                        .by_day(day, lower, upper, _SCAN_PAGE_SIZE)
+                        .await
+                }
+                None => {
+                    application
+                        .storage
+                        .audit_log
+                        .by_target(request.target_id, lower, upper, _SCAN_PAGE_SIZE)
+                        .await
+                }
+            }
+            .map_err(super::ApplicationService::error)?;
+
+            let exhausted = page.len() < _SCAN_PAGE_SIZE as usize;
+            upper = page.last().map_or(upper, |entry| entry.id);
+            for entry in page.into_iter().filter(|entry| matches(entry)) {
+                result.push(_to_proto(entry));
+                if result.len() == limit {
+                    return Ok(result);
+                }
+            }
+
+            if exhausted {
+                break;
+            }
+        }
+    }
+
+    Ok(result)
+}
@@ -1,263 +1,514 @@
 use std::collections;
 use std::collections::hash_map::Entry;
+use std::pin::Pin;
 
-use lapin::options;
-use lapin::types;
-use prost::Message;
-use scylla::macros;
-use scylla::prepared_statement;
-use scylla::statement::Consistency;
-use tokio::sync;
+use futures::{stream, Stream, StreamExt};
 
+use super::attachment;
+use super::audit;
+use super::embed;
+use super::idempotency;
+use super::mention;
 use super::p_channels;
 use super::p_channels::channel_service_server;
 use super::p_users;
+use super::rate_limit;
+use super::relationship;
+use super::retention;
+use super::rich_text;
+use super::search;
+use crate::events::{Event, Topics};
+use crate::moderation;
+use crate::storage;
 
-/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
-static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+/// Default number of channels returned by a single [`channel_service_server::ChannelService::query`] call.
+const _DEFAULT_PAGE_SIZE: i32 = 50;
 
-/// Private struct holding prepared statements in this module.
-///
-/// Access the underlying statements via the singleton [`_STATEMENTS`].
-/// See also: [`_prepare`].
-struct _Statements {
-    create_channel: prepared_statement::PreparedStatement,
-    create_message1: prepared_statement::PreparedStatement,
-    create_message2: prepared_statement::PreparedStatement,
-    fetch_user: prepared_statement::PreparedStatement,
-    query: prepared_statement::PreparedStatement,
-    history: Vec<prepared_statement::PreparedStatement>,
-    channel: prepared_statement::PreparedStatement,
+/// Maximum number of channels returned by a single [`channel_service_server::ChannelService::query`] call.
+const _MAX_PAGE_SIZE: i32 = 500;
+
+/// Maximum number of channels read by a single [`channel_service_server::ChannelService::query`] call, so that
+/// rare filters return a partial page rather than scanning the whole index.
+const _MAX_SCANNED_CHANNELS: usize = 2000;
+
+/// Default number of messages returned by a single [`channel_service_server::ChannelService::search_messages`] call.
+const _DEFAULT_SEARCH_LIMIT: i32 = 25;
+
+/// Maximum number of messages returned by a single [`channel_service_server::ChannelService::search_messages`] call.
+const _MAX_SEARCH_LIMIT: i32 = 100;
+
+/// Default number of channels returned by a single [`channel_service_server::ChannelService::search_channels`] call.
+const _DEFAULT_CHANNEL_SEARCH_LIMIT: i32 = 25;
+
+/// Maximum number of channels returned by a single [`channel_service_server::ChannelService::search_channels`] call.
+const _MAX_CHANNEL_SEARCH_LIMIT: i32 = 100;
+
+/// Default number of recent hours to count messages in when ranking channels by activity.
+const _DEFAULT_ACTIVITY_HOURS: i32 = 24;
+
+/// Maximum number of messages replayed per channel by [`channel_service_server::ChannelService::subscribe`].
+const _MAX_REPLAY_MESSAGES: i32 = 1000;
+
+/// Maximum number of messages returned by a single [`channel_service_server::ChannelService::catchup`] call.
+const _MAX_CATCHUP_MESSAGES: i32 = 500;
+
+/// Maximum number of unread messages counted per channel by `get_unread_counts`.
+const _MAX_UNREAD_COUNT: i32 = 1000;
+
+/// Maximum number of attachments of a single message.
+const _MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+/// Maximum slow mode of a channel, in seconds.
+const _MAX_SLOW_MODE_SECONDS: i32 = 6 * 60 * 60;
+
+async fn _fetch_user(
+    application: &super::ApplicationService,
+    id: i64,
+) -> Result<p_users::PUser, Box<dyn std::error::Error + Send + Sync>> {
+    let account = application
+        .storage
+        .accounts
+        .by_id(id)
+        .await?
+        .ok_or_else(|| format!("User {} does not exist", id))?;
+
+    Ok(p_users::PUser {
+        id: account.id,
+        username: account.username,
+        permissions: account.permissions,
+    })
 }
 
-#[allow(dead_code)]
-#[derive(Debug, macros::DeserializeRow)]
-struct _UserRow {
+async fn _fetch_channel(
+    application: &super::ApplicationService,
     id: i64,
-    username: String,
-    permissions: i64,
+) -> Result<storage::Channel, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(application
+        .storage
+        .channels
+        .get(id)
+        .await?
+        .ok_or_else(|| format!("Channel {} does not exist", id))?)
 }
 
-#[allow(dead_code)]
-#[derive(Debug, macros::DeserializeRow)]
-struct _ChannelRow {
+async fn _fetch_message(
+    application: &super::ApplicationService,
     id: i64,
-    name: String,
-    description: String,
-    owner_id: i64,
+) -> Result<storage::Message, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(application
+        .storage
+        .messages
+        .get(id)
+        .await?
+        .ok_or_else(|| format!("Message {} does not exist", id))?)
+}
+
+/// Convert a channel into its protobuf representation, with its owner only when given.
+fn _channel_to_proto(
+    channel: storage::Channel,
+    owner: Option<p_users::PUser>,
+) -> p_channels::PChannel {
+    p_channels::PChannel {
+        id: channel.id,
+        name: channel.name,
+        description: channel.description,
+        slow_mode_seconds: channel.slow_mode_seconds,
+        owner,
+    }
+}
+
+/// Convert a channel into its protobuf representation, fetching its owner.
+async fn _owned_channel_to_proto(
+    application: &super::ApplicationService,
+    channel: storage::Channel,
+) -> Result<p_channels::PChannel, Box<dyn std::error::Error + Send + Sync>> {
+    let owner = _fetch_user(application, channel.owner_id).await?;
+    Ok(_channel_to_proto(channel, Some(owner)))
+}
+
+/// Convert messages into their protobuf representation, fetching their authors.
+///
+/// Channels of the messages are taken from `channels`, other channels are fetched without their owner.
+pub async fn hydrate_messages(
+    application: &super::ApplicationService,
+    rows: Vec<storage::Message>,
+    mut channels: collections::HashMap<i64, p_channels::PChannel>,
+) -> Result<Vec<p_channels::PMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let attachments = attachment::fetch_attachments_by_id(
+        application,
+        rows.iter()
+            .flat_map(|row| row.attachment_ids.iter().copied()),
+    )
+    .await?;
+    let mut embeds = embed::fetch_embeds_by_message(
+        application,
+        &rows.iter().map(|row| row.id).collect::<Vec<_>>(),
+    )
+    .await?;
+    let mut authors = collections::HashMap::new();
+    let mut result = Vec::new();
+    for row in rows {
+        if let Entry::Vacant(e) = authors.entry(row.author_id) {
+            e.insert(_fetch_user(application, row.author_id).await?);
+        }
+
+        if let Entry::Vacant(e) = channels.entry(row.channel_id) {
+            e.insert(_channel_to_proto(
+                _fetch_channel(application, row.channel_id).await?,
+                None,
+            ));
+        }
+
+        let rich_content = rich_text::stored(&row.content, &row.rich_content);
+        result.push(p_channels::PMessage {
+            id: row.id,
+            content: row.content,
+            author: Some(authors[&row.author_id].clone()),
+            channel: Some(channels[&row.channel_id].clone()),
+            mentioned_user_ids: row.mentions.user_ids,
+            mentions_channel: row.mentions.channel,
+            attachments: row
+                .attachment_ids
+                .iter()
+                .filter_map(|id| attachments.get(id).cloned())
+                .collect(),
+            embeds: embeds.remove(&row.id).unwrap_or_default(),
+            rich_content,
+        });
+    }
+
+    Ok(result)
 }
 
-#[allow(dead_code)]
-#[derive(Debug, macros::DeserializeRow)]
-struct _AppliedChannelRow {
-    #[scylla(rename = "[applied]")]
-    applied: bool,
-    id: Option<i64>,
-    name: Option<String>,
-    description: Option<String>,
-    owner_id: Option<i64>,
+/// The response of a create request replayed with the same idempotency key, which reserved channel `id`.
+///
+/// Returns `None` if the original request was abandoned before creating the channel, see [`idempotency::abandoned`].
+async fn _replay_channel(
+    application: &super::ApplicationService,
+    id: i64,
+    request: &p_channels::PCreateChannelRequest,
+) -> Result<Option<tonic::Response<p_channels::PChannel>>, tonic::Status> {
+    let Some(channel) = application
+        .storage
+        .channels
+        .get(id)
+        .await
+        .map_err(super::ApplicationService::error)?
+    else {
+        return match idempotency::abandoned(application, id) {
+            true => Ok(None),
+            false => Err(idempotency::in_progress()),
+        };
+    };
+    if channel.name != request.name || channel.description != request.description {
+        return Err(tonic::Status::invalid_argument(
+            "The idempotency key was already used for another request",
+        ));
+    }
+
+    Ok(Some(tonic::Response::new(
+        _owned_channel_to_proto(application, channel)
+            .await
+            .map_err(super::ApplicationService::error)?,
+    )))
 }
 
-#[allow(dead_code)]
-#[derive(Debug, macros::DeserializeRow)]
-struct _MessageRow {
+/// The response of a create request replayed with the same idempotency key, which reserved message `id`.
+///
+/// Returns `None` if the original request was abandoned before creating the message, see
+/// [`idempotency::abandoned`].
+async fn _replay_message(
+    application: &super::ApplicationService,
     id: i64,
-    content: String,
-    author_id: i64,
     channel_id: i64,
+) -> Result<Option<tonic::Response<p_channels::PMessage>>, tonic::Status> {
+    let Some(message) = application
+        .storage
+        .messages
+        .get(id)
+        .await
+        .map_err(super::ApplicationService::error)?
+    else {
+        return match idempotency::abandoned(application, id) {
+            true => Ok(None),
+            false => Err(idempotency::in_progress()),
+        };
+    };
+    if message.channel_id != channel_id {
+        return Err(tonic::Status::invalid_argument(
+            "The idempotency key was already used for another request",
+        ));
+    }
+
+    let channel = _fetch_channel(application, channel_id)
+        .await
+        .map_err(super::ApplicationService::error)?;
+    let channel = _owned_channel_to_proto(application, channel)
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+    let message = hydrate_messages(application, vec![message], [(channel.id, channel)].into())
+        .await
+        .map_err(super::ApplicationService::error)?
+        .pop()
+        .ok_or_else(|| tonic::Status::not_found("Message not found"))?;
+
+    Ok(Some(tonic::Response::new(message)))
 }
 
-#[allow(dead_code)]
-#[derive(Debug, macros::DeserializeRow)]
-struct _AppliedMessageRow {
-    #[scylla(rename = "[applied]")]
-    applied: bool,
-    id: Option<i64>,
-    content: Option<String>,
-    author_id: Option<i64>,
-    channel_id: Option<i64>,
+/// Check that `user_id` is set and may read `channel_id`, i.e. is not banned from it.
+///
+/// Timed out users can still read the channel.
+async fn _check_reader(
+    application: &super::ApplicationService,
+    channel_id: i64,
+    user_id: i64,
+) -> Result<(), tonic::Status> {
+    if user_id == 0 {
+        return Err(tonic::Status::invalid_argument("User ID is required"));
+    }
+
+    if let Some(ban) = super::moderation::active_ban(application, channel_id, user_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .filter(|ban| !ban.timeout)
+    {
+        return Err(super::moderation::banned(&ban));
+    }
+
+    Ok(())
 }
 
-/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+/// Convert pins of `channel` into their protobuf representation, fetching their messages and pinners.
 ///
-/// This function is automatically called by [`sync::OnceCell`], a reference to
-/// [`_STATEMENTS`] can be retrieved via:
-/// ```rust
-/// let statements =_STATEMENTS.get_or_try_init(|| _prepare(application)).await?;
-/// ```
-async fn _prepare(
+/// Pins of messages which no longer exist are skipped.
+async fn _hydrate_pins(
     application: &super::ApplicationService,
-) -> Result<_Statements, Box<dyn std::error::Error>> {
-    let mut create_channel = application
-        .session
-        .prepare(
-            r"INSERT INTO data.channel_by_id (id, name, description, owner_id)
-            VALUES (?, ?, ?, ?)
-            IF NOT EXISTS",
-        )
-        .await?;
-    create_channel.set_consistency(Consistency::Quorum);
-
-    let mut create_message1 = application
-        .session
-        .prepare(
-            r"INSERT INTO data.message_by_id (id, content, author_id, channel_id)
-            VALUES (?, ?, ?, ?)
-            IF NOT EXISTS",
-        )
-        .await?;
-    create_message1.set_consistency(Consistency::Quorum);
+    pins: Vec<storage::Pin>,
+    channel: &p_channels::PChannel,
+) -> Result<Vec<p_channels::PPin>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut users = collections::HashMap::new();
+    let mut result = Vec::new();
+    for pin in pins {
+        let message = match application.storage.messages.get(pin.message_id).await? {
+            Some(message) => message,
+            None => continue,
+        };
 
-    let mut create_message2 = application
-        .session
-        .prepare(
-            r"INSERT INTO data.message_by_channel_id (id, content, author_id, channel_id)
-            VALUES (?, ?, ?, ?)",
-        )
-        .await?;
-    create_message2.set_consistency(Consistency::One);
-
-    let mut fetch_user = application
-        .session
-        .prepare(
-            r"SELECT id, username, permissions
-            FROM accounts.info_by_id
-            WHERE id = ?",
-        )
-        .await?;
-    fetch_user.set_consistency(Consistency::One);
+        if let Entry::Vacant(e) = users.entry(pin.pinned_by) {
+            e.insert(_fetch_user(application, pin.pinned_by).await?);
+        }
 
-    let mut query = application
-        .session
-        .prepare(
-            r"SELECT id, name, description, owner_id
-            FROM data.channel_by_id",
-        )
-        .await?;
-    query.set_consistency(Consistency::One);
-
-    let mut history = Vec::new();
-    for newest in [false, true] {
-        let mut statement = application
-            .session
-            .prepare(format!(
-                r"SELECT id, content, author_id, channel_id
-                FROM data.message_by_channel_id
-                WHERE channel_id = ? AND id <= ? AND id >= ?
-                ORDER BY id {}
-                LIMIT ?",
-                if newest { "DESC" } else { "ASC" }
-            ))
-            .await?;
-        statement.set_consistency(Consistency::One);
-
-        history.push(statement);
+        result.push(p_channels::PPin {
+            message: hydrate_messages(
+                application,
+                vec![message],
+                [(channel.id, channel.clone())].into(),
+            )
+            .await?
+            .pop(),
+            pinned_by: Some(users[&pin.pinned_by].clone()),
+            pinned_at: pin.pinned_at,
+        });
     }
 
-    let mut channel = application
-        .session
-        .prepare(
-            r"SELECT id, name, description, owner_id
-            FROM data.channel_by_id
-            WHERE id = ?",
-        )
-        .await?;
-    channel.set_consistency(Consistency::One);
-
-    Ok(_Statements {
-        create_channel,
-        create_message1,
-        create_message2,
-        fetch_user,
-        query,
-        history,
-        channel,
-    })
+    Ok(result)
 }
 
-async fn _fetch_user(
+/// Check that the user of `request` may (un)pin its message, i.e. owns the channel or has the `MANAGE_MESSAGES`
+/// permission, returning the channel.
+async fn _authorize_pin(
     application: &super::ApplicationService,
-    id: i64,
-) -> Result<_UserRow, Box<dyn std::error::Error>> {
-    let statements = _STATEMENTS
-        .get_or_try_init(|| _prepare(application))
-        .await?;
-    let row = application
-        .session
-        .execute_unpaged(&statements.fetch_user, (&id,))
-        .await?
-        .into_rows_result()?
-        .single_row::<_UserRow>()?;
+    request: &p_channels::PPinRequest,
+) -> Result<storage::Channel, tonic::Status> {
+    let user = _fetch_user(application, request.user_id)
+        .await
+        .map_err(|_| tonic::Status::not_found("User not found"))?;
+    let channel = _fetch_channel(application, request.channel_id)
+        .await
+        .map_err(|_| tonic::Status::not_found("Channel not found"))?;
+
+    match _fetch_message(application, request.message_id).await {
+        Ok(message) if message.channel_id == channel.id => {}
+        _ => return Err(tonic::Status::not_found("Message not found")),
+    }
+
+    if user.id != channel.owner_id
+        && user.permissions & p_users::PPermission::ManageMessages as i64 == 0
+    {
+        return Err(tonic::Status::permission_denied(
+            "Only the channel owner or users allowed to manage messages can pin messages",
+        ));
+    }
 
-    Ok(row)
+    Ok(channel)
 }
 
-async fn _fetch_channel(
+/// Take a token from the rate limits of `author` and of `channel`, then enforce the slow mode of `channel`.
+///
+/// The channel owner and users allowed to manage messages are not subject to slow mode.
+async fn _check_rate_limits(
     application: &super::ApplicationService,
-    id: i64,
-) -> Result<_ChannelRow, Box<dyn std::error::Error>> {
-    let statements = _STATEMENTS
-        .get_or_try_init(|| _prepare(application))
-        .await?;
-    let row = application
-        .session
-        .execute_unpaged(&statements.channel, (&id,))
-        .await?
-        .into_rows_result()?
-        .single_row::<_ChannelRow>()?;
+    author: &p_users::PUser,
+    channel: &storage::Channel,
+) -> Result<(), tonic::Status> {
+    let options = &application.options;
+    let limits = [
+        (
+            format!("user:{}", author.id),
+            rate_limit::Quota::per_minute(
+                options.user_messages_per_minute,
+                options.user_message_burst,
+            ),
+            "You are sending messages too fast",
+        ),
+        (
+            format!("channel:{}", channel.id),
+            rate_limit::Quota::per_minute(
+                options.channel_messages_per_minute,
+                options.channel_message_burst,
+            ),
+            "Too many messages are being sent in this channel",
+        ),
+        (
+            format!("slow:{}:{}", channel.id, author.id),
+            (channel.slow_mode_seconds > 0
+                && author.id != channel.owner_id
+                && author.permissions & p_users::PPermission::ManageMessages as i64 == 0)
+                .then_some(rate_limit::Quota {
+                    burst: 1,
+                    interval_milliseconds: i64::from(channel.slow_mode_seconds) * 1000,
+                }),
+            "This channel is in slow mode",
+        ),
+    ];
+
+    for (key, quota, message) in limits {
+        let Some(quota) = quota else { continue };
+        match rate_limit::acquire(&application.storage, &key, quota)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            rate_limit::Acquisition::Acquired => {}
+            rate_limit::Acquisition::Exhausted(retry) => {
+                return Err(rate_limit::exhausted(message, retry))
+            }
+            rate_limit::Acquisition::Contended => return Err(rate_limit::contended()),
+        }
+    }
+
+    Ok(())
+}
 
-    Ok(row)
+/// Publish a pin event of `channel`, with the next sequence number of the channel.
+async fn _publish_pin(
+    application: &super::ApplicationService,
+    event_type: p_channels::PChannelEventType,
+    channel: p_channels::PChannel,
+    pin: p_channels::PPin,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sequence = application
+        .storage
+        .channels
+        .next_sequence(channel.id)
+        .await?;
+    application
+        .events
+        .publish(Event::Channel(p_channels::PChannelEvent {
+            event_type: event_type.into(),
+            channel: Some(channel),
+            sequence,
+            pin: Some(pin),
+            ban: None,
+        }))
+        .await
 }
 
 #[tonic::async_trait]
 impl channel_service_server::ChannelService for super::ApplicationService {
+    type SubscribeStream =
+        Pin<Box<dyn Stream<Item = Result<p_channels::PSubscribeEvent, tonic::Status>> + Send>>;
+
     async fn create_channel(
         &self,
         request: tonic::Request<p_channels::PCreateChannelRequest>,
     ) -> Result<tonic::Response<p_channels::PChannel>, tonic::Status> {
         let request = request.into_inner();
-        let statements = _STATEMENTS
-            .get_or_try_init(|| _prepare(self))
-            .await
-            .map_err(super::ApplicationService::error)?;
-
-        let mut id = self.generate_id();
-        loop {
-            let row = self
-                .session
-                .execute_unpaged(
-                    &statements.create_channel,
-                    (&id, &request.name, &request.description, &request.owner_id),
-                )
-                .await
-                .map_err(super::ApplicationService::error)?
-                .into_rows_result()
-                .map_err(super::ApplicationService::error)?
-                .single_row::<_AppliedChannelRow>()
-                .map_err(super::ApplicationService::error)?;
-
-            if row.applied {
-                break;
+        let idempotency_key =
+            idempotency::scope("channel", request.owner_id, &request.idempotency_key)?;
+        let mut reserved = None;
+        if let Some(key) = &idempotency_key {
+            let mut abandoned = None;
+            if let Some(id) = idempotency::lookup(self, key).await? {
+                match _replay_channel(self, id, &request).await? {
+                    Some(response) => return Ok(response),
+                    None => abandoned = Some(id),
+                }
             }
 
-            id = self.generate_id();
+            match idempotency::claim(self, key, abandoned).await? {
+                idempotency::Claim::Reserved(id) => reserved = Some(id),
+                idempotency::Claim::Replayed(id) => {
+                    return _replay_channel(self, id, &request)
+                        .await?
+                        .ok_or_else(idempotency::in_progress)
+                }
+            }
         }
 
-        Ok(tonic::Response::new(p_channels::PChannel {
+        let generate_id = idempotency::starting_with(reserved, || self.generate_id());
+        let id = self
+            .storage
+            .channels
+            .create(
+                &request.name,
+                &request.description,
+                request.owner_id,
+                &generate_id,
+            )
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let result = _owned_channel_to_proto(
+            self,
+            storage::Channel {
+                id,
+                name: request.name,
+                description: request.description,
+                owner_id: request.owner_id,
+                slow_mode_seconds: 0,
+            },
+        )
+        .await
+        .map_err(super::ApplicationService::error)?;
+        audit::record(
+            self,
+            request.owner_id,
+            storage::AuditAction::ChannelCreated,
             id,
-            name: request.name,
-            description: request.description,
-            owner: Some(
-                _fetch_user(self, request.owner_id)
-                    .await
-                    .map(|user| p_users::PUser {
-                        id: user.id,
-                        username: user.username,
-                        permissions: user.permissions,
-                    })
-                    .map_err(super::ApplicationService::error)?,
-            ),
-        }))
+            id,
+            result.name.clone(),
+        )
+        .await?;
+
+        let sequence = self
+            .storage
+            .channels
+            .next_sequence(id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        self.events
+            .publish(Event::Channel(p_channels::PChannelEvent {
+                event_type: p_channels::PChannelEventType::ChannelCreated.into(),
+                channel: Some(result.clone()),
+                sequence,
+                pin: None,
+                ban: None,
+            }))
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(result))
     }
 
     async fn create_message(
@@ -265,10 +516,17 @@ impl channel_service_server::ChannelService for super::ApplicationService {
         request: tonic::Request<p_channels::PCreateMessageRequest>,
     ) -> Result<tonic::Response<p_channels::PMessage>, tonic::Status> {
         let request = request.into_inner();
-        let statements = _STATEMENTS
-            .get_or_try_init(|| _prepare(self))
-            .await
-            .map_err(super::ApplicationService::error)?;
+        let idempotency_key =
+            idempotency::scope("message", request.author_id, &request.idempotency_key)?;
+        let mut abandoned = None;
+        if let Some(key) = &idempotency_key {
+            if let Some(id) = idempotency::lookup(self, key).await? {
+                match _replay_message(self, id, request.channel_id).await? {
+                    Some(response) => return Ok(response),
+                    None => abandoned = Some(id),
+                }
+            }
+        }
 
         let author = _fetch_user(self, request.author_id)
             .await
@@ -278,91 +536,190 @@ impl channel_service_server::ChannelService for super::ApplicationService {
             .await
             .map_err(super::ApplicationService::error)?;
 
-        let mut id = self.generate_id();
-        loop {
-            let row = self
-                .session
-                .execute_unpaged(
-                    &statements.create_message1,
-                    (
-                        &id,
-                        &request.content,
-                        &request.author_id,
-                        &request.channel_id,
-                    ),
-                )
+        if let Some(ban) = super::moderation::active_ban(self, channel.id, author.id)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            return Err(super::moderation::banned(&ban));
+        }
+
+        if request.content.chars().count() > self.options.max_message_length {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Messages cannot exceed {} characters",
+                self.options.max_message_length
+            )));
+        }
+        if request
+            .content
+            .chars()
+            .any(|c| c.is_control() && !matches!(c, '\n' | '\r' | '\t'))
+        {
+            return Err(tonic::Status::invalid_argument(
+                "Messages cannot contain control characters",
+            ));
+        }
+
+        if request.attachment_ids.len() > _MAX_ATTACHMENTS_PER_MESSAGE {
+            return Err(tonic::Status::invalid_argument(format!(
+                "At most {} files can be attached to a message",
+                _MAX_ATTACHMENTS_PER_MESSAGE
+            )));
+        }
+        let attachments = attachment::fetch_attachments(self, &request.attachment_ids)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        if attachments.len() != request.attachment_ids.len()
+            || attachments.iter().any(|a| a.uploader_id != author.id)
+        {
+            return Err(tonic::Status::invalid_argument(
+                "Only existing files uploaded by the author can be attached",
+            ));
+        }
+
+        // Moderation runs before anything is stored, and may mask the content
+        let mut draft = moderation::Draft {
+            author_id: author.id,
+            channel_id: channel.id,
+            content: request.content.clone(),
+            reasons: Vec::new(),
+        };
+        if let moderation::Verdict::Reject(reason) = self
+            .moderation
+            .run(&mut draft)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Message rejected by moderation: {}",
+                reason
+            )));
+        }
+        let content = draft.content;
+
+        // Only messages which would be created take a token, so that invalid ones can be fixed and sent right away
+        _check_rate_limits(self, &author, &channel).await?;
+
+        // Unknown usernames are left as plain text
+        let rich_content = rich_text::parse(&content);
+        let parsed = mention::parse(&rich_content);
+        let mut mentions = storage::Mentions {
+            user_ids: Vec::new(),
+            channel: parsed.channel,
+        };
+        for username in &parsed.usernames {
+            if let Some(account) = self
+                .storage
+                .accounts
+                .by_username(username)
                 .await
                 .map_err(super::ApplicationService::error)?
-                .into_rows_result()
-                .map_err(super::ApplicationService::error)?
-                .single_row::<_AppliedMessageRow>()
-                .map_err(super::ApplicationService::error)?;
-
-            if row.applied {
-                break;
+            {
+                mentions.user_ids.push(account.id);
             }
+        }
 
-            id = self.generate_id();
+        // The key is claimed last, so that rejected messages can be fixed and retried with the same key
+        let mut reserved = None;
+        if let Some(key) = &idempotency_key {
+            match idempotency::claim(self, key, abandoned).await? {
+                idempotency::Claim::Reserved(id) => reserved = Some(id),
+                idempotency::Claim::Replayed(id) => {
+                    return _replay_message(self, id, request.channel_id)
+                        .await?
+                        .ok_or_else(idempotency::in_progress)
+                }
+            }
         }
 
-        self.session
-            .execute_unpaged(
-                &statements.create_message2,
-                (
-                    &id,
-                    &request.content,
-                    &request.author_id,
-                    &request.channel_id,
-                ),
+        // The sequence number is allocated once the request is known to create a message, before the message is
+        // persisted. A message failing to be persisted afterwards leaves a gap, after which subscribers merely catch up.
+        let sequence = self
+            .storage
+            .channels
+            .next_sequence(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let ttl_seconds = retention::ttl_seconds(&self.storage, request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let generate_id = idempotency::starting_with(reserved, || self.generate_id());
+        let id = self
+            .storage
+            .messages
+            .create(
+                &content,
+                &rich_text::encode(rich_content.clone()),
+                request.author_id,
+                request.channel_id,
+                &mentions,
+                &request.attachment_ids,
+                ttl_seconds,
+                &generate_id,
             )
             .await
             .map_err(super::ApplicationService::error)?;
+        self.moderation.created(author.id, &request.content);
+
+        // Posting a first message in a channel makes the author a member of that channel
+        if self
+            .storage
+            .channels
+            .joined_id(request.channel_id, request.author_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .is_none()
+        {
+            self.storage
+                .channels
+                .add_member(request.channel_id, request.author_id, id)
+                .await
+                .map_err(super::ApplicationService::error)?;
+        }
+
+        if !draft.reasons.is_empty() {
+            self.storage
+                .moderation
+                .flag(&storage::Flag {
+                    message_id: id,
+                    channel_id: channel.id,
+                    reasons: draft.reasons,
+                    flagged_at: chrono::Utc::now().timestamp_millis(),
+                })
+                .await
+                .map_err(super::ApplicationService::error)?;
+        }
 
         let result = p_channels::PMessage {
             id,
-            content: request.content,
-            author: Some(p_users::PUser {
-                id: author.id,
-                username: author.username,
-                permissions: author.permissions,
-            }),
-            channel: Some(p_channels::PChannel {
-                id: channel.id,
-                name: channel.name,
-                description: channel.description,
-                owner: Some(
-                    _fetch_user(self, channel.owner_id)
-                        .await
-                        .map(|user| p_users::PUser {
-                            id: user.id,
-                            username: user.username,
-                            permissions: user.permissions,
-                        })
-                        .map_err(super::ApplicationService::error)?,
-                ),
-            }),
+            content,
+            author: Some(author),
+            channel: Some(
+                _owned_channel_to_proto(self, channel)
+                    .await
+                    .map_err(super::ApplicationService::error)?,
+            ),
+            mentioned_user_ids: mentions.user_ids,
+            mentions_channel: mentions.channel,
+            attachments,
+            // Filled in later by the unfurler, which publishes an update
+            embeds: Vec::new(),
+            rich_content,
         };
 
-        self.rabbitmq
-            .exchange_declare(
-                "channel-messages",
-                lapin::ExchangeKind::Direct,
-                options::ExchangeDeclareOptions::default(),
-                types::FieldTable::default(),
-            )
-            .await
-            .map_err(super::ApplicationService::error)?;
-        self.rabbitmq
-            .basic_publish(
-                "channel-messages",
-                format!("channel-{}", &request.channel_id).as_str(),
-                options::BasicPublishOptions::default(),
-                result.encode_to_vec().as_slice(),
-                lapin::BasicProperties::default(),
-            )
+        self.events
+            .publish(Event::Message(p_channels::PMessageEvent {
+                event_type: p_channels::PMessageEventType::MessageCreated.into(),
+                message: Some(result.clone()),
+                sequence,
+            }))
             .await
             .map_err(super::ApplicationService::error)?;
 
+        if !crate::embeds::extract_urls(&result.content).is_empty() {
+            self.unfurler.enqueue(result.clone());
+        }
+
         Ok(tonic::Response::new(result))
     }
 
@@ -371,10 +728,9 @@ impl channel_service_server::ChannelService for super::ApplicationService {
         request: tonic::Request<p_channels::PHistoryQuery>,
     ) -> Result<tonic::Response<p_channels::PHistoryQueryResult>, tonic::Status> {
         let request = request.into_inner();
-        let statements = _STATEMENTS
-            .get_or_try_init(|| _prepare(self))
-            .await
-            .map_err(super::ApplicationService::error)?;
+        if request.user_id == 0 {
+            return Err(tonic::Status::invalid_argument("User ID is required"));
+        }
 
         let before_id = if request.before_id == 0 {
             i64::MAX
@@ -383,115 +739,921 @@ impl channel_service_server::ChannelService for super::ApplicationService {
         };
         let after_id = request.after_id;
 
-        let temp = self
-            .session
-            .execute_unpaged(
-                &statements.history[request.newest as usize],
-                (request.id, before_id, after_id, request.limit),
+        let channel = _fetch_channel(self, request.id)
+            .await
+            .map(|channel| _channel_to_proto(channel, None))
+            .map_err(super::ApplicationService::error)?;
+
+        _check_reader(self, channel.id, request.user_id).await?;
+
+        // Messages cannot be newer than the current time
+        let rows = self
+            .storage
+            .messages
+            .history(
+                request.id,
+                after_id,
+                before_id.min(self.latest_id()),
+                request.newest,
+                request.limit,
             )
             .await
-            .map_err(super::ApplicationService::error)?
-            .into_rows_result()
             .map_err(super::ApplicationService::error)?;
 
-        let channel = _fetch_channel(self, request.id)
+        let blocked = relationship::blocked_ids(self, request.user_id)
             .await
-            .map(|channel| p_channels::PChannel {
-                id: channel.id,
-                name: channel.name,
-                description: channel.description,
-                owner: None,
-            })
             .map_err(super::ApplicationService::error)?;
+        let rows = rows
+            .into_iter()
+            .filter(|row| !blocked.contains(&row.author_id))
+            .collect();
 
-        let mut authors = collections::HashMap::new();
+        Ok(tonic::Response::new(p_channels::PHistoryQueryResult {
+            messages: hydrate_messages(self, rows, [(channel.id, channel)].into())
+                .await
+                .map_err(super::ApplicationService::error)?,
+        }))
+    }
+
+    async fn query(
+        &self,
+        request: tonic::Request<p_channels::PChannelQuery>,
+    ) -> Result<tonic::Response<p_channels::PChannelQueryResult>, tonic::Status> {
+        let request = request.into_inner();
+
+        let mut temp = Vec::new();
+        let mut paging_state = Vec::new();
+        if request.id == 0 {
+            let page_size = if request.page_size > 0 {
+                request.page_size.min(_MAX_PAGE_SIZE)
+            } else {
+                _DEFAULT_PAGE_SIZE
+            };
+
+            // The paging state is the ID of the last scanned channel, encoded in big-endian.
+            let cursor = if request.paging_state.is_empty() {
+                None
+            } else {
+                Some(i64::from_be_bytes(
+                    request
+                        .paging_state
+                        .as_slice()
+                        .try_into()
+                        .map_err(|_| tonic::Status::invalid_argument("Invalid paging state"))?,
+                ))
+            };
+
+            let (mut lower, mut upper) = if request.newest {
+                (i64::MIN, cursor.unwrap_or(i64::MAX))
+            } else {
+                (cursor.unwrap_or(i64::MIN), i64::MAX)
+            };
+
+            let source = if request.member_id != 0 {
+                storage::ChannelSource::Member(request.member_id)
+            } else if request.owner_id != 0 {
+                storage::ChannelSource::Owner(request.owner_id)
+            } else {
+                storage::ChannelSource::All
+            };
+            let name_prefix = request.name_prefix.to_lowercase();
+
+            let mut scanned = 0;
+            let exhausted = 'scan: loop {
+                // Channels cannot be newer than the current time
+                let ids = self
+                    .storage
+                    .channels
+                    .scan(
+                        &source,
+                        request.newest,
+                        lower,
+                        upper.min(self.latest_id() + 1),
+                        page_size,
+                    )
+                    .await
+                    .map_err(super::ApplicationService::error)?;
+                let partial = ids.len() < page_size as usize;
+                let count = ids.len();
+
+                for (index, id) in ids.into_iter().enumerate() {
+                    scanned += 1;
+                    if request.newest {
+                        upper = id;
+                    } else {
+                        lower = id;
+                    }
+
+                    // Index tables may briefly reference channels whose row is not yet visible
+                    if let Ok(channel) = _fetch_channel(self, id).await {
+                        if (request.owner_id == 0 || channel.owner_id == request.owner_id)
+                            && channel.name.to_lowercase().starts_with(&name_prefix)
+                        {
+                            temp.push(channel);
+                        }
+                    }
+
+                    if temp.len() >= page_size as usize || scanned >= _MAX_SCANNED_CHANNELS {
+                        break 'scan partial && index + 1 == count;
+                    }
+                }
+
+                if partial {
+                    break true;
+                }
+            };
+
+            if !exhausted {
+                paging_state = if request.newest { upper } else { lower }
+                    .to_be_bytes()
+                    .to_vec();
+            }
+        } else if let Ok(channel) = _fetch_channel(self, request.id).await {
+            temp.push(channel);
+        }
+
+        let mut owners = collections::HashMap::new();
         let mut result = Vec::new();
-        for row in temp
-            .rows::<_MessageRow>()
-            .map_err(super::ApplicationService::error)?
-            .flatten()
-        {
-            if let Entry::Vacant(e) = authors.entry(row.author_id) {
+        for row in temp {
+            if let Entry::Vacant(e) = owners.entry(row.owner_id) {
                 e.insert(
-                    _fetch_user(self, row.author_id)
+                    _fetch_user(self, row.owner_id)
                         .await
-                        .map_err(super::ApplicationService::error)
-                        .map(|user| p_users::PUser {
-                            id: user.id,
-                            username: user.username,
-                            permissions: user.permissions,
-                        })?,
+                        .map_err(super::ApplicationService::error)?,
                 );
             }
 
-            result.push(p_channels::PMessage {
-                id: row.id,
-                content: row.content,
-                author: Some(authors[&row.author_id].clone()),
-                channel: Some(channel.clone()),
-            });
+            let owner = owners[&row.owner_id].clone();
+            result.push(_channel_to_proto(row, Some(owner)));
         }
 
-        Ok(tonic::Response::new(p_channels::PHistoryQueryResult {
-            messages: result,
+        Ok(tonic::Response::new(p_channels::PChannelQueryResult {
+            channels: result,
+            paging_state,
         }))
     }
 
-    async fn query(
+    async fn search_messages(
         &self,
-        request: tonic::Request<p_channels::PChannelQuery>,
-    ) -> Result<tonic::Response<p_channels::PChannelQueryResult>, tonic::Status> {
+        request: tonic::Request<p_channels::PMessageSearchQuery>,
+    ) -> Result<tonic::Response<p_channels::PMessageSearchResult>, tonic::Status> {
         let request = request.into_inner();
-        let statements = _STATEMENTS
-            .get_or_try_init(|| _prepare(self))
+        let limit = if request.limit > 0 {
+            request.limit.min(_MAX_SEARCH_LIMIT)
+        } else {
+            _DEFAULT_SEARCH_LIMIT
+        };
+
+        if request.user_id == 0 {
+            return Err(tonic::Status::invalid_argument("User ID is required"));
+        }
+
+        let mut filter = search::SearchFilter {
+            author_id: (request.author_id != 0).then_some(request.author_id),
+            channel_id: (request.channel_id != 0).then_some(request.channel_id),
+            before_id: if request.before_id == 0 {
+                i64::MAX
+            } else {
+                request.before_id
+            },
+            after_id: request.after_id,
+            hidden_author_ids: relationship::blocked_ids(self, request.user_id)
+                .await
+                .map_err(super::ApplicationService::error)?,
+            hidden_channel_ids: collections::HashSet::new(),
+        };
+
+        // Bans are only known per channel, so hits from a banned channel hide it and the search is run again
+        let mut readable = collections::HashSet::new();
+        let rows = 'search: loop {
+            let ids = self.search.messages.search(
+                &request.keywords,
+                &filter,
+                request.order() == p_channels::PMessageSearchOrder::Relevance,
+                limit as usize,
+            );
+
+            let mut rows = Vec::new();
+            for id in ids {
+                // The index may briefly lag behind deletions
+                let row = match _fetch_message(self, id).await {
+                    Ok(row) => row,
+                    Err(_) => continue,
+                };
+
+                if !readable.contains(&row.channel_id) {
+                    let ban = super::moderation::active_ban(self, row.channel_id, request.user_id)
+                        .await
+                        .map_err(super::ApplicationService::error)?;
+                    if ban.is_some_and(|ban| !ban.timeout) {
+                        filter.hidden_channel_ids.insert(row.channel_id);
+                        continue 'search;
+                    }
+
+                    readable.insert(row.channel_id);
+                }
+
+                rows.push(row);
+            }
+
+            break rows;
+        };
+
+        let messages = hydrate_messages(self, rows, collections::HashMap::new())
             .await
             .map_err(super::ApplicationService::error)?;
 
-        let temp = if request.id == 0 {
-            self.session
-                .execute_unpaged(&statements.query, ())
-                .await
-                .map_err(super::ApplicationService::error)?
-                .into_rows_result()
-                .map_err(super::ApplicationService::error)?
-                .rows::<_ChannelRow>()
-                .map_err(super::ApplicationService::error)?
-                .flatten()
-                .collect()
+        Ok(tonic::Response::new(p_channels::PMessageSearchResult {
+            messages,
+        }))
+    }
+
+    async fn search_channels(
+        &self,
+        request: tonic::Request<p_channels::PChannelSearchQuery>,
+    ) -> Result<tonic::Response<p_channels::PChannelSearchResult>, tonic::Status> {
+        let request = request.into_inner();
+        let limit = if request.limit > 0 {
+            request.limit.min(_MAX_CHANNEL_SEARCH_LIMIT)
         } else {
-            let channel = _fetch_channel(self, request.id).await;
-            match channel {
-                Ok(channel) => vec![channel],
-                Err(_) => Vec::new(),
+            _DEFAULT_CHANNEL_SEARCH_LIMIT
+        };
+
+        let activity = match request.order() {
+            p_channels::PChannelSearchOrder::Match => None,
+            p_channels::PChannelSearchOrder::Activity => {
+                let hours = if request.activity_hours > 0 {
+                    request.activity_hours
+                } else {
+                    _DEFAULT_ACTIVITY_HOURS
+                };
+                Some(self.search.messages.activity(
+                    self.snowflake(chrono::Utc::now() - chrono::Duration::hours(hours.into())),
+                ))
             }
         };
 
+        let ids = self
+            .search
+            .channels
+            .search(&request.query, activity.as_ref(), limit as usize);
+
         let mut owners = collections::HashMap::new();
         let mut result = Vec::new();
-        for row in temp {
+        for id in ids {
+            // The directory may briefly lag behind deletions
+            let row = match _fetch_channel(self, id).await {
+                Ok(row) => row,
+                Err(_) => continue,
+            };
+
             if let Entry::Vacant(e) = owners.entry(row.owner_id) {
                 e.insert(
                     _fetch_user(self, row.owner_id)
                         .await
-                        .map_err(super::ApplicationService::error)
-                        .map(|user| p_users::PUser {
-                            id: user.id,
-                            username: user.username,
-                            permissions: user.permissions,
-                        })?,
+                        .map_err(super::ApplicationService::error)?,
                 );
             }
 
-            result.push(p_channels::PChannel {
-                id: row.id,
-                name: row.name,
-                description: row.description,
-                owner: Some(owners[&row.owner_id].clone()),
-            });
+            let owner = owners[&row.owner_id].clone();
+            result.push(_channel_to_proto(row, Some(owner)));
         }
 
-        Ok(tonic::Response::new(p_channels::PChannelQueryResult {
+        Ok(tonic::Response::new(p_channels::PChannelSearchResult {
             channels: result,
         }))
     }
+
+    async fn subscribe(
+        &self,
+        request: tonic::Request<p_channels::PSubscribeRequest>,
+    ) -> Result<tonic::Response<Self::SubscribeStream>, tonic::Status> {
+        let request = request.into_inner();
+        if request.channel_ids.is_empty() {
+            return Err(tonic::Status::invalid_argument(
+                "At least one channel must be subscribed to",
+            ));
+        }
+
+        let mut channels = collections::HashMap::new();
+        for id in request.channel_ids {
+            let channel = self
+                .storage
+                .channels
+                .get(id)
+                .await
+                .map_err(super::ApplicationService::error)?
+                .ok_or_else(|| tonic::Status::not_found(format!("Channel {} not found", id)))?;
+            _check_reader(self, id, request.user_id).await?;
+            channels.insert(id, _channel_to_proto(channel, None));
+        }
+
+        // Subscribe before replaying, so that messages created in the meantime are not missed
+        let topics = Topics {
+            channel_ids: Some(channels.keys().copied().collect()),
+            user_ids: Some(vec![request.user_id]),
+            presence_user_ids: Some(request.presence_user_ids.clone()),
+            skip_typing: false,
+        };
+        let live = self
+            .events
+            .subscribe(&format!("subscribe-{}", self.generate_id()), topics)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        // ID of the newest replayed message of each channel, live creations up to it are duplicates
+        let mut replayed = collections::HashMap::new();
+        let mut replay = Vec::new();
+        if request.resume_from_id != 0 {
+            let blocked = relationship::blocked_ids(self, request.user_id)
+                .await
+                .map_err(super::ApplicationService::error)?;
+            for (id, channel) in &channels {
+                let rows = self
+                    .storage
+                    .messages
+                    .history(
+                        *id,
+                        request.resume_from_id.saturating_add(1),
+                        self.latest_id(),
+                        false,
+                        _MAX_REPLAY_MESSAGES,
+                    )
+                    .await
+                    .map_err(super::ApplicationService::error)?;
+
+                if let Some(row) = rows.last() {
+                    replayed.insert(*id, row.id);
+                }
+                let rows = rows
+                    .into_iter()
+                    .filter(|row| !blocked.contains(&row.author_id))
+                    .collect();
+                replay.extend(
+                    hydrate_messages(self, rows, [(*id, channel.clone())].into())
+                        .await
+                        .map_err(super::ApplicationService::error)?,
+                );
+            }
+        }
+        replay.sort_by_key(|message| message.id);
+
+        let replay = stream::iter(replay.into_iter().map(|message| {
+            Ok(p_channels::PSubscribeEvent {
+                event: Some(p_channels::p_subscribe_event::Event::MessageEvent(
+                    p_channels::PMessageEvent {
+                        event_type: p_channels::PMessageEventType::MessageCreated.into(),
+                        message: Some(message),
+                        sequence: 0,
+                    },
+                )),
+            })
+        }));
+
+        let presence_user_ids = request
+            .presence_user_ids
+            .into_iter()
+            .collect::<collections::HashSet<_>>();
+        let live = live.filter_map(move |event| {
+            let result = match event {
+                Ok(Event::Message(event)) => {
+                    let message = event.message.as_ref();
+                    let channel_id = message.and_then(|m| m.channel.as_ref()).map_or(0, |c| c.id);
+                    let duplicate = event.event_type()
+                        == p_channels::PMessageEventType::MessageCreated
+                        && replayed
+                            .get(&channel_id)
+                            .is_some_and(|newest| message.map_or(0, |m| m.id) <= *newest);
+
+                    (channels.contains_key(&channel_id) && !duplicate).then(|| {
+                        Ok(p_channels::PSubscribeEvent {
+                            event: Some(p_channels::p_subscribe_event::Event::MessageEvent(event)),
+                        })
+                    })
+                }
+                Ok(Event::Channel(event)) => {
+                    let channel_id = event.channel.as_ref().map_or(0, |c| c.id);
+                    let subscribed = channels.contains_key(&channel_id);
+
+                    // Banned users are told once, then stop receiving the events of the channel
+                    if event.event_type() == p_channels::PChannelEventType::MemberBanned
+                        && event
+                            .ban
+                            .as_ref()
+                            .and_then(|ban| ban.user.as_ref())
+                            .is_some_and(|user| user.id == request.user_id)
+                    {
+                        channels.remove(&channel_id);
+                    }
+
+                    subscribed.then(|| {
+                        Ok(p_channels::PSubscribeEvent {
+                            event: Some(p_channels::p_subscribe_event::Event::ChannelEvent(event)),
+                        })
+                    })
+                }
+                Ok(Event::ReadState(event)) => (event.user_id == request.user_id).then(|| {
+                    Ok(p_channels::PSubscribeEvent {
+                        event: Some(p_channels::p_subscribe_event::Event::ReadStateEvent(event)),
+                    })
+                }),
+                Ok(Event::Typing(event)) => channels.contains_key(&event.channel_id).then(|| {
+                    Ok(p_channels::PSubscribeEvent {
+                        event: Some(p_channels::p_subscribe_event::Event::TypingEvent(event)),
+                    })
+                }),
+                Ok(Event::Presence(event)) => {
+                    presence_user_ids.contains(&event.user_id).then(|| {
+                        Ok(p_channels::PSubscribeEvent {
+                            event: Some(p_channels::p_subscribe_event::Event::PresenceEvent(event)),
+                        })
+                    })
+                }
+                Err(e) => Some(Err(super::ApplicationService::error(e))),
+            };
+
+            async move { result }
+        });
+
+        Ok(tonic::Response::new(Box::pin(replay.chain(live))))
+    }
+
+    async fn catchup(
+        &self,
+        request: tonic::Request<p_channels::PCatchupRequest>,
+    ) -> Result<tonic::Response<p_channels::PCatchupResult>, tonic::Status> {
+        let request = request.into_inner();
+        let limit = if request.limit > 0 {
+            request.limit.min(_MAX_CATCHUP_MESSAGES)
+        } else {
+            _MAX_CATCHUP_MESSAGES
+        };
+
+        let channel = self
+            .storage
+            .channels
+            .get(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+        _check_reader(self, channel.id, request.user_id).await?;
+
+        // Read the sequence number first, so that every later event is either fetched below or delivered afterwards
+        let sequence = self
+            .storage
+            .channels
+            .sequence(channel.id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let blocked = relationship::blocked_ids(self, request.user_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        // Messages of blocked users are skipped, so windows are fetched until enough messages are left
+        let before_id = self.latest_id();
+        let mut after_id = request.last_seen_id.saturating_add(1);
+        let mut rows = Vec::new();
+        let complete = loop {
+            // Fetch one extra message to know whether the window was exhausted
+            let window = self
+                .storage
+                .messages
+                .history(channel.id, after_id, before_id, false, limit + 1)
+                .await
+                .map_err(super::ApplicationService::error)?;
+            let exhausted = window.len() <= limit as usize;
+            if let Some(row) = window.last() {
+                after_id = row.id.saturating_add(1);
+            }
+            rows.extend(
+                window
+                    .into_iter()
+                    .filter(|row| !blocked.contains(&row.author_id)),
+            );
+
+            if rows.len() > limit as usize {
+                break false;
+            }
+            if exhausted {
+                break true;
+            }
+        };
+        rows.truncate(limit as usize);
+
+        let messages = hydrate_messages(
+            self,
+            rows,
+            [(channel.id, _channel_to_proto(channel, None))].into(),
+        )
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(p_channels::PCatchupResult {
+            messages,
+            complete,
+            sequence,
+        }))
+    }
+
+    async fn mark_read(
+        &self,
+        request: tonic::Request<p_channels::PMarkReadRequest>,
+    ) -> Result<tonic::Response<p_channels::PReadState>, tonic::Status> {
+        let request = request.into_inner();
+        let channel = self
+            .storage
+            .channels
+            .get(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+
+        let message_id = if request.message_id > 0 {
+            request.message_id
+        } else {
+            self.latest_id()
+        };
+
+        let last_read_id = self
+            .storage
+            .read_states
+            .mark_read(request.user_id, channel.id, message_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let read_state = p_channels::PReadState {
+            channel_id: channel.id,
+            last_read_id,
+        };
+        if let Err(e) = self
+            .events
+            .publish(Event::ReadState(p_channels::PReadStateEvent {
+                user_id: request.user_id,
+                read_state: Some(read_state),
+            }))
+            .await
+        {
+            eprintln!("Unable to publish read state event: {}", e);
+        }
+
+        Ok(tonic::Response::new(read_state))
+    }
+
+    async fn get_unread_counts(
+        &self,
+        request: tonic::Request<p_channels::PUnreadCountsRequest>,
+    ) -> Result<tonic::Response<p_channels::PUnreadCounts>, tonic::Status> {
+        let request = request.into_inner();
+        let user = _fetch_user(self, request.user_id)
+            .await
+            .map_err(|_| tonic::Status::not_found("User not found"))?;
+
+        let explicit = !request.channel_ids.is_empty();
+        let channel_ids = if !explicit {
+            let source = storage::ChannelSource::Member(user.id);
+            let mut channel_ids = Vec::new();
+            let mut lower = 0;
+            loop {
+                let page = self
+                    .storage
+                    .channels
+                    .scan(&source, false, lower, i64::MAX, _MAX_PAGE_SIZE)
+                    .await
+                    .map_err(super::ApplicationService::error)?;
+                let exhausted = page.len() < _MAX_PAGE_SIZE as usize;
+                lower = page.last().copied().unwrap_or(lower);
+                channel_ids.extend(page);
+                if exhausted {
+                    break;
+                }
+            }
+            channel_ids
+        } else {
+            request.channel_ids
+        };
+
+        let read_states = self
+            .storage
+            .read_states
+            .list(user.id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .into_iter()
+            .map(|state| (state.channel_id, state.last_read_id))
+            .collect::<collections::HashMap<_, _>>();
+
+        let blocked = relationship::blocked_ids(self, user.id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let muted = relationship::muted_ids(self, user.id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let mut counts = Vec::new();
+        for channel_id in channel_ids {
+            // Only members can read a channel, and banned members no longer can
+            let joined_id = self
+                .storage
+                .channels
+                .joined_id(channel_id, user.id)
+                .await
+                .map_err(super::ApplicationService::error)?;
+            let ban = super::moderation::active_ban(self, channel_id, user.id)
+                .await
+                .map_err(super::ApplicationService::error)?
+                .filter(|ban| !ban.timeout);
+            let joined_id = match (joined_id, ban) {
+                (Some(joined_id), None) => joined_id,
+                (None, _) if explicit => {
+                    return Err(tonic::Status::permission_denied(format!(
+                        "Not a member of channel {}",
+                        channel_id
+                    )))
+                }
+                (_, Some(ban)) if explicit => return Err(super::moderation::banned(&ban)),
+                _ => continue,
+            };
+
+            // Messages older than the membership are never unread, which also bounds the scan of new members
+            let last_read_id = read_states.get(&channel_id).copied().unwrap_or(0);
+            let unread = self
+                .storage
+                .messages
+                .history(
+                    channel_id,
+                    last_read_id.max(joined_id).saturating_add(1),
+                    self.latest_id(),
+                    false,
+                    _MAX_UNREAD_COUNT,
+                )
+                .await
+                .map_err(super::ApplicationService::error)?
+                .into_iter()
+                .filter(|message| {
+                    message.author_id != user.id && !blocked.contains(&message.author_id)
+                })
+                .collect::<Vec<_>>();
+
+            counts.push(p_channels::PUnreadCount {
+                channel_id,
+                last_read_id,
+                unread_count: unread.len() as i32,
+                mention_count: unread
+                    .iter()
+                    .filter(|message| {
+                        !muted.contains(&channel_id)
+                            && (message.mentions.channel
+                                || message.mentions.user_ids.contains(&user.id))
+                    })
+                    .count() as i32,
+            });
+        }
+
+        Ok(tonic::Response::new(p_channels::PUnreadCounts { counts }))
+    }
+
+    async fn list_mentions(
+        &self,
+        request: tonic::Request<p_channels::PListMentionsRequest>,
+    ) -> Result<tonic::Response<p_channels::PListMentionsResult>, tonic::Status> {
+        let request = request.into_inner();
+        let limit = if request.limit > 0 {
+            request.limit.min(_MAX_PAGE_SIZE)
+        } else {
+            _DEFAULT_PAGE_SIZE
+        };
+        let before_id = if request.before_id > 0 {
+            request.before_id
+        } else {
+            i64::MAX
+        };
+
+        let rows = self
+            .storage
+            .messages
+            .mentioning(request.user_id, before_id, limit)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        let blocked = relationship::blocked_ids(self, request.user_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let muted = relationship::muted_ids(self, request.user_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let rows = rows
+            .into_iter()
+            .filter(|row| !blocked.contains(&row.author_id) && !muted.contains(&row.channel_id))
+            .collect();
+        let messages = hydrate_messages(self, rows, collections::HashMap::new())
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(p_channels::PListMentionsResult {
+            messages,
+        }))
+    }
+
+    async fn pin_message(
+        &self,
+        request: tonic::Request<p_channels::PPinRequest>,
+    ) -> Result<tonic::Response<p_channels::PPin>, tonic::Status> {
+        let request = request.into_inner();
+        let channel = _authorize_pin(self, &request).await?;
+
+        let pin = storage::Pin {
+            channel_id: channel.id,
+            message_id: request.message_id,
+            pinned_by: request.user_id,
+            pinned_at: chrono::Utc::now().timestamp_millis(),
+        };
+        if !self
+            .storage
+            .pins
+            .pin(&pin, self.options.max_pins_per_channel)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            return Err(tonic::Status::failed_precondition(format!(
+                "At most {} messages can be pinned in a channel",
+                self.options.max_pins_per_channel
+            )));
+        }
+        audit::record(
+            self,
+            pin.pinned_by,
+            storage::AuditAction::MessagePinned,
+            pin.message_id,
+            pin.channel_id,
+            String::new(),
+        )
+        .await?;
+
+        let channel = _channel_to_proto(channel, None);
+        let result = _hydrate_pins(self, vec![pin], &channel)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .pop()
+            .ok_or_else(|| tonic::Status::not_found("Message not found"))?;
+
+        _publish_pin(
+            self,
+            p_channels::PChannelEventType::MessagePinned,
+            channel,
+            result.clone(),
+        )
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(result))
+    }
+
+    async fn unpin_message(
+        &self,
+        request: tonic::Request<p_channels::PPinRequest>,
+    ) -> Result<tonic::Response<p_channels::PPin>, tonic::Status> {
+        let request = request.into_inner();
+        let channel = _authorize_pin(self, &request).await?;
+
+        let pin = self
+            .storage
+            .pins
+            .list(channel.id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .into_iter()
+            .find(|pin| pin.message_id == request.message_id)
+            .ok_or_else(|| tonic::Status::not_found("Message is not pinned"))?;
+        if !self
+            .storage
+            .pins
+            .unpin(channel.id, request.message_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            return Err(tonic::Status::not_found("Message is not pinned"));
+        }
+        audit::record(
+            self,
+            request.user_id,
+            storage::AuditAction::MessageUnpinned,
+            request.message_id,
+            channel.id,
+            String::new(),
+        )
+        .await?;
+
+        let channel = _channel_to_proto(channel, None);
+        let result = _hydrate_pins(self, vec![pin], &channel)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .pop()
+            .ok_or_else(|| tonic::Status::not_found("Message not found"))?;
+
+        _publish_pin(
+            self,
+            p_channels::PChannelEventType::MessageUnpinned,
+            channel,
+            result.clone(),
+        )
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(result))
+    }
+
+    async fn list_pins(
+        &self,
+        request: tonic::Request<p_channels::PListPinsRequest>,
+    ) -> Result<tonic::Response<p_channels::PListPinsResult>, tonic::Status> {
+        let request = request.into_inner();
+        let channel = _fetch_channel(self, request.channel_id)
+            .await
+            .map_err(|_| tonic::Status::not_found("Channel not found"))?;
+        _check_reader(self, channel.id, request.user_id).await?;
+
+        let pins = self
+            .storage
+            .pins
+            .list(channel.id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let pins = _hydrate_pins(self, pins, &_channel_to_proto(channel, None))
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(p_channels::PListPinsResult { pins }))
+    }
+
+    async fn set_slow_mode(
+        &self,
+        request: tonic::Request<p_channels::PSetSlowModeRequest>,
+    ) -> Result<tonic::Response<p_channels::PChannel>, tonic::Status> {
+        let request = request.into_inner();
+        if !(0..=_MAX_SLOW_MODE_SECONDS).contains(&request.seconds) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Slow mode must be between 0 and {} seconds",
+                _MAX_SLOW_MODE_SECONDS
+            )));
+        }
+
+        let user = _fetch_user(self, request.user_id)
+            .await
+            .map_err(|_| tonic::Status::not_found("User not found"))?;
+        let channel = _fetch_channel(self, request.channel_id)
+            .await
+            .map_err(|_| tonic::Status::not_found("Channel not found"))?;
+        if user.id != channel.owner_id
+            && user.permissions & p_users::PPermission::ManageMessages as i64 == 0
+        {
+            return Err(tonic::Status::permission_denied(
+                "Only the channel owner or users allowed to manage messages can change slow mode",
+            ));
+        }
+
+        self.storage
+            .channels
+            .set_slow_mode(channel.id, request.seconds)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        audit::record(
+            self,
+            user.id,
+            storage::AuditAction::SlowModeChanged,
+            channel.id,
+            channel.id,
+            format!("{} seconds", request.seconds),
+        )
+        .await?;
+
+        let result = _owned_channel_to_proto(
+            self,
+            storage::Channel {
+                slow_mode_seconds: request.seconds,
+                ..channel
+            },
+        )
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+        let sequence = self
+            .storage
+            .channels
+            .next_sequence(channel.id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        self.events
+            .publish(Event::Channel(p_channels::PChannelEvent {
+                event_type: p_channels::PChannelEventType::ChannelUpdated.into(),
+                channel: Some(result.clone()),
+                sequence,
+                pin: None,
+                ban: None,
+            }))
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(result))
+    }
 }
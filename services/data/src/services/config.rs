@@ -1,10 +1,7 @@
-use std::sync::Arc;
-
 use rand::distr;
 use rand::rngs;
 use rand::Rng;
 use rand::SeedableRng;
-use scylla::macros;
 use tokio::sync;
 
 use super::p_config;
@@ -13,45 +10,22 @@ use super::p_config::PConfigType;
 
 static SECRET_KEY: sync::OnceCell<String> = sync::OnceCell::const_new();
 
-#[allow(dead_code)]
-#[derive(Debug, macros::DeserializeRow)]
-struct InsertCFGText {
-    #[scylla(rename = "[applied]")]
-    applied: bool,
-    key: Option<String>,
-    value: Option<String>,
-}
-
 async fn _get_secret_key(
-    session: &Arc<scylla::Session>,
-) -> Result<&String, Box<dyn std::error::Error>> {
-    async fn _fetch_secret_key(
-        session: Arc<scylla::Session>,
-    ) -> Result<String, Box<dyn std::error::Error>> {
-        let mut rng = rngs::StdRng::from_os_rng();
-        let new_key = (0..32)
-            .map(|_| rng.sample(distr::Alphanumeric) as char)
-            .collect::<String>();
-        let row = session
-            .query_unpaged(
-                r"INSERT INTO config.cfg_text (key, value)
-                VALUES ('secret_key', ?)
-                IF NOT EXISTS",
-                (&new_key,),
-            )
-            .await?
-            .into_rows_result()?
-            .single_row::<InsertCFGText>()?;
-
-        if row.applied {
-            Ok(new_key)
-        } else {
-            row.value.ok_or("Unable to fetch secret key".into())
-        }
-    }
-
+    application: &super::ApplicationService,
+) -> Result<&String, Box<dyn std::error::Error + Send + Sync>> {
     SECRET_KEY
-        .get_or_try_init(|| _fetch_secret_key(session.clone()))
+        .get_or_try_init(|| async {
+            let mut rng = rngs::StdRng::from_os_rng();
+            let new_key = (0..32)
+                .map(|_| rng.sample(distr::Alphanumeric) as char)
+                .collect::<String>();
+
+            application
+                .storage
+                .config
+                .insert_text("secret_key", &new_key)
+                .await
+        })
         .await
 }
 
@@ -69,7 +43,7 @@ impl config_service_server::ConfigService for super::ApplicationService {
 
         match config_type {
             PConfigType::SecretKey => Ok(tonic::Response::new(
-                _get_secret_key(&self.session)
+                _get_secret_key(self)
                     .await
                     .map_err(super::ApplicationService::error)?
                     .clone(),
@@ -0,0 +1,220 @@
+use std::collections;
+use std::sync::Arc;
+
+use futures::{stream, StreamExt};
+use tokio::sync::mpsc;
+
+use super::p_channels;
+use crate::embeds::{self, Fetcher};
+use crate::events::{Event, EventPublisher};
+use crate::storage::{self, Storage};
+
+/// Maximum number of messages waiting to be unfurled, further messages are not unfurled.
+const _QUEUE_CAPACITY: usize = 1024;
+
+/// Maximum number of messages unfurled at the same time.
+const _CONCURRENCY: usize = 4;
+
+/// Convert an embed into its protobuf representation.
+pub fn to_proto(embed: storage::Embed) -> p_channels::PEmbed {
+    p_channels::PEmbed {
+        url: embed.url,
+        title: embed.title,
+        description: embed.description,
+        image_url: embed.image_url,
+    }
+}
+
+/// Fetch the embeds of several messages in a single query, e.g. those of a page of messages, keyed by message ID.
+pub async fn fetch_embeds_by_message(
+    application: &super::ApplicationService,
+    message_ids: &[i64],
+) -> Result<
+    collections::HashMap<i64, Vec<p_channels::PEmbed>>,
+    Box<dyn std::error::Error + Send + Sync>,
+> {
+    if message_ids.is_empty() {
+        return Ok(collections::HashMap::new());
+    }
+
+    Ok(application
+        .storage
+        .embeds
+        .list_many(message_ids)
+        .await?
+        .into_iter()
+        .map(|(id, embeds)| (id, embeds.into_iter().map(to_proto).collect()))
+        .collect())
+}
+
+/// Queue of messages whose links are unfurled in the background, see [`_work`].
+pub struct Unfurler {
+    sender: mpsc::Sender<p_channels::PMessage>,
+}
+
+impl Unfurler {
+    /// Create a queue, and spawn the worker consuming it.
+    pub fn spawn(
+        fetcher: Arc<dyn Fetcher>,
+        storage: Storage,
+        events: Arc<dyn EventPublisher>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel(_QUEUE_CAPACITY);
+        tokio::spawn(_work(receiver, fetcher, storage, events));
+
+        Self { sender }
+    }
+
+    /// Unfurl the links of `message` eventually, dropping it if the queue is full.
+    pub fn enqueue(&self, message: p_channels::PMessage) {
+        if let Err(e) = self.sender.try_send(message) {
+            eprintln!("Unable to queue links for unfurling: {}", e);
+        }
+    }
+}
+
+/// Unfurl the links of queued messages until the queue is closed.
+async fn _work(
+    receiver: mpsc::Receiver<p_channels::PMessage>,
+    fetcher: Arc<dyn Fetcher>,
+    storage: Storage,
+    events: Arc<dyn EventPublisher>,
+) {
+    let messages = stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|message| (message, receiver))
+    });
+    messages
+        .for_each_concurrent(_CONCURRENCY, |message| {
+            let (fetcher, storage, events) = (fetcher.clone(), storage.clone(), events.clone());
+            async move {
+                let id = message.id;
+                if let Err(e) = _unfurl(message, fetcher.as_ref(), &storage, events.as_ref()).await
+                {
+                    eprintln!("Unable to unfurl links of message {}: {}", id, e);
+                }
+            }
+        })
+        .await;
+}
+
+/// Unfurl the links of `message`, store the resulting embeds and announce the updated message.
+///
+/// Links which cannot be fetched are skipped, nothing is stored or published if no link could be unfurled.
+async fn _unfurl(
+    mut message: p_channels::PMessage,
+    fetcher: &dyn Fetcher,
+    storage: &Storage,
+    events: &dyn EventPublisher,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut embeds = Vec::new();
+    for url in embeds::extract_urls(&message.content) {
+        match fetcher.fetch(&url).await {
+            Ok(Some(html)) => embeds.extend(embeds::parse(&url, &html)),
+            Ok(None) => {}
+            Err(e) => eprintln!("Unable to fetch {}: {}", url, e),
+        }
+    }
+    if embeds.is_empty() {
+        return Ok(());
+    }
+
+    storage.embeds.set(message.id, &embeds).await?;
+    message.embeds = embeds.into_iter().map(to_proto).collect();
+
+    let channel_id = message.channel.as_ref().map_or(0, |channel| channel.id);
+    let sequence = storage.channels.next_sequence(channel_id).await?;
+    events
+        .publish(Event::Message(p_channels::PMessageEvent {
+            event_type: p_channels::PMessageEventType::MessageUpdated.into(),
+            message: Some(message),
+            sequence,
+        }))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::broadcast::BroadcastPublisher;
+    use crate::events::Topics;
+    use crate::storage::memory::MemoryStorage;
+
+    /// Serves fixed pages, and fails for any other link.
+    struct _Pages(collections::HashMap<&'static str, &'static str>);
+
+    #[tonic::async_trait]
+    impl Fetcher for _Pages {
+        async fn fetch(
+            &self,
+            url: &str,
+        ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+            match self.0.get(url) {
+                Some(html) => Ok(Some(html.to_string())),
+                None => Err(format!("{} not found", url).into()),
+            }
+        }
+    }
+
+    fn _message(id: i64, content: &str) -> p_channels::PMessage {
+        p_channels::PMessage {
+            id,
+            content: content.to_string(),
+            channel: Some(p_channels::PChannel {
+                id: 1,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn unfurl_stores_and_announces_embeds() {
+        let storage = Storage::from_backend(Arc::new(MemoryStorage::default()));
+        let events = BroadcastPublisher::default();
+        let mut updates = events.subscribe("test", Topics::default()).await.unwrap();
+        let fetcher = _Pages(collections::HashMap::from([
+            ("https://a.b", "<title>A</title>"),
+            ("https://c.d", "<p>No metadata</p>"),
+        ]));
+
+        // Pages without metadata and failing links are skipped
+        let message = _message(7, "https://missing.example https://c.d https://a.b");
+        _unfurl(message, &fetcher, &storage, &events).await.unwrap();
+
+        let stored = storage.embeds.list_many(&[7]).await.unwrap();
+        assert_eq!(stored[&7].len(), 1);
+        assert_eq!(stored[&7][0].title, "A");
+
+        let Some(Ok(Event::Message(event))) = updates.next().await else {
+            panic!("Expected a message event");
+        };
+        assert_eq!(
+            event.event_type(),
+            p_channels::PMessageEventType::MessageUpdated
+        );
+        assert_eq!(event.sequence, 1);
+        let message = event.message.unwrap();
+        assert_eq!(message.id, 7);
+        assert_eq!(message.embeds.len(), 1);
+        assert_eq!(message.embeds[0].url, "https://a.b");
+    }
+
+    #[tokio::test]
+    async fn unfurl_leaves_messages_without_previews_untouched() {
+        let storage = Storage::from_backend(Arc::new(MemoryStorage::default()));
+        let events = BroadcastPublisher::default();
+        let fetcher = _Pages(collections::HashMap::from([(
+            "https://c.d",
+            "<p>No metadata</p>",
+        )]));
+
+        _unfurl(_message(7, "https://c.d"), &fetcher, &storage, &events)
+            .await
+            .unwrap();
+
+        assert!(storage.embeds.list_many(&[7]).await.unwrap().is_empty());
+        assert_eq!(storage.channels.sequence(1).await.unwrap(), 0);
+    }
+}
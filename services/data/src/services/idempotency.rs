@@ -0,0 +1,100 @@
+use std::sync::atomic;
+
+use chrono::Utc;
+
+/// Maximum length of an idempotency key, in characters.
+const _MAX_KEY_LENGTH: usize = 128;
+
+/// The outcome of [`claim`].
+pub enum Claim {
+    /// The request is the first with its key, and must create its entity with this ID
+    Reserved(i64),
+
+    /// A previous request with the same key created (or is creating) the entity with this ID
+    Replayed(i64),
+}
+
+/// The storage key of a client-provided idempotency key, scoped to the `kind` requests of `user_id` so that clients
+/// cannot observe the requests of one another. Returns `None` if `key` is empty.
+pub fn scope(kind: &str, user_id: i64, key: &str) -> Result<Option<String>, tonic::Status> {
+    if key.is_empty() {
+        return Ok(None);
+    }
+
+    if key.chars().count() > _MAX_KEY_LENGTH {
+        return Err(tonic::Status::invalid_argument(format!(
+            "Idempotency keys cannot exceed {} characters",
+            _MAX_KEY_LENGTH
+        )));
+    }
+
+    Ok(Some(format!("{}:{}:{}", kind, user_id, key)))
+}
+
+/// The ID of the entity created by a previous request with the same key, if any.
+pub async fn lookup(
+    application: &super::ApplicationService,
+    key: &str,
+) -> Result<Option<i64>, tonic::Status> {
+    application
+        .storage
+        .idempotency_keys
+        .get(key)
+        .await
+        .map_err(super::ApplicationService::error)
+}
+
+/// Whether the request which reserved `id` gave up before creating its entity, given that its entity is missing.
+///
+/// Entities are created right after their ID is reserved, so a reservation older than the claim timeout whose entity
+/// is missing belongs to a request which failed in between, and which a retry can take over.
+pub fn abandoned(application: &super::ApplicationService, id: i64) -> bool {
+    let timeout = chrono::Duration::from_std(application.options.idempotency_claim_timeout)
+        .unwrap_or(chrono::Duration::MAX);
+    Utc::now()
+        .checked_sub_signed(timeout)
+        .is_some_and(|time| id < application.snowflake(time))
+}
+
+/// The status of a retried request whose entity is missing while the original request may still create it.
+pub fn in_progress() -> tonic::Status {
+    tonic::Status::aborted("A request with the same idempotency key is still in progress")
+}
+
+/// Reserve a fresh ID for the request identified by `key`, unless a concurrent request with the same key did first.
+///
+/// This should be called once the request is validated, right before its entity is stored: a request failing after
+/// this point leaves its key unusable until the claim timeout, after which the key can be claimed again by passing
+/// the `abandoned` ID it was claimed with, see [`abandoned`].
+pub async fn claim(
+    application: &super::ApplicationService,
+    key: &str,
+    abandoned: Option<i64>,
+) -> Result<Claim, tonic::Status> {
+    let id = application.generate_id();
+    let ttl_seconds = application.options.idempotency_key_ttl_seconds;
+    let keys = &application.storage.idempotency_keys;
+    let claimed = match abandoned {
+        None => keys.claim(key, id, ttl_seconds).await,
+        Some(abandoned_id) => keys.reclaim(key, abandoned_id, id, ttl_seconds).await,
+    }
+    .map_err(super::ApplicationService::error)?;
+
+    Ok(if claimed == id {
+        Claim::Reserved(id)
+    } else {
+        Claim::Replayed(claimed)
+    })
+}
+
+/// An ID generator returning `reserved` first if set, then the IDs of `generate`.
+pub fn starting_with(
+    reserved: Option<i64>,
+    generate: impl Fn() -> i64 + Send + Sync,
+) -> impl Fn() -> i64 + Send + Sync {
+    let reserved = atomic::AtomicI64::new(reserved.unwrap_or(0));
+    move || match reserved.swap(0, atomic::Ordering::SeqCst) {
+        0 => generate(),
+        id => id,
+    }
+}
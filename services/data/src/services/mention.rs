@@ -0,0 +1,85 @@
+use super::p_channels::{PRichTextNode, PRichTextNodeType};
+
+/// Mentions notifying every member of the channel instead of a single user.
+const _CHANNEL_MENTIONS: &[&str] = &["channel", "everyone", "here"];
+
+/// Mentions found in the content of a message, before usernames are resolved.
+#[derive(Debug, Default)]
+pub struct ParsedMentions {
+    /// Mentioned usernames, without duplicates and in order of appearance
+    pub usernames: Vec<String>,
+
+    /// Whether the whole channel is mentioned
+    pub channel: bool,
+}
+
+/// Whether `c` can be part of a username following `@`.
+pub fn is_username_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Whether `@name` mentions the whole channel.
+pub fn is_channel_mention(name: &str) -> bool {
+    _CHANNEL_MENTIONS.contains(&name)
+}
+
+/// Find every `@username` and `@channel`-style mention in the rich text of a message, see [`super::rich_text`].
+///
+/// Mentions within code are not mentions, and neither are e-mail addresses since a mention must start a word.
+pub fn parse(nodes: &[PRichTextNode]) -> ParsedMentions {
+    let mut result = ParsedMentions::default();
+    _collect(nodes, &mut result);
+    result
+}
+
+fn _collect(nodes: &[PRichTextNode], result: &mut ParsedMentions) {
+    for node in nodes {
+        match node.node_type() {
+            PRichTextNodeType::ChannelMention => result.channel = true,
+            PRichTextNodeType::UserMention => {
+                if !result.usernames.contains(&node.text) {
+                    result.usernames.push(node.text.clone());
+                }
+            }
+            _ => _collect(&node.children, result),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::rich_text;
+
+    #[test]
+    fn parse_usernames_and_channel_mentions() {
+        for (content, usernames, channel) in [
+            ("no mentions", &[][..], false),
+            ("hi @alice and @bob", &["alice", "bob"][..], false),
+            ("@alice @bob @alice", &["alice", "bob"][..], false),
+            ("@first.last-name_2!", &["first.last-name_2"][..], false),
+            ("@here", &[][..], true),
+            ("@everyone and @alice", &["alice"][..], true),
+            ("**@alice** in *@bob*", &["alice", "bob"][..], false),
+            ("`@alice` and ```\n@bob\n```", &[][..], false),
+            ("alice@example.com", &[][..], false),
+            ("@", &[][..], false),
+        ] {
+            let parsed = parse(&rich_text::parse(content));
+            assert_eq!(parsed.usernames, usernames, "{:?}", content);
+            assert_eq!(parsed.channel, channel, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn channel_mentions_are_reserved_names() {
+        for name in ["channel", "everyone", "here"] {
+            assert!(is_channel_mention(name));
+        }
+        assert!(!is_channel_mention("Here"));
+        assert!(!is_channel_mention("alice"));
+
+        assert!("a1_-.".chars().all(is_username_char));
+        assert!(!"@ ,!".chars().any(is_username_char));
+    }
+}
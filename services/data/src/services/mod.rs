@@ -3,15 +3,41 @@ use std::sync::Arc;
 
 use chrono::{DateTime, Utc};
 
+use crate::blobs::BlobStore;
+use crate::embeds::Fetcher;
+use crate::events::EventPublisher;
+use crate::moderation::Pipeline;
+use crate::storage::Storage;
+
 // Import `impl`s for `ApplicationService`.
+mod attachment;
+mod audit;
 mod authorization;
 mod channel;
 mod config;
+mod embed;
+mod idempotency;
+mod mention;
+mod moderation;
+mod presence;
+mod rate_limit;
+mod relationship;
+mod retention;
+mod rich_text;
+mod search;
+#[cfg(test)]
+mod tests;
+
+pub mod p_attachments {
+    tonic::include_proto!("p_attachments");
+}
 
 pub mod p_authorization {
     tonic::include_proto!("p_authorization");
 }
 
+// Event types are named after the entity they concern, e.g. `MessageCreated`
+#[allow(clippy::enum_variant_names, clippy::large_enum_variant)]
 pub mod p_channels {
     tonic::include_proto!("p_channels");
 }
@@ -20,6 +46,22 @@ pub mod p_config {
     tonic::include_proto!("p_config");
 }
 
+pub mod p_moderation {
+    tonic::include_proto!("p_moderation");
+}
+
+pub mod p_presence {
+    tonic::include_proto!("p_presence");
+}
+
+pub mod p_relationships {
+    tonic::include_proto!("p_relationships");
+}
+
+pub mod p_retention {
+    tonic::include_proto!("p_retention");
+}
+
 pub mod p_status {
     tonic::include_proto!("p_status");
 }
@@ -35,35 +77,117 @@ struct SettingsJson {
     epoch: i64,
 }
 
+/// Limits of the application, configured from the command line.
+#[derive(Clone, Debug)]
+pub struct ServiceOptions {
+    /// Maximum number of pinned messages in a single channel
+    pub max_pins_per_channel: usize,
+
+    /// Maximum size of an uploaded attachment, in bytes
+    pub max_attachment_bytes: i64,
+
+    /// Maximum length of the content of a message, in characters
+    pub max_message_length: usize,
+
+    /// Average number of messages a user can post per minute across every channel, 0 to disable
+    pub user_messages_per_minute: u32,
+
+    /// Number of messages a user can post at once before being rate limited
+    pub user_message_burst: u32,
+
+    /// Average number of messages which can be posted per minute in a single channel, 0 to disable
+    pub channel_messages_per_minute: u32,
+
+    /// Number of messages which can be posted at once in a single channel before it is rate limited
+    pub channel_message_burst: u32,
+
+    /// Number of seconds during which a request can be retried with the same idempotency key
+    pub idempotency_key_ttl_seconds: i32,
+
+    /// Time after which a request with an idempotency key which did not create its entity is considered failed, and
+    /// retries with the same key create it instead
+    pub idempotency_claim_timeout: std::time::Duration,
+
+    /// Maximum number of recent messages held in the in-process search index, 0 for no limit
+    pub search_index_max_messages: usize,
+
+    /// Interval between sweeps applying retention policies to existing messages
+    pub retention_sweep_interval: std::time::Duration,
+}
+
 pub struct ApplicationService {
     bcrypt_cost: u32,
+    blobs: Arc<dyn BlobStore>,
     epoch: DateTime<Utc>,
-    rabbitmq: Arc<lapin::Channel>,
-    session: Arc<scylla::Session>,
+    events: Arc<dyn EventPublisher>,
+    moderation: Pipeline,
+    options: ServiceOptions,
+    presence: Arc<presence::PresenceStore>,
+    search: Arc<search::SearchIndex>,
+    storage: Storage,
+    sweeper: retention::Sweeper,
+    unfurler: embed::Unfurler,
 }
 
 static _ID_COUNTER: atomic::AtomicI16 = atomic::AtomicI16::new(0);
 
 impl ApplicationService {
     pub async fn new(
-        rabbitmq: Arc<lapin::Channel>,
-        session: Arc<scylla::Session>,
+        events: Arc<dyn EventPublisher>,
+        storage: Storage,
+        blobs: Arc<dyn BlobStore>,
+        fetcher: Arc<dyn Fetcher>,
+        moderation: Pipeline,
+        options: ServiceOptions,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        for mut statement in include_str!("../../scripts/database.cql").split(";") {
-            statement = statement.trim();
-            if !statement.is_empty() {
-                session.query_unpaged(statement, ()).await?;
-            }
-        }
-
         let json =
             serde_json::from_str::<SettingsJson>(include_str!("../../../../setup.json")).unwrap();
 
+        let epoch = DateTime::from_timestamp_millis(json.epoch).expect("Invalid epoch");
+
+        let search = Arc::new(search::SearchIndex::new(options.search_index_max_messages));
+        tokio::spawn({
+            let (search, events, storage) = (search.clone(), events.clone(), storage.clone());
+            async move {
+                if let Err(e) = search::maintain(search, events, storage, epoch).await {
+                    eprintln!("Search index is no longer maintained: {}", e);
+                }
+            }
+        });
+
+        let presence = Arc::new(presence::PresenceStore::default());
+        tokio::spawn({
+            let (presence, events) = (presence.clone(), events.clone());
+            async move {
+                if let Err(e) = presence::maintain(presence, events).await {
+                    eprintln!("Presence is no longer maintained: {}", e);
+                }
+            }
+        });
+
+        let unfurler = embed::Unfurler::spawn(fetcher, storage.clone(), events.clone());
+
+        let sweeper = retention::Sweeper::spawn(
+            storage.clone(),
+            events.clone(),
+            blobs.clone(),
+            search.clone(),
+            epoch,
+            options.retention_sweep_interval,
+        );
+
         Ok(Self {
             bcrypt_cost: json.bcrypt_cost,
-            epoch: DateTime::from_timestamp_millis(json.epoch).expect("Invalid epoch"),
-            rabbitmq,
-            session,
+            blobs,
+            epoch,
+            events,
+            moderation,
+            options,
+            presence,
+            search,
+            storage,
+            sweeper,
+            unfurler,
         })
     }
 
@@ -73,6 +197,16 @@ impl ApplicationService {
         timedelta.num_milliseconds() << 16 | (counter as i64)
     }
 
+    /// Compute the smallest snowflake ID that can be generated at `time`.
+    fn snowflake(&self, time: DateTime<Utc>) -> i64 {
+        time.signed_duration_since(self.epoch).num_milliseconds() << 16
+    }
+
+    /// Compute the largest snowflake ID that can have been generated so far.
+    fn latest_id(&self) -> i64 {
+        self.snowflake(chrono::Utc::now()) | 0xFFFF
+    }
+
     fn hash(&self, password: &str) -> String {
         bcrypt::hash(password, self.bcrypt_cost).unwrap()
     }
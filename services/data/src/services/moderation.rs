@@ -0,0 +1,578 @@
+use super::audit;
+use super::channel;
+use super::p_channels;
+use super::p_moderation;
+use super::p_moderation::moderation_service_server;
+use super::p_users;
+use crate::events::Event;
+use crate::moderation::filters;
+use crate::storage;
+
+/// Default number of messages returned by a single [`moderation_service_server::ModerationService::moderation_queue`] call.
+const _DEFAULT_QUEUE_LIMIT: i32 = 50;
+
+/// Maximum number of messages returned by a single [`moderation_service_server::ModerationService::moderation_queue`] call.
+const _MAX_QUEUE_LIMIT: i32 = 500;
+
+/// Maximum number of filters of a single channel.
+const _MAX_FILTERS_PER_CHANNEL: usize = 100;
+
+/// Maximum length of a filter pattern, in characters.
+const _MAX_PATTERN_LENGTH: usize = 256;
+
+/// Maximum duration of a temporary ban, in seconds.
+const _MAX_BAN_SECONDS: i64 = 365 * 24 * 60 * 60;
+
+/// Maximum duration of a timeout, in seconds.
+const _MAX_TIMEOUT_SECONDS: i64 = 28 * 24 * 60 * 60;
+
+/// Maximum length of the reason of a ban, in characters.
+const _MAX_REASON_LENGTH: usize = 512;
+
+fn _filter_to_proto(filter: storage::ModerationFilter) -> p_moderation::PChannelFilter {
+    p_moderation::PChannelFilter {
+        pattern: filter.pattern,
+        regex: filter.regex,
+        action: match filter.action {
+            storage::ModerationAction::Reject => p_moderation::PModerationAction::Reject,
+            storage::ModerationAction::Mask => p_moderation::PModerationAction::Mask,
+            storage::ModerationAction::Flag => p_moderation::PModerationAction::Flag,
+        }
+        .into(),
+    }
+}
+
+fn _filter_from_proto(filter: &p_moderation::PChannelFilter) -> storage::ModerationFilter {
+    storage::ModerationFilter {
+        pattern: filter.pattern.clone(),
+        regex: filter.regex,
+        action: match filter.action() {
+            p_moderation::PModerationAction::Reject => storage::ModerationAction::Reject,
+            p_moderation::PModerationAction::Mask => storage::ModerationAction::Mask,
+            p_moderation::PModerationAction::Flag => storage::ModerationAction::Flag,
+        },
+    }
+}
+
+/// Fetch a user, failing unless they have the `MODERATE` permission, or own `channel_id` if set.
+async fn _authorize(
+    application: &super::ApplicationService,
+    user_id: i64,
+    channel_id: Option<i64>,
+) -> Result<(), tonic::Status> {
+    let user = application
+        .storage
+        .accounts
+        .by_id(user_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+    if user.permissions & p_users::PPermission::Moderate as i64 != 0 {
+        return Ok(());
+    }
+
+    match channel_id {
+        Some(channel_id) => {
+            let channel = application
+                .storage
+                .channels
+                .get(channel_id)
+                .await
+                .map_err(super::ApplicationService::error)?
+                .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+            if channel.owner_id == user.id {
+                return Ok(());
+            }
+
+            Err(tonic::Status::permission_denied(
+                "Only the channel owner or moderators can moderate a channel",
+            ))
+        }
+        None => Err(tonic::Status::permission_denied(
+            "Only moderators can review flagged messages and the audit log",
+        )),
+    }
+}
+
+/// The ban of a user in a channel, `None` if the user is not banned or their ban expired.
+pub async fn active_ban(
+    application: &super::ApplicationService,
+    channel_id: i64,
+    user_id: i64,
+) -> Result<Option<storage::Ban>, Box<dyn std::error::Error + Send + Sync>> {
+    let now = chrono::Utc::now().timestamp_millis();
+    Ok(application
+        .storage
+        .bans
+        .get(channel_id, user_id)
+        .await?
+        .filter(|ban| ban.is_active(now)))
+}
+
+/// A `PERMISSION_DENIED` status telling a banned or timed out user why they cannot access a channel.
+pub fn banned(ban: &storage::Ban) -> tonic::Status {
+    let mut message = if ban.timeout {
+        "You are timed out in this channel".to_string()
+    } else {
+        "You are banned from this channel".to_string()
+    };
+    if let Some(expires_at) = ban
+        .expires_at
+        .and_then(chrono::DateTime::from_timestamp_millis)
+    {
+        message.push_str(&format!(" until {}", expires_at.to_rfc3339()));
+    }
+
+    tonic::Status::permission_denied(message)
+}
+
+/// Convert a ban into its protobuf representation.
+async fn _ban_to_proto(
+    application: &super::ApplicationService,
+    ban: storage::Ban,
+) -> Result<p_channels::PChannelBan, tonic::Status> {
+    let account = application
+        .storage
+        .accounts
+        .by_id(ban.user_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+
+    Ok(p_channels::PChannelBan {
+        channel_id: ban.channel_id,
+        user: Some(p_users::PUser {
+            id: account.id,
+            username: account.username,
+            permissions: account.permissions,
+        }),
+        moderator_id: ban.moderator_id,
+        reason: ban.reason,
+        timeout: ban.timeout,
+        created_at: ban.created_at,
+        expires_at: ban.expires_at.unwrap_or(0),
+    })
+}
+
+/// Validate and store a ban or timeout, then announce it to the subscribers of the channel.
+async fn _ban(
+    application: &super::ApplicationService,
+    channel_id: i64,
+    user_id: i64,
+    target_id: i64,
+    reason: String,
+    duration_seconds: i64,
+    timeout: bool,
+) -> Result<p_channels::PChannelBan, tonic::Status> {
+    _authorize(application, user_id, Some(channel_id)).await?;
+
+    let (max_seconds, min_seconds) = if timeout {
+        (_MAX_TIMEOUT_SECONDS, 1)
+    } else {
+        (_MAX_BAN_SECONDS, 0)
+    };
+    if !(min_seconds..=max_seconds).contains(&duration_seconds) {
+        return Err(tonic::Status::invalid_argument(format!(
+            "The duration must be between {} and {} seconds",
+            min_seconds, max_seconds
+        )));
+    }
+    if reason.chars().count() > _MAX_REASON_LENGTH {
+        return Err(tonic::Status::invalid_argument(format!(
+            "Reasons cannot exceed {} characters",
+            _MAX_REASON_LENGTH
+        )));
+    }
+
+    let channel = application
+        .storage
+        .channels
+        .get(channel_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+    if target_id == channel.owner_id || target_id == user_id {
+        return Err(tonic::Status::invalid_argument(
+            "The channel owner and the moderator themselves cannot be banned",
+        ));
+    }
+
+    // A timeout must not shorten a ban
+    if timeout {
+        if let Some(existing) = active_ban(application, channel_id, target_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            if !existing.timeout {
+                return Err(tonic::Status::failed_precondition(
+                    "The user is already banned from this channel",
+                ));
+            }
+        }
+    }
+
+    let created_at = chrono::Utc::now().timestamp_millis();
+    let ban = storage::Ban {
+        channel_id,
+        user_id: target_id,
+        moderator_id: user_id,
+        reason,
+        timeout,
+        created_at,
+        expires_at: (duration_seconds > 0).then(|| created_at + duration_seconds * 1000),
+    };
+    let result = _ban_to_proto(application, ban.clone()).await?;
+    application
+        .storage
+        .bans
+        .ban(&ban)
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+    let duration = if duration_seconds > 0 {
+        format!("for {} seconds", duration_seconds)
+    } else {
+        "permanently".to_string()
+    };
+    audit::record(
+        application,
+        user_id,
+        if timeout {
+            storage::AuditAction::MemberTimedOut
+        } else {
+            storage::AuditAction::MemberBanned
+        },
+        target_id,
+        channel_id,
+        if ban.reason.is_empty() {
+            duration
+        } else {
+            format!("{}: {}", duration, ban.reason)
+        },
+    )
+    .await?;
+
+    // Banned users have to be re-added by posting once unbanned
+    if !timeout {
+        application
+            .storage
+            .channels
+            .remove_member(channel_id, target_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+    }
+
+    let event_type = if timeout {
+        p_channels::PChannelEventType::MemberTimedOut
+    } else {
+        p_channels::PChannelEventType::MemberBanned
+    };
+    _publish_ban(application, channel, event_type, result.clone())
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+    Ok(result)
+}
+
+/// Announce a change of the bans of `channel` to its subscribers.
+///
+/// Like every sequenced event, bans are also relayed on the routing key of the channel the websockets of the API
+/// service listen to, which close when their user is banned.
+async fn _publish_ban(
+    application: &super::ApplicationService,
+    channel: storage::Channel,
+    event_type: p_channels::PChannelEventType,
+    ban: p_channels::PChannelBan,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sequence = application
+        .storage
+        .channels
+        .next_sequence(channel.id)
+        .await?;
+    application
+        .events
+        .publish(Event::Channel(p_channels::PChannelEvent {
+            event_type: event_type.into(),
+            channel: Some(p_channels::PChannel {
+                id: channel.id,
+                name: channel.name,
+                description: channel.description,
+                slow_mode_seconds: channel.slow_mode_seconds,
+                owner: None,
+            }),
+            sequence,
+            pin: None,
+            ban: Some(ban),
+        }))
+        .await
+}
+
+/// Convert flags into their protobuf representation, skipping those whose message does not exist.
+async fn _hydrate_flags(
+    application: &super::ApplicationService,
+    flags: Vec<storage::Flag>,
+) -> Result<Vec<p_moderation::PFlaggedMessage>, Box<dyn std::error::Error + Send + Sync>> {
+    let mut rows = Vec::new();
+    let mut found = Vec::new();
+    for flag in flags {
+        if let Some(message) = application.storage.messages.get(flag.message_id).await? {
+            rows.push(message);
+            found.push(flag);
+        }
+    }
+
+    let messages = channel::hydrate_messages(application, rows, Default::default()).await?;
+    Ok(found
+        .into_iter()
+        .zip(messages)
+        .map(|(flag, message)| p_moderation::PFlaggedMessage {
+            message: Some(message),
+            reasons: flag.reasons,
+            flagged_at: flag.flagged_at,
+        })
+        .collect())
+}
+
+#[tonic::async_trait]
+impl moderation_service_server::ModerationService for super::ApplicationService {
+    async fn set_channel_filters(
+        &self,
+        request: tonic::Request<p_moderation::PSetChannelFiltersRequest>,
+    ) -> Result<tonic::Response<p_moderation::PChannelFilters>, tonic::Status> {
+        let request = request.into_inner();
+        _authorize(self, request.user_id, Some(request.channel_id)).await?;
+
+        if request.filters.len() > _MAX_FILTERS_PER_CHANNEL {
+            return Err(tonic::Status::invalid_argument(format!(
+                "A channel can have at most {} filters",
+                _MAX_FILTERS_PER_CHANNEL
+            )));
+        }
+
+        let filters = request
+            .filters
+            .iter()
+            .map(_filter_from_proto)
+            .collect::<Vec<_>>();
+        for filter in &filters {
+            if filter.pattern.is_empty() || filter.pattern.chars().count() > _MAX_PATTERN_LENGTH {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "Filter patterns must be between 1 and {} characters",
+                    _MAX_PATTERN_LENGTH
+                )));
+            }
+
+            if let Err(e) = filters::compile(filter) {
+                return Err(tonic::Status::invalid_argument(format!(
+                    "Invalid filter {:?}: {}",
+                    filter.pattern, e
+                )));
+            }
+        }
+
+        self.storage
+            .moderation
+            .set_filters(request.channel_id, &filters)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        self.moderation.invalidate(request.channel_id);
+        audit::record(
+            self,
+            request.user_id,
+            storage::AuditAction::ChannelFiltersChanged,
+            request.channel_id,
+            request.channel_id,
+            format!("{} filters", filters.len()),
+        )
+        .await?;
+
+        Ok(tonic::Response::new(p_moderation::PChannelFilters {
+            channel_id: request.channel_id,
+            filters: filters.into_iter().map(_filter_to_proto).collect(),
+        }))
+    }
+
+    async fn get_channel_filters(
+        &self,
+        request: tonic::Request<p_moderation::PGetChannelFiltersRequest>,
+    ) -> Result<tonic::Response<p_moderation::PChannelFilters>, tonic::Status> {
+        let request = request.into_inner();
+        _authorize(self, request.user_id, Some(request.channel_id)).await?;
+
+        let filters = self
+            .storage
+            .moderation
+            .filters(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(p_moderation::PChannelFilters {
+            channel_id: request.channel_id,
+            filters: filters.into_iter().map(_filter_to_proto).collect(),
+        }))
+    }
+
+    async fn moderation_queue(
+        &self,
+        request: tonic::Request<p_moderation::PModerationQueueRequest>,
+    ) -> Result<tonic::Response<p_moderation::PModerationQueueResult>, tonic::Status> {
+        let request = request.into_inner();
+        _authorize(self, request.user_id, None).await?;
+
+        let limit = if request.limit > 0 {
+            request.limit.min(_MAX_QUEUE_LIMIT)
+        } else {
+            _DEFAULT_QUEUE_LIMIT
+        };
+        let before_id = if request.before_id > 0 {
+            request.before_id
+        } else {
+            i64::MAX
+        };
+
+        let flags = self
+            .storage
+            .moderation
+            .flags(before_id, limit)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        let messages = _hydrate_flags(self, flags)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(p_moderation::PModerationQueueResult {
+            messages,
+        }))
+    }
+
+    async fn resolve_flag(
+        &self,
+        request: tonic::Request<p_moderation::PResolveFlagRequest>,
+    ) -> Result<tonic::Response<p_moderation::PFlaggedMessage>, tonic::Status> {
+        let request = request.into_inner();
+        _authorize(self, request.user_id, None).await?;
+
+        let flag = self
+            .storage
+            .moderation
+            .get_flag(request.message_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Message is not flagged"))?;
+        self.storage
+            .moderation
+            .resolve(request.message_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        audit::record(
+            self,
+            request.user_id,
+            storage::AuditAction::FlagResolved,
+            flag.message_id,
+            flag.channel_id,
+            flag.reasons.join("; "),
+        )
+        .await?;
+
+        _hydrate_flags(self, vec![flag])
+            .await
+            .map_err(super::ApplicationService::error)?
+            .pop()
+            .map(tonic::Response::new)
+            .ok_or_else(|| tonic::Status::not_found("Message not found"))
+    }
+
+    async fn ban_from_channel(
+        &self,
+        request: tonic::Request<p_moderation::PBanRequest>,
+    ) -> Result<tonic::Response<p_channels::PChannelBan>, tonic::Status> {
+        let request = request.into_inner();
+        _ban(
+            self,
+            request.channel_id,
+            request.user_id,
+            request.target_id,
+            request.reason,
+            request.duration_seconds,
+            false,
+        )
+        .await
+        .map(tonic::Response::new)
+    }
+
+    async fn unban_from_channel(
+        &self,
+        request: tonic::Request<p_moderation::PUnbanRequest>,
+    ) -> Result<tonic::Response<p_channels::PChannelBan>, tonic::Status> {
+        let request = request.into_inner();
+        _authorize(self, request.user_id, Some(request.channel_id)).await?;
+
+        let ban = active_ban(self, request.channel_id, request.target_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("User is not banned"))?;
+        let channel = self
+            .storage
+            .channels
+            .get(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+
+        let result = _ban_to_proto(self, ban).await?;
+        self.storage
+            .bans
+            .unban(request.channel_id, request.target_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        audit::record(
+            self,
+            request.user_id,
+            storage::AuditAction::MemberUnbanned,
+            request.target_id,
+            request.channel_id,
+            String::new(),
+        )
+        .await?;
+        _publish_ban(
+            self,
+            channel,
+            p_channels::PChannelEventType::MemberUnbanned,
+            result.clone(),
+        )
+        .await
+        .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(result))
+    }
+
+    async fn timeout_user(
+        &self,
+        request: tonic::Request<p_moderation::PTimeoutRequest>,
+    ) -> Result<tonic::Response<p_channels::PChannelBan>, tonic::Status> {
+        let request = request.into_inner();
+        _ban(
+            self,
+            request.channel_id,
+            request.user_id,
+            request.target_id,
+            request.reason,
+            request.duration_seconds,
+            true,
+        )
+        .await
+        .map(tonic::Response::new)
+    }
+
+    async fn query_audit_log(
+        &self,
+        request: tonic::Request<p_moderation::PQueryAuditLogRequest>,
+    ) -> Result<tonic::Response<p_moderation::PQueryAuditLogResult>, tonic::Status> {
+        let request = request.into_inner();
+        _authorize(self, request.user_id, None).await?;
+
+        Ok(tonic::Response::new(p_moderation::PQueryAuditLogResult {
+            entries: audit::query(self, &request).await?,
+        }))
+    }
+}
@@ -0,0 +1,244 @@
+use std::collections;
+use std::sync::{Arc, RwLock};
+
+use futures::StreamExt;
+
+use super::p_presence;
+use super::p_presence::presence_service_server;
+use crate::events::{Event, EventPublisher, Topics};
+
+/// Time-to-live of a presence when not specified, in seconds.
+const _DEFAULT_PRESENCE_TTL_SECONDS: i32 = 60;
+
+/// Maximum time-to-live of a presence, in seconds.
+const _MAX_PRESENCE_TTL_SECONDS: i32 = 300;
+
+/// Time after which a typing indicator is hidden unless refreshed, in milliseconds.
+const _TYPING_TTL_MILLISECONDS: i64 = 8000;
+
+/// Interval between two sweeps of expired presences.
+const _SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Presence of users as last announced by any replica, kept in memory only.
+///
+/// Entries are never persisted, a restarted replica learns them back from the heartbeats of connected clients.
+#[derive(Default)]
+pub struct PresenceStore {
+    presences: RwLock<collections::HashMap<i64, p_presence::PPresence>>,
+}
+
+impl PresenceStore {
+    /// Record a presence, replacing the previous one of the same user.
+    pub fn apply(&self, presence: &p_presence::PPresence) {
+        let mut presences = self.presences.write().unwrap();
+        if presence.status() == p_presence::PPresenceStatus::Offline {
+            presences.remove(&presence.user_id);
+        } else {
+            presences.insert(presence.user_id, *presence);
+        }
+    }
+
+    /// The presence of a user at `now` (in milliseconds since the UNIX epoch), offline if it expired.
+    pub fn get(&self, user_id: i64, now: i64) -> p_presence::PPresence {
+        match self.presences.read().unwrap().get(&user_id) {
+            Some(presence) if presence.expires_at > now => *presence,
+            _ => p_presence::PPresence {
+                user_id,
+                status: p_presence::PPresenceStatus::Offline.into(),
+                expires_at: 0,
+            },
+        }
+    }
+
+    /// Forget every presence which expired before `now`.
+    pub fn sweep(&self, now: i64) {
+        self.presences
+            .write()
+            .unwrap()
+            .retain(|_, presence| presence.expires_at > now);
+    }
+}
+
+/// Keep `store` in sync with the presences announced by every replica.
+///
+/// Expired entries are swept periodically, so that the store does not grow with users who left.
+pub async fn maintain(
+    store: Arc<PresenceStore>,
+    events: Arc<dyn EventPublisher>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut events = events
+        .subscribe(
+            "presence",
+            Topics {
+                channel_ids: Some(Vec::new()),
+                user_ids: Some(Vec::new()),
+                presence_user_ids: None,
+                skip_typing: false,
+            },
+        )
+        .await?;
+    let mut sweeper = tokio::time::interval(_SWEEP_INTERVAL);
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(Ok(Event::Presence(presence))) => store.apply(&presence),
+                Some(Ok(_)) => {}
+                // Missed presences are recovered from the next heartbeats
+                Some(Err(e)) => eprintln!("Missed presence events: {}", e),
+                None => return Ok(()),
+            },
+            _ = sweeper.tick() => store.sweep(chrono::Utc::now().timestamp_millis()),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl presence_service_server::PresenceService for super::ApplicationService {
+    async fn set_typing(
+        &self,
+        request: tonic::Request<p_presence::PSetTypingRequest>,
+    ) -> Result<tonic::Response<p_presence::PTypingEvent>, tonic::Status> {
+        let request = request.into_inner();
+
+        self.storage
+            .accounts
+            .by_id(request.user_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+        self.storage
+            .channels
+            .get(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+
+        // Users who cannot post in the channel are not announced as typing
+        if let Some(ban) = super::moderation::active_ban(self, request.channel_id, request.user_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+        {
+            return Err(super::moderation::banned(&ban));
+        }
+
+        let event = p_presence::PTypingEvent {
+            user_id: request.user_id,
+            channel_id: request.channel_id,
+            typing: request.typing,
+            expires_at: if request.typing {
+                chrono::Utc::now().timestamp_millis() + _TYPING_TTL_MILLISECONDS
+            } else {
+                0
+            },
+        };
+
+        self.events
+            .publish(Event::Typing(event))
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(event))
+    }
+
+    async fn set_presence(
+        &self,
+        request: tonic::Request<p_presence::PSetPresenceRequest>,
+    ) -> Result<tonic::Response<p_presence::PPresence>, tonic::Status> {
+        let request = request.into_inner();
+        let status = p_presence::PPresenceStatus::try_from(request.status)
+            .map_err(|_| tonic::Status::invalid_argument("Unknown presence status"))?;
+        let ttl_seconds = if request.ttl_seconds > 0 {
+            request.ttl_seconds.min(_MAX_PRESENCE_TTL_SECONDS)
+        } else {
+            _DEFAULT_PRESENCE_TTL_SECONDS
+        };
+
+        let presence = p_presence::PPresence {
+            user_id: request.user_id,
+            status: status.into(),
+            expires_at: if status == p_presence::PPresenceStatus::Offline {
+                0
+            } else {
+                chrono::Utc::now().timestamp_millis() + i64::from(ttl_seconds) * 1000
+            },
+        };
+
+        // Apply locally right away, so that the caller reads its own presence even without a broker
+        self.presence.apply(&presence);
+        self.events
+            .publish(Event::Presence(presence))
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        Ok(tonic::Response::new(presence))
+    }
+
+    async fn get_presence(
+        &self,
+        request: tonic::Request<p_presence::PGetPresenceRequest>,
+    ) -> Result<tonic::Response<p_presence::PGetPresenceResult>, tonic::Status> {
+        let request = request.into_inner();
+        let now = chrono::Utc::now().timestamp_millis();
+
+        Ok(tonic::Response::new(p_presence::PGetPresenceResult {
+            presences: request
+                .user_ids
+                .into_iter()
+                .map(|user_id| self.presence.get(user_id, now))
+                .collect(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn _presence(
+        user_id: i64,
+        status: p_presence::PPresenceStatus,
+        expires_at: i64,
+    ) -> p_presence::PPresence {
+        p_presence::PPresence {
+            user_id,
+            status: status.into(),
+            expires_at,
+        }
+    }
+
+    #[test]
+    fn presences_expire_and_are_swept() {
+        let store = PresenceStore::default();
+        store.apply(&_presence(1, p_presence::PPresenceStatus::Online, 100));
+        store.apply(&_presence(2, p_presence::PPresenceStatus::Idle, 200));
+
+        assert_eq!(
+            store.get(1, 99).status(),
+            p_presence::PPresenceStatus::Online
+        );
+        assert_eq!(
+            store.get(1, 100).status(),
+            p_presence::PPresenceStatus::Offline
+        );
+        assert_eq!(
+            store.get(3, 0).status(),
+            p_presence::PPresenceStatus::Offline
+        );
+
+        // Going offline forgets the presence right away
+        store.apply(&_presence(2, p_presence::PPresenceStatus::Offline, 0));
+        assert_eq!(
+            store.get(2, 0).status(),
+            p_presence::PPresenceStatus::Offline
+        );
+        assert_eq!(store.presences.read().unwrap().len(), 1);
+
+        store.apply(&_presence(2, p_presence::PPresenceStatus::Dnd, 200));
+        store.sweep(150);
+        assert_eq!(
+            store.presences.read().unwrap().keys().collect::<Vec<_>>(),
+            [&2]
+        );
+        assert_eq!(store.get(2, 150).status(), p_presence::PPresenceStatus::Dnd);
+    }
+}
@@ -0,0 +1,116 @@
+use std::time::Duration;
+
+use crate::storage::Storage;
+
+/// Number of times a rate limit is read and updated again when another request updated it concurrently.
+const _MAX_ATTEMPTS: u32 = 5;
+
+/// Delay before reading a rate limit again after losing a race, doubled after each attempt and jittered.
+const _BACKOFF: Duration = Duration::from_millis(2);
+
+/// Number of milliseconds clients are told to wait before retrying a request which lost every race.
+const _CONTENDED_RETRY_MILLISECONDS: i64 = 100;
+
+/// A token bucket holding at most `burst` tokens, refilled with one token every `interval_milliseconds`.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub burst: i64,
+    pub interval_milliseconds: i64,
+}
+
+impl Quota {
+    /// A quota of `per_minute` requests per minute on average, `None` if `per_minute` is 0.
+    pub fn per_minute(per_minute: u32, burst: u32) -> Option<Self> {
+        (per_minute > 0).then(|| Self {
+            burst: burst.max(1).into(),
+            interval_milliseconds: 60_000 / i64::from(per_minute),
+        })
+    }
+}
+
+/// The outcome of [`acquire`].
+#[derive(Debug)]
+pub enum Acquisition {
+    /// A token was taken from the bucket
+    Acquired,
+
+    /// The bucket is empty, and will hold a token again after the given number of milliseconds
+    Exhausted(i64),
+
+    /// Concurrent requests kept updating the bucket, so the request should be retried shortly
+    Contended,
+}
+
+/// Take a token from the bucket `key`.
+///
+/// Buckets are stored as the theoretical arrival time of their next request (GCRA), which is updated with a
+/// compare-and-set so that concurrent requests on any replica never take the same token twice. Requests losing the
+/// race back off and try again, and are told to retry if they keep losing, since the bucket may well hold tokens.
+pub async fn acquire(
+    storage: &Storage,
+    key: &str,
+    quota: Quota,
+) -> Result<Acquisition, Box<dyn std::error::Error + Send + Sync>> {
+    for attempt in 0.._MAX_ATTEMPTS {
+        if attempt > 0 {
+            let backoff = _BACKOFF * 2u32.pow(attempt - 1);
+            tokio::time::sleep(backoff.mul_f64(rand::random_range(0.5..1.0))).await;
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let arrival = storage.rate_limits.get(key).await?;
+
+        // The bucket is full when the theoretical arrival time is in the past
+        let next = arrival.unwrap_or(now).max(now) + quota.interval_milliseconds;
+        let allowed_at = next - quota.burst * quota.interval_milliseconds;
+        if allowed_at > now {
+            return Ok(Acquisition::Exhausted(allowed_at - now));
+        }
+
+        // The state is useless once the bucket is full again
+        let ttl_seconds = ((next - now) / 1000 + 1) as i32;
+        if storage
+            .rate_limits
+            .compare_and_set(key, arrival, next, ttl_seconds)
+            .await?
+        {
+            return Ok(Acquisition::Acquired);
+        }
+    }
+
+    Ok(Acquisition::Contended)
+}
+
+/// A `RESOURCE_EXHAUSTED` status telling clients when to retry, in the `retry-after` (seconds, rounded up) and
+/// `retry-after-ms` metadata.
+pub fn exhausted(message: &str, retry_milliseconds: i64) -> tonic::Status {
+    _with_retry(
+        tonic::Status::resource_exhausted(message),
+        retry_milliseconds,
+    )
+}
+
+/// An `UNAVAILABLE` status for requests which lost every race for a rate limit, which clients can retry shortly,
+/// see [`exhausted`].
+pub fn contended() -> tonic::Status {
+    _with_retry(
+        tonic::Status::unavailable("Too many concurrent requests, please retry"),
+        _CONTENDED_RETRY_MILLISECONDS,
+    )
+}
+
+fn _with_retry(mut status: tonic::Status, retry_milliseconds: i64) -> tonic::Status {
+    let metadata = status.metadata_mut();
+    metadata.insert(
+        "retry-after",
+        ((retry_milliseconds + 999) / 1000)
+            .to_string()
+            .parse()
+            .unwrap(),
+    );
+    metadata.insert(
+        "retry-after-ms",
+        retry_milliseconds.to_string().parse().unwrap(),
+    );
+    status
+}
@@ -0,0 +1,166 @@
+use std::collections;
+
+use super::p_relationships;
+use super::p_relationships::relationship_service_server;
+use super::p_users;
+
+/// Fail unless the user exists.
+async fn _check_user(
+    application: &super::ApplicationService,
+    user_id: i64,
+) -> Result<(), tonic::Status> {
+    application
+        .storage
+        .accounts
+        .by_id(user_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+
+    Ok(())
+}
+
+/// The users blocked and channels muted by a user, skipping blocked users which do not exist anymore.
+async fn _list(
+    application: &super::ApplicationService,
+    user_id: i64,
+) -> Result<p_relationships::PBlockedList, Box<dyn std::error::Error + Send + Sync>> {
+    let mut blocked_users = Vec::new();
+    for blocked_id in application.storage.relationships.blocked(user_id).await? {
+        if let Some(account) = application.storage.accounts.by_id(blocked_id).await? {
+            blocked_users.push(p_users::PUser {
+                id: account.id,
+                username: account.username,
+                permissions: account.permissions,
+            });
+        }
+    }
+
+    Ok(p_relationships::PBlockedList {
+        blocked_users,
+        muted_channel_ids: application.storage.relationships.muted(user_id).await?,
+    })
+}
+
+/// The IDs of the users blocked by `user_id`, empty if `user_id` is 0.
+pub async fn blocked_ids(
+    application: &super::ApplicationService,
+    user_id: i64,
+) -> Result<collections::HashSet<i64>, Box<dyn std::error::Error + Send + Sync>> {
+    if user_id == 0 {
+        return Ok(collections::HashSet::new());
+    }
+
+    Ok(application
+        .storage
+        .relationships
+        .blocked(user_id)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+/// The IDs of the channels muted by `user_id`.
+pub async fn muted_ids(
+    application: &super::ApplicationService,
+    user_id: i64,
+) -> Result<collections::HashSet<i64>, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(application
+        .storage
+        .relationships
+        .muted(user_id)
+        .await?
+        .into_iter()
+        .collect())
+}
+
+#[tonic::async_trait]
+impl relationship_service_server::RelationshipService for super::ApplicationService {
+    async fn block_user(
+        &self,
+        request: tonic::Request<p_relationships::PBlockRequest>,
+    ) -> Result<tonic::Response<p_relationships::PBlockedList>, tonic::Status> {
+        let request = request.into_inner();
+        if request.user_id == request.blocked_id {
+            return Err(tonic::Status::invalid_argument(
+                "Users cannot block themselves",
+            ));
+        }
+
+        _check_user(self, request.user_id).await?;
+        _check_user(self, request.blocked_id).await?;
+        self.storage
+            .relationships
+            .block(request.user_id, request.blocked_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        _list(self, request.user_id)
+            .await
+            .map(tonic::Response::new)
+            .map_err(super::ApplicationService::error)
+    }
+
+    async fn unblock_user(
+        &self,
+        request: tonic::Request<p_relationships::PBlockRequest>,
+    ) -> Result<tonic::Response<p_relationships::PBlockedList>, tonic::Status> {
+        let request = request.into_inner();
+        _check_user(self, request.user_id).await?;
+        self.storage
+            .relationships
+            .unblock(request.user_id, request.blocked_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        _list(self, request.user_id)
+            .await
+            .map(tonic::Response::new)
+            .map_err(super::ApplicationService::error)
+    }
+
+    async fn mute_channel(
+        &self,
+        request: tonic::Request<p_relationships::PMuteChannelRequest>,
+    ) -> Result<tonic::Response<p_relationships::PBlockedList>, tonic::Status> {
+        let request = request.into_inner();
+        _check_user(self, request.user_id).await?;
+
+        // Channels which do not exist anymore can still be unmuted
+        if request.muted
+            && self
+                .storage
+                .channels
+                .get(request.channel_id)
+                .await
+                .map_err(super::ApplicationService::error)?
+                .is_none()
+        {
+            return Err(tonic::Status::not_found("Channel not found"));
+        }
+
+        self.storage
+            .relationships
+            .set_muted(request.user_id, request.channel_id, request.muted)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        _list(self, request.user_id)
+            .await
+            .map(tonic::Response::new)
+            .map_err(super::ApplicationService::error)
+    }
+
+    async fn list_blocked(
+        &self,
+        request: tonic::Request<p_relationships::PListBlockedRequest>,
+    ) -> Result<tonic::Response<p_relationships::PBlockedList>, tonic::Status> {
+        let request = request.into_inner();
+        _check_user(self, request.user_id).await?;
+
+        _list(self, request.user_id)
+            .await
+            .map(tonic::Response::new)
+            .map_err(super::ApplicationService::error)
+    }
+}
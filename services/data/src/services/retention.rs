@@ -0,0 +1,561 @@
+use std::collections;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use rand::distr;
+use rand::rngs;
+use rand::Rng;
+use rand::SeedableRng;
+use tokio::sync::{mpsc, oneshot};
+
+use super::audit;
+use super::p_channels;
+use super::p_retention;
+use super::p_retention::retention_service_server;
+use super::p_users;
+use super::search::SearchIndex;
+use crate::blobs::BlobStore;
+use crate::events::{Event, EventPublisher};
+use crate::storage::{self, Storage};
+
+/// Maximum retention of a policy, in days.
+const _MAX_RETENTION_DAYS: i32 = 3650;
+
+const _SECONDS_PER_DAY: i32 = 24 * 60 * 60;
+
+/// Number of messages read at once while sweeping a channel.
+const _SWEEP_PAGE_SIZE: i32 = 500;
+
+/// Time messages are kept by the backend past their retention, so that sweeps find them and delete their pins,
+/// embeds and attachments along with them. The backend only deletes the messages sweeps failed to.
+const _EXPIRY_GRACE_SECONDS: i32 = _SECONDS_PER_DAY;
+
+/// Time-to-live of the sweep lease in seconds, after which a crashed sweeper no longer blocks others.
+const _LEASE_TTL_SECONDS: i32 = 600;
+
+/// Delay between refreshes of the held sweep lease, well within [`_LEASE_TTL_SECONDS`].
+const _LEASE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The policy of a channel, which follows the workspace policy until another one is set.
+async fn _channel_policy(
+    storage: &Storage,
+    channel_id: i64,
+) -> Result<storage::RetentionPolicy, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(storage
+        .retention
+        .get(channel_id)
+        .await?
+        .unwrap_or(storage::RetentionPolicy {
+            inherit: channel_id != 0,
+            ..Default::default()
+        }))
+}
+
+/// The number of days the messages of a channel are kept for, 0 if they are kept forever.
+async fn _effective_days(
+    storage: &Storage,
+    channel_id: i64,
+) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
+    let policy = _channel_policy(storage, channel_id).await?;
+    if policy.legal_hold {
+        return Ok(0);
+    }
+    if channel_id == 0 || !policy.inherit {
+        return Ok(policy.retention_days);
+    }
+
+    Ok(_channel_policy(storage, 0).await?.retention_days)
+}
+
+/// The retention of the messages of a channel in seconds, 0 if they are kept forever.
+async fn _retention_seconds(
+    storage: &Storage,
+    channel_id: i64,
+) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(_effective_days(storage, channel_id).await? * _SECONDS_PER_DAY)
+}
+
+/// The time to live of the messages created in a channel, 0 if they are kept forever.
+pub async fn ttl_seconds(
+    storage: &Storage,
+    channel_id: i64,
+) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(_with_grace(_retention_seconds(storage, channel_id).await?))
+}
+
+/// The time to live of a message which is kept for `seconds`, see [`_EXPIRY_GRACE_SECONDS`].
+fn _with_grace(seconds: i32) -> i32 {
+    if seconds > 0 {
+        seconds + _EXPIRY_GRACE_SECONDS
+    } else {
+        0
+    }
+}
+
+/// The ID below which the messages created `seconds` ago or earlier lie.
+fn _cutoff_id(epoch: DateTime<Utc>, now: i64, seconds: i32) -> i64 {
+    ((now - i64::from(seconds) * 1000 - epoch.timestamp_millis()) << 16) - 1
+}
+
+async fn _to_proto(
+    storage: &Storage,
+    channel_id: i64,
+    policy: storage::RetentionPolicy,
+) -> Result<p_retention::PRetentionPolicy, tonic::Status> {
+    Ok(p_retention::PRetentionPolicy {
+        channel_id,
+        retention_days: policy.retention_days,
+        inherit: policy.inherit,
+        legal_hold: policy.legal_hold,
+        effective_retention_days: _effective_days(storage, channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?,
+    })
+}
+
+/// Fail unless `user_id` has the `MODERATE` permission, or owns `channel_id` if `owner_allowed` is set.
+async fn _authorize(
+    application: &super::ApplicationService,
+    user_id: i64,
+    channel_id: i64,
+    owner_allowed: bool,
+) -> Result<(), tonic::Status> {
+    let user = application
+        .storage
+        .accounts
+        .by_id(user_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .ok_or_else(|| tonic::Status::not_found("User not found"))?;
+    if user.permissions & p_users::PPermission::Moderate as i64 != 0 {
+        return Ok(());
+    }
+
+    if owner_allowed && channel_id != 0 {
+        let channel = application
+            .storage
+            .channels
+            .get(channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?
+            .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+        if channel.owner_id == user.id {
+            return Ok(());
+        }
+
+        return Err(tonic::Status::permission_denied(
+            "Only the channel owner or moderators can change its retention policy",
+        ));
+    }
+
+    Err(tonic::Status::permission_denied(
+        "Only moderators can change the workspace retention policy and legal holds",
+    ))
+}
+
+/// Fail unless the channel exists.
+async fn _check_channel(
+    application: &super::ApplicationService,
+    channel_id: i64,
+) -> Result<(), tonic::Status> {
+    application
+        .storage
+        .channels
+        .get(channel_id)
+        .await
+        .map_err(super::ApplicationService::error)?
+        .ok_or_else(|| tonic::Status::not_found("Channel not found"))?;
+
+    Ok(())
+}
+
+/// A request to sweep a single channel, answered with whether it was swept, see [`Sweeper::sweep_channel`].
+type _Request = (
+    i64,
+    oneshot::Sender<Result<bool, Box<dyn std::error::Error + Send + Sync>>>,
+);
+
+/// Handle to the background task applying the retention policies to existing messages, see [`_work`].
+pub struct Sweeper {
+    sender: mpsc::UnboundedSender<_Request>,
+}
+
+impl Sweeper {
+    /// Spawn the task sweeping every channel every `interval`, and the channels requested in between.
+    pub fn spawn(
+        storage: Storage,
+        events: Arc<dyn EventPublisher>,
+        blobs: Arc<dyn BlobStore>,
+        search: Arc<SearchIndex>,
+        epoch: DateTime<Utc>,
+        interval: Duration,
+    ) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(_work(
+            receiver, storage, events, blobs, search, epoch, interval,
+        ));
+
+        Self { sender }
+    }
+
+    /// Sweep a channel right away, returning `false` if another replica holds the sweep lease, in which case its
+    /// next sweep applies the policy of the channel.
+    pub async fn sweep_channel(
+        &self,
+        channel_id: i64,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let (reply, result) = oneshot::channel();
+        self.sender.send((channel_id, reply))?;
+        result.await?
+    }
+}
+
+/// Apply the retention policies to existing messages every `interval` and on request, until the process exits.
+///
+/// Messages are created with the time to live of their channel plus [`_EXPIRY_GRACE_SECONDS`]. Sweeps delete the
+/// expired messages with their pins, embeds and attachments, and update the time to live of the others when a
+/// policy changes. Backends which do not expire messages rely on sweeps only.
+///
+/// Every replica ages out the messages of its own search index, while a cluster-wide lease lets a single replica
+/// sweep storage at a time.
+async fn _work(
+    mut receiver: mpsc::UnboundedReceiver<_Request>,
+    storage: Storage,
+    events: Arc<dyn EventPublisher>,
+    blobs: Arc<dyn BlobStore>,
+    search: Arc<SearchIndex>,
+    epoch: DateTime<Utc>,
+    interval: Duration,
+) {
+    let mut rng = rngs::StdRng::from_os_rng();
+    let owner = (0..16)
+        .map(|_| rng.sample(distr::Alphanumeric) as char)
+        .collect::<String>();
+    let sweep_requested = |(channel_id, reply): _Request| {
+        let (storage, events, blobs, owner) = (&storage, &events, &blobs, &owner);
+        async move {
+            let result = match storage.retention.lease(owner, _LEASE_TTL_SECONDS).await {
+                Ok(true) => {
+                    _sweep_channel(storage, events.as_ref(), blobs.as_ref(), epoch, channel_id)
+                        .await
+                        .map(|()| true)
+                }
+                result => result,
+            };
+            // The requester may have given up waiting
+            let _ = reply.send(result);
+        }
+    };
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            Some(request) = receiver.recv() => {
+                sweep_requested(request).await;
+                continue;
+            }
+        }
+
+        let mut channel_ids = Vec::new();
+        if let Err(e) = storage
+            .channels
+            .for_each(&mut |channel| channel_ids.push(channel.id))
+            .await
+        {
+            eprintln!("Unable to list channels to apply retention policies: {}", e);
+            continue;
+        }
+
+        let now = Utc::now().timestamp_millis();
+        let mut cutoffs = collections::HashMap::new();
+        for &channel_id in &channel_ids {
+            match _retention_seconds(&storage, channel_id).await {
+                Ok(0) => {}
+                Ok(seconds) => {
+                    cutoffs.insert(channel_id, _cutoff_id(epoch, now, seconds));
+                }
+                Err(e) => eprintln!(
+                    "Unable to read the retention policy of channel {}: {}",
+                    channel_id, e
+                ),
+            }
+        }
+        search.messages.expire(&cutoffs);
+
+        match storage.retention.lease(&owner, _LEASE_TTL_SECONDS).await {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                eprintln!("Unable to take the retention sweep lease: {}", e);
+                continue;
+            }
+        }
+
+        let mut refreshed_at = Instant::now();
+        for channel_id in channel_ids {
+            if refreshed_at.elapsed() >= _LEASE_REFRESH_INTERVAL {
+                match storage.retention.lease(&owner, _LEASE_TTL_SECONDS).await {
+                    Ok(true) => refreshed_at = Instant::now(),
+                    Ok(false) => {
+                        eprintln!("Lost the retention sweep lease, abandoning this sweep");
+                        break;
+                    }
+                    Err(e) => {
+                        eprintln!("Unable to refresh the retention sweep lease: {}", e);
+                        break;
+                    }
+                }
+            }
+
+            // Requested channels do not wait for the end of a long sweep
+            while let Ok(request) = receiver.try_recv() {
+                sweep_requested(request).await;
+            }
+
+            if let Err(e) =
+                _sweep_channel(&storage, events.as_ref(), blobs.as_ref(), epoch, channel_id).await
+            {
+                eprintln!(
+                    "Unable to apply the retention policy of channel {}: {}",
+                    channel_id, e
+                );
+            }
+        }
+    }
+}
+
+/// Delete the messages of a channel older than its retention, and update the time to live of the others if its
+/// policy changed since the last sweep.
+async fn _sweep_channel(
+    storage: &Storage,
+    events: &dyn EventPublisher,
+    blobs: &dyn BlobStore,
+    epoch: DateTime<Utc>,
+    channel_id: i64,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let retention_seconds = _retention_seconds(storage, channel_id).await?;
+    let applied = storage.retention.applied_ttl(channel_id).await?;
+    if retention_seconds == 0 && applied == 0 {
+        return Ok(());
+    }
+
+    // Unless the policy changed, only expired messages have to be read
+    let now = Utc::now().timestamp_millis();
+    let before_id = if retention_seconds != applied {
+        (now - epoch.timestamp_millis()) << 16 | 0xFFFF
+    } else {
+        _cutoff_id(epoch, now, retention_seconds)
+    };
+
+    let mut after_id = 0;
+    loop {
+        let page = storage
+            .messages
+            .history(channel_id, after_id, before_id, false, _SWEEP_PAGE_SIZE)
+            .await?;
+        let exhausted = page.len() < _SWEEP_PAGE_SIZE as usize;
+
+        for message in page {
+            after_id = message.id + 1;
+            let age_seconds =
+                ((now - ((message.id >> 16) + epoch.timestamp_millis())) / 1000).max(0);
+            if retention_seconds > 0 && age_seconds >= i64::from(retention_seconds) {
+                _delete_expired(storage, events, blobs, message).await?;
+            } else if retention_seconds != applied {
+                let remaining = if retention_seconds > 0 {
+                    _with_grace(retention_seconds - age_seconds as i32)
+                } else {
+                    0
+                };
+                storage.messages.set_ttl(&message, remaining).await?;
+            }
+        }
+
+        if exhausted {
+            break;
+        }
+    }
+
+    if retention_seconds != applied {
+        storage
+            .retention
+            .set_applied_ttl(channel_id, retention_seconds)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Delete an expired message along with its pin, embeds and the attachments no other message is attached to.
+///
+/// The message itself is deleted last, so that a failed deletion is retried in full by the next sweep.
+async fn _delete_expired(
+    storage: &Storage,
+    events: &dyn EventPublisher,
+    blobs: &dyn BlobStore,
+    message: storage::Message,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    storage.pins.unpin(message.channel_id, message.id).await?;
+    storage.embeds.set(message.id, &[]).await?;
+    for &attachment_id in &message.attachment_ids {
+        // An attachment can be attached to several messages, it is only deleted along with the last of them
+        let attached_to = storage.messages.attached_to(attachment_id).await?;
+        if attached_to.iter().any(|&id| id != message.id) {
+            continue;
+        }
+
+        blobs.delete(&attachment_id.to_string()).await?;
+        storage.attachments.delete(attachment_id).await?;
+    }
+
+    storage.messages.delete(&message).await?;
+    _publish_deleted(storage, events, message).await
+}
+
+/// Announce the deletion of an expired message to subscribers.
+async fn _publish_deleted(
+    storage: &Storage,
+    events: &dyn EventPublisher,
+    message: storage::Message,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let sequence = storage.channels.next_sequence(message.channel_id).await?;
+    events
+        .publish(Event::Message(p_channels::PMessageEvent {
+            event_type: p_channels::PMessageEventType::MessageDeleted.into(),
+            message: Some(p_channels::PMessage {
+                id: message.id,
+                channel: Some(p_channels::PChannel {
+                    id: message.channel_id,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            sequence,
+        }))
+        .await
+}
+
+#[tonic::async_trait]
+impl retention_service_server::RetentionService for super::ApplicationService {
+    async fn set_retention_policy(
+        &self,
+        request: tonic::Request<p_retention::PSetRetentionPolicyRequest>,
+    ) -> Result<tonic::Response<p_retention::PRetentionPolicy>, tonic::Status> {
+        let request = request.into_inner();
+        if !(0..=_MAX_RETENTION_DAYS).contains(&request.retention_days) {
+            return Err(tonic::Status::invalid_argument(format!(
+                "Retention must be between 0 and {} days",
+                _MAX_RETENTION_DAYS
+            )));
+        }
+        if request.channel_id == 0 && request.inherit {
+            return Err(tonic::Status::invalid_argument(
+                "The workspace policy cannot be inherited",
+            ));
+        }
+
+        _authorize(self, request.user_id, request.channel_id, true).await?;
+        if request.channel_id != 0 {
+            _check_channel(self, request.channel_id).await?;
+        }
+
+        let mut policy = _channel_policy(&self.storage, request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        policy.retention_days = request.retention_days;
+        policy.inherit = request.inherit;
+        self.storage
+            .retention
+            .set(request.channel_id, &policy)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        audit::record(
+            self,
+            request.user_id,
+            storage::AuditAction::RetentionPolicyChanged,
+            request.channel_id,
+            request.channel_id,
+            if policy.inherit {
+                "inherited".to_string()
+            } else if policy.retention_days == 0 {
+                "forever".to_string()
+            } else {
+                format!("{} days", policy.retention_days)
+            },
+        )
+        .await?;
+
+        _to_proto(&self.storage, request.channel_id, policy)
+            .await
+            .map(tonic::Response::new)
+    }
+
+    async fn set_legal_hold(
+        &self,
+        request: tonic::Request<p_retention::PSetLegalHoldRequest>,
+    ) -> Result<tonic::Response<p_retention::PRetentionPolicy>, tonic::Status> {
+        let request = request.into_inner();
+        if request.channel_id == 0 {
+            return Err(tonic::Status::invalid_argument(
+                "Legal holds apply to channels only",
+            ));
+        }
+
+        _authorize(self, request.user_id, request.channel_id, false).await?;
+        _check_channel(self, request.channel_id).await?;
+
+        let mut policy = _channel_policy(&self.storage, request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        policy.legal_hold = request.legal_hold;
+        self.storage
+            .retention
+            .set(request.channel_id, &policy)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        // Sweep right away unless another replica sweeps, so that no message expires once the hold is placed
+        self.sweeper
+            .sweep_channel(request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+
+        audit::record(
+            self,
+            request.user_id,
+            storage::AuditAction::LegalHoldChanged,
+            request.channel_id,
+            request.channel_id,
+            if policy.legal_hold {
+                "placed".to_string()
+            } else {
+                "released".to_string()
+            },
+        )
+        .await?;
+
+        _to_proto(&self.storage, request.channel_id, policy)
+            .await
+            .map(tonic::Response::new)
+    }
+
+    async fn get_retention_policy(
+        &self,
+        request: tonic::Request<p_retention::PGetRetentionPolicyRequest>,
+    ) -> Result<tonic::Response<p_retention::PRetentionPolicy>, tonic::Status> {
+        let request = request.into_inner();
+        if request.channel_id != 0 {
+            _check_channel(self, request.channel_id).await?;
+        }
+
+        let policy = _channel_policy(&self.storage, request.channel_id)
+            .await
+            .map_err(super::ApplicationService::error)?;
+        _to_proto(&self.storage, request.channel_id, policy)
+            .await
+            .map(tonic::Response::new)
+    }
+}
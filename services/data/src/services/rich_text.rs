@@ -0,0 +1,458 @@
+use prost::Message;
+
+use super::mention;
+use super::p_channels::{PRichText, PRichTextNode, PRichTextNodeType};
+
+/// Characters shown literally when preceded by a backslash.
+const _ESCAPABLE: &str = "\\`*_[]()@#<>";
+
+/// Characters which can start or end a code block language, e.g. `c++`, `c#` or `objective-c`.
+fn _is_language_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '+' | '#' | '-' | '_' | '.')
+}
+
+/// Either a parsed node, or an emphasis delimiter which may still be matched by a later one.
+enum _Item {
+    Node(PRichTextNode),
+    Delimiter(&'static str),
+}
+
+fn _node(node_type: PRichTextNodeType, text: &str) -> PRichTextNode {
+    PRichTextNode {
+        node_type: node_type.into(),
+        text: text.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Append `text` to `items`, merging it into the previous node if it is text too.
+fn _push_text(items: &mut Vec<_Item>, text: &str) {
+    if let Some(_Item::Node(node)) = items.last_mut() {
+        if node.node_type() == PRichTextNodeType::Text {
+            node.text.push_str(text);
+            return;
+        }
+    }
+
+    items.push(_Item::Node(_node(PRichTextNodeType::Text, text)));
+}
+
+/// Convert `items` into nodes, unmatched delimiters being kept as text.
+fn _finish(items: Vec<_Item>) -> Vec<PRichTextNode> {
+    let mut result = Vec::new();
+    for item in items {
+        match item {
+            _Item::Node(node) => _append(&mut result, node),
+            _Item::Delimiter(delimiter) => {
+                _append(&mut result, _node(PRichTextNodeType::Text, delimiter))
+            }
+        }
+    }
+
+    result
+}
+
+/// Append `node` to `nodes`, merging adjacent text nodes.
+fn _append(nodes: &mut Vec<PRichTextNode>, node: PRichTextNode) {
+    if node.node_type() == PRichTextNodeType::Text {
+        if let Some(last) = nodes.last_mut() {
+            if last.node_type() == PRichTextNodeType::Text {
+                last.text.push_str(&node.text);
+                return;
+            }
+        }
+    }
+
+    nodes.push(node);
+}
+
+/// Whether `url` is an absolute HTTP(S) link.
+fn _is_url(url: &str) -> bool {
+    ["http://", "https://"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme) && url.len() > scheme.len())
+        && !url.contains(char::is_whitespace)
+}
+
+/// The position of the next occurrence of a pattern in the content being parsed, searched for again only once the
+/// parser has moved past it.
+///
+/// Parsers look ahead from positions which only ever increase, so that caching the next occurrence of every pattern
+/// scans each part of the content at most once per pattern, and parsing stays linear whatever the markup.
+#[derive(Default)]
+struct _Next(Option<Option<usize>>);
+
+impl _Next {
+    /// The position of the next occurrence at or after `from`, found by `search` in the rest of `content`.
+    fn find(
+        &mut self,
+        content: &str,
+        from: usize,
+        search: impl FnOnce(&str) -> Option<usize>,
+    ) -> Option<usize> {
+        match self.0 {
+            Some(Some(position)) if position >= from => Some(position),
+            Some(None) => None,
+            _ => {
+                let position = search(&content[from..]).map(|position| from + position);
+                self.0 = Some(position);
+                position
+            }
+        }
+    }
+}
+
+/// The patterns looked ahead for while parsing, see [`_Next`].
+#[derive(Default)]
+struct _Lookahead {
+    code_block_end: _Next,
+    code_end: _Next,
+    label_end: _Next,
+    url_end: _Next,
+    link_end: _Next,
+}
+
+/// Parse a code block starting with triple backticks at `start` in `content`, returning it and its length.
+fn _code_block(
+    content: &str,
+    start: usize,
+    lookahead: &mut _Lookahead,
+) -> Option<(PRichTextNode, usize)> {
+    let end = lookahead
+        .code_block_end
+        .find(content, start + 3, |rest| rest.find("```"))?;
+    let mut body = &content[start + 3..end];
+    let mut language = "";
+    if let Some((first, remaining)) = body.split_once('\n') {
+        let first = first.trim_end_matches('\r');
+        if first.chars().all(_is_language_char) {
+            language = first;
+            body = remaining;
+        }
+    }
+
+    let mut node = _node(
+        PRichTextNodeType::CodeBlock,
+        body.strip_suffix('\n').unwrap_or(body),
+    );
+    node.language = language.to_string();
+    Some((node, end + 3 - start))
+}
+
+/// Parse inline code starting with a backtick at `start` in `content`, returning it and its length.
+fn _code(
+    content: &str,
+    start: usize,
+    lookahead: &mut _Lookahead,
+) -> Option<(PRichTextNode, usize)> {
+    let end = lookahead
+        .code_end
+        .find(content, start + 1, |rest| rest.find(['`', '\n']))?;
+    if end == start + 1 || !content[end..].starts_with('`') {
+        return None;
+    }
+
+    Some((
+        _node(PRichTextNodeType::Code, &content[start + 1..end]),
+        end + 1 - start,
+    ))
+}
+
+/// Parse a `[text](url)` link starting at `start` in `content`, returning it and its length.
+fn _link(
+    content: &str,
+    start: usize,
+    lookahead: &mut _Lookahead,
+) -> Option<(PRichTextNode, usize)> {
+    let label_end = lookahead
+        .label_end
+        .find(content, start + 1, |rest| rest.find(']'))?;
+    if !content[label_end + 1..].starts_with('(') {
+        return None;
+    }
+    let url_end = lookahead
+        .url_end
+        .find(content, label_end + 2, |rest| rest.find(')'))?;
+    let label = &content[start + 1..label_end];
+    let url = content[label_end + 2..url_end].trim();
+    if label.trim().is_empty() || !_is_url(url) {
+        return None;
+    }
+
+    let mut node = _node(PRichTextNodeType::Link, "");
+    node.children = _parse(label, false);
+    node.url = url.to_string();
+    Some((node, url_end + 1 - start))
+}
+
+/// Parse a bare link starting at `start` in `content`, returning it and its length.
+///
+/// Links are delimited by whitespace and angle brackets, trailing punctuation is ignored.
+fn _bare_link(
+    content: &str,
+    start: usize,
+    lookahead: &mut _Lookahead,
+) -> Option<(PRichTextNode, usize)> {
+    let end = lookahead
+        .link_end
+        .find(content, start, |rest| {
+            rest.find(|c: char| c.is_whitespace() || c == '<' || c == '>')
+        })
+        .unwrap_or(content.len());
+    let url = content[start..end]
+        .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', '\'', '"', '*', '_']);
+    if !_is_url(url) {
+        return None;
+    }
+
+    let mut node = _node(PRichTextNodeType::Link, "");
+    node.children = vec![_node(PRichTextNodeType::Text, url)];
+    node.url = url.to_string();
+    Some((node, url.len()))
+}
+
+/// Parse a `@mention` or `#channel` reference starting with a sigil at the beginning of `rest`, returning it and
+/// its length. Names follow the rules of usernames, trailing dots being treated as punctuation.
+fn _reference(rest: &str) -> Option<(PRichTextNode, usize)> {
+    let name = &rest[1..];
+    let end = name
+        .find(|c| !mention::is_username_char(c))
+        .unwrap_or(name.len());
+    let name = name[..end].trim_end_matches('.');
+    if name.is_empty() {
+        return None;
+    }
+
+    let node_type = if rest.starts_with('#') {
+        PRichTextNodeType::ChannelReference
+    } else if mention::is_channel_mention(name) {
+        PRichTextNodeType::ChannelMention
+    } else {
+        PRichTextNodeType::UserMention
+    };
+    Some((_node(node_type, name), 1 + name.len()))
+}
+
+/// Parse the content of a message into normalized rich text nodes, see `PRichTextNode`.
+pub fn parse(content: &str) -> Vec<PRichTextNode> {
+    _parse(content, true)
+}
+
+/// Encode parsed nodes to be stored alongside the content of a message, see [`stored`].
+pub fn encode(nodes: Vec<PRichTextNode>) -> Vec<u8> {
+    PRichText { nodes }.encode_to_vec()
+}
+
+/// The rich text of a message from the nodes stored with it, parsing `content` again if none are, e.g. for messages
+/// created before rich text was stored.
+pub fn stored(content: &str, encoded: &[u8]) -> Vec<PRichTextNode> {
+    match PRichText::decode(encoded) {
+        Ok(rich_text) if !encoded.is_empty() => rich_text.nodes,
+        _ => parse(content),
+    }
+}
+
+/// Parse `content`, recognizing links only if `links` is set so that links are not nested.
+///
+/// Code, links and references are parsed as soon as they are found, while emphasis delimiters are kept on a
+/// stack until a matching delimiter closes them. Delimiters left open when another one closes, or at the end of
+/// `content`, are kept as text, so that parsing never backtracks whatever the markup.
+fn _parse(content: &str, links: bool) -> Vec<PRichTextNode> {
+    let mut items = Vec::new();
+    let mut lookahead = _Lookahead::default();
+    let mut openers: Vec<(&'static str, usize)> = Vec::new();
+    let mut index = 0;
+    let mut previous = None;
+    while let Some(c) = content[index..].chars().next() {
+        let rest = &content[index..];
+        let next = rest[c.len_utf8()..].chars().next();
+        let word_start = !previous.is_some_and(mention::is_username_char);
+
+        let parsed = match c {
+            '`' if rest.starts_with("```") => _code_block(content, index, &mut lookahead),
+            '`' => _code(content, index, &mut lookahead),
+            '[' if links => _link(content, index, &mut lookahead),
+            'h' if links && !previous.is_some_and(char::is_alphanumeric) => {
+                _bare_link(content, index, &mut lookahead)
+            }
+            '@' | '#' if word_start => _reference(rest),
+            _ => None,
+        };
+
+        let length = if let Some((node, length)) = parsed {
+            items.push(_Item::Node(node));
+            length
+        } else if let Some(escaped) = next.filter(|next| c == '\\' && _ESCAPABLE.contains(*next)) {
+            _push_text(&mut items, &escaped.to_string());
+            1 + escaped.len_utf8()
+        } else if rest.starts_with("```") {
+            // An unclosed code block must not become inline code
+            _push_text(&mut items, "```");
+            3
+        } else if c == '*'
+            || (c == '_'
+                && !(previous.is_some_and(char::is_alphanumeric)
+                    && next.is_some_and(char::is_alphanumeric)))
+        {
+            let delimiter = match c {
+                '*' if next == Some('*') => "**",
+                '*' => "*",
+                _ => "_",
+            };
+
+            match openers.iter().rposition(|(opener, _)| *opener == delimiter) {
+                Some(position) => {
+                    let start = openers[position].1;
+                    openers.truncate(position);
+                    let children = _finish(items.split_off(start + 1));
+                    items.pop();
+                    if children.iter().all(|node| {
+                        node.node_type() == PRichTextNodeType::Text && node.text.trim().is_empty()
+                    }) {
+                        // Emphasis of nothing but whitespace is kept as text
+                        let text = children
+                            .iter()
+                            .map(|node| node.text.as_str())
+                            .collect::<String>();
+                        _push_text(&mut items, &format!("{}{}{}", delimiter, text, delimiter));
+                    } else {
+                        let mut node = _node(
+                            if delimiter == "**" {
+                                PRichTextNodeType::Bold
+                            } else {
+                                PRichTextNodeType::Italic
+                            },
+                            "",
+                        );
+                        node.children = children;
+                        items.push(_Item::Node(node));
+                    }
+                }
+                None => {
+                    openers.push((delimiter, items.len()));
+                    items.push(_Item::Delimiter(delimiter));
+                }
+            }
+            delimiter.len()
+        } else {
+            _push_text(&mut items, &rest[..c.len_utf8()]);
+            c.len_utf8()
+        };
+
+        index += length;
+        previous = content[..index].chars().next_back();
+    }
+
+    _finish(items)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render nodes with HTML-like tags, so that expected trees can be written inline.
+    fn _render(nodes: &[PRichTextNode]) -> String {
+        nodes
+            .iter()
+            .map(|node| match node.node_type() {
+                PRichTextNodeType::Text => node.text.clone(),
+                PRichTextNodeType::Bold => format!("<b>{}</b>", _render(&node.children)),
+                PRichTextNodeType::Italic => format!("<i>{}</i>", _render(&node.children)),
+                PRichTextNodeType::Code => format!("<code>{}</code>", node.text),
+                PRichTextNodeType::CodeBlock => {
+                    format!("<pre {}>{}</pre>", node.language, node.text)
+                }
+                PRichTextNodeType::Link => {
+                    format!("<a {}>{}</a>", node.url, _render(&node.children))
+                }
+                PRichTextNodeType::UserMention => format!("<@{}>", node.text),
+                PRichTextNodeType::ChannelMention => format!("<@@{}>", node.text),
+                PRichTextNodeType::ChannelReference => format!("<#{}>", node.text),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_nested_markup() {
+        for (content, expected) in [
+            ("plain text", "plain text"),
+            ("**bold** and *italics*", "<b>bold</b> and <i>italics</i>"),
+            ("**bold _both_**", "<b>bold <i>both</i></b>"),
+            ("_a **b** c_", "<i>a <b>b</b> c</i>"),
+            ("**`code`**", "<b><code>code</code></b>"),
+            ("`**not bold**`", "<code>**not bold**</code>"),
+            ("[**x**](https://a.b)", "<a https://a.b><b>x</b></a>"),
+            (
+                "[see https://a.b](https://c.d)",
+                "<a https://c.d>see https://a.b</a>",
+            ),
+            ("*a _b* c_", "<i>a _b</i> c_"),
+            ("```rust\nfn main() {}\n```", "<pre rust>fn main() {}</pre>"),
+            (
+                "@alice and @here in #general",
+                "<@alice> and <@@here> in <#general>",
+            ),
+            ("bob@example.com", "bob@example.com"),
+            ("\\*not italics\\*", "*not italics*"),
+        ] {
+            assert_eq!(_render(&parse(content)), expected, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn parse_unterminated_markup() {
+        for (content, expected) in [
+            ("**bold", "**bold"),
+            ("*italics", "*italics"),
+            ("`code", "`code"),
+            ("`co\nde`", "`co\nde`"),
+            ("```code", "```code"),
+            ("```code`", "```code`"),
+            ("** **", "** **"),
+            (
+                "[label](https://a.b",
+                "[label](<a https://a.b>https://a.b</a>",
+            ),
+            ("[label](not a url)", "[label](not a url)"),
+            ("[](https://a.b)", "[](<a https://a.b>https://a.b</a>)"),
+            ("[[[[", "[[[["),
+            ("``````", "<pre ></pre>"),
+            ("@", "@"),
+            ("\\", "\\"),
+        ] {
+            assert_eq!(_render(&parse(content)), expected, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn parse_multibyte_boundaries() {
+        for (content, expected) in [
+            ("**héllo** ünï `çödé`", "<b>héllo</b> ünï <code>çödé</code>"),
+            ("é*ü*é", "é<i>ü</i>é"),
+            ("é_ü_é", "é_ü_é"),
+            ("🎉`🎉`🎉", "🎉<code>🎉</code>🎉"),
+            ("🎉`", "🎉`"),
+            ("[🎉](https://a.b)🎉", "<a https://a.b>🎉</a>🎉"),
+            ("@zoë. #café", "<@zoë>. <#café>"),
+            (
+                "https://例え.jp/パス 🎉",
+                "<a https://例え.jp/パス>https://例え.jp/パス</a> 🎉",
+            ),
+            ("```日本\n語```", "<pre 日本>語</pre>"),
+            ("\\é", "\\é"),
+        ] {
+            assert_eq!(_render(&parse(content)), expected, "{:?}", content);
+        }
+    }
+
+    #[test]
+    fn stored_nodes_fall_back_to_parsing() {
+        let content = "**bold** @alice";
+        let encoded = encode(parse(content));
+        assert_eq!(
+            _render(&stored("ignored", &encoded)),
+            "<b>bold</b> <@alice>"
+        );
+        assert_eq!(_render(&stored(content, &[])), "<b>bold</b> <@alice>");
+    }
+}
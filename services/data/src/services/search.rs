@@ -0,0 +1,528 @@
+use std::collections;
+use std::sync::{Arc, RwLock};
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+
+use super::p_channels;
+use super::p_channels::{PChannelEventType, PMessageEventType};
+use crate::events::{Event, EventPublisher, Topics};
+use crate::storage::Storage;
+
+/// Age in days of the oldest messages indexed when bootstrapping, older messages are only indexed when edited.
+const _BOOTSTRAP_DAYS: i64 = 30;
+
+/// Number of messages read at once while bootstrapping.
+const _BOOTSTRAP_PAGE_SIZE: i32 = 500;
+
+/// BM25 term frequency saturation parameter.
+const _K1: f64 = 1.2;
+
+/// BM25 document length normalization parameter.
+const _B: f64 = 0.75;
+
+/// A document stored in [`MessageIndex`].
+struct _Document {
+    author_id: i64,
+    channel_id: i64,
+    terms: collections::HashMap<String, u32>,
+    length: u32,
+}
+
+#[derive(Default)]
+struct _IndexState {
+    /// Indexed documents, ordered by snowflake ID (and therefore by creation time).
+    documents: collections::BTreeMap<i64, _Document>,
+
+    /// Mapping from a term to the IDs of the documents containing it.
+    postings: collections::HashMap<String, collections::HashSet<i64>>,
+
+    /// Sum of the lengths of all indexed documents.
+    total_length: u64,
+}
+
+/// Filters applied to a [`MessageIndex::search`] query.
+pub struct SearchFilter {
+    /// Only match messages sent by this user
+    pub author_id: Option<i64>,
+
+    /// Only match messages sent in this channel
+    pub channel_id: Option<i64>,
+
+    /// Only match snowflake IDs smaller than or equal to this ID
+    pub before_id: i64,
+
+    /// Only match snowflake IDs greater than or equal to this ID
+    pub after_id: i64,
+
+    /// Never match messages sent by these users
+    pub hidden_author_ids: collections::HashSet<i64>,
+
+    /// Never match messages sent in these channels
+    pub hidden_channel_ids: collections::HashSet<i64>,
+}
+
+impl SearchFilter {
+    fn matches(&self, id: i64, document: &_Document) -> bool {
+        id <= self.before_id
+            && id >= self.after_id
+            && self
+                .author_id
+                .is_none_or(|author_id| author_id == document.author_id)
+            && self
+                .channel_id
+                .is_none_or(|channel_id| channel_id == document.channel_id)
+            && !self.hidden_author_ids.contains(&document.author_id)
+            && !self.hidden_channel_ids.contains(&document.channel_id)
+    }
+}
+
+/// An embedded inverted index over message contents.
+///
+/// Each data service replica maintains its own index by consuming the message events of the `channel-stream` exchange,
+/// see [`maintain`]. The whole index is held in memory, so it only keeps the `capacity` most recent messages
+/// and older messages cannot be searched.
+#[derive(Default)]
+pub struct MessageIndex {
+    state: RwLock<_IndexState>,
+
+    /// Maximum number of indexed messages, 0 for no limit
+    capacity: usize,
+}
+
+/// Split a text into lowercase alphanumeric terms.
+pub fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+impl MessageIndex {
+    /// An empty index keeping at most `capacity` messages, 0 for no limit.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            state: RwLock::default(),
+            capacity,
+        }
+    }
+
+    /// Add a message to the index, replacing any previous version of it.
+    ///
+    /// The oldest messages are evicted once the index holds more than its capacity.
+    pub fn insert(&self, id: i64, author_id: i64, channel_id: i64, content: &str) {
+        let mut state = self.state.write().unwrap();
+        Self::_remove(&mut state, id);
+
+        let mut terms = collections::HashMap::new();
+        let mut length = 0;
+        for term in tokenize(content) {
+            *terms.entry(term).or_insert(0) += 1;
+            length += 1;
+        }
+
+        for term in terms.keys() {
+            state.postings.entry(term.clone()).or_default().insert(id);
+        }
+
+        state.total_length += length as u64;
+        state.documents.insert(
+            id,
+            _Document {
+                author_id,
+                channel_id,
+                terms,
+                length,
+            },
+        );
+
+        while self.capacity > 0 && state.documents.len() > self.capacity {
+            if let Some(oldest) = state.documents.keys().next().copied() {
+                Self::_remove(&mut state, oldest);
+            }
+        }
+    }
+
+    /// Remove a message from the index.
+    pub fn remove(&self, id: i64) {
+        let mut state = self.state.write().unwrap();
+        Self::_remove(&mut state, id);
+    }
+
+    /// Remove the messages older than the cutoff of their channel, a snowflake ID.
+    ///
+    /// Every replica holds its own index, so each one ages out expired messages instead of relying on the
+    /// deletions published by the replica sweeping them.
+    pub fn expire(&self, cutoffs: &collections::HashMap<i64, i64>) {
+        let mut state = self.state.write().unwrap();
+        let expired = state
+            .documents
+            .iter()
+            .filter(|(id, document)| {
+                cutoffs
+                    .get(&document.channel_id)
+                    .is_some_and(|cutoff| *id < cutoff)
+            })
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            Self::_remove(&mut state, id);
+        }
+    }
+
+    fn _remove(state: &mut _IndexState, id: i64) {
+        if let Some(document) = state.documents.remove(&id) {
+            for term in document.terms.keys() {
+                if let Some(ids) = state.postings.get_mut(term) {
+                    ids.remove(&id);
+                    if ids.is_empty() {
+                        state.postings.remove(term);
+                    }
+                }
+            }
+
+            state.total_length -= document.length as u64;
+        }
+    }
+
+    /// Apply a message event consumed from the `channel-stream` exchange.
+    pub fn apply(&self, event: &p_channels::PMessageEvent) {
+        if let Some(message) = &event.message {
+            match event.event_type() {
+                PMessageEventType::MessageCreated | PMessageEventType::MessageUpdated => {
+                    self.insert(
+                        message.id,
+                        message.author.as_ref().map_or(0, |author| author.id),
+                        message.channel.as_ref().map_or(0, |channel| channel.id),
+                        &message.content,
+                    );
+                }
+                PMessageEventType::MessageDeleted => self.remove(message.id),
+            }
+        }
+    }
+
+    /// Search for messages containing every term of `keywords`, returning at most `limit` message IDs.
+    ///
+    /// Results are ordered by BM25 relevance when `relevance` is `true`, otherwise from newest to oldest.
+    /// An empty `keywords` matches every message satisfying `filter`.
+    pub fn search(
+        &self,
+        keywords: &str,
+        filter: &SearchFilter,
+        relevance: bool,
+        limit: usize,
+    ) -> Vec<i64> {
+        let state = self.state.read().unwrap();
+        if filter.after_id > filter.before_id {
+            return Vec::new();
+        }
+
+        let mut terms = tokenize(keywords);
+        terms.sort();
+        terms.dedup();
+
+        if terms.is_empty() {
+            return state
+                .documents
+                .range(filter.after_id..=filter.before_id)
+                .rev()
+                .filter(|(id, document)| filter.matches(**id, document))
+                .map(|(id, _)| *id)
+                .take(limit)
+                .collect();
+        }
+
+        let mut postings = Vec::new();
+        for term in &terms {
+            match state.postings.get(term) {
+                Some(ids) => postings.push(ids),
+                None => return Vec::new(),
+            }
+        }
+
+        // Iterate over the shortest posting list and check the remaining ones
+        postings.sort_by_key(|ids| ids.len());
+        let mut candidates = postings[0]
+            .iter()
+            .filter(|id| postings[1..].iter().all(|ids| ids.contains(*id)))
+            .filter_map(|id| state.documents.get(id).map(|document| (*id, document)))
+            .filter(|(id, document)| filter.matches(*id, document))
+            .map(|(id, document)| {
+                let score = if relevance {
+                    Self::_score(&state, &terms, document)
+                } else {
+                    0.0
+                };
+                (id, score)
+            })
+            .collect::<Vec<_>>();
+
+        // Newer messages rank first among equally relevant results
+        candidates.sort_by(|(a_id, a_score), (b_id, b_score)| {
+            b_score.total_cmp(a_score).then(b_id.cmp(a_id))
+        });
+
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(id, _)| id)
+            .collect()
+    }
+
+    /// Count the indexed messages of each channel with snowflake ID greater than or equal to `after_id`.
+    pub fn activity(&self, after_id: i64) -> collections::HashMap<i64, u64> {
+        let state = self.state.read().unwrap();
+        let mut counts = collections::HashMap::new();
+        for document in state
+            .documents
+            .range(after_id..)
+            .map(|(_, document)| document)
+        {
+            *counts.entry(document.channel_id).or_insert(0) += 1;
+        }
+
+        counts
+    }
+
+    fn _score(state: &_IndexState, terms: &[String], document: &_Document) -> f64 {
+        let count = state.documents.len() as f64;
+        let average_length = state.total_length as f64 / count.max(1.0);
+
+        terms
+            .iter()
+            .map(|term| {
+                let frequency = *document.terms.get(term).unwrap_or(&0) as f64;
+                let matches = state.postings.get(term).map_or(0, |ids| ids.len()) as f64;
+                let idf = (1.0 + (count - matches + 0.5) / (matches + 0.5)).ln();
+                let normalization =
+                    1.0 - _B + _B * document.length as f64 / average_length.max(1.0);
+
+                idf * frequency * (_K1 + 1.0) / (frequency + _K1 * normalization)
+            })
+            .sum()
+    }
+}
+
+/// A channel stored in [`ChannelIndex`], with lowercase name and description.
+struct _ChannelEntry {
+    name: String,
+    description: String,
+}
+
+/// An embedded directory of channel names and descriptions.
+///
+/// Each data service replica maintains its own directory by consuming the channel events of the `channel-stream` exchange,
+/// see [`maintain`].
+#[derive(Default)]
+pub struct ChannelIndex {
+    channels: RwLock<collections::BTreeMap<i64, _ChannelEntry>>,
+}
+
+impl ChannelIndex {
+    /// Add a channel to the directory, replacing any previous version of it.
+    pub fn insert(&self, id: i64, name: &str, description: &str) {
+        self.channels.write().unwrap().insert(
+            id,
+            _ChannelEntry {
+                name: name.to_lowercase(),
+                description: description.to_lowercase(),
+            },
+        );
+    }
+
+    /// Remove a channel from the directory.
+    pub fn remove(&self, id: i64) {
+        self.channels.write().unwrap().remove(&id);
+    }
+
+    /// Remove every channel but those of `ids` from the directory.
+    pub fn retain(&self, ids: &collections::HashSet<i64>) {
+        self.channels
+            .write()
+            .unwrap()
+            .retain(|id, _| ids.contains(id));
+    }
+
+    /// Apply a channel event consumed from the `channel-stream` exchange.
+    pub fn apply(&self, event: &p_channels::PChannelEvent) {
+        if let Some(channel) = &event.channel {
+            match event.event_type() {
+                PChannelEventType::ChannelCreated | PChannelEventType::ChannelUpdated => {
+                    self.insert(channel.id, &channel.name, &channel.description);
+                }
+                PChannelEventType::ChannelDeleted => self.remove(channel.id),
+                PChannelEventType::MessagePinned
+                | PChannelEventType::MessageUnpinned
+                | PChannelEventType::MemberBanned
+                | PChannelEventType::MemberUnbanned
+                | PChannelEventType::MemberTimedOut => {}
+            }
+        }
+    }
+
+    /// Search for channels whose name or description contains `query` (case-insensitive), returning
+    /// at most `limit` channel IDs.
+    ///
+    /// When `activity` is `None`, name prefix matches rank first, followed by name substring matches,
+    /// description prefix matches and description substring matches. Otherwise, channels are ordered by
+    /// their message count in `activity`. Ties are broken by the newest channel first.
+    pub fn search(
+        &self,
+        query: &str,
+        activity: Option<&collections::HashMap<i64, u64>>,
+        limit: usize,
+    ) -> Vec<i64> {
+        let query = query.to_lowercase();
+        let channels = self.channels.read().unwrap();
+
+        let mut candidates = channels
+            .iter()
+            .filter_map(|(id, channel)| {
+                let rank = if channel.name.starts_with(&query) {
+                    0
+                } else if channel.name.contains(&query) {
+                    1
+                } else if channel.description.starts_with(&query) {
+                    2
+                } else if channel.description.contains(&query) {
+                    3
+                } else {
+                    return None;
+                };
+
+                let rank = match activity {
+                    Some(counts) => u64::MAX - counts.get(id).copied().unwrap_or(0),
+                    None => rank,
+                };
+                Some((rank, *id))
+            })
+            .collect::<Vec<_>>();
+
+        candidates
+            .sort_by(|(a_rank, a_id), (b_rank, b_id)| a_rank.cmp(b_rank).then(b_id.cmp(a_id)));
+        candidates
+            .into_iter()
+            .take(limit)
+            .map(|(_, id)| id)
+            .collect()
+    }
+}
+
+/// The in-process search indexes of a data service replica.
+pub struct SearchIndex {
+    pub channels: ChannelIndex,
+    pub messages: MessageIndex,
+}
+
+impl SearchIndex {
+    /// Empty indexes, keeping at most `max_messages` of the most recent messages.
+    pub fn new(max_messages: usize) -> Self {
+        Self {
+            channels: ChannelIndex::default(),
+            messages: MessageIndex::with_capacity(max_messages),
+        }
+    }
+}
+
+/// Keep `index` in sync with the published channel and message events, after bootstrapping it from `storage`.
+///
+/// Events are subscribed to before bootstrapping, so that changes made in the meantime are not missed. Whenever
+/// events are missed, e.g. when the subscriber lags behind a burst, the index is bootstrapped again. Messages deleted
+/// in the meantime may then linger in the index, which search results skip.
+/// This function only returns on error, or when the publisher shuts down.
+pub async fn maintain(
+    index: Arc<SearchIndex>,
+    events: Arc<dyn EventPublisher>,
+    storage: Storage,
+    epoch: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut events = events
+        .subscribe(
+            "search-index",
+            Topics {
+                channel_ids: None,
+                user_ids: Some(Vec::new()),
+                presence_user_ids: Some(Vec::new()),
+                skip_typing: true,
+            },
+        )
+        .await?;
+
+    _bootstrap(&index, &storage, epoch).await?;
+    while let Some(event) = events.next().await {
+        match event {
+            Ok(Event::Channel(event)) => index.channels.apply(&event),
+            Ok(Event::Message(event)) => index.messages.apply(&event),
+            Ok(Event::ReadState(_) | Event::Typing(_) | Event::Presence(_)) => {}
+            Err(e) => {
+                eprintln!("Search index missed events, bootstrapping it again: {}", e);
+                _bootstrap(&index, &storage, epoch).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill `index` with every channel, and the messages of the last [`_BOOTSTRAP_DAYS`] days in each channel, at most
+/// as many per channel as the message index holds.
+async fn _bootstrap(
+    index: &SearchIndex,
+    storage: &Storage,
+    epoch: DateTime<Utc>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let mut channel_ids = collections::HashSet::new();
+    storage
+        .channels
+        .for_each(&mut |channel| {
+            index
+                .channels
+                .insert(channel.id, &channel.name, &channel.description);
+            channel_ids.insert(channel.id);
+        })
+        .await?;
+    index.channels.retain(&channel_ids);
+
+    let after_id = (Utc::now() - chrono::Duration::days(_BOOTSTRAP_DAYS))
+        .signed_duration_since(epoch)
+        .num_milliseconds()
+        .max(0)
+        << 16;
+    for channel_id in channel_ids {
+        let mut before_id = i64::MAX;
+        let mut count = 0;
+        loop {
+            let limit = if index.messages.capacity > 0 {
+                (index.messages.capacity - count).min(_BOOTSTRAP_PAGE_SIZE as usize) as i32
+            } else {
+                _BOOTSTRAP_PAGE_SIZE
+            };
+            if limit == 0 {
+                break;
+            }
+
+            let page = storage
+                .messages
+                .history(channel_id, after_id, before_id, true, limit)
+                .await?;
+            for message in &page {
+                index.messages.insert(
+                    message.id,
+                    message.author_id,
+                    message.channel_id,
+                    &message.content,
+                );
+            }
+
+            count += page.len();
+            match page.last() {
+                Some(oldest) if page.len() == limit as usize => before_id = oldest.id - 1,
+                _ => break,
+            }
+        }
+    }
+
+    Ok(())
+}
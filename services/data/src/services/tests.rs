@@ -0,0 +1,2293 @@
+//! Service-level tests, running the services against in-memory storage and in-process events.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use tonic::codec::Codec;
+
+use super::p_attachments;
+use super::p_attachments::attachment_service_server::AttachmentService;
+use super::p_channels;
+use super::p_channels::channel_service_server::ChannelService;
+use super::p_moderation;
+use super::p_moderation::moderation_service_server::ModerationService;
+use super::p_presence;
+use super::p_presence::presence_service_server::PresenceService;
+use super::p_relationships;
+use super::p_relationships::relationship_service_server::RelationshipService;
+use super::{ApplicationService, ServiceOptions};
+use crate::blobs::local::LocalBlobStore;
+use crate::embeds::noop::NoopFetcher;
+use crate::events::broadcast::BroadcastPublisher;
+use crate::events::{Event, Topics};
+use crate::moderation::filters::ChannelFilters;
+use crate::moderation::spam::SpamHeuristics;
+use crate::moderation::{Moderator, Pipeline};
+use crate::storage;
+use crate::storage::memory::MemoryStorage;
+use crate::storage::Storage;
+
+pub fn options() -> ServiceOptions {
+    ServiceOptions {
+        max_pins_per_channel: 50,
+        max_attachment_bytes: 1024 * 1024,
+        max_message_length: 4000,
+        user_messages_per_minute: 0,
+        user_message_burst: 10,
+        channel_messages_per_minute: 0,
+        channel_message_burst: 100,
+        idempotency_key_ttl_seconds: 60,
+        idempotency_claim_timeout: Duration::from_secs(10),
+        search_index_max_messages: 1000,
+        retention_sweep_interval: Duration::from_secs(3600),
+    }
+}
+
+/// An application without moderation stages, whose blobs are stored in a fresh temporary directory.
+pub async fn application_with(options: ServiceOptions) -> Arc<ApplicationService> {
+    moderated_application(options, |_| Vec::new()).await
+}
+
+/// An application moderating messages with the stages built by `stages` from its storage, see [`application_with`].
+pub async fn moderated_application(
+    options: ServiceOptions,
+    stages: impl FnOnce(&Storage) -> Vec<Arc<dyn Moderator>>,
+) -> Arc<ApplicationService> {
+    let storage = Storage::from_backend(Arc::new(MemoryStorage::default()));
+    let pipeline = Pipeline::new(stages(&storage));
+    _application_from(storage, pipeline, options).await
+}
+
+/// An application running against `storage`, see [`moderated_application`].
+async fn _application_from(
+    storage: Storage,
+    pipeline: Pipeline,
+    options: ServiceOptions,
+) -> Arc<ApplicationService> {
+    let blobs = LocalBlobStore::open(
+        std::env::temp_dir().join(format!("data-service-test-{}", rand::random::<u64>())),
+    )
+    .await
+    .unwrap();
+
+    Arc::new(
+        ApplicationService::new(
+            Arc::new(BroadcastPublisher::default()),
+            storage,
+            Arc::new(blobs),
+            Arc::new(NoopFetcher),
+            pipeline,
+            options,
+        )
+        .await
+        .unwrap(),
+    )
+}
+
+pub async fn application() -> Arc<ApplicationService> {
+    application_with(options()).await
+}
+
+/// Create an account without going through password hashing, returning its ID.
+pub async fn user(application: &ApplicationService, username: &str) -> i64 {
+    application
+        .storage
+        .accounts
+        .create(username, "", &|| application.generate_id())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+pub async fn channel(application: &ApplicationService, owner_id: i64) -> i64 {
+    application
+        .create_channel(tonic::Request::new(p_channels::PCreateChannelRequest {
+            name: "general".to_string(),
+            description: "General discussions".to_string(),
+            owner_id,
+            idempotency_key: String::new(),
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .id
+}
+
+pub async fn post(
+    application: &ApplicationService,
+    channel_id: i64,
+    author_id: i64,
+    content: &str,
+) -> Result<p_channels::PMessage, tonic::Status> {
+    application
+        .create_message(tonic::Request::new(p_channels::PCreateMessageRequest {
+            content: content.to_string(),
+            author_id,
+            channel_id,
+            attachment_ids: Vec::new(),
+            idempotency_key: String::new(),
+        }))
+        .await
+        .map(tonic::Response::into_inner)
+}
+
+pub async fn history(
+    application: &ApplicationService,
+    channel_id: i64,
+    user_id: i64,
+) -> Result<Vec<p_channels::PMessage>, tonic::Status> {
+    application
+        .history(tonic::Request::new(p_channels::PHistoryQuery {
+            id: channel_id,
+            newest: false,
+            before_id: 0,
+            after_id: 0,
+            limit: 100,
+            user_id,
+        }))
+        .await
+        .map(|response| response.into_inner().messages)
+}
+
+fn _contents(messages: &[p_channels::PMessage]) -> Vec<&str> {
+    messages
+        .iter()
+        .map(|message| message.content.as_str())
+        .collect()
+}
+
+#[tokio::test]
+async fn history_returns_created_messages_in_order() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    for content in ["first", "second", "third"] {
+        post(&application, channel_id, owner_id, content)
+            .await
+            .unwrap();
+    }
+
+    let messages = history(&application, channel_id, owner_id).await.unwrap();
+    assert_eq!(_contents(&messages), ["first", "second", "third"]);
+    assert!(messages
+        .iter()
+        .all(|message| message.author.as_ref().unwrap().id == owner_id));
+}
+
+#[tokio::test]
+async fn create_message_rejects_unknown_channels() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+
+    assert!(post(&application, 42, owner_id, "hello").await.is_err());
+}
+
+#[tokio::test]
+async fn catchup_returns_messages_after_last_seen() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let first = post(&application, channel_id, owner_id, "first")
+        .await
+        .unwrap();
+    post(&application, channel_id, owner_id, "second")
+        .await
+        .unwrap();
+    post(&application, channel_id, owner_id, "third")
+        .await
+        .unwrap();
+
+    let result = application
+        .catchup(tonic::Request::new(p_channels::PCatchupRequest {
+            channel_id,
+            last_seen_id: first.id,
+            limit: 0,
+            user_id: owner_id,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(_contents(&result.messages), ["second", "third"]);
+    assert!(result.complete);
+
+    let result = application
+        .catchup(tonic::Request::new(p_channels::PCatchupRequest {
+            channel_id,
+            last_seen_id: first.id,
+            limit: 1,
+            user_id: owner_id,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(_contents(&result.messages), ["second"]);
+    assert!(!result.complete);
+}
+
+#[tokio::test]
+async fn idempotency_key_replays_the_created_message() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let request = p_channels::PCreateMessageRequest {
+        content: "hello".to_string(),
+        author_id: owner_id,
+        channel_id,
+        attachment_ids: Vec::new(),
+        idempotency_key: "retry-me".to_string(),
+    };
+    let first = application
+        .create_message(tonic::Request::new(request.clone()))
+        .await
+        .unwrap()
+        .into_inner();
+    let second = application
+        .create_message(tonic::Request::new(request))
+        .await
+        .unwrap()
+        .into_inner();
+
+    assert_eq!(first.id, second.id);
+    assert_eq!(
+        history(&application, channel_id, owner_id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+/// Messages stored in memory, whose creation fails while `failing` is set.
+struct _FlakyMessages {
+    inner: Arc<dyn storage::MessageRepository>,
+    failing: std::sync::atomic::AtomicBool,
+}
+
+#[tonic::async_trait]
+impl storage::MessageRepository for _FlakyMessages {
+    async fn create(
+        &self,
+        content: &str,
+        rich_content: &[u8],
+        author_id: i64,
+        channel_id: i64,
+        mentions: &storage::Mentions,
+        attachment_ids: &[i64],
+        ttl_seconds: i32,
+        generate_id: storage::IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        if self.failing.load(std::sync::atomic::Ordering::SeqCst) {
+            return Err("Storage unavailable".into());
+        }
+        self.inner
+            .create(
+                content,
+                rich_content,
+                author_id,
+                channel_id,
+                mentions,
+                attachment_ids,
+                ttl_seconds,
+                generate_id,
+            )
+            .await
+    }
+
+    async fn set_ttl(
+        &self,
+        message: &storage::Message,
+        ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.set_ttl(message, ttl_seconds).await
+    }
+
+    async fn delete(
+        &self,
+        message: &storage::Message,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.delete(message).await
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<storage::Message>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.get(id).await
+    }
+
+    async fn history(
+        &self,
+        channel_id: i64,
+        after_id: i64,
+        before_id: i64,
+        newest: bool,
+        limit: i32,
+    ) -> Result<Vec<storage::Message>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner
+            .history(channel_id, after_id, before_id, newest, limit)
+            .await
+    }
+
+    async fn mentioning(
+        &self,
+        user_id: i64,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<storage::Message>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.mentioning(user_id, before_id, limit).await
+    }
+
+    async fn attached_to(
+        &self,
+        attachment_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        self.inner.attached_to(attachment_id).await
+    }
+}
+
+#[tokio::test]
+async fn idempotency_keys_of_failed_requests_are_taken_over_by_retries() {
+    let mut storage = Storage::from_backend(Arc::new(MemoryStorage::default()));
+    let messages = Arc::new(_FlakyMessages {
+        inner: storage.messages.clone(),
+        failing: true.into(),
+    });
+    storage.messages = messages.clone();
+    let application = _application_from(
+        storage,
+        Pipeline::new(Vec::new()),
+        ServiceOptions {
+            idempotency_claim_timeout: Duration::from_millis(100),
+            ..options()
+        },
+    )
+    .await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let request = p_channels::PCreateMessageRequest {
+        content: "hello".to_string(),
+        author_id: owner_id,
+        channel_id,
+        attachment_ids: Vec::new(),
+        idempotency_key: "retry-me".to_string(),
+    };
+    let create = || application.create_message(tonic::Request::new(request.clone()));
+
+    // The key is claimed before the message fails to be stored
+    assert!(create().await.is_err());
+    messages
+        .failing
+        .store(false, std::sync::atomic::Ordering::SeqCst);
+
+    // The failed request could still be running until the claim timeout
+    let status = create().await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::Aborted);
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let first = create().await.unwrap().into_inner();
+    let second = create().await.unwrap().into_inner();
+
+    assert_eq!(first.id, second.id);
+    assert_eq!(
+        history(&application, channel_id, owner_id)
+            .await
+            .unwrap()
+            .len(),
+        1
+    );
+}
+
+#[tokio::test]
+async fn idempotency_keys_are_scoped_to_their_author() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let other_id = user(&application, "other").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let mut ids = Vec::new();
+    for author_id in [owner_id, other_id] {
+        ids.push(
+            application
+                .create_message(tonic::Request::new(p_channels::PCreateMessageRequest {
+                    content: "hello".to_string(),
+                    author_id,
+                    channel_id,
+                    attachment_ids: Vec::new(),
+                    idempotency_key: "same-key".to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner()
+                .id,
+        );
+    }
+
+    assert_ne!(ids[0], ids[1]);
+}
+
+#[tokio::test]
+async fn banned_users_cannot_post_or_read() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let banned_id = user(&application, "banned").await;
+    let channel_id = channel(&application, owner_id).await;
+    post(&application, channel_id, banned_id, "before")
+        .await
+        .unwrap();
+
+    application
+        .ban_from_channel(tonic::Request::new(p_moderation::PBanRequest {
+            channel_id,
+            user_id: owner_id,
+            target_id: banned_id,
+            reason: "spam".to_string(),
+            duration_seconds: 0,
+        }))
+        .await
+        .unwrap();
+
+    let status = post(&application, channel_id, banned_id, "after")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    let status = history(&application, channel_id, banned_id)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    let status = history(&application, channel_id, 0).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    application
+        .unban_from_channel(tonic::Request::new(p_moderation::PUnbanRequest {
+            channel_id,
+            user_id: owner_id,
+            target_id: banned_id,
+        }))
+        .await
+        .unwrap();
+
+    post(&application, channel_id, banned_id, "after")
+        .await
+        .unwrap();
+    assert_eq!(
+        _contents(&history(&application, channel_id, banned_id).await.unwrap()),
+        ["before", "after"]
+    );
+}
+
+#[tokio::test]
+async fn banned_users_cannot_subscribe_catch_up_or_list_pins() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let banned_id = user(&application, "banned").await;
+    let timed_out_id = user(&application, "timed-out").await;
+    let channel_id = channel(&application, owner_id).await;
+    for user_id in [banned_id, timed_out_id] {
+        post(&application, channel_id, user_id, "hello")
+            .await
+            .unwrap();
+    }
+
+    application
+        .ban_from_channel(tonic::Request::new(p_moderation::PBanRequest {
+            channel_id,
+            user_id: owner_id,
+            target_id: banned_id,
+            reason: "spam".to_string(),
+            duration_seconds: 0,
+        }))
+        .await
+        .unwrap();
+    application
+        .timeout_user(tonic::Request::new(p_moderation::PTimeoutRequest {
+            channel_id,
+            user_id: owner_id,
+            target_id: timed_out_id,
+            reason: String::new(),
+            duration_seconds: 60,
+        }))
+        .await
+        .unwrap();
+
+    let application = &application;
+    let read = |user_id| async move {
+        let subscribed = application
+            .subscribe(tonic::Request::new(p_channels::PSubscribeRequest {
+                channel_ids: vec![channel_id],
+                resume_from_id: 0,
+                user_id,
+                presence_user_ids: Vec::new(),
+            }))
+            .await
+            .map(|_| ());
+        let caught_up = application
+            .catchup(tonic::Request::new(p_channels::PCatchupRequest {
+                channel_id,
+                last_seen_id: 0,
+                limit: 0,
+                user_id,
+            }))
+            .await
+            .map(|_| ());
+        let listed = application
+            .list_pins(tonic::Request::new(p_channels::PListPinsRequest {
+                channel_id,
+                user_id,
+            }))
+            .await
+            .map(|_| ());
+        [subscribed, caught_up, listed].map(|result| result.err().map(|status| status.code()))
+    };
+
+    assert_eq!(read(0).await, [Some(tonic::Code::InvalidArgument); 3]);
+    assert_eq!(
+        read(banned_id).await,
+        [Some(tonic::Code::PermissionDenied); 3]
+    );
+    // Timed out users can still read the channel
+    assert_eq!(read(timed_out_id).await, [None; 3]);
+}
+
+async fn _set_filters(
+    application: &ApplicationService,
+    channel_id: i64,
+    owner_id: i64,
+    filters: &[(&str, bool, p_moderation::PModerationAction)],
+) {
+    application
+        .set_channel_filters(tonic::Request::new(
+            p_moderation::PSetChannelFiltersRequest {
+                channel_id,
+                user_id: owner_id,
+                filters: filters
+                    .iter()
+                    .map(|(pattern, regex, action)| p_moderation::PChannelFilter {
+                        pattern: pattern.to_string(),
+                        regex: *regex,
+                        action: (*action).into(),
+                    })
+                    .collect(),
+            },
+        ))
+        .await
+        .unwrap();
+}
+
+/// Reasons of the flag of message `id`, empty if it is not flagged.
+async fn _flag_reasons(application: &ApplicationService, id: i64) -> Vec<String> {
+    application
+        .storage
+        .moderation
+        .get_flag(id)
+        .await
+        .unwrap()
+        .map(|flag| flag.reasons)
+        .unwrap_or_default()
+}
+
+#[tokio::test]
+async fn filters_reject_mask_or_flag_messages() {
+    use p_moderation::PModerationAction::{Flag, Mask, Reject};
+
+    let application = moderated_application(options(), |storage| {
+        vec![Arc::new(ChannelFilters::new(storage.clone()))]
+    })
+    .await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    _set_filters(
+        &application,
+        channel_id,
+        owner_id,
+        &[
+            ("spoiler", false, Mask),
+            ("buy now", false, Reject),
+            (r"\d{4}-\d{4}", true, Flag),
+        ],
+    )
+    .await;
+
+    let message = post(
+        &application,
+        channel_id,
+        owner_id,
+        "A SPOILER, not spoilers",
+    )
+    .await
+    .unwrap();
+    assert_eq!(message.content, "A *******, not spoilers");
+    assert!(_flag_reasons(&application, message.id).await.is_empty());
+
+    let status = post(&application, channel_id, owner_id, "Buy  now? Buy now!")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    let message = post(&application, channel_id, owner_id, "Card 1234-5678")
+        .await
+        .unwrap();
+    assert_eq!(message.content, "Card 1234-5678");
+    assert_eq!(
+        _flag_reasons(&application, message.id).await,
+        [r#"Matches filter "\\d{4}-\\d{4}""#]
+    );
+}
+
+#[tokio::test]
+async fn filters_apply_in_order_and_changes_apply_immediately() {
+    use p_moderation::PModerationAction::{Flag, Mask, Reject};
+
+    let application = moderated_application(options(), |storage| {
+        vec![Arc::new(ChannelFilters::new(storage.clone()))]
+    })
+    .await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    post(&application, channel_id, owner_id, "darn")
+        .await
+        .unwrap();
+
+    // Masked words no longer match the filters after
+    _set_filters(
+        &application,
+        channel_id,
+        owner_id,
+        &[("darn", false, Mask), ("darn", false, Reject)],
+    )
+    .await;
+    let message = post(&application, channel_id, owner_id, "darn")
+        .await
+        .unwrap();
+    assert_eq!(message.content, "****");
+
+    // Rejecting wins over flagging
+    _set_filters(
+        &application,
+        channel_id,
+        owner_id,
+        &[("darn", false, Flag), ("darn", false, Reject)],
+    )
+    .await;
+    let status = post(&application, channel_id, owner_id, "darn")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    _set_filters(&application, channel_id, owner_id, &[]).await;
+    let message = post(&application, channel_id, owner_id, "darn")
+        .await
+        .unwrap();
+    assert_eq!(message.content, "darn");
+    assert!(_flag_reasons(&application, message.id).await.is_empty());
+}
+
+#[tokio::test]
+async fn timed_out_users_can_read_but_not_post() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let target_id = user(&application, "target").await;
+    let channel_id = channel(&application, owner_id).await;
+    post(&application, channel_id, owner_id, "hello")
+        .await
+        .unwrap();
+
+    application
+        .timeout_user(tonic::Request::new(p_moderation::PTimeoutRequest {
+            channel_id,
+            user_id: owner_id,
+            target_id,
+            reason: String::new(),
+            duration_seconds: 60,
+        }))
+        .await
+        .unwrap();
+
+    let status = post(&application, channel_id, target_id, "reply")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    assert_eq!(
+        _contents(&history(&application, channel_id, target_id).await.unwrap()),
+        ["hello"]
+    );
+}
+
+#[tokio::test]
+async fn only_owners_and_moderators_can_ban() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let member_id = user(&application, "member").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let status = application
+        .ban_from_channel(tonic::Request::new(p_moderation::PBanRequest {
+            channel_id,
+            user_id: member_id,
+            target_id: owner_id,
+            reason: String::new(),
+            duration_seconds: 0,
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+}
+
+type _Subscription = <ApplicationService as ChannelService>::SubscribeStream;
+
+async fn _subscribe(
+    application: &ApplicationService,
+    user_id: i64,
+    channel_ids: Vec<i64>,
+    resume_from_id: i64,
+) -> _Subscription {
+    application
+        .subscribe(tonic::Request::new(p_channels::PSubscribeRequest {
+            channel_ids,
+            resume_from_id,
+            user_id,
+            presence_user_ids: Vec::new(),
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+}
+
+/// The next message event of a subscription, skipping other events.
+async fn _next_message(subscription: &mut _Subscription) -> p_channels::PMessageEvent {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), subscription.next())
+            .await
+            .expect("No event received")
+            .expect("Subscription ended")
+            .unwrap();
+        if let Some(p_channels::p_subscribe_event::Event::MessageEvent(event)) = event.event {
+            return event;
+        }
+    }
+}
+
+#[tokio::test]
+async fn subscribe_delivers_messages_of_subscribed_channels_only() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let subscribed_id = channel(&application, owner_id).await;
+    let other_id = channel(&application, owner_id).await;
+
+    let mut subscription = _subscribe(&application, owner_id, vec![subscribed_id], 0).await;
+    post(&application, other_id, owner_id, "elsewhere")
+        .await
+        .unwrap();
+    let created = post(&application, subscribed_id, owner_id, "hello")
+        .await
+        .unwrap();
+
+    let event = _next_message(&mut subscription).await;
+    assert_eq!(
+        event.event_type(),
+        p_channels::PMessageEventType::MessageCreated
+    );
+    assert_eq!(event.message.unwrap().id, created.id);
+}
+
+#[tokio::test]
+async fn subscribe_replays_missed_messages_without_duplicates() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let seen = post(&application, channel_id, owner_id, "seen")
+        .await
+        .unwrap();
+    post(&application, channel_id, owner_id, "missed")
+        .await
+        .unwrap();
+
+    let mut subscription = _subscribe(&application, owner_id, vec![channel_id], seen.id).await;
+    post(&application, channel_id, owner_id, "live")
+        .await
+        .unwrap();
+
+    let mut contents = Vec::new();
+    for _ in 0..2 {
+        contents.push(
+            _next_message(&mut subscription)
+                .await
+                .message
+                .unwrap()
+                .content,
+        );
+    }
+    assert_eq!(contents, ["missed", "live"]);
+}
+
+#[tokio::test]
+async fn catchup_fills_the_gap_in_live_sequences() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    let seen = post(&application, channel_id, owner_id, "seen")
+        .await
+        .unwrap();
+
+    let mut subscription = _subscribe(&application, owner_id, vec![channel_id], 0).await;
+    for content in ["first", "second"] {
+        post(&application, channel_id, owner_id, content)
+            .await
+            .unwrap();
+    }
+    let first = _next_message(&mut subscription).await;
+    let second = _next_message(&mut subscription).await;
+    assert_eq!(second.sequence, first.sequence + 1);
+
+    // A client which only saw `seen` fetches what it missed, up to the latest live sequence
+    let result = application
+        .catchup(tonic::Request::new(p_channels::PCatchupRequest {
+            channel_id,
+            last_seen_id: seen.id,
+            limit: 0,
+            user_id: owner_id,
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(_contents(&result.messages), ["first", "second"]);
+    assert!(result.complete);
+    assert_eq!(result.sequence, second.sequence);
+}
+
+async fn _unread_counts(
+    application: &ApplicationService,
+    user_id: i64,
+    channel_ids: Vec<i64>,
+) -> Result<Vec<p_channels::PUnreadCount>, tonic::Status> {
+    application
+        .get_unread_counts(tonic::Request::new(p_channels::PUnreadCountsRequest {
+            user_id,
+            channel_ids,
+        }))
+        .await
+        .map(|response| response.into_inner().counts)
+}
+
+#[tokio::test]
+async fn unread_counts_start_when_the_user_joined() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let member_id = user(&application, "member").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    post(&application, channel_id, owner_id, "before")
+        .await
+        .unwrap();
+    post(&application, channel_id, member_id, "joined")
+        .await
+        .unwrap();
+    post(&application, channel_id, owner_id, "after")
+        .await
+        .unwrap();
+
+    let counts = _unread_counts(&application, member_id, Vec::new())
+        .await
+        .unwrap();
+    assert_eq!(counts.len(), 1);
+    assert_eq!(counts[0].channel_id, channel_id);
+    assert_eq!(counts[0].unread_count, 1);
+}
+
+#[tokio::test]
+async fn blocked_authors_are_hidden_from_history_and_unread_counts() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let reader_id = user(&application, "reader").await;
+    let blocked_id = user(&application, "blocked").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    post(&application, channel_id, reader_id, "joined")
+        .await
+        .unwrap();
+    post(&application, channel_id, blocked_id, "hello @reader")
+        .await
+        .unwrap();
+    post(&application, channel_id, owner_id, "visible")
+        .await
+        .unwrap();
+    post(&application, channel_id, reader_id, "own")
+        .await
+        .unwrap();
+
+    application
+        .block_user(tonic::Request::new(p_relationships::PBlockRequest {
+            user_id: reader_id,
+            blocked_id,
+        }))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        _contents(&history(&application, channel_id, reader_id).await.unwrap()),
+        ["joined", "visible", "own"]
+    );
+    assert_eq!(
+        _contents(&history(&application, channel_id, owner_id).await.unwrap()),
+        ["joined", "hello @reader", "visible", "own"]
+    );
+
+    // Neither the reader's own messages nor those of blocked users are unread
+    let counts = _unread_counts(&application, reader_id, vec![channel_id])
+        .await
+        .unwrap();
+    assert_eq!(counts[0].unread_count, 1);
+    assert_eq!(counts[0].mention_count, 0);
+}
+
+#[tokio::test]
+async fn replays_and_catchups_omit_blocked_users() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let reader_id = user(&application, "reader").await;
+    let blocked_id = user(&application, "blocked").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let seen = post(&application, channel_id, reader_id, "seen")
+        .await
+        .unwrap();
+    for content in ["first", "second"] {
+        post(&application, channel_id, blocked_id, content)
+            .await
+            .unwrap();
+    }
+    post(&application, channel_id, owner_id, "visible")
+        .await
+        .unwrap();
+
+    application
+        .block_user(tonic::Request::new(p_relationships::PBlockRequest {
+            user_id: reader_id,
+            blocked_id,
+        }))
+        .await
+        .unwrap();
+
+    let mut subscription = _subscribe(&application, reader_id, vec![channel_id], seen.id).await;
+    let replayed = _next_message(&mut subscription).await;
+    assert_eq!(replayed.message.unwrap().content, "visible");
+
+    // Windows made only of blocked messages are skipped rather than returned empty
+    let catchup = |limit| {
+        application.catchup(tonic::Request::new(p_channels::PCatchupRequest {
+            channel_id,
+            last_seen_id: seen.id,
+            limit,
+            user_id: reader_id,
+        }))
+    };
+    let result = catchup(1).await.unwrap().into_inner();
+    assert_eq!(_contents(&result.messages), ["visible"]);
+    assert!(result.complete);
+    let result = catchup(0).await.unwrap().into_inner();
+    assert_eq!(_contents(&result.messages), ["visible"]);
+}
+
+#[tokio::test]
+async fn unread_counts_reject_channels_the_user_cannot_read() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let outsider_id = user(&application, "outsider").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let status = _unread_counts(&application, outsider_id, vec![channel_id])
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+}
+
+/// Query the audit log as an authorized caller.
+async fn _audit_log(
+    application: &ApplicationService,
+    request: p_moderation::PQueryAuditLogRequest,
+) -> Vec<(i64, i64)> {
+    super::audit::query(application, &request)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.actor_id, entry.target_id))
+        .collect()
+}
+
+#[tokio::test]
+async fn creating_a_channel_is_audited() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let entries = super::audit::query(
+        &application,
+        &p_moderation::PQueryAuditLogRequest {
+            target_id: channel_id,
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(
+        entries[0].action(),
+        p_moderation::PAuditAction::ChannelCreated
+    );
+    assert_eq!(entries[0].actor_id, owner_id);
+    assert_eq!(entries[0].details, "general");
+
+    // Only moderators can query the audit log
+    let status = application
+        .query_audit_log(tonic::Request::new(p_moderation::PQueryAuditLogRequest {
+            user_id: owner_id,
+            ..Default::default()
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn audit_log_queries_filter_and_page_entries() {
+    let application = application().await;
+
+    // One entry every 12 hours over 3 days, alternating actors and actions, each entry targeting its index
+    let start = chrono::Utc::now() - chrono::Duration::days(3);
+    let actions = [
+        storage::AuditAction::MemberBanned,
+        storage::AuditAction::MessagePinned,
+        storage::AuditAction::MemberBanned,
+    ];
+    for index in 0..6 {
+        let time = start + chrono::Duration::hours(12 * index);
+        application
+            .storage
+            .audit_log
+            .append(&storage::AuditEntry {
+                id: application.snowflake(time),
+                actor_id: 1 + index % 2,
+                action: actions[index as usize % 3],
+                target_id: 100 + index,
+                channel_id: 0,
+                details: String::new(),
+                created_at: time.timestamp_millis(),
+            })
+            .await
+            .unwrap();
+    }
+
+    let all = _audit_log(&application, Default::default()).await;
+    assert_eq!(
+        all,
+        [(2, 105), (1, 104), (2, 103), (1, 102), (2, 101), (1, 100)]
+    );
+
+    let by_actor = _audit_log(
+        &application,
+        p_moderation::PQueryAuditLogRequest {
+            actor_id: 1,
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(by_actor, [(1, 104), (1, 102), (1, 100)]);
+
+    let by_action = _audit_log(
+        &application,
+        p_moderation::PQueryAuditLogRequest {
+            actions: vec![p_moderation::PAuditAction::MessagePinned.into()],
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(by_action, [(1, 104), (2, 101)]);
+
+    // The start time is inclusive and the end time exclusive
+    let by_time = _audit_log(
+        &application,
+        p_moderation::PQueryAuditLogRequest {
+            start_time: (start + chrono::Duration::hours(12)).timestamp_millis(),
+            end_time: (start + chrono::Duration::hours(48)).timestamp_millis(),
+            ..Default::default()
+        },
+    )
+    .await;
+    assert_eq!(by_time, [(2, 103), (1, 102), (2, 101)]);
+
+    // Pages continue before the ID of the last entry of the previous page, across days
+    let mut pages = Vec::new();
+    let mut before_id = 0;
+    loop {
+        let page = super::audit::query(
+            &application,
+            &p_moderation::PQueryAuditLogRequest {
+                actor_id: 2,
+                before_id,
+                limit: 2,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+        let Some(last) = page.last() else { break };
+        before_id = last.id;
+        pages.push(page.iter().map(|entry| entry.target_id).collect::<Vec<_>>());
+    }
+    assert_eq!(pages, [vec![105, 103], vec![101]]);
+}
+
+#[tokio::test]
+async fn expired_messages_are_swept_with_their_pins_embeds_and_attachments() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    application
+        .storage
+        .retention
+        .set(
+            channel_id,
+            &storage::RetentionPolicy {
+                retention_days: 1,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+    // A message posted two days ago, pinned, with a link preview and two attachments, one of which is shared
+    let mut attachment_ids = Vec::new();
+    for filename in ["notes.txt", "shared.txt"] {
+        let attachment_id = application.generate_id();
+        let mut writer = application
+            .blobs
+            .write(&attachment_id.to_string())
+            .await
+            .unwrap();
+        writer.write(b"content").await.unwrap();
+        writer.commit().await.unwrap();
+        application
+            .storage
+            .attachments
+            .create(&storage::Attachment {
+                id: attachment_id,
+                uploader_id: owner_id,
+                filename: filename.to_string(),
+                mime_type: "text/plain".to_string(),
+                size: 7,
+                sha256: String::new(),
+            })
+            .await
+            .unwrap();
+        attachment_ids.push(attachment_id);
+    }
+    let (attachment_id, shared_id) = (attachment_ids[0], attachment_ids[1]);
+    let posted_at = application.snowflake(chrono::Utc::now() - chrono::Duration::days(2));
+    let expired_id = application
+        .storage
+        .messages
+        .create(
+            "expired",
+            &[],
+            owner_id,
+            channel_id,
+            &storage::Mentions::default(),
+            &attachment_ids,
+            0,
+            &|| posted_at,
+        )
+        .await
+        .unwrap();
+    application
+        .storage
+        .pins
+        .pin(
+            &storage::Pin {
+                channel_id,
+                message_id: expired_id,
+                pinned_by: owner_id,
+                pinned_at: 0,
+            },
+            50,
+        )
+        .await
+        .unwrap();
+    application
+        .storage
+        .embeds
+        .set(
+            expired_id,
+            &[storage::Embed {
+                url: "https://example.com".to_string(),
+                title: "Example".to_string(),
+                description: String::new(),
+                image_url: String::new(),
+            }],
+        )
+        .await
+        .unwrap();
+    application
+        .storage
+        .messages
+        .create(
+            "recent",
+            &[],
+            owner_id,
+            channel_id,
+            &storage::Mentions::default(),
+            &[shared_id],
+            0,
+            &|| application.generate_id(),
+        )
+        .await
+        .unwrap();
+
+    // This replica holds the sweep lease since its first sweep on startup
+    let sweep = || async { assert!(application.sweeper.sweep_channel(channel_id).await.unwrap()) };
+
+    // Nothing expires while the channel is under legal hold
+    let policy = storage::RetentionPolicy {
+        retention_days: 1,
+        legal_hold: true,
+        ..Default::default()
+    };
+    application
+        .storage
+        .retention
+        .set(channel_id, &policy)
+        .await
+        .unwrap();
+    sweep().await;
+    let messages = history(&application, channel_id, owner_id).await.unwrap();
+    assert_eq!(_contents(&messages), ["expired", "recent"]);
+
+    let policy = storage::RetentionPolicy {
+        legal_hold: false,
+        ..policy
+    };
+    application
+        .storage
+        .retention
+        .set(channel_id, &policy)
+        .await
+        .unwrap();
+    sweep().await;
+    let messages = history(&application, channel_id, owner_id).await.unwrap();
+    assert_eq!(_contents(&messages), ["recent"]);
+
+    assert!(application
+        .storage
+        .pins
+        .list(channel_id)
+        .await
+        .unwrap()
+        .is_empty());
+    let embeds = application
+        .storage
+        .embeds
+        .list_many(&[expired_id])
+        .await
+        .unwrap();
+    assert!(embeds.get(&expired_id).is_none_or(Vec::is_empty));
+    assert!(application
+        .storage
+        .attachments
+        .get(attachment_id)
+        .await
+        .unwrap()
+        .is_none());
+    assert!(application
+        .blobs
+        .read(&attachment_id.to_string())
+        .await
+        .unwrap()
+        .is_none());
+
+    // The attachment still attached to the recent message is kept
+    assert_eq!(
+        _download(&application, shared_id).await.unwrap().1,
+        b"content"
+    );
+}
+
+#[tokio::test]
+async fn search_index_ages_out_messages_past_their_cutoff() {
+    let application = application().await;
+    let index = &application.search.messages;
+    index.insert(1, 10, 100, "old news");
+    index.insert(2, 10, 100, "fresh news");
+    index.insert(1 << 20, 10, 200, "other news");
+
+    index.expire(&std::collections::HashMap::from([(100, 2), (200, 1)]));
+
+    let filter = super::search::SearchFilter {
+        author_id: None,
+        channel_id: None,
+        before_id: i64::MAX,
+        after_id: 0,
+        hidden_author_ids: Default::default(),
+        hidden_channel_ids: Default::default(),
+    };
+    let mut ids = index.search("news", &filter, false, 10);
+    ids.sort();
+    assert_eq!(ids, [2, 1 << 20]);
+}
+
+/// Search `keywords` among the messages readable by `user_id`, from the newest one.
+async fn _search(
+    application: &ApplicationService,
+    user_id: i64,
+    query: p_channels::PMessageSearchQuery,
+) -> Vec<i64> {
+    application
+        .search_messages(tonic::Request::new(p_channels::PMessageSearchQuery {
+            user_id,
+            ..query
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .messages
+        .iter()
+        .map(|message| message.id)
+        .collect()
+}
+
+/// Search `keywords` like [`_search`], waiting a little for the index to catch up with recent events.
+async fn _search_eventually(
+    application: &ApplicationService,
+    user_id: i64,
+    keywords: &str,
+) -> Vec<i64> {
+    let query = p_channels::PMessageSearchQuery {
+        keywords: keywords.to_string(),
+        ..Default::default()
+    };
+    for _ in 0..100 {
+        let ids = _search(application, user_id, query.clone()).await;
+        if !ids.is_empty() {
+            return ids;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    Vec::new()
+}
+
+#[tokio::test]
+async fn search_index_bootstraps_again_after_missing_events() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    let first = post(&application, channel_id, owner_id, "first")
+        .await
+        .unwrap();
+    assert_eq!(
+        _search_eventually(&application, owner_id, "first").await,
+        [first.id]
+    );
+
+    // Stored without publishing its creation, then buried under a burst of events the index lags behind
+    let id = application
+        .storage
+        .messages
+        .create(
+            "unannounced",
+            &[],
+            owner_id,
+            channel_id,
+            &storage::Mentions::default(),
+            &[],
+            0,
+            &|| application.generate_id(),
+        )
+        .await
+        .unwrap();
+    for _ in 0..5000 {
+        application
+            .events
+            .publish(Event::Typing(p_presence::PTypingEvent {
+                user_id: owner_id,
+                channel_id,
+                typing: true,
+                expires_at: 0,
+            }))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        _search_eventually(&application, owner_id, "unannounced").await,
+        [id]
+    );
+
+    // The index keeps following events afterwards
+    let last = post(&application, channel_id, owner_id, "last")
+        .await
+        .unwrap();
+    assert_eq!(
+        _search_eventually(&application, owner_id, "last").await,
+        [last.id]
+    );
+}
+
+#[tokio::test]
+async fn search_ranks_and_filters_messages() {
+    let application = application().await;
+    let alice_id = user(&application, "alice").await;
+    let bob_id = user(&application, "bob").await;
+    let general_id = channel(&application, alice_id).await;
+    let random_id = channel(&application, bob_id).await;
+
+    let mut ids = Vec::new();
+    for (channel_id, author_id, content) in [
+        (general_id, alice_id, "rust rust rust compiler"),
+        (
+            general_id,
+            bob_id,
+            "rust is one of the many languages discussed at length in this channel",
+        ),
+        (random_id, alice_id, "rust"),
+        (random_id, bob_id, "python"),
+    ] {
+        ids.push(
+            post(&application, channel_id, author_id, content)
+                .await
+                .unwrap()
+                .id,
+        );
+    }
+    assert_eq!(
+        _search_eventually(&application, alice_id, "python").await,
+        [ids[3]]
+    );
+
+    let rust = |query: p_channels::PMessageSearchQuery| p_channels::PMessageSearchQuery {
+        keywords: "rust".to_string(),
+        ..query
+    };
+
+    // Frequent terms in short messages rank first
+    let relevance = _search(&application, alice_id, rust(Default::default())).await;
+    assert_eq!(relevance, [ids[0], ids[2], ids[1]]);
+
+    let recency = rust(p_channels::PMessageSearchQuery {
+        order: p_channels::PMessageSearchOrder::Recency.into(),
+        ..Default::default()
+    });
+    assert_eq!(
+        _search(&application, alice_id, recency.clone()).await,
+        [ids[2], ids[1], ids[0]]
+    );
+
+    // Every keyword must appear
+    let both = p_channels::PMessageSearchQuery {
+        keywords: "Rust COMPILER".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(_search(&application, alice_id, both).await, [ids[0]]);
+
+    let by_author = p_channels::PMessageSearchQuery {
+        author_id: alice_id,
+        ..recency.clone()
+    };
+    assert_eq!(
+        _search(&application, alice_id, by_author).await,
+        [ids[2], ids[0]]
+    );
+
+    let by_channel = p_channels::PMessageSearchQuery {
+        channel_id: random_id,
+        ..recency.clone()
+    };
+    assert_eq!(_search(&application, alice_id, by_channel).await, [ids[2]]);
+
+    // Both bounds are inclusive
+    let by_date = p_channels::PMessageSearchQuery {
+        after_id: ids[1],
+        before_id: ids[2],
+        ..recency.clone()
+    };
+    assert_eq!(
+        _search(&application, alice_id, by_date).await,
+        [ids[2], ids[1]]
+    );
+
+    let limited = p_channels::PMessageSearchQuery {
+        limit: 1,
+        ..recency
+    };
+    assert_eq!(_search(&application, alice_id, limited).await, [ids[2]]);
+}
+
+async fn _named_channel(
+    application: &ApplicationService,
+    owner_id: i64,
+    name: &str,
+    description: &str,
+) -> i64 {
+    application
+        .create_channel(tonic::Request::new(p_channels::PCreateChannelRequest {
+            name: name.to_string(),
+            description: description.to_string(),
+            owner_id,
+            idempotency_key: String::new(),
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .id
+}
+
+async fn _search_channels(
+    application: &ApplicationService,
+    query: p_channels::PChannelSearchQuery,
+) -> Vec<i64> {
+    application
+        .search_channels(tonic::Request::new(query))
+        .await
+        .unwrap()
+        .into_inner()
+        .channels
+        .iter()
+        .map(|channel| channel.id)
+        .collect()
+}
+
+#[tokio::test]
+async fn search_ranks_channels_by_match_or_activity() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let described_id = _named_channel(&application, owner_id, "misc", "All about Rust").await;
+    let prefixed_id = _named_channel(&application, owner_id, "Rustaceans", "").await;
+    let contained_id = _named_channel(&application, owner_id, "trusty", "").await;
+    let described_first_id =
+        _named_channel(&application, owner_id, "offtopic", "Rust and more").await;
+    let newest_prefixed_id = _named_channel(&application, owner_id, "rust", "").await;
+    _named_channel(&application, owner_id, "random", "Anything else").await;
+
+    let matching = p_channels::PChannelSearchQuery {
+        query: "RUST".to_string(),
+        ..Default::default()
+    };
+    for _ in 0..100 {
+        if _search_channels(&application, matching.clone()).await.len() == 5 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // Name prefixes, name substrings, description prefixes then description substrings, newest first
+    assert_eq!(
+        _search_channels(&application, matching.clone()).await,
+        [
+            newest_prefixed_id,
+            prefixed_id,
+            contained_id,
+            described_first_id,
+            described_id
+        ]
+    );
+
+    let limited = p_channels::PChannelSearchQuery {
+        limit: 2,
+        ..matching.clone()
+    };
+    assert_eq!(
+        _search_channels(&application, limited).await,
+        [newest_prefixed_id, prefixed_id]
+    );
+
+    // The most active channels first, then the newest
+    for channel_id in [described_id, described_id, contained_id] {
+        post(&application, channel_id, owner_id, "hello")
+            .await
+            .unwrap();
+    }
+    let hello = p_channels::PMessageSearchQuery {
+        keywords: "hello".to_string(),
+        ..Default::default()
+    };
+    for _ in 0..100 {
+        if _search(&application, owner_id, hello.clone()).await.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let active = p_channels::PChannelSearchQuery {
+        order: p_channels::PChannelSearchOrder::Activity.into(),
+        ..matching
+    };
+    assert_eq!(
+        _search_channels(&application, active).await,
+        [
+            described_id,
+            contained_id,
+            newest_prefixed_id,
+            described_first_id,
+            prefixed_id
+        ]
+    );
+
+    // An empty query matches every channel
+    assert_eq!(
+        _search_channels(&application, Default::default())
+            .await
+            .len(),
+        6
+    );
+}
+
+#[tokio::test]
+async fn mentions_resolve_known_usernames_into_the_inbox() {
+    let application = application().await;
+    let alice_id = user(&application, "alice").await;
+    let bob_id = user(&application, "bob").await;
+    let channel_id = channel(&application, alice_id).await;
+
+    let first = post(&application, channel_id, alice_id, "@bob @nobody `@alice`")
+        .await
+        .unwrap();
+    assert_eq!(first.mentioned_user_ids, [bob_id]);
+    assert!(!first.mentions_channel);
+
+    let second = post(&application, channel_id, alice_id, "@here and @bob")
+        .await
+        .unwrap();
+    assert_eq!(second.mentioned_user_ids, [bob_id]);
+    assert!(second.mentions_channel);
+
+    let application = &application;
+    let mentions = |user_id| async move {
+        application
+            .list_mentions(tonic::Request::new(p_channels::PListMentionsRequest {
+                user_id,
+                before_id: 0,
+                limit: 0,
+            }))
+            .await
+            .unwrap()
+            .into_inner()
+            .messages
+            .iter()
+            .map(|message| message.id)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(mentions(bob_id).await, [second.id, first.id]);
+    assert!(mentions(alice_id).await.is_empty());
+}
+
+async fn _set_typing(
+    application: &ApplicationService,
+    channel_id: i64,
+    user_id: i64,
+) -> Result<p_presence::PTypingEvent, tonic::Status> {
+    application
+        .set_typing(tonic::Request::new(p_presence::PSetTypingRequest {
+            user_id,
+            channel_id,
+            typing: true,
+        }))
+        .await
+        .map(tonic::Response::into_inner)
+}
+
+#[tokio::test]
+async fn typing_is_announced_in_the_channel_only_for_users_who_can_post() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let typist_id = user(&application, "typist").await;
+    let channel_id = channel(&application, owner_id).await;
+    let mut events = application
+        .events
+        .subscribe(
+            "test",
+            Topics {
+                channel_ids: Some(vec![channel_id]),
+                user_ids: Some(Vec::new()),
+                presence_user_ids: Some(Vec::new()),
+                skip_typing: false,
+            },
+        )
+        .await
+        .unwrap();
+
+    let event = _set_typing(&application, channel_id, typist_id)
+        .await
+        .unwrap();
+    assert!(event.typing);
+    assert!(event.expires_at > chrono::Utc::now().timestamp_millis());
+    let Some(Ok(Event::Typing(received))) = events.next().await else {
+        panic!("Expected a typing event");
+    };
+    assert_eq!(received, event);
+
+    let status = _set_typing(&application, channel_id + 1, typist_id)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+    let status = _set_typing(&application, channel_id, typist_id + 1)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    // Timed out users can read, but they cannot post and so are not typing either
+    application
+        .timeout_user(tonic::Request::new(p_moderation::PTimeoutRequest {
+            channel_id,
+            user_id: owner_id,
+            target_id: typist_id,
+            reason: String::new(),
+            duration_seconds: 60,
+        }))
+        .await
+        .unwrap();
+    let status = _set_typing(&application, channel_id, typist_id)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+}
+
+#[tokio::test]
+async fn presences_are_read_back_until_they_expire() {
+    let application = application().await;
+    let now = chrono::Utc::now().timestamp_millis();
+
+    let set = |user_id, status: p_presence::PPresenceStatus, ttl_seconds| {
+        application.set_presence(tonic::Request::new(p_presence::PSetPresenceRequest {
+            user_id,
+            status: status.into(),
+            ttl_seconds,
+        }))
+    };
+    let online = set(1, p_presence::PPresenceStatus::Online, 0)
+        .await
+        .unwrap()
+        .into_inner();
+    let idle = set(2, p_presence::PPresenceStatus::Idle, 3600)
+        .await
+        .unwrap()
+        .into_inner();
+    set(3, p_presence::PPresenceStatus::Dnd, 60).await.unwrap();
+    set(3, p_presence::PPresenceStatus::Offline, 60)
+        .await
+        .unwrap();
+
+    // Time-to-live defaults to 60 seconds and is capped at 300 seconds
+    assert!((now + 60_000..now + 61_000).contains(&online.expires_at));
+    assert!((now + 300_000..now + 301_000).contains(&idle.expires_at));
+
+    let presences = application
+        .get_presence(tonic::Request::new(p_presence::PGetPresenceRequest {
+            user_ids: vec![3, 2, 4, 1],
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .presences;
+    assert_eq!(
+        presences
+            .iter()
+            .map(|presence| (presence.user_id, presence.status()))
+            .collect::<Vec<_>>(),
+        [
+            (3, p_presence::PPresenceStatus::Offline),
+            (2, p_presence::PPresenceStatus::Idle),
+            (4, p_presence::PPresenceStatus::Offline),
+            (1, p_presence::PPresenceStatus::Online),
+        ]
+    );
+
+    // Unknown statuses are rejected
+    let status = application
+        .set_presence(tonic::Request::new(p_presence::PSetPresenceRequest {
+            user_id: 1,
+            status: 42,
+            ttl_seconds: 0,
+        }))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+async fn _pin(
+    application: &ApplicationService,
+    channel_id: i64,
+    message_id: i64,
+    user_id: i64,
+) -> Result<p_channels::PPin, tonic::Status> {
+    application
+        .pin_message(tonic::Request::new(p_channels::PPinRequest {
+            channel_id,
+            message_id,
+            user_id,
+        }))
+        .await
+        .map(tonic::Response::into_inner)
+}
+
+async fn _pinned_ids(application: &ApplicationService, channel_id: i64, user_id: i64) -> Vec<i64> {
+    application
+        .list_pins(tonic::Request::new(p_channels::PListPinsRequest {
+            channel_id,
+            user_id,
+        }))
+        .await
+        .unwrap()
+        .into_inner()
+        .pins
+        .into_iter()
+        .map(|pin| pin.message.unwrap().id)
+        .collect()
+}
+
+#[tokio::test]
+async fn pins_are_listed_from_the_newest_message_up_to_the_limit() {
+    let application = application_with(ServiceOptions {
+        max_pins_per_channel: 2,
+        ..options()
+    })
+    .await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    let mut ids = Vec::new();
+    for content in ["first", "second", "third"] {
+        ids.push(
+            post(&application, channel_id, owner_id, content)
+                .await
+                .unwrap()
+                .id,
+        );
+    }
+
+    let pin = _pin(&application, channel_id, ids[0], owner_id)
+        .await
+        .unwrap();
+    assert_eq!(pin.message.unwrap().content, "first");
+    assert_eq!(pin.pinned_by.unwrap().id, owner_id);
+    _pin(&application, channel_id, ids[1], owner_id)
+        .await
+        .unwrap();
+    assert_eq!(
+        _pinned_ids(&application, channel_id, owner_id).await,
+        [ids[1], ids[0]]
+    );
+
+    let status = _pin(&application, channel_id, ids[2], owner_id)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+
+    // Pinning again a pinned message does not count towards the limit
+    _pin(&application, channel_id, ids[0], owner_id)
+        .await
+        .unwrap();
+
+    let unpin = |message_id| {
+        application.unpin_message(tonic::Request::new(p_channels::PPinRequest {
+            channel_id,
+            message_id,
+            user_id: owner_id,
+        }))
+    };
+    unpin(ids[0]).await.unwrap();
+    let status = unpin(ids[0]).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    _pin(&application, channel_id, ids[2], owner_id)
+        .await
+        .unwrap();
+    assert_eq!(
+        _pinned_ids(&application, channel_id, owner_id).await,
+        [ids[2], ids[1]]
+    );
+}
+
+#[tokio::test]
+async fn concurrent_pins_do_not_exceed_the_limit() {
+    let application = application_with(ServiceOptions {
+        max_pins_per_channel: 3,
+        ..options()
+    })
+    .await;
+    let owner_id = user(&application, "owner").await;
+    let channel_id = channel(&application, owner_id).await;
+    let mut ids = Vec::new();
+    for i in 0..10 {
+        ids.push(
+            post(&application, channel_id, owner_id, &i.to_string())
+                .await
+                .unwrap()
+                .id,
+        );
+    }
+
+    let pins = futures::future::join_all(
+        ids.iter()
+            .map(|id| _pin(&application, channel_id, *id, owner_id)),
+    )
+    .await;
+    assert_eq!(pins.iter().filter(|pin| pin.is_ok()).count(), 3);
+    assert_eq!(
+        _pinned_ids(&application, channel_id, owner_id).await.len(),
+        3
+    );
+}
+
+#[tokio::test]
+async fn only_owners_pin_messages_of_their_channel() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let member_id = user(&application, "member").await;
+    let channel_id = channel(&application, owner_id).await;
+    let other_id = channel(&application, member_id).await;
+    let message = post(&application, channel_id, member_id, "hello")
+        .await
+        .unwrap();
+
+    let status = _pin(&application, channel_id, message.id, member_id)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+
+    // Messages are only pinned in their own channel
+    let status = _pin(&application, other_id, message.id, member_id)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+
+    let mut subscription = _subscribe(&application, owner_id, vec![channel_id], 0).await;
+    _pin(&application, channel_id, message.id, owner_id)
+        .await
+        .unwrap();
+    assert_eq!(
+        _pinned_ids(&application, channel_id, owner_id).await,
+        [message.id]
+    );
+    assert!(_pinned_ids(&application, other_id, owner_id)
+        .await
+        .is_empty());
+
+    let event = loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), subscription.next())
+            .await
+            .expect("No event received")
+            .expect("Subscription ended")
+            .unwrap();
+        if let Some(p_channels::p_subscribe_event::Event::ChannelEvent(event)) = event.event {
+            break event;
+        }
+    };
+    assert_eq!(
+        event.event_type(),
+        p_channels::PChannelEventType::MessagePinned
+    );
+    assert_eq!(event.pin.unwrap().message.unwrap().id, message.id);
+}
+
+/// Upload an attachment as if its chunks were streamed by a client.
+async fn _upload(
+    application: &ApplicationService,
+    chunks: Vec<p_attachments::p_upload_attachment_chunk::Chunk>,
+) -> Result<p_attachments::PAttachment, tonic::Status> {
+    let mut codec = tonic::codec::ProstCodec::<
+        p_attachments::PUploadAttachmentChunk,
+        p_attachments::PUploadAttachmentChunk,
+    >::default();
+    let body = tonic::codec::EncodeBody::new_client(
+        codec.encoder(),
+        futures::stream::iter(
+            chunks
+                .into_iter()
+                .map(|chunk| Ok(p_attachments::PUploadAttachmentChunk { chunk: Some(chunk) })),
+        ),
+        None,
+        None,
+    );
+    application
+        .upload_attachment(tonic::Request::new(tonic::Streaming::new_request(
+            codec.decoder(),
+            body,
+            None,
+            None,
+        )))
+        .await
+        .map(tonic::Response::into_inner)
+}
+
+fn _metadata(uploader_id: i64, filename: &str) -> p_attachments::p_upload_attachment_chunk::Chunk {
+    p_attachments::p_upload_attachment_chunk::Chunk::Metadata(
+        p_attachments::PUploadAttachmentMetadata {
+            uploader_id,
+            filename: filename.to_string(),
+            mime_type: String::new(),
+        },
+    )
+}
+
+fn _data(data: &[u8]) -> p_attachments::p_upload_attachment_chunk::Chunk {
+    p_attachments::p_upload_attachment_chunk::Chunk::Data(data.to_vec())
+}
+
+/// Download an attachment, returning its metadata and content.
+async fn _download(
+    application: &ApplicationService,
+    attachment_id: i64,
+) -> Result<(p_attachments::PAttachment, Vec<u8>), tonic::Status> {
+    let mut chunks = application
+        .download_attachment(tonic::Request::new(
+            p_attachments::PDownloadAttachmentRequest { attachment_id },
+        ))
+        .await?
+        .into_inner();
+
+    let Some(p_attachments::p_download_attachment_chunk::Chunk::Attachment(attachment)) =
+        chunks.next().await.unwrap()?.chunk
+    else {
+        panic!("Expected the attachment first");
+    };
+    let mut content = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let Some(p_attachments::p_download_attachment_chunk::Chunk::Data(data)) = chunk?.chunk
+        else {
+            panic!("Expected only data after the attachment");
+        };
+        content.extend(data);
+    }
+
+    Ok((attachment, content))
+}
+
+#[tokio::test]
+async fn uploaded_attachments_are_downloaded_back() {
+    let application = application().await;
+    let uploader_id = user(&application, "uploader").await;
+
+    let attachment = _upload(
+        &application,
+        vec![
+            _metadata(uploader_id, " notes.txt "),
+            _data(b"hello "),
+            _data(b"world"),
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(attachment.uploader_id, uploader_id);
+    assert_eq!(attachment.filename, "notes.txt");
+    assert_eq!(attachment.mime_type, "application/octet-stream");
+    assert_eq!(attachment.size, 11);
+    assert_eq!(
+        attachment.sha256,
+        "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9"
+    );
+
+    let (downloaded, content) = _download(&application, attachment.id).await.unwrap();
+    assert_eq!(downloaded, attachment);
+    assert_eq!(content, b"hello world");
+
+    let status = _download(&application, attachment.id + 1)
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+}
+
+#[tokio::test]
+async fn invalid_uploads_are_rejected() {
+    let application = application_with(ServiceOptions {
+        max_attachment_bytes: 8,
+        ..options()
+    })
+    .await;
+    let uploader_id = user(&application, "uploader").await;
+
+    for (chunks, code) in [
+        (vec![_data(b"data")], tonic::Code::InvalidArgument),
+        (vec![], tonic::Code::InvalidArgument),
+        (
+            vec![_metadata(uploader_id, "a"), _metadata(uploader_id, "b")],
+            tonic::Code::InvalidArgument,
+        ),
+        (
+            vec![_metadata(uploader_id, "  ")],
+            tonic::Code::InvalidArgument,
+        ),
+        (
+            vec![_metadata(uploader_id, &"a".repeat(256))],
+            tonic::Code::InvalidArgument,
+        ),
+        (vec![_metadata(uploader_id + 1, "a")], tonic::Code::NotFound),
+        (
+            vec![_metadata(uploader_id, "a"), _data(b"12345"), _data(b"6789")],
+            tonic::Code::InvalidArgument,
+        ),
+    ] {
+        let status = _upload(&application, chunks).await.unwrap_err();
+        assert_eq!(status.code(), code, "{}", status.message());
+    }
+
+    // Uploads up to the limit are accepted, even when empty
+    for data in [&b""[..], b"12345678"] {
+        let attachment = _upload(&application, vec![_metadata(uploader_id, "a"), _data(data)])
+            .await
+            .unwrap();
+        assert_eq!(attachment.size, data.len() as i64);
+        assert_eq!(
+            _download(&application, attachment.id).await.unwrap().1,
+            data
+        );
+    }
+}
+
+#[tokio::test]
+async fn rate_limits_apply_per_author_and_per_channel() {
+    let application = application_with(ServiceOptions {
+        user_messages_per_minute: 1,
+        user_message_burst: 2,
+        channel_messages_per_minute: 1,
+        channel_message_burst: 2,
+        ..options()
+    })
+    .await;
+    let owner_id = user(&application, "owner").await;
+    let member_id = user(&application, "member").await;
+    let channel_id = channel(&application, owner_id).await;
+    let other_id = _named_channel(&application, owner_id, "other", "").await;
+
+    // Invalid messages do not take a token
+    let status = post(&application, channel_id, owner_id, "\u{7}")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    post(&application, channel_id, owner_id, "one")
+        .await
+        .unwrap();
+    post(&application, other_id, owner_id, "two").await.unwrap();
+    let status = post(&application, channel_id, owner_id, "three")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(
+        status.message().contains("too fast"),
+        "{}",
+        status.message()
+    );
+
+    // The channel holds a single token more, whoever the author
+    post(&application, channel_id, member_id, "four")
+        .await
+        .unwrap();
+    let status = post(&application, channel_id, member_id, "five")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(
+        status.message().contains("in this channel"),
+        "{}",
+        status.message()
+    );
+    assert_eq!(
+        _contents(&history(&application, channel_id, owner_id).await.unwrap()),
+        ["one", "four"]
+    );
+}
+
+#[tokio::test]
+async fn rate_limited_retries_are_not_counted_as_repeats() {
+    let application = moderated_application(
+        ServiceOptions {
+            user_messages_per_minute: 600,
+            user_message_burst: 1,
+            ..options()
+        },
+        |_| {
+            vec![Arc::new(SpamHeuristics::new(
+                5,
+                1,
+                60_000,
+                storage::ModerationAction::Reject,
+            ))]
+        },
+    )
+    .await;
+    let author_id = user(&application, "author").await;
+    let channel_id = channel(&application, author_id).await;
+
+    post(&application, channel_id, author_id, "first")
+        .await
+        .unwrap();
+    for _ in 0..3 {
+        let status = post(&application, channel_id, author_id, "second")
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    }
+
+    // A token is back after 100 milliseconds, and the retried content was never posted
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    post(&application, channel_id, author_id, "second")
+        .await
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    let status = post(&application, channel_id, author_id, "second")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn slow_mode_applies_to_members_but_not_the_owner() {
+    let application = application().await;
+    let owner_id = user(&application, "owner").await;
+    let member_id = user(&application, "member").await;
+    let channel_id = channel(&application, owner_id).await;
+
+    let set_slow_mode = |user_id, seconds| {
+        application.set_slow_mode(tonic::Request::new(p_channels::PSetSlowModeRequest {
+            channel_id,
+            user_id,
+            seconds,
+        }))
+    };
+    let status = set_slow_mode(member_id, 60).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::PermissionDenied);
+    let status = set_slow_mode(owner_id, 6 * 60 * 60 + 1).await.unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+    let channel = set_slow_mode(owner_id, 60).await.unwrap().into_inner();
+    assert_eq!(channel.slow_mode_seconds, 60);
+    assert_eq!(channel.owner.unwrap().id, owner_id);
+
+    post(&application, channel_id, member_id, "one")
+        .await
+        .unwrap();
+    let status = post(&application, channel_id, member_id, "two")
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::ResourceExhausted);
+    assert!(
+        status.message().contains("slow mode"),
+        "{}",
+        status.message()
+    );
+
+    for content in ["three", "four"] {
+        post(&application, channel_id, owner_id, content)
+            .await
+            .unwrap();
+    }
+
+    set_slow_mode(owner_id, 0).await.unwrap();
+    post(&application, channel_id, member_id, "five")
+        .await
+        .unwrap();
+    assert_eq!(
+        _contents(&history(&application, channel_id, owner_id).await.unwrap()),
+        ["one", "three", "four", "five"]
+    );
+}
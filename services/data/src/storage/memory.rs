@@ -0,0 +1,1175 @@
+use std::collections;
+use std::hash::Hash;
+use std::ops::Bound;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::{
+    Account, AccountRepository, Attachment, AttachmentRepository, AuditEntry, AuditLogRepository,
+    Ban, BanRepository, Channel, ChannelRepository, ChannelSource, ConfigRepository, Embed,
+    EmbedRepository, Flag, IdGenerator, IdempotencyRepository, Mentions, Message,
+    MessageRepository, ModerationFilter, ModerationRepository, Pin, PinRepository,
+    RateLimitRepository, ReadState, ReadStateRepository, RelationshipRepository, RetentionPolicy,
+    RetentionRepository,
+};
+
+#[derive(Default)]
+struct _State {
+    accounts: collections::HashMap<i64, Account>,
+    account_by_username: collections::HashMap<String, i64>,
+    channels: collections::BTreeMap<i64, Channel>,
+    channel_by_owner: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    channel_by_member: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    joined_ids: collections::HashMap<(i64, i64), i64>,
+    channel_sequence: collections::HashMap<i64, i64>,
+    messages: collections::BTreeMap<i64, Message>,
+    message_by_channel: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    mentions_by_user: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    messages_by_attachment: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    config: collections::HashMap<String, String>,
+    read_states: collections::HashMap<i64, collections::HashMap<i64, i64>>,
+    pins: collections::HashMap<i64, collections::BTreeMap<i64, Pin>>,
+    attachments: collections::HashMap<i64, Attachment>,
+    embeds: collections::HashMap<i64, Vec<Embed>>,
+    moderation_filters: collections::HashMap<i64, Vec<ModerationFilter>>,
+    flags: collections::BTreeMap<i64, Flag>,
+    rate_limits: _ExpiringMap<String, i64>,
+    idempotency_keys: _ExpiringMap<String, i64>,
+    blocked_by_user: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    muted_by_user: collections::HashMap<i64, collections::BTreeSet<i64>>,
+    bans: _ExpiringMap<(i64, i64), Ban>,
+    audit_log: collections::BTreeMap<i64, AuditEntry>,
+    retention_policies: collections::HashMap<i64, RetentionPolicy>,
+    applied_ttls: collections::HashMap<i64, i32>,
+    retention_lease: _ExpiringMap<(), String>,
+}
+
+/// Rows deleted after a time to live, like rows written `USING TTL` in Scylla.
+///
+/// Expired rows are hidden as soon as they expire, and purged on the next write.
+struct _ExpiringMap<K, V> {
+    rows: collections::HashMap<K, (V, Option<Instant>)>,
+    expirations: collections::BTreeSet<(Instant, K)>,
+}
+
+impl<K, V> Default for _ExpiringMap<K, V> {
+    fn default() -> Self {
+        Self {
+            rows: collections::HashMap::new(),
+            expirations: collections::BTreeSet::new(),
+        }
+    }
+}
+
+impl<K: Clone + Eq + Hash + Ord, V: Clone> _ExpiringMap<K, V> {
+    fn get(&self, key: &K) -> Option<V> {
+        let now = Instant::now();
+        self.rows
+            .get(key)
+            .filter(|(_, expires_at)| expires_at.is_none_or(|expires_at| expires_at > now))
+            .map(|(value, _)| value.clone())
+    }
+
+    /// Store a row expiring after `ttl_seconds`, or never if `ttl_seconds` is 0.
+    fn insert(&mut self, key: K, value: V, ttl_seconds: i32) {
+        let now = Instant::now();
+        self.purge(now);
+        self.remove(&key);
+
+        let expires_at = (ttl_seconds > 0).then(|| now + Duration::from_secs(ttl_seconds as u64));
+        if let Some(expires_at) = expires_at {
+            self.expirations.insert((expires_at, key.clone()));
+        }
+        self.rows.insert(key, (value, expires_at));
+    }
+
+    fn remove(&mut self, key: &K) {
+        if let Some((_, Some(expires_at))) = self.rows.remove(key) {
+            self.expirations.remove(&(expires_at, key.clone()));
+        }
+    }
+
+    fn purge(&mut self, now: Instant) {
+        while let Some((expires_at, key)) = self.expirations.first().cloned() {
+            if expires_at > now {
+                break;
+            }
+
+            self.expirations.pop_first();
+            self.rows.remove(&key);
+        }
+    }
+}
+
+/// Storage kept in process memory, for development and testing without a database cluster.
+///
+/// Every piece of data is lost when the process exits, and is not shared between replicas.
+#[derive(Default)]
+pub struct MemoryStorage {
+    state: RwLock<_State>,
+}
+
+/// Collect at most `limit` IDs of an ordered iterator, starting from its end if `newest` is set.
+fn _range<'a>(ids: impl DoubleEndedIterator<Item = &'a i64>, newest: bool, limit: i32) -> Vec<i64> {
+    let limit = limit.max(0) as usize;
+    if newest {
+        ids.rev().take(limit).copied().collect()
+    } else {
+        ids.take(limit).copied().collect()
+    }
+}
+
+#[tonic::async_trait]
+impl AccountRepository for MemoryStorage {
+    async fn create(
+        &self,
+        username: &str,
+        hashed_password: &str,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        if state.account_by_username.contains_key(username) {
+            return Ok(None);
+        }
+
+        let mut id = generate_id();
+        while state.accounts.contains_key(&id) {
+            id = generate_id();
+        }
+
+        state.accounts.insert(
+            id,
+            Account {
+                id,
+                username: username.to_string(),
+                hashed_password: hashed_password.to_string(),
+                permissions: 0,
+            },
+        );
+        state.account_by_username.insert(username.to_string(), id);
+
+        Ok(Some(id))
+    }
+
+    async fn by_id(
+        &self,
+        id: i64,
+    ) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().accounts.get(&id).cloned())
+    }
+
+    async fn by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .account_by_username
+            .get(username)
+            .and_then(|id| state.accounts.get(id))
+            .cloned())
+    }
+}
+
+#[tonic::async_trait]
+impl ChannelRepository for MemoryStorage {
+    async fn create(
+        &self,
+        name: &str,
+        description: &str,
+        owner_id: i64,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+
+        let mut id = generate_id();
+        while state.channels.contains_key(&id) {
+            id = generate_id();
+        }
+
+        state.channels.insert(
+            id,
+            Channel {
+                id,
+                name: name.to_string(),
+                description: description.to_string(),
+                owner_id,
+                slow_mode_seconds: 0,
+            },
+        );
+        state
+            .channel_by_owner
+            .entry(owner_id)
+            .or_default()
+            .insert(id);
+        state
+            .channel_by_member
+            .entry(owner_id)
+            .or_default()
+            .insert(id);
+        state.joined_ids.insert((id, owner_id), id);
+
+        Ok(id)
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Channel>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().channels.get(&id).cloned())
+    }
+
+    async fn set_slow_mode(
+        &self,
+        id: i64,
+        seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(channel) = self.state.write().unwrap().channels.get_mut(&id) {
+            channel.slow_mode_seconds = seconds;
+        }
+
+        Ok(())
+    }
+
+    async fn add_member(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+        joined_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        state
+            .channel_by_member
+            .entry(member_id)
+            .or_default()
+            .insert(channel_id);
+        state.joined_ids.insert((channel_id, member_id), joined_id);
+
+        Ok(())
+    }
+
+    async fn joined_id(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .joined_ids
+            .get(&(channel_id, member_id))
+            .copied())
+    }
+
+    async fn remove_member(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        if let Some(channels) = state.channel_by_member.get_mut(&member_id) {
+            channels.remove(&channel_id);
+        }
+        state.joined_ids.remove(&(channel_id, member_id));
+
+        Ok(())
+    }
+
+    async fn next_sequence(
+        &self,
+        channel_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        let sequence = state.channel_sequence.entry(channel_id).or_insert(0);
+        *sequence += 1;
+
+        Ok(*sequence)
+    }
+
+    async fn sequence(
+        &self,
+        channel_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .channel_sequence
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    async fn scan(
+        &self,
+        source: &ChannelSource,
+        newest: bool,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        if lower >= upper {
+            return Ok(Vec::new());
+        }
+
+        let state = self.state.read().unwrap();
+        let range = (Bound::Excluded(lower), Bound::Excluded(upper));
+        let empty = collections::BTreeSet::new();
+        Ok(match source {
+            ChannelSource::All => {
+                _range(state.channels.range(range).map(|(id, _)| id), newest, limit)
+            }
+            ChannelSource::Owner(owner_id) => _range(
+                state
+                    .channel_by_owner
+                    .get(owner_id)
+                    .unwrap_or(&empty)
+                    .range(range),
+                newest,
+                limit,
+            ),
+            ChannelSource::Member(member_id) => _range(
+                state
+                    .channel_by_member
+                    .get(member_id)
+                    .unwrap_or(&empty)
+                    .range(range),
+                newest,
+                limit,
+            ),
+        })
+    }
+
+    async fn for_each(
+        &self,
+        f: &mut (dyn FnMut(Channel) + Send),
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let channels = self
+            .state
+            .read()
+            .unwrap()
+            .channels
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        channels.into_iter().for_each(f);
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl MessageRepository for MemoryStorage {
+    async fn create(
+        &self,
+        content: &str,
+        rich_content: &[u8],
+        author_id: i64,
+        channel_id: i64,
+        mentions: &Mentions,
+        attachment_ids: &[i64],
+        _ttl_seconds: i32,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+
+        let mut id = generate_id();
+        while state.messages.contains_key(&id) {
+            id = generate_id();
+        }
+
+        state.messages.insert(
+            id,
+            Message {
+                id,
+                content: content.to_string(),
+                author_id,
+                channel_id,
+                mentions: mentions.clone(),
+                attachment_ids: attachment_ids.to_vec(),
+                rich_content: rich_content.to_vec(),
+            },
+        );
+        state
+            .message_by_channel
+            .entry(channel_id)
+            .or_default()
+            .insert(id);
+        for user_id in &mentions.user_ids {
+            state
+                .mentions_by_user
+                .entry(*user_id)
+                .or_default()
+                .insert(id);
+        }
+        for attachment_id in attachment_ids {
+            state
+                .messages_by_attachment
+                .entry(*attachment_id)
+                .or_default()
+                .insert(id);
+        }
+
+        Ok(id)
+    }
+
+    /// Messages never expire in memory, they are only deleted explicitly.
+    async fn set_ttl(
+        &self,
+        _message: &Message,
+        _ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn delete(
+        &self,
+        message: &Message,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        state.messages.remove(&message.id);
+        if let Some(ids) = state.message_by_channel.get_mut(&message.channel_id) {
+            ids.remove(&message.id);
+        }
+        for user_id in &message.mentions.user_ids {
+            if let Some(ids) = state.mentions_by_user.get_mut(user_id) {
+                ids.remove(&message.id);
+            }
+        }
+        for attachment_id in &message.attachment_ids {
+            if let Some(ids) = state.messages_by_attachment.get_mut(attachment_id) {
+                ids.remove(&message.id);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().messages.get(&id).cloned())
+    }
+
+    async fn history(
+        &self,
+        channel_id: i64,
+        after_id: i64,
+        before_id: i64,
+        newest: bool,
+        limit: i32,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        if after_id > before_id {
+            return Ok(Vec::new());
+        }
+
+        let state = self.state.read().unwrap();
+        let ids = match state.message_by_channel.get(&channel_id) {
+            Some(ids) => _range(ids.range(after_id..=before_id), newest, limit),
+            None => Vec::new(),
+        };
+
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| state.messages.get(&id).cloned())
+            .collect())
+    }
+
+    async fn mentioning(
+        &self,
+        user_id: i64,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        let state = self.state.read().unwrap();
+        let ids = match state.mentions_by_user.get(&user_id) {
+            Some(ids) => _range(ids.range(..before_id), true, limit),
+            None => Vec::new(),
+        };
+
+        Ok(ids
+            .into_iter()
+            .filter_map(|id| state.messages.get(&id).cloned())
+            .collect())
+    }
+
+    async fn attached_to(
+        &self,
+        attachment_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .messages_by_attachment
+            .get(&attachment_id)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default())
+    }
+}
+
+#[tonic::async_trait]
+impl ConfigRepository for MemoryStorage {
+    async fn insert_text(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .write()
+            .unwrap()
+            .config
+            .entry(key.to_string())
+            .or_insert_with(|| value.to_string())
+            .clone())
+    }
+}
+
+#[tonic::async_trait]
+impl ReadStateRepository for MemoryStorage {
+    async fn mark_read(
+        &self,
+        user_id: i64,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        let last_read_id = state
+            .read_states
+            .entry(user_id)
+            .or_default()
+            .entry(channel_id)
+            .or_insert(message_id);
+        *last_read_id = (*last_read_id).max(message_id);
+
+        Ok(*last_read_id)
+    }
+
+    async fn list(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<ReadState>, Box<dyn std::error::Error + Send + Sync>> {
+        let state = self.state.read().unwrap();
+        Ok(state
+            .read_states
+            .get(&user_id)
+            .map(|channels| {
+                channels
+                    .iter()
+                    .map(|(channel_id, last_read_id)| ReadState {
+                        channel_id: *channel_id,
+                        last_read_id: *last_read_id,
+                    })
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+}
+
+#[tonic::async_trait]
+impl PinRepository for MemoryStorage {
+    async fn pin(
+        &self,
+        pin: &Pin,
+        max_pins: usize,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        let pins = state.pins.entry(pin.channel_id).or_default();
+        if pins.len() >= max_pins && !pins.contains_key(&pin.message_id) {
+            return Ok(false);
+        }
+
+        pins.insert(pin.message_id, pin.clone());
+        Ok(true)
+    }
+
+    async fn unpin(
+        &self,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .write()
+            .unwrap()
+            .pins
+            .get_mut(&channel_id)
+            .is_some_and(|pins| pins.remove(&message_id).is_some()))
+    }
+
+    async fn list(
+        &self,
+        channel_id: i64,
+    ) -> Result<Vec<Pin>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .pins
+            .get(&channel_id)
+            .map(|pins| pins.values().rev().cloned().collect())
+            .unwrap_or_default())
+    }
+}
+
+#[tonic::async_trait]
+impl AttachmentRepository for MemoryStorage {
+    async fn create(
+        &self,
+        attachment: &Attachment,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .attachments
+            .insert(attachment.id, attachment.clone());
+
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Attachment>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().attachments.get(&id).cloned())
+    }
+
+    async fn get_many(
+        &self,
+        ids: &[i64],
+    ) -> Result<Vec<Attachment>, Box<dyn std::error::Error + Send + Sync>> {
+        let state = self.state.read().unwrap();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.attachments.get(id).cloned())
+            .collect())
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state.write().unwrap().attachments.remove(&id);
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl EmbedRepository for MemoryStorage {
+    async fn set(
+        &self,
+        message_id: i64,
+        embeds: &[Embed],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .embeds
+            .insert(message_id, embeds.to_vec());
+
+        Ok(())
+    }
+
+    async fn list_many(
+        &self,
+        message_ids: &[i64],
+    ) -> Result<collections::HashMap<i64, Vec<Embed>>, Box<dyn std::error::Error + Send + Sync>>
+    {
+        let state = self.state.read().unwrap();
+        Ok(message_ids
+            .iter()
+            .filter_map(|id| state.embeds.get(id).map(|embeds| (*id, embeds.clone())))
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl ModerationRepository for MemoryStorage {
+    async fn set_filters(
+        &self,
+        channel_id: i64,
+        filters: &[ModerationFilter],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .moderation_filters
+            .insert(channel_id, filters.to_vec());
+
+        Ok(())
+    }
+
+    async fn filters(
+        &self,
+        channel_id: i64,
+    ) -> Result<Vec<ModerationFilter>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .moderation_filters
+            .get(&channel_id)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    async fn flag(&self, flag: &Flag) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .flags
+            .insert(flag.message_id, flag.clone());
+
+        Ok(())
+    }
+
+    async fn get_flag(
+        &self,
+        message_id: i64,
+    ) -> Result<Option<Flag>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().flags.get(&message_id).cloned())
+    }
+
+    async fn flags(
+        &self,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Flag>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .flags
+            .range(..before_id)
+            .rev()
+            .take(limit.max(0) as usize)
+            .map(|(_, flag)| flag.clone())
+            .collect())
+    }
+
+    async fn resolve(
+        &self,
+        message_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state.write().unwrap().flags.remove(&message_id);
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl RateLimitRepository for MemoryStorage {
+    async fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().rate_limits.get(&key.to_string()))
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<i64>,
+        value: i64,
+        ttl_seconds: i32,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        if state.rate_limits.get(&key.to_string()) != expected {
+            return Ok(false);
+        }
+
+        state
+            .rate_limits
+            .insert(key.to_string(), value, ttl_seconds);
+        Ok(true)
+    }
+}
+
+#[tonic::async_trait]
+impl IdempotencyRepository for MemoryStorage {
+    async fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .idempotency_keys
+            .get(&key.to_string()))
+    }
+
+    async fn claim(
+        &self,
+        key: &str,
+        id: i64,
+        ttl_seconds: i32,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        if let Some(claimed) = state.idempotency_keys.get(&key.to_string()) {
+            return Ok(claimed);
+        }
+
+        state
+            .idempotency_keys
+            .insert(key.to_string(), id, ttl_seconds);
+        Ok(id)
+    }
+
+    async fn reclaim(
+        &self,
+        key: &str,
+        abandoned_id: i64,
+        id: i64,
+        ttl_seconds: i32,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        match state.idempotency_keys.get(&key.to_string()) {
+            Some(claimed) if claimed != abandoned_id => Ok(claimed),
+            _ => {
+                state
+                    .idempotency_keys
+                    .insert(key.to_string(), id, ttl_seconds);
+                Ok(id)
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RelationshipRepository for MemoryStorage {
+    async fn block(
+        &self,
+        user_id: i64,
+        blocked_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .blocked_by_user
+            .entry(user_id)
+            .or_default()
+            .insert(blocked_id);
+
+        Ok(())
+    }
+
+    async fn unblock(
+        &self,
+        user_id: i64,
+        blocked_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if let Some(blocked) = self
+            .state
+            .write()
+            .unwrap()
+            .blocked_by_user
+            .get_mut(&user_id)
+        {
+            blocked.remove(&blocked_id);
+        }
+
+        Ok(())
+    }
+
+    async fn blocked(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .blocked_by_user
+            .get(&user_id)
+            .map(|blocked| blocked.iter().copied().collect())
+            .unwrap_or_default())
+    }
+
+    async fn set_muted(
+        &self,
+        user_id: i64,
+        channel_id: i64,
+        muted: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        let channels = state.muted_by_user.entry(user_id).or_default();
+        if muted {
+            channels.insert(channel_id);
+        } else {
+            channels.remove(&channel_id);
+        }
+
+        Ok(())
+    }
+
+    async fn muted(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .muted_by_user
+            .get(&user_id)
+            .map(|muted| muted.iter().copied().collect())
+            .unwrap_or_default())
+    }
+}
+
+#[tonic::async_trait]
+impl BanRepository for MemoryStorage {
+    async fn ban(&self, ban: &Ban) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        // Rounded up, so that the row never expires before the ban
+        let ttl_seconds = ban.expires_at.map_or(0, |expires_at| {
+            ((expires_at - ban.created_at + 999) / 1000).clamp(1, i32::MAX.into()) as i32
+        });
+        self.state.write().unwrap().bans.insert(
+            (ban.channel_id, ban.user_id),
+            ban.clone(),
+            ttl_seconds,
+        );
+
+        Ok(())
+    }
+
+    async fn unban(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .bans
+            .remove(&(channel_id, user_id));
+
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Ban>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.state.read().unwrap().bans.get(&(channel_id, user_id)))
+    }
+}
+
+#[tonic::async_trait]
+impl AuditLogRepository for MemoryStorage {
+    async fn append(
+        &self,
+        entry: &AuditEntry,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .audit_log
+            .insert(entry.id, entry.clone());
+
+        Ok(())
+    }
+
+    async fn by_day(
+        &self,
+        day: i64,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        if lower >= upper {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .audit_log
+            .range((Bound::Excluded(lower), Bound::Excluded(upper)))
+            .rev()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.day() == day)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn by_target(
+        &self,
+        target_id: i64,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        if lower >= upper {
+            return Ok(Vec::new());
+        }
+
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .audit_log
+            .range((Bound::Excluded(lower), Bound::Excluded(upper)))
+            .rev()
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.target_id == target_id)
+            .take(limit.max(0) as usize)
+            .cloned()
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl RetentionRepository for MemoryStorage {
+    async fn get(
+        &self,
+        channel_id: i64,
+    ) -> Result<Option<RetentionPolicy>, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .retention_policies
+            .get(&channel_id)
+            .cloned())
+    }
+
+    async fn set(
+        &self,
+        channel_id: i64,
+        policy: &RetentionPolicy,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .retention_policies
+            .insert(channel_id, policy.clone());
+
+        Ok(())
+    }
+
+    async fn applied_ttl(
+        &self,
+        channel_id: i64,
+    ) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self
+            .state
+            .read()
+            .unwrap()
+            .applied_ttls
+            .get(&channel_id)
+            .copied()
+            .unwrap_or(0))
+    }
+
+    async fn set_applied_ttl(
+        &self,
+        channel_id: i64,
+        ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.state
+            .write()
+            .unwrap()
+            .applied_ttls
+            .insert(channel_id, ttl_seconds);
+
+        Ok(())
+    }
+
+    async fn lease(
+        &self,
+        owner: &str,
+        ttl_seconds: i32,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let mut state = self.state.write().unwrap();
+        if state
+            .retention_lease
+            .get(&())
+            .is_some_and(|holder| holder != owner)
+        {
+            return Ok(false);
+        }
+
+        state
+            .retention_lease
+            .insert((), owner.to_string(), ttl_seconds);
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn idempotency_keys_expire() {
+        let storage = MemoryStorage::default();
+        assert_eq!(
+            IdempotencyRepository::claim(&storage, "key", 1, 60)
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            IdempotencyRepository::claim(&storage, "key", 2, 60)
+                .await
+                .unwrap(),
+            1
+        );
+
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert_eq!(
+            IdempotencyRepository::get(&storage, "key").await.unwrap(),
+            None
+        );
+        assert_eq!(
+            IdempotencyRepository::claim(&storage, "key", 3, 60)
+                .await
+                .unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limits_expire() {
+        let storage = MemoryStorage::default();
+        assert!(storage.compare_and_set("key", None, 5, 10).await.unwrap());
+        assert!(!storage.compare_and_set("key", None, 6, 10).await.unwrap());
+        assert_eq!(
+            RateLimitRepository::get(&storage, "key").await.unwrap(),
+            Some(5)
+        );
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(
+            RateLimitRepository::get(&storage, "key").await.unwrap(),
+            None
+        );
+        assert!(storage.compare_and_set("key", None, 7, 10).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_rows_are_purged_on_write() {
+        let storage = MemoryStorage::default();
+        for key in ["a", "b", "c"] {
+            storage.compare_and_set(key, None, 1, 10).await.unwrap();
+        }
+        storage
+            .compare_and_set("forever", None, 1, 0)
+            .await
+            .unwrap();
+
+        tokio::time::advance(Duration::from_secs(11)).await;
+        storage.compare_and_set("d", None, 1, 10).await.unwrap();
+
+        let state = storage.state.read().unwrap();
+        let mut keys = state.rate_limits.rows.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, ["d", "forever"]);
+        assert_eq!(state.rate_limits.expirations.len(), 1);
+    }
+}
@@ -0,0 +1,745 @@
+use std::collections;
+use std::sync::Arc;
+
+pub mod memory;
+pub mod scylla;
+
+/// A user account.
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub id: i64,
+    pub username: String,
+    pub hashed_password: String,
+    pub permissions: i64,
+}
+
+/// A channel.
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub id: i64,
+    pub name: String,
+    pub description: String,
+    pub owner_id: i64,
+
+    /// Minimum number of seconds between two messages of the same user in this channel, 0 if disabled
+    pub slow_mode_seconds: i32,
+}
+
+/// A message posted in a channel.
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub id: i64,
+    pub content: String,
+    pub author_id: i64,
+    pub channel_id: i64,
+    pub mentions: Mentions,
+    pub attachment_ids: Vec<i64>,
+
+    /// Rich text parsed from `content` when the message was created, encoded as a `PRichText`, empty for messages
+    /// created before it was stored
+    pub rich_content: Vec<u8>,
+}
+
+/// The users and groups notified by a message, resolved when the message is created.
+#[derive(Clone, Debug, Default)]
+pub struct Mentions {
+    /// IDs of the users mentioned with `@username`
+    pub user_ids: Vec<i64>,
+
+    /// Whether the whole channel is mentioned with `@channel`, `@everyone` or `@here`
+    pub channel: bool,
+}
+
+/// The position of a user in a channel, see [`ReadStateRepository`].
+#[derive(Clone, Debug)]
+pub struct ReadState {
+    pub channel_id: i64,
+    pub last_read_id: i64,
+}
+
+/// Metadata of an uploaded file, whose content is kept in a [`crate::blobs::BlobStore`] under its ID.
+#[derive(Clone, Debug)]
+pub struct Attachment {
+    pub id: i64,
+    pub uploader_id: i64,
+    pub filename: String,
+    pub mime_type: String,
+    pub size: i64,
+
+    /// Lowercase hexadecimal SHA-256 digest of the content
+    pub sha256: String,
+}
+
+/// Preview of a link found in a message.
+#[derive(Clone, Debug)]
+pub struct Embed {
+    pub url: String,
+    pub title: String,
+    pub description: String,
+
+    /// Empty if the page has no preview image
+    pub image_url: String,
+}
+
+/// A message pinned in its channel.
+#[derive(Clone, Debug)]
+pub struct Pin {
+    pub channel_id: i64,
+    pub message_id: i64,
+    pub pinned_by: i64,
+
+    /// Milliseconds since the UNIX epoch
+    pub pinned_at: i64,
+}
+
+/// What happens to a message matching a [`ModerationFilter`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModerationAction {
+    Reject,
+    Mask,
+    Flag,
+}
+
+/// A word, phrase or regular expression the messages of a channel are checked against.
+#[derive(Clone, Debug)]
+pub struct ModerationFilter {
+    pub pattern: String,
+
+    /// Whether `pattern` is a regular expression instead of a word or phrase
+    pub regex: bool,
+    pub action: ModerationAction,
+}
+
+/// A message waiting for review by a moderator.
+#[derive(Clone, Debug)]
+pub struct Flag {
+    pub message_id: i64,
+    pub channel_id: i64,
+    pub reasons: Vec<String>,
+
+    /// Milliseconds since the UNIX epoch
+    pub flagged_at: i64,
+}
+
+/// A user banned or timed out from a channel.
+#[derive(Clone, Debug)]
+pub struct Ban {
+    pub channel_id: i64,
+    pub user_id: i64,
+    pub moderator_id: i64,
+    pub reason: String,
+
+    /// Whether the user can still read the channel, and is only prevented from posting
+    pub timeout: bool,
+
+    /// Milliseconds since the UNIX epoch
+    pub created_at: i64,
+
+    /// Milliseconds since the UNIX epoch, `None` if the ban is permanent
+    pub expires_at: Option<i64>,
+}
+
+impl Ban {
+    /// Whether the ban still applies at `now`, in milliseconds since the UNIX epoch.
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+}
+
+/// A privileged operation recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditAction {
+    ChannelCreated,
+    SlowModeChanged,
+    ChannelFiltersChanged,
+    MessagePinned,
+    MessageUnpinned,
+    FlagResolved,
+    MemberBanned,
+    MemberUnbanned,
+    MemberTimedOut,
+    RetentionPolicyChanged,
+    LegalHoldChanged,
+}
+
+/// An entry of the audit log, which is never updated nor deleted.
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub actor_id: i64,
+    pub action: AuditAction,
+
+    /// The channel, message or user the operation applies to, depending on `action`
+    pub target_id: i64,
+    pub channel_id: i64,
+    pub details: String,
+
+    /// Milliseconds since the UNIX epoch
+    pub created_at: i64,
+}
+
+impl AuditEntry {
+    /// The day the entry was created, in days since the UNIX epoch (UTC).
+    pub fn day(&self) -> i64 {
+        day(self.created_at)
+    }
+}
+
+/// The day of `time` in milliseconds since the UNIX epoch, in days since the UNIX epoch (UTC).
+pub fn day(time: i64) -> i64 {
+    time.div_euclid(24 * 60 * 60 * 1000)
+}
+
+/// How long the messages of the workspace, or of a channel, are kept.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    /// Number of days messages are kept for, 0 to keep them forever
+    pub retention_days: i32,
+
+    /// Whether a channel follows the workspace policy instead, always unset for the workspace
+    pub inherit: bool,
+
+    /// Whether the messages of a channel are kept forever whatever the policies, always unset for the workspace
+    pub legal_hold: bool,
+}
+
+/// The set of channels to list IDs from in [`ChannelRepository::scan`].
+pub enum ChannelSource {
+    All,
+    Owner(i64),
+    Member(i64),
+}
+
+/// A generator of fresh snowflake IDs, called again whenever a generated ID is already taken.
+pub type IdGenerator<'a> = &'a (dyn Fn() -> i64 + Send + Sync);
+
+#[tonic::async_trait]
+pub trait AccountRepository: Send + Sync {
+    /// Create a new account with the given credentials, returning its ID, or `None` if the
+    /// username is already taken.
+    async fn create(
+        &self,
+        username: &str,
+        hashed_password: &str,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn by_id(
+        &self,
+        id: i64,
+    ) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait ChannelRepository: Send + Sync {
+    /// Create a new channel owned (and joined) by `owner_id`, returning its ID.
+    async fn create(
+        &self,
+        name: &str,
+        description: &str,
+        owner_id: i64,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Channel>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Change the slow mode of an existing channel, see [`Channel::slow_mode_seconds`].
+    async fn set_slow_mode(
+        &self,
+        id: i64,
+        seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Record `member_id` as a member of a channel since `joined_id`, the ID of the first message of the member.
+    /// Adding an existing member again moves its join time.
+    async fn add_member(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+        joined_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The ID `member_id` joined a channel at, `None` if it is not a member.
+    ///
+    /// Owners join at the ID of their channel, as do members recorded before join times were.
+    async fn joined_id(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Remove `member_id` from the members of a channel. This operation is idempotent.
+    async fn remove_member(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Atomically increment the event sequence number of a channel, returning the new value.
+    ///
+    /// Sequence numbers start at 1, so that subscribers can detect missed events from a gap. Fails if the number
+    /// cannot be incremented after a few attempts, e.g. when too many events of the channel are published at once.
+    async fn next_sequence(
+        &self,
+        channel_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The latest event sequence number of a channel, 0 if no event was ever published.
+    async fn sequence(
+        &self,
+        channel_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Fetch at most `limit` channel IDs from `source`, strictly between `lower` and `upper`.
+    ///
+    /// IDs are ordered from the newest channel if `newest` is set, from the oldest otherwise.
+    async fn scan(
+        &self,
+        source: &ChannelSource,
+        newest: bool,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Call `f` with every existing channel, in no particular order.
+    async fn for_each(
+        &self,
+        f: &mut (dyn FnMut(Channel) + Send),
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait MessageRepository: Send + Sync {
+    /// Store a new message, returning its ID.
+    ///
+    /// The message is also added to the mentions inbox of every user in `mentions.user_ids`, and to the references of
+    /// its attachments. It may be deleted by the backend after `ttl_seconds`, or is kept until deleted if
+    /// `ttl_seconds` is 0.
+    #[allow(clippy::too_many_arguments)]
+    async fn create(
+        &self,
+        content: &str,
+        rich_content: &[u8],
+        author_id: i64,
+        channel_id: i64,
+        mentions: &Mentions,
+        attachment_ids: &[i64],
+        ttl_seconds: i32,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Replace the time to live of an existing message, see [`MessageRepository::create`].
+    async fn set_ttl(
+        &self,
+        message: &Message,
+        ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Delete a message, its mentions and its references to its attachments. This operation is idempotent.
+    async fn delete(
+        &self,
+        message: &Message,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Fetch at most `limit` messages of a channel whose IDs are between `after_id` and `before_id` (inclusive).
+    ///
+    /// Messages are ordered from the newest one if `newest` is set, from the oldest otherwise.
+    async fn history(
+        &self,
+        channel_id: i64,
+        after_id: i64,
+        before_id: i64,
+        newest: bool,
+        limit: i32,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Fetch at most `limit` messages mentioning `user_id` by username, whose IDs are strictly lower than `before_id`.
+    ///
+    /// Messages are ordered from the newest one.
+    async fn mentioning(
+        &self,
+        user_id: i64,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// IDs of the messages an attachment is attached to, in no particular order.
+    async fn attached_to(
+        &self,
+        attachment_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait ConfigRepository: Send + Sync {
+    /// Store `value` under `key` unless the key already exists, returning the stored value.
+    async fn insert_text(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait ReadStateRepository: Send + Sync {
+    /// Move the read position of a user in a channel forward to `message_id`, returning the resulting position.
+    ///
+    /// Positions never move backwards, so that stale sessions cannot mark read messages as unread again.
+    async fn mark_read(
+        &self,
+        user_id: i64,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Every read position of a user, in no particular order.
+    async fn list(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<ReadState>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait PinRepository: Send + Sync {
+    /// Pin a message in its channel, replacing the previous pin of the same message. This operation is idempotent.
+    ///
+    /// Returns `false` without pinning when `max_pins` other messages of the channel are already pinned, the limit
+    /// holds even when messages are pinned concurrently.
+    async fn pin(
+        &self,
+        pin: &Pin,
+        max_pins: usize,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Unpin a message, returning whether it was pinned.
+    async fn unpin(
+        &self,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Every pin of a channel, from the newest pinned message.
+    async fn list(
+        &self,
+        channel_id: i64,
+    ) -> Result<Vec<Pin>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait AttachmentRepository: Send + Sync {
+    /// Store the metadata of an uploaded attachment, whose ID was generated before its content was uploaded.
+    async fn create(
+        &self,
+        attachment: &Attachment,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Attachment>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Fetch the metadata of several attachments at once, skipping those which do not exist.
+    async fn get_many(
+        &self,
+        ids: &[i64],
+    ) -> Result<Vec<Attachment>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Delete the metadata of an attachment, but not its content. This operation is idempotent.
+    async fn delete(&self, id: i64) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait EmbedRepository: Send + Sync {
+    /// Replace the link previews of a message.
+    async fn set(
+        &self,
+        message_id: i64,
+        embeds: &[Embed],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The link previews of several messages at once, keyed by message ID, in order of appearance of the links.
+    /// Messages without previews are omitted.
+    async fn list_many(
+        &self,
+        message_ids: &[i64],
+    ) -> Result<collections::HashMap<i64, Vec<Embed>>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait ModerationRepository: Send + Sync {
+    /// Replace the moderation filters of a channel.
+    async fn set_filters(
+        &self,
+        channel_id: i64,
+        filters: &[ModerationFilter],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The moderation filters of a channel, in the order they are applied.
+    async fn filters(
+        &self,
+        channel_id: i64,
+    ) -> Result<Vec<ModerationFilter>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Add a message to the review queue, replacing its previous flag if any.
+    async fn flag(&self, flag: &Flag) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    async fn get_flag(
+        &self,
+        message_id: i64,
+    ) -> Result<Option<Flag>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// At most `limit` flags of messages older than `before_id`, newest first.
+    async fn flags(
+        &self,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Flag>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Remove a message from the review queue. This operation is idempotent.
+    async fn resolve(
+        &self,
+        message_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait RateLimitRepository: Send + Sync {
+    /// The state of a rate limit, `None` if it is unused or expired.
+    async fn get(&self, key: &str)
+        -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Atomically replace the state of a rate limit if it still is `expected`, returning whether it was replaced.
+    ///
+    /// The new state expires after `ttl_seconds`, as if the rate limit was never used.
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<i64>,
+        value: i64,
+        ttl_seconds: i32,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait IdempotencyRepository: Send + Sync {
+    /// The ID of the entity created by the request identified by `key`, `None` if the key is unused or expired.
+    async fn get(&self, key: &str)
+        -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Atomically associate `key` with `id` for `ttl_seconds` unless it already is, returning the associated ID.
+    async fn claim(
+        &self,
+        key: &str,
+        id: i64,
+        ttl_seconds: i32,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Atomically associate `key` with `id` for `ttl_seconds` if it is still associated with `abandoned_id` or no
+    /// longer associated at all, returning the associated ID.
+    async fn reclaim(
+        &self,
+        key: &str,
+        abandoned_id: i64,
+        id: i64,
+        ttl_seconds: i32,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait RelationshipRepository: Send + Sync {
+    /// Block `blocked_id` on behalf of `user_id`. This operation is idempotent.
+    async fn block(
+        &self,
+        user_id: i64,
+        blocked_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Unblock `blocked_id` on behalf of `user_id`. This operation is idempotent.
+    async fn unblock(
+        &self,
+        user_id: i64,
+        blocked_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The IDs of the users blocked by `user_id`, in ascending order.
+    async fn blocked(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Mute or unmute `channel_id` on behalf of `user_id`. This operation is idempotent.
+    async fn set_muted(
+        &self,
+        user_id: i64,
+        channel_id: i64,
+        muted: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The IDs of the channels muted by `user_id`, in ascending order.
+    async fn muted(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait BanRepository: Send + Sync {
+    /// Ban a user from a channel, replacing their previous ban or timeout if any.
+    ///
+    /// Expired bans may be deleted by the backend, and must be ignored otherwise, see [`Ban::is_active`].
+    async fn ban(&self, ban: &Ban) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Lift the ban of a user. This operation is idempotent.
+    async fn unban(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The ban of a user in a channel, which may have expired.
+    async fn get(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Ban>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait AuditLogRepository: Send + Sync {
+    /// Append an entry to the audit log.
+    async fn append(
+        &self,
+        entry: &AuditEntry,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// At most `limit` entries created during `day` (see [`day`]), with IDs strictly between `lower` and `upper`,
+    /// newest first.
+    async fn by_day(
+        &self,
+        day: i64,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// At most `limit` entries of `target_id`, with IDs strictly between `lower` and `upper`, newest first.
+    async fn by_target(
+        &self,
+        target_id: i64,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[tonic::async_trait]
+pub trait RetentionRepository: Send + Sync {
+    /// The policy of a channel, or of the workspace if `channel_id` is 0. `None` if it was never set.
+    async fn get(
+        &self,
+        channel_id: i64,
+    ) -> Result<Option<RetentionPolicy>, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Replace the policy of a channel, or of the workspace if `channel_id` is 0.
+    async fn set(
+        &self,
+        channel_id: i64,
+        policy: &RetentionPolicy,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// The retention in seconds last applied to the existing messages of a channel, 0 if they are kept forever.
+    async fn applied_ttl(
+        &self,
+        channel_id: i64,
+    ) -> Result<i32, Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Record the retention applied to the existing messages of a channel.
+    async fn set_applied_ttl(
+        &self,
+        channel_id: i64,
+        ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+    /// Take the cluster-wide lease of the retention sweeper for `owner`, or extend it if `owner` already holds it,
+    /// for `ttl_seconds`. Returns whether `owner` holds the lease.
+    async fn lease(
+        &self,
+        owner: &str,
+        ttl_seconds: i32,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The repositories backing the application state.
+#[derive(Clone)]
+pub struct Storage {
+    pub accounts: Arc<dyn AccountRepository>,
+    pub channels: Arc<dyn ChannelRepository>,
+    pub messages: Arc<dyn MessageRepository>,
+    pub config: Arc<dyn ConfigRepository>,
+    pub read_states: Arc<dyn ReadStateRepository>,
+    pub pins: Arc<dyn PinRepository>,
+    pub attachments: Arc<dyn AttachmentRepository>,
+    pub embeds: Arc<dyn EmbedRepository>,
+    pub moderation: Arc<dyn ModerationRepository>,
+    pub rate_limits: Arc<dyn RateLimitRepository>,
+    pub idempotency_keys: Arc<dyn IdempotencyRepository>,
+    pub relationships: Arc<dyn RelationshipRepository>,
+    pub bans: Arc<dyn BanRepository>,
+    pub audit_log: Arc<dyn AuditLogRepository>,
+    pub retention: Arc<dyn RetentionRepository>,
+}
+
+impl Storage {
+    /// Use a single backend for every repository.
+    pub fn from_backend<T>(backend: Arc<T>) -> Self
+    where
+        T: AccountRepository
+            + ChannelRepository
+            + MessageRepository
+            + ConfigRepository
+            + ReadStateRepository
+            + PinRepository
+            + AttachmentRepository
+            + EmbedRepository
+            + ModerationRepository
+            + RateLimitRepository
+            + IdempotencyRepository
+            + RelationshipRepository
+            + BanRepository
+            + AuditLogRepository
+            + RetentionRepository
+            + 'static,
+    {
+        Self {
+            accounts: backend.clone(),
+            channels: backend.clone(),
+            messages: backend.clone(),
+            config: backend.clone(),
+            read_states: backend.clone(),
+            pins: backend.clone(),
+            attachments: backend.clone(),
+            embeds: backend.clone(),
+            moderation: backend.clone(),
+            rate_limits: backend.clone(),
+            idempotency_keys: backend.clone(),
+            relationships: backend.clone(),
+            bans: backend.clone(),
+            audit_log: backend.clone(),
+            retention: backend,
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{Account, AccountRepository, IdGenerator};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    create1: prepared_statement::PreparedStatement,
+    create2: prepared_statement::PreparedStatement,
+    create3: prepared_statement::PreparedStatement,
+    by_id: prepared_statement::PreparedStatement,
+    by_username: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AccountRow {
+    id: i64,
+    username: String,
+    hashed_password: String,
+    permissions: i64,
+}
+
+impl From<_AccountRow> for Account {
+    fn from(row: _AccountRow) -> Self {
+        Self {
+            id: row.id,
+            username: row.username,
+            hashed_password: row.hashed_password,
+            permissions: row.permissions,
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _InsertAccountRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    id: Option<i64>,
+    username: Option<String>,
+    hashed_password: Option<String>,
+    permissions: Option<i64>,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut create1 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${accounts}.info_by_username (id, username, hashed_password, permissions)
+            VALUES (?, ?, ?, 0)
+            IF NOT EXISTS",
+        ))
+        .await?;
+    create1.set_consistency(storage.consistency.account_writes);
+    create1.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut create2 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${accounts}.info_by_id (id, username, hashed_password, permissions)
+            VALUES (?, ?, ?, 0)
+            IF NOT EXISTS",
+        ))
+        .await?;
+    create2.set_consistency(storage.consistency.account_writes);
+    create2.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut create3 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${accounts}.info_by_username
+            SET id = ?
+            WHERE username = ?",
+        ))
+        .await?;
+    create3.set_consistency(storage.consistency.account_writes);
+
+    let mut by_id = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, username, hashed_password, permissions
+            FROM ${accounts}.info_by_id
+            WHERE id = ?",
+        ))
+        .await?;
+    by_id.set_consistency(storage.consistency.account_reads);
+
+    let mut by_username = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, username, hashed_password, permissions
+            FROM ${accounts}.info_by_username
+            WHERE username = ?",
+        ))
+        .await?;
+    by_username.set_consistency(storage.consistency.account_reads);
+
+    Ok(_Statements {
+        create1,
+        create2,
+        create3,
+        by_id,
+        by_username,
+    })
+}
+
+async fn _create_helper(
+    storage: &super::ScyllaStorage,
+    id: &i64,
+    statement: &prepared_statement::PreparedStatement,
+    username: &str,
+    hashed_password: &str,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    Ok(storage
+        .session
+        .execute_unpaged(statement, (&id, username, hashed_password))
+        .await?
+        .into_rows_result()?
+        .single_row::<_InsertAccountRow>()?
+        .applied)
+}
+
+#[tonic::async_trait]
+impl AccountRepository for super::ScyllaStorage {
+    async fn create(
+        &self,
+        username: &str,
+        hashed_password: &str,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        let id = generate_id();
+        if !_create_helper(self, &id, &statements.create1, username, hashed_password).await? {
+            // Username already exists
+            return Ok(None);
+        }
+
+        // Username is unique, insert into the second table
+        if _create_helper(self, &id, &statements.create2, username, hashed_password).await? {
+            // ID is unique
+            return Ok(Some(id));
+        }
+
+        // ID already exists (this is very unlikely, but may happen during extremely high concurrency scenarios).
+        // In this case, we repeatedly generate a new ID and try inserting.
+        let mut id = generate_id();
+        while !_create_helper(self, &id, &statements.create2, username, hashed_password).await? {
+            id = generate_id();
+        }
+
+        // At this point, the ID inserted to the second table is guaranteed to be unique.
+        // We now update the first table.
+        self.session
+            .execute_unpaged(&statements.create3, (id, username))
+            .await?;
+
+        Ok(Some(id))
+    }
+
+    async fn by_id(
+        &self,
+        id: i64,
+    ) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.by_id, (&id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_AccountRow>()?;
+
+        Ok(row.map(Account::from))
+    }
+
+    async fn by_username(
+        &self,
+        username: &str,
+    ) -> Result<Option<Account>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.by_username, (username,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_AccountRow>()?;
+
+        Ok(row.map(Account::from))
+    }
+}
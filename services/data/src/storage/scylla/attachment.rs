@@ -0,0 +1,167 @@
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{Attachment, AttachmentRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    create: prepared_statement::PreparedStatement,
+    attachment: prepared_statement::PreparedStatement,
+    attachments: prepared_statement::PreparedStatement,
+    delete: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AttachmentRow {
+    id: i64,
+    uploader_id: i64,
+    filename: String,
+    mime_type: String,
+    size: i64,
+    sha256: String,
+}
+
+impl _AttachmentRow {
+    fn into_attachment(self) -> Attachment {
+        Attachment {
+            id: self.id,
+            uploader_id: self.uploader_id,
+            filename: self.filename,
+            mime_type: self.mime_type,
+            size: self.size,
+            sha256: self.sha256,
+        }
+    }
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut create = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.attachment_by_id (id, uploader_id, filename, mime_type, size, sha256)
+            VALUES (?, ?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    create.set_consistency(storage.consistency.writes);
+
+    let mut attachment = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, uploader_id, filename, mime_type, size, sha256
+            FROM ${data}.attachment_by_id
+            WHERE id = ?",
+        ))
+        .await?;
+    attachment.set_consistency(storage.consistency.reads);
+
+    let mut attachments = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, uploader_id, filename, mime_type, size, sha256
+            FROM ${data}.attachment_by_id
+            WHERE id IN ?",
+        ))
+        .await?;
+    attachments.set_consistency(storage.consistency.reads);
+
+    let mut delete = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.attachment_by_id
+            WHERE id = ?",
+        ))
+        .await?;
+    delete.set_consistency(storage.consistency.writes);
+
+    Ok(_Statements {
+        create,
+        attachment,
+        attachments,
+        delete,
+    })
+}
+
+#[tonic::async_trait]
+impl AttachmentRepository for super::ScyllaStorage {
+    async fn create(
+        &self,
+        attachment: &Attachment,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(
+                &statements.create,
+                (
+                    &attachment.id,
+                    &attachment.uploader_id,
+                    &attachment.filename,
+                    &attachment.mime_type,
+                    &attachment.size,
+                    &attachment.sha256,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Attachment>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.attachment, (&id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_AttachmentRow>()?;
+
+        Ok(row.map(_AttachmentRow::into_attachment))
+    }
+
+    async fn get_many(
+        &self,
+        ids: &[i64],
+    ) -> Result<Vec<Attachment>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let rows = self
+            .session
+            .execute_unpaged(&statements.attachments, (ids,))
+            .await?
+            .into_rows_result()?
+            .rows::<_AttachmentRow>()?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(rows
+            .into_iter()
+            .map(_AttachmentRow::into_attachment)
+            .collect())
+    }
+
+    async fn delete(&self, id: i64) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.delete, (&id,))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{AuditAction, AuditEntry, AuditLogRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    insert_by_day: prepared_statement::PreparedStatement,
+    insert_by_target: prepared_statement::PreparedStatement,
+    query_by_day: prepared_statement::PreparedStatement,
+    query_by_target: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AuditEntryRow {
+    id: i64,
+    actor_id: i64,
+    action: String,
+    target_id: i64,
+    channel_id: i64,
+    details: Option<String>,
+    created_at: i64,
+}
+
+impl TryFrom<_AuditEntryRow> for AuditEntry {
+    type Error = Box<dyn std::error::Error + Send + Sync>;
+
+    fn try_from(row: _AuditEntryRow) -> Result<Self, Self::Error> {
+        Ok(Self {
+            id: row.id,
+            actor_id: row.actor_id,
+            action: _parse_action(&row.action)?,
+            target_id: row.target_id,
+            channel_id: row.channel_id,
+            details: row.details.unwrap_or_default(),
+            created_at: row.created_at,
+        })
+    }
+}
+
+fn _action_name(action: AuditAction) -> &'static str {
+    match action {
+        AuditAction::ChannelCreated => "channel_created",
+        AuditAction::SlowModeChanged => "slow_mode_changed",
+        AuditAction::ChannelFiltersChanged => "channel_filters_changed",
+        AuditAction::MessagePinned => "message_pinned",
+        AuditAction::MessageUnpinned => "message_unpinned",
+        AuditAction::FlagResolved => "flag_resolved",
+        AuditAction::MemberBanned => "member_banned",
+        AuditAction::MemberUnbanned => "member_unbanned",
+        AuditAction::MemberTimedOut => "member_timed_out",
+        AuditAction::RetentionPolicyChanged => "retention_policy_changed",
+        AuditAction::LegalHoldChanged => "legal_hold_changed",
+    }
+}
+
+fn _parse_action(name: &str) -> Result<AuditAction, Box<dyn std::error::Error + Send + Sync>> {
+    match name {
+        "channel_created" => Ok(AuditAction::ChannelCreated),
+        "slow_mode_changed" => Ok(AuditAction::SlowModeChanged),
+        "channel_filters_changed" => Ok(AuditAction::ChannelFiltersChanged),
+        "message_pinned" => Ok(AuditAction::MessagePinned),
+        "message_unpinned" => Ok(AuditAction::MessageUnpinned),
+        "flag_resolved" => Ok(AuditAction::FlagResolved),
+        "member_banned" => Ok(AuditAction::MemberBanned),
+        "member_unbanned" => Ok(AuditAction::MemberUnbanned),
+        "member_timed_out" => Ok(AuditAction::MemberTimedOut),
+        "retention_policy_changed" => Ok(AuditAction::RetentionPolicyChanged),
+        "legal_hold_changed" => Ok(AuditAction::LegalHoldChanged),
+        _ => Err(format!("Unknown audit action {:?}", name).into()),
+    }
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut insert_by_day = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.audit_log_by_day (day, id, actor_id, action, target_id, channel_id, details, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    insert_by_day.set_consistency(storage.consistency.writes);
+
+    let mut insert_by_target = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.audit_log_by_target (target_id, id, actor_id, action, channel_id, details, created_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    insert_by_target.set_consistency(storage.consistency.writes);
+
+    let mut query_by_day = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, actor_id, action, target_id, channel_id, details, created_at
+            FROM ${data}.audit_log_by_day
+            WHERE day = ? AND id > ? AND id < ?
+            LIMIT ?",
+        ))
+        .await?;
+    query_by_day.set_consistency(storage.consistency.reads);
+
+    let mut query_by_target = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, actor_id, action, target_id, channel_id, details, created_at
+            FROM ${data}.audit_log_by_target
+            WHERE target_id = ? AND id > ? AND id < ?
+            LIMIT ?",
+        ))
+        .await?;
+    query_by_target.set_consistency(storage.consistency.reads);
+
+    Ok(_Statements {
+        insert_by_day,
+        insert_by_target,
+        query_by_day,
+        query_by_target,
+    })
+}
+
+#[tonic::async_trait]
+impl AuditLogRepository for super::ScyllaStorage {
+    async fn append(
+        &self,
+        entry: &AuditEntry,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let action = _action_name(entry.action);
+        self.session
+            .execute_unpaged(
+                &statements.insert_by_day,
+                (
+                    entry.day(),
+                    &entry.id,
+                    &entry.actor_id,
+                    action,
+                    &entry.target_id,
+                    &entry.channel_id,
+                    &entry.details,
+                    &entry.created_at,
+                ),
+            )
+            .await?;
+        self.session
+            .execute_unpaged(
+                &statements.insert_by_target,
+                (
+                    &entry.target_id,
+                    &entry.id,
+                    &entry.actor_id,
+                    action,
+                    &entry.channel_id,
+                    &entry.details,
+                    &entry.created_at,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn by_day(
+        &self,
+        day: i64,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.query_by_day, (&day, &lower, &upper, &limit))
+            .await?
+            .into_rows_result()?;
+
+        let mut entries = Vec::new();
+        for row in result.rows::<_AuditEntryRow>()? {
+            entries.push(AuditEntry::try_from(row?)?);
+        }
+
+        Ok(entries)
+    }
+
+    async fn by_target(
+        &self,
+        target_id: i64,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<AuditEntry>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(
+                &statements.query_by_target,
+                (&target_id, &lower, &upper, &limit),
+            )
+            .await?
+            .into_rows_result()?;
+
+        let mut entries = Vec::new();
+        for row in result.rows::<_AuditEntryRow>()? {
+            entries.push(AuditEntry::try_from(row?)?);
+        }
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,144 @@
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{Ban, BanRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    ban: prepared_statement::PreparedStatement,
+    unban: prepared_statement::PreparedStatement,
+    get: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _BanRow {
+    channel_id: i64,
+    user_id: i64,
+    moderator_id: i64,
+    reason: Option<String>,
+    timeout: bool,
+    created_at: i64,
+    expires_at: i64,
+}
+
+impl From<_BanRow> for Ban {
+    fn from(row: _BanRow) -> Self {
+        Self {
+            channel_id: row.channel_id,
+            user_id: row.user_id,
+            moderator_id: row.moderator_id,
+            reason: row.reason.unwrap_or_default(),
+            timeout: row.timeout,
+            created_at: row.created_at,
+            expires_at: (row.expires_at != 0).then_some(row.expires_at),
+        }
+    }
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    // A TTL of 0 never expires
+    let mut ban = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.channel_bans (channel_id, user_id, moderator_id, reason, timeout, created_at, expires_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
+            USING TTL ?",
+        ))
+        .await?;
+    ban.set_consistency(storage.consistency.writes);
+
+    let mut unban = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.channel_bans
+            WHERE channel_id = ? AND user_id = ?",
+        ))
+        .await?;
+    unban.set_consistency(storage.consistency.writes);
+
+    let mut get = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT channel_id, user_id, moderator_id, reason, timeout, created_at, expires_at
+            FROM ${data}.channel_bans
+            WHERE channel_id = ? AND user_id = ?",
+        ))
+        .await?;
+    get.set_consistency(storage.consistency.reads);
+
+    Ok(_Statements { ban, unban, get })
+}
+
+#[tonic::async_trait]
+impl BanRepository for super::ScyllaStorage {
+    async fn ban(&self, ban: &Ban) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        // Rounded up, so that the row never expires before the ban
+        let ttl_seconds = ban.expires_at.map_or(0, |expires_at| {
+            ((expires_at - ban.created_at + 999) / 1000).clamp(1, i32::MAX.into()) as i32
+        });
+        self.session
+            .execute_unpaged(
+                &statements.ban,
+                (
+                    &ban.channel_id,
+                    &ban.user_id,
+                    &ban.moderator_id,
+                    &ban.reason,
+                    &ban.timeout,
+                    &ban.created_at,
+                    ban.expires_at.unwrap_or(0),
+                    ttl_seconds,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn unban(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.unban, (&channel_id, &user_id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        channel_id: i64,
+        user_id: i64,
+    ) -> Result<Option<Ban>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        Ok(self
+            .session
+            .execute_unpaged(&statements.get, (&channel_id, &user_id))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_BanRow>()?
+            .map(Ban::from))
+    }
+}
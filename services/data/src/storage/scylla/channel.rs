@@ -0,0 +1,607 @@
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::prepared_statement;
+use scylla::statement::{Consistency, PagingState, SerialConsistency};
+use tokio::sync;
+
+use super::{_ChannelRow, _LegacyChannelRow, _LegacyMessageRow};
+use crate::database::DatabaseLayout;
+use crate::storage::{Channel, ChannelRepository, ChannelSource, IdGenerator};
+
+/// Number of compare-and-set attempts to increment a sequence number before giving up under contention.
+const _MAX_SEQUENCE_ATTEMPTS: u32 = 8;
+
+/// Delay before retrying to increment a sequence number, doubled after each attempt and jittered.
+const _SEQUENCE_BACKOFF: Duration = Duration::from_millis(4);
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    create_channel: prepared_statement::PreparedStatement,
+    create_channel_bucket: prepared_statement::PreparedStatement,
+    create_channel_owner: prepared_statement::PreparedStatement,
+    create_channel_member: prepared_statement::PreparedStatement,
+    delete_channel_member: prepared_statement::PreparedStatement,
+    channel_member: prepared_statement::PreparedStatement,
+    query_bucket: Vec<prepared_statement::PreparedStatement>,
+    query_owner: Vec<prepared_statement::PreparedStatement>,
+    query_member: Vec<prepared_statement::PreparedStatement>,
+    channel: prepared_statement::PreparedStatement,
+    set_slow_mode: prepared_statement::PreparedStatement,
+    all: prepared_statement::PreparedStatement,
+    sequence: prepared_statement::PreparedStatement,
+    sequence_serial: prepared_statement::PreparedStatement,
+    sequence_insert: prepared_statement::PreparedStatement,
+    sequence_update: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _ChannelIdRow {
+    id: i64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _MemberRow {
+    joined_id: Option<i64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _SequenceRow {
+    sequence: i64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AppliedChannelRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    id: Option<i64>,
+    name: Option<String>,
+    description: Option<String>,
+    owner_id: Option<i64>,
+    slow_mode_seconds: Option<i32>,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut create_channel = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.channel_by_id (id, name, description, owner_id)
+            VALUES (?, ?, ?, ?)
+            IF NOT EXISTS",
+        ))
+        .await?;
+    create_channel.set_consistency(storage.consistency.lightweight_transactions);
+    create_channel.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut create_channel_bucket = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.channel_by_bucket (id, bucket)
+            VALUES (?, ?)",
+        ))
+        .await?;
+    create_channel_bucket.set_consistency(storage.consistency.writes);
+
+    let mut create_channel_owner = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.channel_by_owner (id, owner_id)
+            VALUES (?, ?)",
+        ))
+        .await?;
+    create_channel_owner.set_consistency(storage.consistency.writes);
+
+    let mut create_channel_member = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.channel_by_member (id, member_id, joined_id)
+            VALUES (?, ?, ?)",
+        ))
+        .await?;
+    create_channel_member.set_consistency(storage.consistency.writes);
+
+    let mut delete_channel_member = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.channel_by_member
+            WHERE member_id = ? AND id = ?",
+        ))
+        .await?;
+    delete_channel_member.set_consistency(storage.consistency.writes);
+
+    let mut channel_member = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT joined_id
+            FROM ${data}.channel_by_member
+            WHERE member_id = ? AND id = ?",
+        ))
+        .await?;
+    channel_member.set_consistency(storage.consistency.reads);
+
+    let mut query_bucket = Vec::new();
+    let mut query_owner = Vec::new();
+    let mut query_member = Vec::new();
+    for newest in [false, true] {
+        for (statements, table, partition_key) in [
+            (&mut query_bucket, "channel_by_bucket", "bucket"),
+            (&mut query_owner, "channel_by_owner", "owner_id"),
+            (&mut query_member, "channel_by_member", "member_id"),
+        ] {
+            let mut statement = storage
+                .session
+                .prepare(storage.layout.resolve(&format!(
+                    r"SELECT id
+                    FROM ${{data}}.{}
+                    WHERE {} = ? AND id > ? AND id < ?
+                    ORDER BY id {}
+                    LIMIT ?",
+                    table,
+                    partition_key,
+                    if newest { "DESC" } else { "ASC" }
+                )))
+                .await?;
+            statement.set_consistency(storage.consistency.reads);
+
+            statements.push(statement);
+        }
+    }
+
+    let mut channel = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, name, description, owner_id, slow_mode_seconds
+            FROM ${data}.channel_by_id
+            WHERE id = ?",
+        ))
+        .await?;
+    channel.set_consistency(storage.consistency.reads);
+
+    let mut set_slow_mode = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.channel_by_id
+            SET slow_mode_seconds = ?
+            WHERE id = ?",
+        ))
+        .await?;
+    set_slow_mode.set_consistency(storage.consistency.writes);
+
+    // Full scans are only used to warm up in-process caches, which tolerate stale rows
+    let mut all = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, name, description, owner_id, slow_mode_seconds
+            FROM ${data}.channel_by_id",
+        ))
+        .await?;
+    all.set_consistency(Consistency::One);
+    all.set_page_size(1000);
+
+    let mut sequence = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT sequence
+            FROM ${data}.channel_sequence
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    sequence.set_consistency(storage.consistency.reads);
+
+    // Read the latest committed value before a compare-and-set, including uncommitted Paxos rounds
+    let mut sequence_serial = sequence.clone();
+    sequence_serial.set_consistency(match storage.consistency.serial {
+        SerialConsistency::Serial => Consistency::Serial,
+        SerialConsistency::LocalSerial => Consistency::LocalSerial,
+    });
+
+    let mut sequence_insert = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.channel_sequence (channel_id, sequence)
+            VALUES (?, 1)
+            IF NOT EXISTS",
+        ))
+        .await?;
+    sequence_insert.set_consistency(storage.consistency.lightweight_transactions);
+    sequence_insert.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut sequence_update = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.channel_sequence
+            SET sequence = ?
+            WHERE channel_id = ?
+            IF sequence = ?",
+        ))
+        .await?;
+    sequence_update.set_consistency(storage.consistency.lightweight_transactions);
+    sequence_update.set_serial_consistency(Some(storage.consistency.serial));
+
+    Ok(_Statements {
+        create_channel,
+        create_channel_bucket,
+        create_channel_owner,
+        create_channel_member,
+        delete_channel_member,
+        channel_member,
+        query_bucket,
+        query_owner,
+        query_member,
+        channel,
+        set_slow_mode,
+        all,
+        sequence,
+        sequence_serial,
+        sequence_insert,
+        sequence_update,
+    })
+}
+
+#[tonic::async_trait]
+impl ChannelRepository for super::ScyllaStorage {
+    async fn create(
+        &self,
+        name: &str,
+        description: &str,
+        owner_id: i64,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        let mut id = generate_id();
+        loop {
+            let row = self
+                .session
+                .execute_unpaged(
+                    &statements.create_channel,
+                    (&id, name, description, &owner_id),
+                )
+                .await?
+                .into_rows_result()?
+                .single_row::<_AppliedChannelRow>()?;
+
+            if row.applied {
+                break;
+            }
+
+            id = generate_id();
+        }
+
+        for (statement, value) in [
+            (&statements.create_channel_bucket, Self::bucket(id)),
+            (&statements.create_channel_owner, owner_id),
+        ] {
+            self.session
+                .execute_unpaged(statement, (&id, &value))
+                .await?;
+        }
+        self.session
+            .execute_unpaged(&statements.create_channel_member, (&id, &owner_id, &id))
+            .await?;
+
+        Ok(id)
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Channel>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.channel, (&id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_ChannelRow>()?;
+
+        Ok(row.map(Channel::from))
+    }
+
+    async fn set_slow_mode(
+        &self,
+        id: i64,
+        seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.set_slow_mode, (&seconds, &id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn add_member(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+        joined_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(
+                &statements.create_channel_member,
+                (&channel_id, &member_id, &joined_id),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn joined_id(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.channel_member, (&member_id, &channel_id))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_MemberRow>()?;
+
+        // Channel IDs are snowflakes of their creation time, the earliest a member could have joined
+        Ok(row.map(|row| row.joined_id.unwrap_or(channel_id)))
+    }
+
+    async fn remove_member(
+        &self,
+        channel_id: i64,
+        member_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.delete_channel_member, (&member_id, &channel_id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn next_sequence(
+        &self,
+        channel_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        for attempt in 0.._MAX_SEQUENCE_ATTEMPTS {
+            let current = self
+                .session
+                .execute_unpaged(&statements.sequence_serial, (&channel_id,))
+                .await?
+                .into_rows_result()?
+                .maybe_first_row::<_SequenceRow>()?;
+
+            let (row, next) = match current {
+                None => (
+                    self.session
+                        .execute_unpaged(&statements.sequence_insert, (&channel_id,))
+                        .await?,
+                    1,
+                ),
+                Some(current) => (
+                    self.session
+                        .execute_unpaged(
+                            &statements.sequence_update,
+                            (current.sequence + 1, &channel_id, current.sequence),
+                        )
+                        .await?,
+                    current.sequence + 1,
+                ),
+            };
+
+            // The columns returned alongside `[applied]` by a conditional update vary, only inspect the first one
+            let row = row.into_rows_result()?.single_row::<Row>()?;
+            if matches!(row.columns.first(), Some(Some(CqlValue::Boolean(true)))) {
+                return Ok(next);
+            }
+
+            // Spread the retries of concurrent writers, which would otherwise keep conflicting
+            let backoff = _SEQUENCE_BACKOFF * 2u32.pow(attempt);
+            tokio::time::sleep(backoff.mul_f64(rand::random_range(0.5..1.0))).await;
+        }
+
+        Err(format!(
+            "Unable to increment the sequence number of channel {} after {} attempts",
+            channel_id, _MAX_SEQUENCE_ATTEMPTS
+        )
+        .into())
+    }
+
+    async fn sequence(
+        &self,
+        channel_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.sequence, (&channel_id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_SequenceRow>()?;
+
+        Ok(row.map_or(0, |row| row.sequence))
+    }
+
+    async fn scan(
+        &self,
+        source: &ChannelSource,
+        newest: bool,
+        lower: i64,
+        upper: i64,
+        limit: i32,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        let partitions: Vec<(&prepared_statement::PreparedStatement, i64)> = match source {
+            ChannelSource::All => {
+                let buckets =
+                    (Self::bucket(lower.max(0))..=Self::bucket(upper)).collect::<Vec<_>>();
+                let statement = &statements.query_bucket[newest as usize];
+                if newest {
+                    buckets.into_iter().rev().map(|b| (statement, b)).collect()
+                } else {
+                    buckets.into_iter().map(|b| (statement, b)).collect()
+                }
+            }
+            ChannelSource::Owner(owner_id) => {
+                vec![(&statements.query_owner[newest as usize], *owner_id)]
+            }
+            ChannelSource::Member(member_id) => {
+                vec![(&statements.query_member[newest as usize], *member_id)]
+            }
+        };
+
+        let mut ids = Vec::new();
+        for (statement, partition) in partitions {
+            let remaining = limit - ids.len() as i32;
+            if remaining <= 0 {
+                break;
+            }
+
+            let temp = self
+                .session
+                .execute_unpaged(statement, (partition, lower, upper, remaining))
+                .await?
+                .into_rows_result()?;
+
+            ids.extend(temp.rows::<_ChannelIdRow>()?.flatten().map(|row| row.id));
+        }
+
+        Ok(ids)
+    }
+
+    async fn for_each(
+        &self,
+        f: &mut (dyn FnMut(Channel) + Send),
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        let mut paging_state = PagingState::start();
+        loop {
+            let (result, paging_state_response) = self
+                .session
+                .execute_single_page(&statements.all, (), paging_state)
+                .await?;
+
+            for row in result.into_rows_result()?.rows::<_ChannelRow>()? {
+                f(row?.into());
+            }
+
+            match paging_state_response.into_paging_control_flow() {
+                ControlFlow::Break(()) => break,
+                ControlFlow::Continue(next) => paging_state = next,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Populate `data.channel_by_bucket`, `data.channel_by_owner` and `data.channel_by_member` from the
+/// existing channels and messages, returning the number of scanned rows.
+///
+/// This operation is idempotent and can be safely interrupted and resumed.
+pub async fn migrate_channel_indexes(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut channels = session
+        .prepare(layout.resolve(
+            r"SELECT id, name, description, owner_id
+            FROM ${data}.channel_by_id",
+        ))
+        .await?;
+    channels.set_consistency(Consistency::Quorum);
+    channels.set_page_size(1000);
+
+    let mut messages = session
+        .prepare(layout.resolve(
+            r"SELECT id, content, author_id, channel_id
+            FROM ${data}.message_by_id",
+        ))
+        .await?;
+    messages.set_consistency(Consistency::Quorum);
+    messages.set_page_size(1000);
+
+    let mut inserts = Vec::new();
+    for query in [
+        r"INSERT INTO ${data}.channel_by_bucket (id, bucket) VALUES (?, ?)",
+        r"INSERT INTO ${data}.channel_by_owner (id, owner_id) VALUES (?, ?)",
+        r"INSERT INTO ${data}.channel_by_member (id, member_id) VALUES (?, ?)",
+    ] {
+        let mut statement = session.prepare(layout.resolve(query)).await?;
+        statement.set_consistency(Consistency::Quorum);
+        inserts.push(statement);
+    }
+
+    let mut count = 0;
+    let mut paging_state = PagingState::start();
+    loop {
+        let (result, paging_state_response) = session
+            .execute_single_page(&channels, (), paging_state)
+            .await?;
+
+        for row in result.into_rows_result()?.rows::<_LegacyChannelRow>()? {
+            let row = row?;
+            for (statement, value) in inserts.iter().zip([
+                super::ScyllaStorage::bucket(row.id),
+                row.owner_id,
+                row.owner_id,
+            ]) {
+                session
+                    .execute_unpaged(statement, (&row.id, &value))
+                    .await?;
+            }
+            count += 1;
+        }
+
+        match paging_state_response.into_paging_control_flow() {
+            ControlFlow::Break(()) => break,
+            ControlFlow::Continue(next) => paging_state = next,
+        }
+    }
+
+    let mut paging_state = PagingState::start();
+    loop {
+        let (result, paging_state_response) = session
+            .execute_single_page(&messages, (), paging_state)
+            .await?;
+
+        for row in result.into_rows_result()?.rows::<_LegacyMessageRow>()? {
+            let row = row?;
+            session
+                .execute_unpaged(&inserts[2], (&row.channel_id, &row.author_id))
+                .await?;
+            count += 1;
+        }
+
+        match paging_state_response.into_paging_control_flow() {
+            ControlFlow::Break(()) => break,
+            ControlFlow::Continue(next) => paging_state = next,
+        }
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,44 @@
+use scylla::macros;
+use scylla::query::Query;
+
+use crate::storage::ConfigRepository;
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _InsertCFGTextRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    key: Option<String>,
+    value: Option<String>,
+}
+
+#[tonic::async_trait]
+impl ConfigRepository for super::ScyllaStorage {
+    async fn insert_text(
+        &self,
+        key: &str,
+        value: &str,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut query = Query::new(self.layout.resolve(
+            r"INSERT INTO ${config}.cfg_text (key, value)
+            VALUES (?, ?)
+            IF NOT EXISTS",
+        ));
+        query.set_consistency(self.consistency.lightweight_transactions);
+        query.set_serial_consistency(Some(self.consistency.serial));
+
+        let row = self
+            .session
+            .query_unpaged(query, (key, value))
+            .await?
+            .into_rows_result()?
+            .single_row::<_InsertCFGTextRow>()?;
+
+        if row.applied {
+            Ok(value.to_string())
+        } else {
+            row.value
+                .ok_or_else(|| format!("Unable to fetch config {:?}", key).into())
+        }
+    }
+}
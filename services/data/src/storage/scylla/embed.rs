@@ -0,0 +1,134 @@
+use std::collections;
+
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{Embed, EmbedRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    clear: prepared_statement::PreparedStatement,
+    insert: prepared_statement::PreparedStatement,
+    list_many: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _MessageEmbedRow {
+    message_id: i64,
+    url: String,
+    title: String,
+    description: String,
+    image_url: String,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut clear = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.embeds_by_message
+            WHERE message_id = ?",
+        ))
+        .await?;
+    clear.set_consistency(storage.consistency.writes);
+
+    let mut insert = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.embeds_by_message (message_id, position, url, title, description, image_url)
+            VALUES (?, ?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    insert.set_consistency(storage.consistency.writes);
+
+    // Rows of each partition are returned in clustering order, i.e. by position
+    let mut list_many = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT message_id, url, title, description, image_url
+            FROM ${data}.embeds_by_message
+            WHERE message_id IN ?",
+        ))
+        .await?;
+    list_many.set_consistency(storage.consistency.reads);
+
+    Ok(_Statements {
+        clear,
+        insert,
+        list_many,
+    })
+}
+
+#[tonic::async_trait]
+impl EmbedRepository for super::ScyllaStorage {
+    async fn set(
+        &self,
+        message_id: i64,
+        embeds: &[Embed],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.clear, (&message_id,))
+            .await?;
+
+        for (position, embed) in embeds.iter().enumerate() {
+            self.session
+                .execute_unpaged(
+                    &statements.insert,
+                    (
+                        &message_id,
+                        position as i32,
+                        &embed.url,
+                        &embed.title,
+                        &embed.description,
+                        &embed.image_url,
+                    ),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_many(
+        &self,
+        message_ids: &[i64],
+    ) -> Result<collections::HashMap<i64, Vec<Embed>>, Box<dyn std::error::Error + Send + Sync>>
+    {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list_many, (message_ids,))
+            .await?
+            .into_rows_result()?;
+
+        let mut embeds = collections::HashMap::<_, Vec<_>>::new();
+        for row in result.rows::<_MessageEmbedRow>()? {
+            let row = row?;
+            embeds.entry(row.message_id).or_default().push(Embed {
+                url: row.url,
+                title: row.title,
+                description: row.description,
+                image_url: row.image_url,
+            });
+        }
+
+        Ok(embeds)
+    }
+}
@@ -0,0 +1,150 @@
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::IdempotencyRepository;
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    get: prepared_statement::PreparedStatement,
+    claim: prepared_statement::PreparedStatement,
+    reclaim: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _IdempotencyKeyRow {
+    id: i64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AppliedIdempotencyKeyRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    key: Option<String>,
+    id: Option<i64>,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut get = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id
+            FROM ${data}.idempotency_keys
+            WHERE key = ?",
+        ))
+        .await?;
+    get.set_consistency(storage.consistency.reads);
+
+    let mut claim = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.idempotency_keys (key, id)
+            VALUES (?, ?)
+            IF NOT EXISTS
+            USING TTL ?",
+        ))
+        .await?;
+    claim.set_consistency(storage.consistency.lightweight_transactions);
+    claim.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut reclaim = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.idempotency_keys
+            USING TTL ?
+            SET id = ?
+            WHERE key = ?
+            IF id = ?",
+        ))
+        .await?;
+    reclaim.set_consistency(storage.consistency.lightweight_transactions);
+    reclaim.set_serial_consistency(Some(storage.consistency.serial));
+
+    Ok(_Statements {
+        get,
+        claim,
+        reclaim,
+    })
+}
+
+#[tonic::async_trait]
+impl IdempotencyRepository for super::ScyllaStorage {
+    async fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        Ok(self
+            .session
+            .execute_unpaged(&statements.get, (key,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_IdempotencyKeyRow>()?
+            .map(|row| row.id))
+    }
+
+    async fn claim(
+        &self,
+        key: &str,
+        id: i64,
+        ttl_seconds: i32,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.claim, (key, &id, &ttl_seconds))
+            .await?
+            .into_rows_result()?
+            .single_row::<_AppliedIdempotencyKeyRow>()?;
+
+        // A failed transaction returns the current row
+        match row {
+            _AppliedIdempotencyKeyRow { applied: true, .. } => Ok(id),
+            _AppliedIdempotencyKeyRow {
+                id: Some(existing), ..
+            } => Ok(existing),
+            _ => Err(format!("Idempotency key {:?} is neither claimed nor available", key).into()),
+        }
+    }
+
+    async fn reclaim(
+        &self,
+        key: &str,
+        abandoned_id: i64,
+        id: i64,
+        ttl_seconds: i32,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.reclaim, (&ttl_seconds, &id, key, &abandoned_id))
+            .await?
+            .into_rows_result()?
+            .single_row::<Row>()?;
+
+        // A failed transaction returns the current ID after `[applied]`, which is null once the key expired
+        match (row.columns.first(), row.columns.get(1)) {
+            (Some(Some(CqlValue::Boolean(true))), _) => Ok(id),
+            (_, Some(Some(CqlValue::BigInt(existing)))) => Ok(*existing),
+            _ => self.claim(key, id, ttl_seconds).await,
+        }
+    }
+}
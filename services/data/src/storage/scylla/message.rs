@@ -0,0 +1,606 @@
+use std::ops::ControlFlow;
+
+use scylla::macros;
+use scylla::prepared_statement;
+use scylla::statement::{Consistency, PagingState};
+use tokio::sync;
+
+use super::{_LegacyMessageRow, _MessageRow};
+use crate::database::DatabaseLayout;
+use crate::storage::{IdGenerator, Mentions, Message, MessageRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    create_message1: prepared_statement::PreparedStatement,
+    create_message2: prepared_statement::PreparedStatement,
+    create_mention: prepared_statement::PreparedStatement,
+    rewrite_message: prepared_statement::PreparedStatement,
+    delete_message1: prepared_statement::PreparedStatement,
+    delete_message2: prepared_statement::PreparedStatement,
+    delete_mention: prepared_statement::PreparedStatement,
+    create_attachment_reference: prepared_statement::PreparedStatement,
+    delete_attachment_reference: prepared_statement::PreparedStatement,
+    history: Vec<prepared_statement::PreparedStatement>,
+    message: prepared_statement::PreparedStatement,
+    mentions: prepared_statement::PreparedStatement,
+    attachment_references: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _AppliedMessageRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    id: Option<i64>,
+    content: Option<String>,
+    author_id: Option<i64>,
+    channel_id: Option<i64>,
+    mention_ids: Option<Vec<i64>>,
+    mentions_channel: Option<bool>,
+    attachment_ids: Option<Vec<i64>>,
+    rich_content: Option<Vec<u8>>,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut create_message1 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.message_by_id (id, content, author_id, channel_id, mention_ids, mentions_channel, attachment_ids, rich_content)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            IF NOT EXISTS
+            USING TTL ?",
+        ))
+        .await?;
+    create_message1.set_consistency(storage.consistency.lightweight_transactions);
+    create_message1.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut create_message2 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.message_by_channel_bucket (id, content, author_id, channel_id, mention_ids, mentions_channel, attachment_ids, rich_content, bucket)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            USING TTL ?",
+        ))
+        .await?;
+    create_message2.set_consistency(storage.consistency.writes);
+
+    let mut create_mention = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.mentions_by_user (user_id, message_id, channel_id)
+            VALUES (?, ?, ?)
+            USING TTL ?",
+        ))
+        .await?;
+    create_mention.set_consistency(storage.consistency.writes);
+
+    // Inserting the same values again replaces the TTL of every cell, a TTL of 0 never expires
+    let mut rewrite_message = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.message_by_id (id, content, author_id, channel_id, mention_ids, mentions_channel, attachment_ids, rich_content)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            USING TTL ?",
+        ))
+        .await?;
+    rewrite_message.set_consistency(storage.consistency.writes);
+
+    let mut delete_message1 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.message_by_id
+            WHERE id = ?",
+        ))
+        .await?;
+    delete_message1.set_consistency(storage.consistency.writes);
+
+    let mut delete_message2 = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.message_by_channel_bucket
+            WHERE channel_id = ? AND bucket = ? AND id = ?",
+        ))
+        .await?;
+    delete_message2.set_consistency(storage.consistency.writes);
+
+    let mut delete_mention = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.mentions_by_user
+            WHERE user_id = ? AND message_id = ?",
+        ))
+        .await?;
+    delete_mention.set_consistency(storage.consistency.writes);
+
+    let mut create_attachment_reference = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.messages_by_attachment (attachment_id, message_id)
+            VALUES (?, ?)
+            USING TTL ?",
+        ))
+        .await?;
+    create_attachment_reference.set_consistency(storage.consistency.writes);
+
+    let mut delete_attachment_reference = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.messages_by_attachment
+            WHERE attachment_id = ? AND message_id = ?",
+        ))
+        .await?;
+    delete_attachment_reference.set_consistency(storage.consistency.writes);
+
+    let mut history = Vec::new();
+    for newest in [false, true] {
+        let mut statement = storage
+            .session
+            .prepare(storage.layout.resolve(&format!(
+                r"SELECT id, content, author_id, channel_id, mention_ids, mentions_channel, attachment_ids, rich_content
+                FROM ${{data}}.message_by_channel_bucket
+                WHERE channel_id = ? AND bucket = ? AND id <= ? AND id >= ?
+                ORDER BY id {}
+                LIMIT ?",
+                if newest { "DESC" } else { "ASC" }
+            )))
+            .await?;
+        statement.set_consistency(storage.consistency.reads);
+
+        history.push(statement);
+    }
+
+    let mut message = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT id, content, author_id, channel_id, mention_ids, mentions_channel, attachment_ids, rich_content
+            FROM ${data}.message_by_id
+            WHERE id = ?",
+        ))
+        .await?;
+    message.set_consistency(storage.consistency.reads);
+
+    let mut mentions = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT message_id
+            FROM ${data}.mentions_by_user
+            WHERE user_id = ? AND message_id < ?
+            LIMIT ?",
+        ))
+        .await?;
+    mentions.set_consistency(storage.consistency.reads);
+
+    let mut attachment_references = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT message_id
+            FROM ${data}.messages_by_attachment
+            WHERE attachment_id = ?",
+        ))
+        .await?;
+    attachment_references.set_consistency(storage.consistency.reads);
+
+    Ok(_Statements {
+        create_message1,
+        create_message2,
+        create_mention,
+        rewrite_message,
+        delete_message1,
+        delete_message2,
+        delete_mention,
+        create_attachment_reference,
+        delete_attachment_reference,
+        history,
+        message,
+        mentions,
+        attachment_references,
+    })
+}
+
+#[tonic::async_trait]
+impl MessageRepository for super::ScyllaStorage {
+    async fn create(
+        &self,
+        content: &str,
+        rich_content: &[u8],
+        author_id: i64,
+        channel_id: i64,
+        mentions: &Mentions,
+        attachment_ids: &[i64],
+        ttl_seconds: i32,
+        generate_id: IdGenerator<'_>,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        let mut id = generate_id();
+        loop {
+            let row = self
+                .session
+                .execute_unpaged(
+                    &statements.create_message1,
+                    (
+                        &id,
+                        content,
+                        &author_id,
+                        &channel_id,
+                        &mentions.user_ids,
+                        &mentions.channel,
+                        attachment_ids,
+                        rich_content,
+                        &ttl_seconds,
+                    ),
+                )
+                .await?
+                .into_rows_result()?
+                .single_row::<_AppliedMessageRow>()?;
+
+            if row.applied {
+                break;
+            }
+
+            id = generate_id();
+        }
+
+        self.session
+            .execute_unpaged(
+                &statements.create_message2,
+                (
+                    &id,
+                    content,
+                    &author_id,
+                    &channel_id,
+                    &mentions.user_ids,
+                    &mentions.channel,
+                    attachment_ids,
+                    rich_content,
+                    Self::bucket(id),
+                    &ttl_seconds,
+                ),
+            )
+            .await?;
+
+        for user_id in &mentions.user_ids {
+            self.session
+                .execute_unpaged(
+                    &statements.create_mention,
+                    (user_id, &id, &channel_id, &ttl_seconds),
+                )
+                .await?;
+        }
+        for attachment_id in attachment_ids {
+            self.session
+                .execute_unpaged(
+                    &statements.create_attachment_reference,
+                    (attachment_id, &id, &ttl_seconds),
+                )
+                .await?;
+        }
+
+        Ok(id)
+    }
+
+    async fn set_ttl(
+        &self,
+        message: &Message,
+        ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(
+                &statements.rewrite_message,
+                (
+                    &message.id,
+                    &message.content,
+                    &message.author_id,
+                    &message.channel_id,
+                    &message.mentions.user_ids,
+                    &message.mentions.channel,
+                    &message.attachment_ids,
+                    &message.rich_content,
+                    &ttl_seconds,
+                ),
+            )
+            .await?;
+        self.session
+            .execute_unpaged(
+                &statements.create_message2,
+                (
+                    &message.id,
+                    &message.content,
+                    &message.author_id,
+                    &message.channel_id,
+                    &message.mentions.user_ids,
+                    &message.mentions.channel,
+                    &message.attachment_ids,
+                    &message.rich_content,
+                    Self::bucket(message.id),
+                    &ttl_seconds,
+                ),
+            )
+            .await?;
+
+        for user_id in &message.mentions.user_ids {
+            self.session
+                .execute_unpaged(
+                    &statements.create_mention,
+                    (user_id, &message.id, &message.channel_id, &ttl_seconds),
+                )
+                .await?;
+        }
+        for attachment_id in &message.attachment_ids {
+            self.session
+                .execute_unpaged(
+                    &statements.create_attachment_reference,
+                    (attachment_id, &message.id, &ttl_seconds),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn delete(
+        &self,
+        message: &Message,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        for user_id in &message.mentions.user_ids {
+            self.session
+                .execute_unpaged(&statements.delete_mention, (user_id, &message.id))
+                .await?;
+        }
+        for attachment_id in &message.attachment_ids {
+            self.session
+                .execute_unpaged(
+                    &statements.delete_attachment_reference,
+                    (attachment_id, &message.id),
+                )
+                .await?;
+        }
+        self.session
+            .execute_unpaged(
+                &statements.delete_message2,
+                (&message.channel_id, Self::bucket(message.id), &message.id),
+            )
+            .await?;
+        self.session
+            .execute_unpaged(&statements.delete_message1, (&message.id,))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get(
+        &self,
+        id: i64,
+    ) -> Result<Option<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.message, (&id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_MessageRow>()?;
+
+        Ok(row.map(Message::from))
+    }
+
+    async fn history(
+        &self,
+        channel_id: i64,
+        after_id: i64,
+        before_id: i64,
+        newest: bool,
+        limit: i32,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        // Messages of a channel cannot be older than the channel itself, this bounds the buckets we have to walk through.
+        let lowest = Self::bucket(after_id.max(channel_id));
+        let highest = Self::bucket(before_id);
+        let buckets: Box<dyn Iterator<Item = i64> + Send> = if newest {
+            Box::new((lowest..=highest).rev())
+        } else {
+            Box::new(lowest..=highest)
+        };
+
+        let mut messages = Vec::new();
+        for bucket in buckets {
+            let remaining = limit - messages.len() as i32;
+            if remaining <= 0 {
+                break;
+            }
+
+            let temp = self
+                .session
+                .execute_unpaged(
+                    &statements.history[newest as usize],
+                    (channel_id, bucket, before_id, after_id, remaining),
+                )
+                .await?
+                .into_rows_result()?;
+
+            for row in temp.rows::<_MessageRow>()? {
+                messages.push(row?.into());
+            }
+        }
+
+        Ok(messages)
+    }
+
+    async fn mentioning(
+        &self,
+        user_id: i64,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Message>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.mentions, (&user_id, &before_id, &limit))
+            .await?
+            .into_rows_result()?;
+
+        let mut messages = Vec::new();
+        for row in result.rows::<(i64,)>()? {
+            let (id,) = row?;
+            if let Some(message) = self.get(id).await? {
+                messages.push(message);
+            }
+        }
+
+        Ok(messages)
+    }
+
+    async fn attached_to(
+        &self,
+        attachment_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.attachment_references, (&attachment_id,))
+            .await?
+            .into_rows_result()?;
+
+        let mut ids = Vec::new();
+        for row in result.rows::<(i64,)>()? {
+            let (id,) = row?;
+            ids.push(id);
+        }
+
+        Ok(ids)
+    }
+}
+
+/// Copy every message from the legacy `data.message_by_channel_id` table into the time-bucketed
+/// `data.message_by_channel_bucket` table, returning the number of copied messages.
+///
+/// This operation is idempotent and can be safely interrupted and resumed.
+pub async fn migrate_message_buckets(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut select = session
+        .prepare(layout.resolve(
+            r"SELECT id, content, author_id, channel_id
+            FROM ${data}.message_by_channel_id",
+        ))
+        .await?;
+    select.set_consistency(Consistency::Quorum);
+    select.set_page_size(1000);
+
+    let mut insert = session
+        .prepare(layout.resolve(
+            r"INSERT INTO ${data}.message_by_channel_bucket (id, content, author_id, channel_id, bucket)
+            VALUES (?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    insert.set_consistency(Consistency::Quorum);
+
+    let mut count = 0;
+    let mut paging_state = PagingState::start();
+    loop {
+        let (result, paging_state_response) = session
+            .execute_single_page(&select, (), paging_state)
+            .await?;
+
+        for row in result.into_rows_result()?.rows::<_LegacyMessageRow>()? {
+            let row = row?;
+            session
+                .execute_unpaged(
+                    &insert,
+                    (
+                        &row.id,
+                        &row.content,
+                        &row.author_id,
+                        &row.channel_id,
+                        super::ScyllaStorage::bucket(row.id),
+                    ),
+                )
+                .await?;
+            count += 1;
+        }
+
+        match paging_state_response.into_paging_control_flow() {
+            ControlFlow::Break(()) => break,
+            ControlFlow::Continue(next) => paging_state = next,
+        }
+    }
+
+    Ok(count)
+}
+
+/// Record the messages each attachment is attached to, for the messages created before they were recorded, returning
+/// the number of messages with attachments.
+///
+/// References expire along with their message. This operation is idempotent and can be safely interrupted and resumed.
+pub async fn migrate_attachment_references(
+    session: &scylla::Session,
+    layout: &DatabaseLayout,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    // The time to live of a collection cannot be selected, all cells of a message share the same one
+    let mut select = session
+        .prepare(layout.resolve(
+            r"SELECT id, attachment_ids, TTL(content)
+            FROM ${data}.message_by_id",
+        ))
+        .await?;
+    select.set_consistency(Consistency::Quorum);
+    select.set_page_size(1000);
+
+    let mut insert = session
+        .prepare(layout.resolve(
+            r"INSERT INTO ${data}.messages_by_attachment (attachment_id, message_id)
+            VALUES (?, ?)
+            USING TTL ?",
+        ))
+        .await?;
+    insert.set_consistency(Consistency::Quorum);
+
+    let mut count = 0;
+    let mut paging_state = PagingState::start();
+    loop {
+        let (result, paging_state_response) = session
+            .execute_single_page(&select, (), paging_state)
+            .await?;
+
+        for row in result
+            .into_rows_result()?
+            .rows::<(i64, Option<Vec<i64>>, Option<i32>)>()?
+        {
+            let (id, attachment_ids, ttl_seconds) = row?;
+            let attachment_ids = attachment_ids.unwrap_or_default();
+            for attachment_id in &attachment_ids {
+                session
+                    .execute_unpaged(&insert, (attachment_id, &id, ttl_seconds.unwrap_or(0)))
+                    .await?;
+            }
+            if !attachment_ids.is_empty() {
+                count += 1;
+            }
+        }
+
+        match paging_state_response.into_paging_control_flow() {
+            ControlFlow::Break(()) => break,
+            ControlFlow::Continue(next) => paging_state = next,
+        }
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use scylla::macros;
+
+use crate::database::{ConsistencyProfile, DatabaseLayout};
+
+// Import `impl`s for `ScyllaStorage`.
+mod account;
+mod attachment;
+mod audit;
+mod ban;
+mod channel;
+mod config;
+mod embed;
+mod idempotency;
+mod message;
+mod moderation;
+mod pin;
+mod rate_limit;
+mod read_state;
+mod relationship;
+mod retention;
+
+pub use channel::migrate_channel_indexes;
+pub use message::{migrate_attachment_references, migrate_message_buckets};
+
+/// Width of a time bucket of `data.message_by_channel_bucket` partitions, in milliseconds (10 days).
+///
+/// Changing this value invalidates every existing partition, do not modify it on a live cluster.
+const _BUCKET_WIDTH_MILLISECONDS: i64 = 10 * 24 * 60 * 60 * 1000;
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _ChannelRow {
+    id: i64,
+    name: String,
+    description: String,
+    owner_id: i64,
+    slow_mode_seconds: Option<i32>,
+}
+
+impl From<_ChannelRow> for super::Channel {
+    fn from(row: _ChannelRow) -> Self {
+        Self {
+            id: row.id,
+            name: row.name,
+            description: row.description,
+            owner_id: row.owner_id,
+            slow_mode_seconds: row.slow_mode_seconds.unwrap_or(0),
+        }
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _MessageRow {
+    id: i64,
+    content: String,
+    author_id: i64,
+    channel_id: i64,
+    mention_ids: Option<Vec<i64>>,
+    mentions_channel: Option<bool>,
+    attachment_ids: Option<Vec<i64>>,
+    rich_content: Option<Vec<u8>>,
+}
+
+impl From<_MessageRow> for super::Message {
+    fn from(row: _MessageRow) -> Self {
+        Self {
+            id: row.id,
+            content: row.content,
+            author_id: row.author_id,
+            channel_id: row.channel_id,
+            mentions: super::Mentions {
+                user_ids: row.mention_ids.unwrap_or_default(),
+                channel: row.mentions_channel.unwrap_or_default(),
+            },
+            attachment_ids: row.attachment_ids.unwrap_or_default(),
+            rich_content: row.rich_content.unwrap_or_default(),
+        }
+    }
+}
+
+/// A message row with the columns of the initial schema only, read by data migrations which may run before later
+/// columns exist.
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _LegacyMessageRow {
+    id: i64,
+    content: String,
+    author_id: i64,
+    channel_id: i64,
+}
+
+/// A channel row with the columns of the initial schema only, see [`_LegacyMessageRow`].
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _LegacyChannelRow {
+    id: i64,
+    name: String,
+    description: String,
+    owner_id: i64,
+}
+
+/// Storage backed by a ScyllaDB cluster, whose schema is managed by [`crate::migrations`].
+pub struct ScyllaStorage {
+    consistency: ConsistencyProfile,
+    layout: DatabaseLayout,
+    session: Arc<scylla::Session>,
+}
+
+impl ScyllaStorage {
+    pub fn new(
+        session: Arc<scylla::Session>,
+        layout: DatabaseLayout,
+        consistency: ConsistencyProfile,
+    ) -> Self {
+        Self {
+            consistency,
+            layout,
+            session,
+        }
+    }
+
+    /// Compute the time bucket of a snowflake ID.
+    fn bucket(id: i64) -> i64 {
+        (id >> 16) / _BUCKET_WIDTH_MILLISECONDS
+    }
+}
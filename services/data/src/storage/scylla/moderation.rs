@@ -0,0 +1,277 @@
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{Flag, ModerationAction, ModerationFilter, ModerationRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// The partition of `flagged_messages` holding every flag.
+const _FLAG_SHARD: i32 = 0;
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    clear_filters: prepared_statement::PreparedStatement,
+    insert_filter: prepared_statement::PreparedStatement,
+    list_filters: prepared_statement::PreparedStatement,
+    flag: prepared_statement::PreparedStatement,
+    get_flag: prepared_statement::PreparedStatement,
+    list_flags: prepared_statement::PreparedStatement,
+    resolve: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _FilterRow {
+    pattern: String,
+    regex: bool,
+    action: String,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _FlagRow {
+    message_id: i64,
+    channel_id: i64,
+    reasons: Option<Vec<String>>,
+    flagged_at: i64,
+}
+
+impl From<_FlagRow> for Flag {
+    fn from(row: _FlagRow) -> Self {
+        Self {
+            message_id: row.message_id,
+            channel_id: row.channel_id,
+            reasons: row.reasons.unwrap_or_default(),
+            flagged_at: row.flagged_at,
+        }
+    }
+}
+
+fn _action_name(action: ModerationAction) -> &'static str {
+    match action {
+        ModerationAction::Reject => "reject",
+        ModerationAction::Mask => "mask",
+        ModerationAction::Flag => "flag",
+    }
+}
+
+fn _parse_action(name: &str) -> Result<ModerationAction, Box<dyn std::error::Error + Send + Sync>> {
+    match name {
+        "reject" => Ok(ModerationAction::Reject),
+        "mask" => Ok(ModerationAction::Mask),
+        "flag" => Ok(ModerationAction::Flag),
+        _ => Err(format!("Unknown moderation action {:?}", name).into()),
+    }
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut clear_filters = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.moderation_filters_by_channel
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    clear_filters.set_consistency(storage.consistency.writes);
+
+    let mut insert_filter = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.moderation_filters_by_channel (channel_id, position, pattern, regex, action)
+            VALUES (?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    insert_filter.set_consistency(storage.consistency.writes);
+
+    let mut list_filters = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT pattern, regex, action
+            FROM ${data}.moderation_filters_by_channel
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    list_filters.set_consistency(storage.consistency.reads);
+
+    let mut flag = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.flagged_messages (shard, message_id, channel_id, reasons, flagged_at)
+            VALUES (?, ?, ?, ?, ?)",
+        ))
+        .await?;
+    flag.set_consistency(storage.consistency.writes);
+
+    let mut get_flag = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT message_id, channel_id, reasons, flagged_at
+            FROM ${data}.flagged_messages
+            WHERE shard = ? AND message_id = ?",
+        ))
+        .await?;
+    get_flag.set_consistency(storage.consistency.reads);
+
+    let mut list_flags = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT message_id, channel_id, reasons, flagged_at
+            FROM ${data}.flagged_messages
+            WHERE shard = ? AND message_id < ?
+            LIMIT ?",
+        ))
+        .await?;
+    list_flags.set_consistency(storage.consistency.reads);
+
+    let mut resolve = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.flagged_messages
+            WHERE shard = ? AND message_id = ?",
+        ))
+        .await?;
+    resolve.set_consistency(storage.consistency.writes);
+
+    Ok(_Statements {
+        clear_filters,
+        insert_filter,
+        list_filters,
+        flag,
+        get_flag,
+        list_flags,
+        resolve,
+    })
+}
+
+#[tonic::async_trait]
+impl ModerationRepository for super::ScyllaStorage {
+    async fn set_filters(
+        &self,
+        channel_id: i64,
+        filters: &[ModerationFilter],
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.clear_filters, (&channel_id,))
+            .await?;
+
+        for (position, filter) in filters.iter().enumerate() {
+            self.session
+                .execute_unpaged(
+                    &statements.insert_filter,
+                    (
+                        &channel_id,
+                        position as i32,
+                        &filter.pattern,
+                        &filter.regex,
+                        _action_name(filter.action),
+                    ),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn filters(
+        &self,
+        channel_id: i64,
+    ) -> Result<Vec<ModerationFilter>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list_filters, (&channel_id,))
+            .await?
+            .into_rows_result()?;
+
+        let mut filters = Vec::new();
+        for row in result.rows::<_FilterRow>()? {
+            let row = row?;
+            filters.push(ModerationFilter {
+                pattern: row.pattern,
+                regex: row.regex,
+                action: _parse_action(&row.action)?,
+            });
+        }
+
+        Ok(filters)
+    }
+
+    async fn flag(&self, flag: &Flag) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(
+                &statements.flag,
+                (
+                    _FLAG_SHARD,
+                    &flag.message_id,
+                    &flag.channel_id,
+                    &flag.reasons,
+                    &flag.flagged_at,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn get_flag(
+        &self,
+        message_id: i64,
+    ) -> Result<Option<Flag>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        Ok(self
+            .session
+            .execute_unpaged(&statements.get_flag, (_FLAG_SHARD, &message_id))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_FlagRow>()?
+            .map(Flag::from))
+    }
+
+    async fn flags(
+        &self,
+        before_id: i64,
+        limit: i32,
+    ) -> Result<Vec<Flag>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list_flags, (_FLAG_SHARD, &before_id, &limit))
+            .await?
+            .into_rows_result()?;
+
+        let mut flags = Vec::new();
+        for row in result.rows::<_FlagRow>()? {
+            flags.push(Flag::from(row?));
+        }
+
+        Ok(flags)
+    }
+
+    async fn resolve(
+        &self,
+        message_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.resolve, (_FLAG_SHARD, &message_id))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+use std::time::Duration;
+
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::prepared_statement;
+use scylla::statement::{Consistency, SerialConsistency};
+use tokio::sync;
+
+use crate::storage::{Pin, PinRepository};
+
+/// Number of compare-and-set attempts to pin a message before giving up under contention.
+const _MAX_PIN_ATTEMPTS: u32 = 8;
+
+/// Delay before retrying to pin a message, doubled after each attempt and jittered.
+const _PIN_BACKOFF: Duration = Duration::from_millis(4);
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    pin: prepared_statement::PreparedStatement,
+    unpin: prepared_statement::PreparedStatement,
+    list: prepared_statement::PreparedStatement,
+    versions_serial: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _PinRow {
+    channel_id: i64,
+
+    /// Null in the single row of a channel whose messages were all unpinned, which still holds its version
+    message_id: Option<i64>,
+    pinned_by: Option<i64>,
+    pinned_at: Option<i64>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _PinVersionRow {
+    /// Null when no message of the channel is pinned anymore
+    message_id: Option<i64>,
+    pin_version: Option<i64>,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut pin = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.pins_by_channel
+            SET pinned_by = ?, pinned_at = ?, pin_version = ?
+            WHERE channel_id = ? AND message_id = ?
+            IF pin_version = ?",
+        ))
+        .await?;
+    pin.set_consistency(storage.consistency.lightweight_transactions);
+    pin.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut unpin = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.pins_by_channel
+            WHERE channel_id = ? AND message_id = ?
+            IF EXISTS",
+        ))
+        .await?;
+    unpin.set_consistency(storage.consistency.lightweight_transactions);
+    unpin.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut list = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT channel_id, message_id, pinned_by, pinned_at
+            FROM ${data}.pins_by_channel
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    list.set_consistency(storage.consistency.reads);
+
+    // Read the latest committed pins before a compare-and-set, including uncommitted Paxos rounds
+    let mut versions_serial = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT message_id, pin_version
+            FROM ${data}.pins_by_channel
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    versions_serial.set_consistency(match storage.consistency.serial {
+        SerialConsistency::Serial => Consistency::Serial,
+        SerialConsistency::LocalSerial => Consistency::LocalSerial,
+    });
+
+    Ok(_Statements {
+        pin,
+        unpin,
+        list,
+        versions_serial,
+    })
+}
+
+#[tonic::async_trait]
+impl PinRepository for super::ScyllaStorage {
+    async fn pin(
+        &self,
+        pin: &Pin,
+        max_pins: usize,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        // Every pin bumps the version of the channel, so that pins counted before a concurrent pin cannot be used
+        for attempt in 0.._MAX_PIN_ATTEMPTS {
+            let result = self
+                .session
+                .execute_unpaged(&statements.versions_serial, (&pin.channel_id,))
+                .await?
+                .into_rows_result()?;
+            let mut version = None;
+            let mut pinned = Vec::new();
+            for row in result.rows::<_PinVersionRow>()? {
+                let row = row?;
+                version = row.pin_version;
+                pinned.extend(row.message_id);
+            }
+            if pinned.len() >= max_pins && !pinned.contains(&pin.message_id) {
+                return Ok(false);
+            }
+
+            let row = self
+                .session
+                .execute_unpaged(
+                    &statements.pin,
+                    (
+                        &pin.pinned_by,
+                        &pin.pinned_at,
+                        version.unwrap_or(0) + 1,
+                        &pin.channel_id,
+                        &pin.message_id,
+                        version,
+                    ),
+                )
+                .await?
+                .into_rows_result()?
+                .single_row::<Row>()?;
+            if matches!(row.columns.first(), Some(Some(CqlValue::Boolean(true)))) {
+                return Ok(true);
+            }
+
+            // Spread the retries of concurrent pins, which would otherwise keep conflicting
+            let backoff = _PIN_BACKOFF * 2u32.pow(attempt);
+            tokio::time::sleep(backoff.mul_f64(rand::random_range(0.5..1.0))).await;
+        }
+
+        Err(format!(
+            "Unable to pin message {} after {} attempts",
+            pin.message_id, _MAX_PIN_ATTEMPTS
+        )
+        .into())
+    }
+
+    async fn unpin(
+        &self,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.unpin, (&channel_id, &message_id))
+            .await?
+            .into_rows_result()?
+            .single_row::<Row>()?;
+
+        Ok(matches!(
+            row.columns.first(),
+            Some(Some(CqlValue::Boolean(true)))
+        ))
+    }
+
+    async fn list(
+        &self,
+        channel_id: i64,
+    ) -> Result<Vec<Pin>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list, (&channel_id,))
+            .await?
+            .into_rows_result()?;
+
+        let mut pins = Vec::new();
+        for row in result.rows::<_PinRow>()? {
+            let row = row?;
+            if let Some(message_id) = row.message_id {
+                pins.push(Pin {
+                    channel_id: row.channel_id,
+                    message_id,
+                    pinned_by: row.pinned_by.unwrap_or_default(),
+                    pinned_at: row.pinned_at.unwrap_or_default(),
+                });
+            }
+        }
+
+        Ok(pins)
+    }
+}
@@ -0,0 +1,123 @@
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::RateLimitRepository;
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    get: prepared_statement::PreparedStatement,
+    insert: prepared_statement::PreparedStatement,
+    update: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _RateLimitRow {
+    arrival: i64,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    // Rate limits are only written by lightweight transactions, reading their committed state requires a quorum
+    let mut get = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT arrival
+            FROM ${data}.rate_limits
+            WHERE key = ?",
+        ))
+        .await?;
+    get.set_consistency(storage.consistency.lightweight_transactions);
+
+    let mut insert = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.rate_limits (key, arrival)
+            VALUES (?, ?)
+            IF NOT EXISTS
+            USING TTL ?",
+        ))
+        .await?;
+    insert.set_consistency(storage.consistency.lightweight_transactions);
+    insert.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut update = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.rate_limits
+            USING TTL ?
+            SET arrival = ?
+            WHERE key = ?
+            IF arrival = ?",
+        ))
+        .await?;
+    update.set_consistency(storage.consistency.lightweight_transactions);
+    update.set_serial_consistency(Some(storage.consistency.serial));
+
+    Ok(_Statements {
+        get,
+        insert,
+        update,
+    })
+}
+
+#[tonic::async_trait]
+impl RateLimitRepository for super::ScyllaStorage {
+    async fn get(
+        &self,
+        key: &str,
+    ) -> Result<Option<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        Ok(self
+            .session
+            .execute_unpaged(&statements.get, (key,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_RateLimitRow>()?
+            .map(|row| row.arrival))
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: Option<i64>,
+        value: i64,
+        ttl_seconds: i32,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = match expected {
+            None => {
+                self.session
+                    .execute_unpaged(&statements.insert, (key, &value, &ttl_seconds))
+                    .await?
+            }
+            Some(expected) => {
+                self.session
+                    .execute_unpaged(&statements.update, (&ttl_seconds, &value, key, &expected))
+                    .await?
+            }
+        };
+        let row = result.into_rows_result()?.single_row::<Row>()?;
+
+        Ok(matches!(
+            row.columns.first(),
+            Some(Some(CqlValue::Boolean(true)))
+        ))
+    }
+}
@@ -0,0 +1,139 @@
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{ReadState, ReadStateRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    insert: prepared_statement::PreparedStatement,
+    advance: prepared_statement::PreparedStatement,
+    list: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _ReadStateRow {
+    channel_id: i64,
+    last_read_id: i64,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut insert = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.read_states (user_id, channel_id, last_read_id)
+            VALUES (?, ?, ?)
+            IF NOT EXISTS",
+        ))
+        .await?;
+    insert.set_consistency(storage.consistency.lightweight_transactions);
+    insert.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut advance = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.read_states
+            SET last_read_id = ?
+            WHERE user_id = ? AND channel_id = ?
+            IF last_read_id < ?",
+        ))
+        .await?;
+    advance.set_consistency(storage.consistency.lightweight_transactions);
+    advance.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut list = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT channel_id, last_read_id
+            FROM ${data}.read_states
+            WHERE user_id = ?",
+        ))
+        .await?;
+    list.set_consistency(storage.consistency.reads);
+
+    Ok(_Statements {
+        insert,
+        advance,
+        list,
+    })
+}
+
+#[tonic::async_trait]
+impl ReadStateRepository for super::ScyllaStorage {
+    async fn mark_read(
+        &self,
+        user_id: i64,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<i64, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+
+        loop {
+            // A failed condition returns `[applied]` followed by the current position, null if there is none yet
+            let row = self
+                .session
+                .execute_unpaged(
+                    &statements.advance,
+                    (&message_id, &user_id, &channel_id, &message_id),
+                )
+                .await?
+                .into_rows_result()?
+                .single_row::<Row>()?;
+            match (row.columns.first(), row.columns.get(1)) {
+                (Some(Some(CqlValue::Boolean(true))), _) => return Ok(message_id),
+                (_, Some(Some(CqlValue::BigInt(current)))) => return Ok(*current),
+                _ => {}
+            }
+
+            let row = self
+                .session
+                .execute_unpaged(&statements.insert, (&user_id, &channel_id, &message_id))
+                .await?
+                .into_rows_result()?
+                .single_row::<Row>()?;
+            if matches!(row.columns.first(), Some(Some(CqlValue::Boolean(true)))) {
+                return Ok(message_id);
+            }
+        }
+    }
+
+    async fn list(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<ReadState>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list, (&user_id,))
+            .await?
+            .into_rows_result()?;
+
+        let mut states = Vec::new();
+        for row in result.rows::<_ReadStateRow>()? {
+            let row = row?;
+            states.push(ReadState {
+                channel_id: row.channel_id,
+                last_read_id: row.last_read_id,
+            });
+        }
+
+        Ok(states)
+    }
+}
@@ -0,0 +1,195 @@
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::RelationshipRepository;
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    block: prepared_statement::PreparedStatement,
+    unblock: prepared_statement::PreparedStatement,
+    list_blocked: prepared_statement::PreparedStatement,
+    mute: prepared_statement::PreparedStatement,
+    unmute: prepared_statement::PreparedStatement,
+    list_muted: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _BlockedRow {
+    blocked_id: i64,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _MutedRow {
+    channel_id: i64,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut block = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.blocked_users_by_user (user_id, blocked_id)
+            VALUES (?, ?)",
+        ))
+        .await?;
+    block.set_consistency(storage.consistency.writes);
+
+    let mut unblock = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.blocked_users_by_user
+            WHERE user_id = ? AND blocked_id = ?",
+        ))
+        .await?;
+    unblock.set_consistency(storage.consistency.writes);
+
+    let mut list_blocked = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT blocked_id
+            FROM ${data}.blocked_users_by_user
+            WHERE user_id = ?",
+        ))
+        .await?;
+    list_blocked.set_consistency(storage.consistency.reads);
+
+    let mut mute = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.muted_channels_by_user (user_id, channel_id)
+            VALUES (?, ?)",
+        ))
+        .await?;
+    mute.set_consistency(storage.consistency.writes);
+
+    let mut unmute = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"DELETE FROM ${data}.muted_channels_by_user
+            WHERE user_id = ? AND channel_id = ?",
+        ))
+        .await?;
+    unmute.set_consistency(storage.consistency.writes);
+
+    let mut list_muted = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT channel_id
+            FROM ${data}.muted_channels_by_user
+            WHERE user_id = ?",
+        ))
+        .await?;
+    list_muted.set_consistency(storage.consistency.reads);
+
+    Ok(_Statements {
+        block,
+        unblock,
+        list_blocked,
+        mute,
+        unmute,
+        list_muted,
+    })
+}
+
+#[tonic::async_trait]
+impl RelationshipRepository for super::ScyllaStorage {
+    async fn block(
+        &self,
+        user_id: i64,
+        blocked_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.block, (&user_id, &blocked_id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn unblock(
+        &self,
+        user_id: i64,
+        blocked_id: i64,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.unblock, (&user_id, &blocked_id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn blocked(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list_blocked, (&user_id,))
+            .await?
+            .into_rows_result()?;
+
+        let mut blocked = Vec::new();
+        for row in result.rows::<_BlockedRow>()? {
+            blocked.push(row?.blocked_id);
+        }
+
+        Ok(blocked)
+    }
+
+    async fn set_muted(
+        &self,
+        user_id: i64,
+        channel_id: i64,
+        muted: bool,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let statement = if muted {
+            &statements.mute
+        } else {
+            &statements.unmute
+        };
+        self.session
+            .execute_unpaged(statement, (&user_id, &channel_id))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn muted(
+        &self,
+        user_id: i64,
+    ) -> Result<Vec<i64>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let result = self
+            .session
+            .execute_unpaged(&statements.list_muted, (&user_id,))
+            .await?
+            .into_rows_result()?;
+
+        let mut muted = Vec::new();
+        for row in result.rows::<_MutedRow>()? {
+            muted.push(row?.channel_id);
+        }
+
+        Ok(muted)
+    }
+}
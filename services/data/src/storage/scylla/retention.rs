@@ -0,0 +1,225 @@
+use scylla::frame::response::result::{CqlValue, Row};
+use scylla::macros;
+use scylla::prepared_statement;
+use tokio::sync;
+
+use crate::storage::{RetentionPolicy, RetentionRepository};
+
+/// A singleton of [`_Statements`], initialized using [`sync::OnceCell`]
+static _STATEMENTS: sync::OnceCell<_Statements> = sync::OnceCell::const_new();
+
+/// Private struct holding prepared statements in this module.
+///
+/// Access the underlying statements via the singleton [`_STATEMENTS`].
+/// See also: [`_prepare`].
+struct _Statements {
+    get: prepared_statement::PreparedStatement,
+    set: prepared_statement::PreparedStatement,
+    get_applied: prepared_statement::PreparedStatement,
+    set_applied: prepared_statement::PreparedStatement,
+    take_lease: prepared_statement::PreparedStatement,
+    extend_lease: prepared_statement::PreparedStatement,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _RetentionPolicyRow {
+    retention_days: Option<i32>,
+    inherit: Option<bool>,
+    legal_hold: Option<bool>,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, macros::DeserializeRow)]
+struct _LeaseRow {
+    #[scylla(rename = "[applied]")]
+    applied: bool,
+    name: Option<String>,
+    owner: Option<String>,
+}
+
+/// Constructs a [`_Statements`] to initialize the singleton [`_STATEMENTS`].
+///
+/// This function is automatically called by [`sync::OnceCell`], a reference to
+/// [`_STATEMENTS`] can be retrieved via:
+/// ```rust
+/// let statements = _STATEMENTS.get_or_try_init(|| _prepare(storage)).await?;
+/// ```
+async fn _prepare(
+    storage: &super::ScyllaStorage,
+) -> Result<_Statements, Box<dyn std::error::Error + Send + Sync>> {
+    let mut get = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT retention_days, inherit, legal_hold
+            FROM ${data}.retention_policies
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    get.set_consistency(storage.consistency.reads);
+
+    let mut set = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.retention_policies (channel_id, retention_days, inherit, legal_hold)
+            VALUES (?, ?, ?, ?)",
+        ))
+        .await?;
+    set.set_consistency(storage.consistency.writes);
+
+    let mut get_applied = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"SELECT ttl_seconds
+            FROM ${data}.retention_applied
+            WHERE channel_id = ?",
+        ))
+        .await?;
+    get_applied.set_consistency(storage.consistency.reads);
+
+    let mut set_applied = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.retention_applied (channel_id, ttl_seconds)
+            VALUES (?, ?)",
+        ))
+        .await?;
+    set_applied.set_consistency(storage.consistency.writes);
+
+    let mut take_lease = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"INSERT INTO ${data}.retention_lease (name, owner)
+            VALUES ('sweep', ?)
+            IF NOT EXISTS
+            USING TTL ?",
+        ))
+        .await?;
+    take_lease.set_consistency(storage.consistency.lightweight_transactions);
+    take_lease.set_serial_consistency(Some(storage.consistency.serial));
+
+    let mut extend_lease = storage
+        .session
+        .prepare(storage.layout.resolve(
+            r"UPDATE ${data}.retention_lease
+            USING TTL ?
+            SET owner = ?
+            WHERE name = 'sweep'
+            IF owner = ?",
+        ))
+        .await?;
+    extend_lease.set_consistency(storage.consistency.lightweight_transactions);
+    extend_lease.set_serial_consistency(Some(storage.consistency.serial));
+
+    Ok(_Statements {
+        get,
+        set,
+        get_applied,
+        set_applied,
+        take_lease,
+        extend_lease,
+    })
+}
+
+#[tonic::async_trait]
+impl RetentionRepository for super::ScyllaStorage {
+    async fn get(
+        &self,
+        channel_id: i64,
+    ) -> Result<Option<RetentionPolicy>, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        Ok(self
+            .session
+            .execute_unpaged(&statements.get, (&channel_id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<_RetentionPolicyRow>()?
+            .map(|row| RetentionPolicy {
+                retention_days: row.retention_days.unwrap_or(0),
+                inherit: row.inherit.unwrap_or(false),
+                legal_hold: row.legal_hold.unwrap_or(false),
+            }))
+    }
+
+    async fn set(
+        &self,
+        channel_id: i64,
+        policy: &RetentionPolicy,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(
+                &statements.set,
+                (
+                    &channel_id,
+                    &policy.retention_days,
+                    &policy.inherit,
+                    &policy.legal_hold,
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn applied_ttl(
+        &self,
+        channel_id: i64,
+    ) -> Result<i32, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        Ok(self
+            .session
+            .execute_unpaged(&statements.get_applied, (&channel_id,))
+            .await?
+            .into_rows_result()?
+            .maybe_first_row::<(Option<i32>,)>()?
+            .and_then(|(ttl_seconds,)| ttl_seconds)
+            .unwrap_or(0))
+    }
+
+    async fn set_applied_ttl(
+        &self,
+        channel_id: i64,
+        ttl_seconds: i32,
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        self.session
+            .execute_unpaged(&statements.set_applied, (&channel_id, &ttl_seconds))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn lease(
+        &self,
+        owner: &str,
+        ttl_seconds: i32,
+    ) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+        let statements = _STATEMENTS.get_or_try_init(|| _prepare(self)).await?;
+        let row = self
+            .session
+            .execute_unpaged(&statements.take_lease, (owner, &ttl_seconds))
+            .await?
+            .into_rows_result()?
+            .single_row::<_LeaseRow>()?;
+        if row.applied {
+            return Ok(true);
+        }
+        if row.owner.as_deref() != Some(owner) {
+            return Ok(false);
+        }
+
+        // The columns returned alongside `[applied]` by a conditional update vary, only inspect the first one
+        let row = self
+            .session
+            .execute_unpaged(&statements.extend_lease, (&ttl_seconds, owner, owner))
+            .await?
+            .into_rows_result()?
+            .single_row::<Row>()?;
+
+        Ok(matches!(
+            row.columns.first(),
+            Some(Some(CqlValue::Boolean(true)))
+        ))
+    }
+}
@@ -11,6 +11,11 @@ class FetchMessageQuery {
   public limit: number = 50;
 }
 
+interface ChannelPage {
+  channels: Channel[];
+  cursor: string;
+}
+
 export class Channel extends Snowflake {
   private static readonly _cache = new Map<bigint, Channel>();
   public static channels: Channel[] = [];
@@ -103,14 +108,26 @@ export class Channel extends Snowflake {
   }
 
   public static async query(): Promise<Channel[]> {
-    const response = await client.get<string>(
-      "/channels",
-      { transformResponse: [data => data] },
-    );
-    const channels = JSONBigInt.parse(response.data) as Channel[];
-    for (const channel of channels) {
-      Channel._cache.set(channel.id, channel);
-    }
+    const channels: Channel[] = [];
+
+    // Pages may hold fewer channels than requested while more remain, only an empty cursor ends the scan
+    let cursor = "";
+    do {
+      const response = await client.get<string>(
+        "/channels",
+        {
+          params: { cursor, page_size: 500 },
+          transformResponse: [data => data],
+        },
+      );
+      const page = JSONBigInt.parse(response.data) as ChannelPage;
+      for (const channel of page.channels) {
+        Channel._cache.set(channel.id, channel);
+      }
+
+      channels.push(...page.channels);
+      cursor = page.cursor;
+    } while (cursor);
 
     channels.sort((a, b) => Number(a.id - b.id));
     Channel.channels = channels;
@@ -118,7 +118,10 @@ class Client {
   }
 
   public websocket(path: string): WebSocket {
-    return new WebSocket(Client._WS_URL + path);
+    // Browsers cannot send headers with websockets, so the access token is passed in the query string
+    const accessToken = localStorage.getItem("access_token") || "";
+    const separator = path.includes("?") ? "&" : "?";
+    return new WebSocket(`${Client._WS_URL}${path}${separator}token=${encodeURIComponent(accessToken)}`);
   }
 
   public get<T, R = AxiosResponse<T>, D = unknown>(url: string, config?: AxiosRequestConfig<D>): Promise<R> {